@@ -0,0 +1,105 @@
+//! Echo throughput with every reply written straight to the output, against the same replies
+//! gathered in a buffer and written once per message.
+//!
+//! The output is `/dev/null`, so that each write to it costs the syscall it would on stdout
+//! and nothing more. Unbuffered, serde hands each reply to it in some fifty pieces.
+
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{BufWriter, Cursor, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use serde_json::json;
+use tempest::clock::SystemClock;
+use tempest::runtime;
+use tempest::transport::LineTransport;
+use tempest::workloads::EchoNode;
+
+const MESSAGES: u64 = 1000;
+
+/// `/dev/null`, counting the writes that reach it.
+struct Null {
+    file: File,
+    writes: Arc<AtomicUsize>,
+}
+
+impl Null {
+    fn new(writes: &Arc<AtomicUsize>) -> Self {
+        Self {
+            file: File::create("/dev/null").unwrap(),
+            writes: Arc::clone(writes),
+        }
+    }
+}
+
+impl Write for Null {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// `init`, then `MESSAGES` echoes.
+fn input() -> Vec<u8> {
+    let init = json!({
+        "src": "c0",
+        "dest": "n0",
+        "body": { "type": "init", "msg_id": 1, "node_id": "n0", "node_ids": ["n0"] },
+    });
+    let echoes = (0..MESSAGES).map(|msg_id| {
+        json!({
+            "src": "c1",
+            "dest": "n0",
+            "body": { "type": "echo", "msg_id": msg_id, "echo": "Please echo 35" },
+        })
+    });
+    let lines: Vec<String> = std::iter::once(init)
+        .chain(echoes)
+        .map(|line| format!("{line}\n"))
+        .collect();
+    lines.concat().into_bytes()
+}
+
+fn echo<W: Write + 'static>(input: &[u8], output: W) {
+    let input = Cursor::new(input.to_vec());
+    let transport = LineTransport::new(output);
+    runtime::run_with_transport(input, transport, Arc::new(SystemClock), |_| Ok(EchoNode)).unwrap();
+}
+
+fn throughput(c: &mut Criterion) {
+    let input = input();
+    let writes = Arc::new(AtomicUsize::new(0));
+
+    echo(&input, Null::new(&writes));
+    let unbuffered = writes.swap(0, Ordering::Relaxed);
+    echo(&input, BufWriter::new(Null::new(&writes)));
+    let buffered = writes.swap(0, Ordering::Relaxed);
+    // And init_ok.
+    let replies = (MESSAGES + 1) as f64;
+    eprintln!(
+        "Writes per reply: {:.1} unbuffered, {:.1} buffered.",
+        unbuffered as f64 / replies,
+        buffered as f64 / replies,
+    );
+
+    let mut group = c.benchmark_group("echo");
+    group.throughput(Throughput::Elements(MESSAGES));
+
+    group.bench_function("unbuffered", |b| {
+        b.iter(|| echo(black_box(&input), Null::new(&writes)))
+    });
+    group.bench_function("buffered", |b| {
+        b.iter(|| echo(black_box(&input), BufWriter::new(Null::new(&writes))))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
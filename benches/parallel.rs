@@ -0,0 +1,131 @@
+//! A CPU-bound handler on the single-threaded runtime, against the same on worker threads.
+//!
+//! The gap is as wide as the machine has cores to spare: on one core, the workers only add
+//! their locking to the same work.
+
+use std::hint::black_box;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tempest::clock::SystemClock;
+use tempest::message::Message;
+use tempest::node::Node;
+use tempest::parallel::{self, SharedNode, SharedRpc};
+use tempest::rpc::Rpc;
+use tempest::runtime;
+use tempest::transport::InMemoryTransport;
+
+const MESSAGES: u64 = 200;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum HashPayload {
+    Hash { seed: u64 },
+    HashOk { digest: u64 },
+}
+
+/// Enough mixing of `seed` to take a while.
+fn digest(seed: u64) -> u64 {
+    (0..50_000).fold(seed, |hash, round| {
+        let hash = (hash ^ round).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        hash ^ (hash >> 29)
+    })
+}
+
+struct Hasher;
+
+impl Node for Hasher {
+    type Payload = HashPayload;
+
+    fn step(&mut self, input: Message<HashPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        let HashPayload::Hash { seed } = input.body.payload else {
+            return Ok(());
+        };
+        rpc.reply(
+            &input,
+            HashPayload::HashOk {
+                digest: digest(seed),
+            },
+        )
+    }
+}
+
+impl SharedNode for Hasher {
+    type Payload = HashPayload;
+
+    fn step(&self, input: Message<HashPayload>, rpc: &SharedRpc) -> anyhow::Result<()> {
+        let HashPayload::Hash { seed } = input.body.payload else {
+            return Ok(());
+        };
+        rpc.reply(
+            &input,
+            HashPayload::HashOk {
+                digest: digest(seed),
+            },
+        )
+    }
+}
+
+/// `init`, then a `hash` from each of `MESSAGES` clients.
+fn input() -> Vec<u8> {
+    let init = json!({
+        "src": "c0",
+        "dest": "n0",
+        "body": { "type": "init", "msg_id": 1, "node_id": "n0", "node_ids": ["n0"] },
+    });
+    let hashes = (0..MESSAGES).map(|seed| {
+        json!({
+            "src": format!("c{seed}"),
+            "dest": "n0",
+            "body": { "type": "hash", "msg_id": 1, "seed": seed },
+        })
+    });
+    let lines: Vec<String> = std::iter::once(init)
+        .chain(hashes)
+        .map(|line| line.to_string())
+        .collect();
+    lines.join("\n").into_bytes()
+}
+
+fn handlers(c: &mut Criterion) {
+    let input = input();
+    let threads = thread::available_parallelism().map_or(4, |threads| threads.get().max(2));
+
+    let mut group = c.benchmark_group("cpu_bound_handler");
+    group.throughput(Throughput::Elements(MESSAGES));
+    group.sample_size(10);
+
+    group.bench_function("single_threaded", |b| {
+        b.iter(|| {
+            let transport = InMemoryTransport::new();
+            let clock = Arc::new(SystemClock);
+            let input = Cursor::new(input.clone());
+            runtime::run_with_transport(input, transport.clone(), clock, |_| Ok(Hasher)).unwrap();
+            black_box(transport.take())
+        })
+    });
+
+    group.bench_with_input(
+        BenchmarkId::new("workers", threads),
+        &threads,
+        |b, &threads| {
+            b.iter(|| {
+                let transport = InMemoryTransport::new();
+                let input = Cursor::new(input.clone());
+                parallel::run_with_transport(threads, input, transport.clone(), |_| Ok(Hasher))
+                    .unwrap();
+                black_box(transport.take())
+            })
+        },
+    );
+
+    group.finish();
+}
+
+criterion_group!(benches, handlers);
+criterion_main!(benches);
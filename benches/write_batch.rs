@@ -0,0 +1,146 @@
+//! A broadcast that's acknowledged and passed on to 10 peers, with the 11 messages it sends
+//! batched into two writes, the reply and then the gossip, against a flush after each of them.
+//!
+//! The output is `/dev/null`, as in the echo bench, so each flush costs one write syscall.
+
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{BufWriter, Cursor, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use serde_json::{json, Value};
+use tempest::clock::SystemClock;
+use tempest::message::Message;
+use tempest::node::Node;
+use tempest::rpc::Rpc;
+use tempest::runtime;
+use tempest::transport::{LineTransport, Transport};
+
+const MESSAGES: u64 = 500;
+
+const FANOUT: usize = 10;
+
+/// `/dev/null`, counting the writes that reach it.
+struct Null {
+    file: File,
+    writes: Arc<AtomicUsize>,
+}
+
+impl Null {
+    fn new(writes: &Arc<AtomicUsize>) -> Self {
+        Self {
+            file: File::create("/dev/null").unwrap(),
+            writes: Arc::clone(writes),
+        }
+    }
+}
+
+impl Write for Null {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Flushes after every message, as the runtime did before batching an event's writes.
+struct FlushEach<T>(T);
+
+impl<T: Transport> Transport for FlushEach<T> {
+    fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()> {
+        self.0.send(msg)?;
+        self.0.flush()
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Acknowledges each broadcast, and passes it on to every peer.
+struct FanOut {
+    peers: Vec<String>,
+}
+
+impl Node for FanOut {
+    type Payload = Value;
+
+    fn step(&mut self, input: Message<Value>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        let message = &input.body.payload["message"];
+        for peer in &self.peers {
+            rpc.notify(peer, json!({"type": "gossip", "message": message}))?;
+        }
+        rpc.reply(&input, json!({"type": "broadcast_ok"}))
+    }
+}
+
+/// `init` for a node with `FANOUT` peers, then `MESSAGES` broadcasts.
+fn input() -> Vec<u8> {
+    let node_ids: Vec<String> = (0..=FANOUT).map(|index| format!("n{index}")).collect();
+    let init = json!({
+        "src": "c0",
+        "dest": "n0",
+        "body": { "type": "init", "msg_id": 1, "node_id": "n0", "node_ids": node_ids },
+    });
+    let broadcasts = (0..MESSAGES).map(|message| {
+        json!({
+            "src": "c1",
+            "dest": "n0",
+            "body": { "type": "broadcast", "msg_id": message, "message": message },
+        })
+    });
+    let lines: Vec<String> = std::iter::once(init)
+        .chain(broadcasts)
+        .map(|line| format!("{line}\n"))
+        .collect();
+    lines.concat().into_bytes()
+}
+
+fn broadcast(input: &[u8], transport: impl Transport + Send + 'static) {
+    let input = Cursor::new(input.to_vec());
+    runtime::run_with_transport(input, transport, Arc::new(SystemClock), |context| {
+        Ok(FanOut {
+            peers: context.peers().map(str::to_owned).collect(),
+        })
+    })
+    .unwrap();
+}
+
+fn flushes(c: &mut Criterion) {
+    let input = input();
+    let writes = Arc::new(AtomicUsize::new(0));
+    let batched = || LineTransport::new(BufWriter::new(Null::new(&writes)));
+    let flushed_each = || FlushEach(LineTransport::new(BufWriter::new(Null::new(&writes))));
+
+    broadcast(&input, flushed_each());
+    let unbatched = writes.swap(0, Ordering::Relaxed);
+    broadcast(&input, batched());
+    let batches = writes.swap(0, Ordering::Relaxed);
+    // And init.
+    let events = (MESSAGES + 1) as f64;
+    eprintln!(
+        "Writes per broadcast: {:.1} flushing each message, {:.1} batched.",
+        unbatched as f64 / events,
+        batches as f64 / events,
+    );
+
+    let mut group = c.benchmark_group("fan_out_of_10");
+    group.throughput(Throughput::Elements(MESSAGES));
+
+    group.bench_function("flushed_each", |b| {
+        b.iter(|| broadcast(black_box(&input), flushed_each()))
+    });
+    group.bench_function("batched", |b| {
+        b.iter(|| broadcast(black_box(&input), batched()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, flushes);
+criterion_main!(benches);
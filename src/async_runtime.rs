@@ -0,0 +1,674 @@
+//! An async alternative to [`crate::runtime`], on tokio.
+//!
+//! Handlers are `async fn`s that can `await` the reply to a request inline. Stdin is read
+//! line by line on its own task, which hands replies straight to the request awaiting them.
+//! Everything else reaches the node one message at a time, between ticks, just like with
+//! [`crate::runtime::run`].
+//!
+//! Only available with the `tokio` feature.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::deadletter::{self, Reason};
+use crate::kv::{self, KvError, KvReply, KvRequest, LIN_KV, LWW_KV, SEQ_KV};
+use crate::log;
+use crate::message::{error_reply, Body, ErrorCode, InitPayload, Message};
+use crate::metrics::{self, Metrics, ProcStats};
+use crate::node::{NodeContext, NodeError, Persistent};
+use crate::rpc::{can_reply, validate, MsgIdGen, RpcError, RPC_TIMEOUT, UNREACHABLE_AFTER};
+use crate::runtime::{
+    self, channel_capacity, decode, handshake, is_for, is_init, parse_line, reinit, Handshake,
+};
+
+/// A Maelstrom node, driven by [`run`].
+// The runtime is single-threaded, so handler futures needn't be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncNode: Sized {
+    /// Every message this node can receive or send, other than the `init` handshake.
+    type Payload: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Runs once, right after the runtime has answered Maelstrom's `init`, and before anything
+    /// else reaches the node.
+    async fn init(&mut self, _context: &NodeContext, _rpc: &mut AsyncRpc) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Handles a message that doesn't answer a request awaited through [`AsyncRpc::request`].
+    async fn step(
+        &mut self,
+        input: Message<Self::Payload>,
+        rpc: &mut AsyncRpc,
+    ) -> anyhow::Result<()>;
+
+    /// How often [`AsyncNode::tick`] should run, if at all. Asked again after every tick, so
+    /// the interval may change as the node goes.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Runs periodically, even when no messages are coming in.
+    async fn tick(&mut self, _rpc: &mut AsyncRpc) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Runs once stdin has closed, right before the runtime flushes stdout and exits.
+    async fn on_shutdown(&mut self, _rpc: &mut AsyncRpc) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Like [`Node::summary`](crate::node::Node::summary).
+    fn summary(&self) -> Option<Value> {
+        None
+    }
+
+    /// Like [`Node::persistent`](crate::node::Node::persistent).
+    fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+        None
+    }
+}
+
+/// Requests awaiting their reply in [`AsyncRpc::request`], keyed by `msg_id`.
+type Waiters = Arc<Mutex<HashMap<usize, oneshot::Sender<Message<Value>>>>>;
+
+/// A node's connection to the outside world, like [`crate::rpc::Rpc`] but async.
+pub struct AsyncRpc {
+    node_id: Option<String>,
+    metrics: Metrics,
+    ids: MsgIdGen,
+    output: BufWriter<Box<dyn AsyncWrite + Unpin>>,
+    waiters: Waiters,
+    /// How many requests to each peer have timed out in a row.
+    timeouts: HashMap<String, u32>,
+    state_file: Option<PathBuf>,
+}
+
+impl AsyncRpc {
+    fn new(output: Box<dyn AsyncWrite + Unpin>, waiters: Waiters) -> anyhow::Result<Self> {
+        Ok(Self {
+            node_id: None,
+            metrics: Metrics::default(),
+            ids: MsgIdGen::default(),
+            output: BufWriter::new(output),
+            waiters,
+            timeouts: HashMap::new(),
+            state_file: runtime::state_file(),
+        })
+    }
+
+    /// A fresh `msg_id` for a message this node is about to send.
+    pub fn next_id(&mut self) -> usize {
+        self.ids.next()
+    }
+
+    /// This node's id, known once Maelstrom's `init` has come in.
+    pub fn node_id(&self) -> Result<&str, NodeError> {
+        self.node_id.as_deref().ok_or(NodeError::NotInitialized)
+    }
+
+    /// What this node has sent and received so far.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Sends `msg` as is, as a single line of JSON.
+    pub async fn send(&mut self, msg: &Message<impl Serialize>) -> anyhow::Result<()> {
+        msg.debug_assert_in_reply_to();
+        validate(msg)?;
+        log::log_send(msg);
+        self.metrics.record_sent(msg.kind());
+
+        let mut line = serde_json::to_vec(msg).map_err(NodeError::Encode)?;
+        line.push(b'\n');
+        metrics::record_written(line.len());
+        self.output
+            .write_all(&line)
+            .await
+            .map_err(NodeError::Write)?;
+        self.output.flush().await.map_err(NodeError::Write)?;
+
+        Ok(())
+    }
+
+    /// Sends `payload` in reply to `request`, under a fresh `msg_id`.
+    ///
+    /// A request without a `msg_id` can't be answered, so the reply is skipped.
+    pub async fn reply<P: Serialize>(
+        &mut self,
+        request: &Message<P>,
+        payload: impl Serialize,
+    ) -> anyhow::Result<()> {
+        if !can_reply(request)? {
+            return Ok(());
+        }
+
+        let reply = request.reply(self.next_id(), payload);
+        self.send(&reply).await
+    }
+
+    /// Sends `payload` to `destination` and waits for the reply, giving up after
+    /// [`RPC_TIMEOUT`].
+    pub async fn request(
+        &mut self,
+        destination: &str,
+        payload: impl Serialize,
+    ) -> Result<Message<Value>, RpcError> {
+        self.request_with_timeout(destination, payload, RPC_TIMEOUT)
+            .await
+    }
+
+    /// Sends `payload` to `destination` and waits for the reply or for `timeout` to pass.
+    ///
+    /// Once `destination` [is down](AsyncRpc::is_down), a timeout is
+    /// [`RpcError::Unreachable`] instead.
+    pub async fn request_with_timeout(
+        &mut self,
+        destination: &str,
+        payload: impl Serialize,
+        timeout: Duration,
+    ) -> Result<Message<Value>, RpcError> {
+        let id = self.next_id();
+        let request = Message {
+            source: self
+                .node_id()
+                .map_err(|error| RpcError::Send(error.into()))?
+                .to_owned(),
+            destination: destination.to_owned(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+
+        // Register before sending so the reply can't overtake us.
+        let (tx, rx) = oneshot::channel();
+        self.waiters
+            .lock()
+            .expect("Waiters lock poisoned.")
+            .insert(id, tx);
+
+        let sent = Instant::now();
+        if let Err(error) = self.send(&request).await {
+            self.forget(id);
+            return Err(RpcError::Send(error));
+        }
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(reply)) => {
+                self.metrics.record_received(reply.kind());
+                self.metrics.record_latency(destination, sent.elapsed());
+                self.heard_from(destination);
+                Ok(reply)
+            }
+            Ok(Err(_)) => Err(RpcError::Closed),
+            Err(_) => {
+                self.forget(id);
+                self.timed_out(destination);
+                if self.is_down(destination) {
+                    Err(RpcError::Unreachable)
+                } else {
+                    Err(RpcError::Timeout)
+                }
+            }
+        }
+    }
+
+    /// Like [`Rpc::is_down`](crate::rpc::Rpc::is_down).
+    pub fn is_down(&self, peer: &str) -> bool {
+        self.timeouts
+            .get(peer)
+            .is_some_and(|timeouts| *timeouts >= UNREACHABLE_AFTER)
+    }
+
+    /// Like [`Rpc::journal`](crate::rpc::Rpc::journal).
+    pub fn journal(&mut self, entry: impl Serialize) -> anyhow::Result<()> {
+        match &self.state_file {
+            Some(path) => runtime::journal(path, entry),
+            None => Ok(()),
+        }
+    }
+
+    fn heard_from(&mut self, peer: &str) {
+        if self.is_down(peer) {
+            log::info!("Heard from {peer} again.");
+        }
+        self.timeouts.remove(peer);
+    }
+
+    fn timed_out(&mut self, peer: &str) {
+        let timeouts = self.timeouts.entry(peer.to_owned()).or_default();
+        *timeouts += 1;
+        if *timeouts == UNREACHABLE_AFTER {
+            log::info!(
+                "{peer} looks down or partitioned away, after {UNREACHABLE_AFTER} timeouts."
+            );
+        }
+        self.metrics.timeouts += 1;
+    }
+
+    /// A client for the kv service called `service`.
+    pub fn kv(&mut self, service: &'static str) -> KvClient<'_> {
+        KvClient { rpc: self, service }
+    }
+
+    pub fn seq_kv(&mut self) -> KvClient<'_> {
+        self.kv(SEQ_KV)
+    }
+
+    pub fn lin_kv(&mut self) -> KvClient<'_> {
+        self.kv(LIN_KV)
+    }
+
+    pub fn lww_kv(&mut self) -> KvClient<'_> {
+        self.kv(LWW_KV)
+    }
+
+    fn forget(&mut self, id: usize) {
+        self.waiters
+            .lock()
+            .expect("Waiters lock poisoned.")
+            .remove(&id);
+    }
+}
+
+/// Typed requests to one of Maelstrom's key-value services, e.g.
+/// `rpc.lin_kv().cas(key, from, to, false).await?`.
+///
+/// The same requests as [`Rpc::read`](crate::rpc::Rpc::read) and friends, with errors that
+/// are [`KvError`]s rather than raw `error` bodies. Nothing is retried.
+pub struct KvClient<'a> {
+    rpc: &'a mut AsyncRpc,
+    service: &'static str,
+}
+
+impl KvClient<'_> {
+    pub async fn read(self, key: impl Serialize) -> Result<Value, KvError> {
+        let key = serde_json::to_value(key)?;
+        match self.request(KvRequest::Read { key }).await? {
+            KvReply::ReadOk { value } => Ok(value),
+            reply => Err(kv::unexpected(reply)),
+        }
+    }
+
+    pub async fn write(self, key: impl Serialize, value: impl Serialize) -> Result<(), KvError> {
+        let request = KvRequest::Write {
+            key: serde_json::to_value(key)?,
+            value: serde_json::to_value(value)?,
+        };
+        match self.request(request).await? {
+            KvReply::WriteOk {} => Ok(()),
+            reply => Err(kv::unexpected(reply)),
+        }
+    }
+
+    pub async fn cas(
+        self,
+        key: impl Serialize,
+        from: impl Serialize,
+        to: impl Serialize,
+        create_if_missing: bool,
+    ) -> Result<(), KvError> {
+        let request = KvRequest::Cas {
+            key: serde_json::to_value(key)?,
+            from: serde_json::to_value(from)?,
+            to: serde_json::to_value(to)?,
+            create_if_not_exists: create_if_missing,
+        };
+        match self.request(request).await? {
+            KvReply::CasOk {} => Ok(()),
+            reply => Err(kv::unexpected(reply)),
+        }
+    }
+
+    async fn request(self, request: KvRequest) -> Result<KvReply, KvError> {
+        let reply = self.rpc.request(self.service, request).await?;
+        kv::decode(reply)
+    }
+}
+
+/// Like [`crate::runtime::run`], on a single-threaded tokio runtime.
+///
+/// Ticks, [`set_state_file`](crate::runtime::set_state_file) and
+/// [`set_summary_path`](crate::metrics::set_summary_path) work the same as there. Unlike
+/// [`crate::runtime::run`], this runtime:
+///
+/// - only has requests awaited inline, not [`Rpc::call`](crate::rpc::Rpc::call) and its
+///   callbacks, [`Rpc::after`](crate::rpc::Rpc::after), forwarding, gathering or retries;
+/// - ignores [`set_max_in_flight`](crate::rpc::set_max_in_flight), since a handler awaits its
+///   requests one at a time;
+/// - flushes every message as it is sent, rather than batching what a handler sends;
+/// - drops a reply that comes in after its request has timed out as one matching no request,
+///   rather than as a late one.
+pub fn run<N: AsyncNode>(
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Could not start the tokio runtime.")?
+        .block_on(main_loop(
+            tokio::io::stdin(),
+            Box::new(tokio::io::stdout()),
+            init,
+        ))
+}
+
+/// Serves the messages read from `input` until it ends, writing everything the node sends to
+/// `output`.
+async fn main_loop<N: AsyncNode>(
+    input: impl AsyncRead + Unpin + Send + 'static,
+    output: Box<dyn AsyncWrite + Unpin>,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<()> {
+    metrics::start();
+    let waiters = Waiters::default();
+    let mut rpc = AsyncRpc::new(output, Arc::clone(&waiters))?;
+
+    let (tx, mut rx) = mpsc::channel(channel_capacity()?);
+    let reader = tokio::spawn(read_input(input, tx, waiters));
+
+    let Some(mut node) = start(&mut rx, &mut rpc, init).await? else {
+        return reader.await.context("Input reader panicked.")?;
+    };
+
+    let mut next_tick = node
+        .tick_interval()
+        .map(|interval| Instant::now() + interval);
+
+    loop {
+        if let Some(at) = next_tick.filter(|at| *at <= Instant::now()) {
+            node.tick(&mut rpc).await?;
+            next_tick = node
+                .tick_interval()
+                .map(|interval| at.max(Instant::now()) + interval);
+        }
+
+        let input = match next_tick {
+            Some(at) => match tokio::time::timeout_at(at.into(), rx.recv()).await {
+                Ok(input) => input,
+                Err(_) => continue,
+            },
+            None => rx.recv().await,
+        };
+        let Some(input) = input else {
+            break;
+        };
+
+        rpc.metrics.record_received(input.kind());
+
+        if !is_for(&input, rpc.node_id()?)? {
+            rpc.metrics.misaddressed += 1;
+            continue;
+        }
+
+        if is_init(&input) {
+            match reinit(&input, rpc.node_id()?) {
+                Ok(()) => rpc.reply(&input, InitPayload::InitOk {}).await?,
+                Err(text) => refuse(&input, &mut rpc, &text).await?,
+            }
+            continue;
+        }
+
+        rpc.heard_from(&input.source);
+
+        // Replies to awaited requests never get this far, so nothing waits for this one, e.g.
+        // because it was delivered twice.
+        if let Some(id) = input.body.in_reply_to {
+            log::warning!("Dropping a reply to {id}, which matches no request: {input:?}");
+            deadletter::record(Reason::UnmatchedReply, None, &input);
+            continue;
+        }
+
+        let input = match decode::<N::Payload>(&input)? {
+            Ok(input) => input,
+            Err(text) => {
+                refuse(&input, &mut rpc, &text).await?;
+                continue;
+            }
+        };
+
+        node.step(input, &mut rpc).await?;
+    }
+
+    node.on_shutdown(&mut rpc).await?;
+    runtime::snapshot(node.persistent(), rpc.state_file.as_deref())?;
+    log::info!("{}", rpc.metrics);
+    log::info!("{}", ProcStats::collect());
+    if let Some(path) = metrics::summary_path() {
+        runtime::write_summary(path, &rpc.metrics, node.summary())?;
+    }
+
+    rpc.output.flush().await.map_err(NodeError::Write)?;
+    drop(rpc);
+
+    reader.await.context("Input reader panicked.")?
+}
+
+/// Waits for `init` and builds the node from it, or returns `None` if stdin closes first.
+async fn start<N: AsyncNode>(
+    rx: &mut mpsc::Receiver<Message<Value>>,
+    rpc: &mut AsyncRpc,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<Option<N>> {
+    while let Some(input) = rx.recv().await {
+        rpc.metrics.record_received(input.kind());
+
+        match handshake(input)? {
+            Handshake::Init { context, mut reply } => {
+                rpc.node_id = Some(context.node_id.clone());
+                let mut node = init(&context)?;
+                runtime::restore(node.persistent(), rpc.state_file.as_deref())?;
+
+                reply.body.id = Some(rpc.next_id());
+                rpc.send(&reply).await?;
+                node.init(&context, rpc).await?;
+
+                return Ok(Some(node));
+            }
+            Handshake::Refuse(reply) => rpc.send(&reply).await?,
+            Handshake::Ignore => {}
+        }
+    }
+
+    log::warning!("Input ended before init.");
+    Ok(None)
+}
+
+/// Like [`crate::node::reject`], for [`AsyncRpc`].
+async fn refuse(input: &Message<Value>, rpc: &mut AsyncRpc, text: &str) -> anyhow::Result<()> {
+    deadletter::record(Reason::Rejected, Some(text), input);
+    if input.body.id.is_none() || input.body.in_reply_to.is_some() {
+        log::warning!("Dropping message: {text} {input:?}");
+        return Ok(());
+    }
+
+    rpc.send(&error_reply(input, ErrorCode::NotSupported.into(), text))
+        .await
+}
+
+/// Forwards `input` to the main loop, except for replies to requests awaited in
+/// [`AsyncRpc::request`], which go straight to the waiting request.
+async fn read_input(
+    input: impl AsyncRead + Unpin,
+    tx: mpsc::Sender<Message<Value>>,
+    waiters: Waiters,
+) -> anyhow::Result<()> {
+    let result = async {
+        let mut lines = BufReader::new(input).lines();
+
+        while let Some(line) = lines.next_line().await.map_err(NodeError::Read)? {
+            let Some(input) = parse_line(&line)? else {
+                continue;
+            };
+            log::log_recv(&input);
+
+            let waiter = input
+                .body
+                .in_reply_to
+                .and_then(|id| waiters.lock().expect("Waiters lock poisoned.").remove(&id));
+
+            match waiter {
+                // The request may have stopped waiting already, there's no one else to tell.
+                Some(waiter) => {
+                    let _ = waiter.send(input);
+                }
+                None => {
+                    if tx.send(input).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // Dropping the senders wakes up any request still waiting for a reply.
+    waiters.lock().expect("Waiters lock poisoned.").clear();
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use serde::Deserialize;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum EchoPayload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct Echo;
+
+    impl AsyncNode for Echo {
+        type Payload = EchoPayload;
+
+        async fn step(
+            &mut self,
+            input: Message<EchoPayload>,
+            rpc: &mut AsyncRpc,
+        ) -> anyhow::Result<()> {
+            match &input.body.payload {
+                EchoPayload::Echo { echo } => {
+                    let echo = echo.clone();
+                    rpc.reply(&input, EchoPayload::EchoOk { echo }).await
+                }
+                EchoPayload::EchoOk { .. } => Ok(()),
+            }
+        }
+    }
+
+    /// Ticks every millisecond until it has ticked twice.
+    struct Ticker {
+        ticks: Rc<Cell<u32>>,
+    }
+
+    impl AsyncNode for Ticker {
+        type Payload = EchoPayload;
+
+        async fn step(&mut self, _: Message<EchoPayload>, _: &mut AsyncRpc) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn tick_interval(&self) -> Option<Duration> {
+            (self.ticks.get() < 2).then_some(Duration::from_millis(1))
+        }
+
+        async fn tick(&mut self, _: &mut AsyncRpc) -> anyhow::Result<()> {
+            self.ticks.set(self.ticks.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn init() -> Value {
+        json!({
+            "src": "c0", "dest": "n1",
+            "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}
+        })
+    }
+
+    async fn write_lines(input: &mut DuplexStream, lines: &[Value]) {
+        for line in lines {
+            input
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn read_lines(mut output: DuplexStream) -> Vec<Value> {
+        let mut written = String::new();
+        output.read_to_string(&mut written).await.unwrap();
+        written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn echoes_are_answered_through_the_main_loop() {
+        let (mut input, node_input) = tokio::io::duplex(1 << 16);
+        let (node_output, output) = tokio::io::duplex(1 << 16);
+        let echo = json!({
+            "src": "c1", "dest": "n1",
+            "body": {"type": "echo", "msg_id": 2, "echo": "hello"}
+        });
+        write_lines(&mut input, &[init(), echo]).await;
+        drop(input);
+
+        main_loop(node_input, Box::new(node_output), |_| Ok(Echo))
+            .await
+            .unwrap();
+
+        let written = read_lines(output).await;
+        assert_eq!(written.len(), 2, "{written:?}");
+        assert_eq!(written[0]["body"]["type"], "init_ok");
+        assert_eq!(written[0]["body"]["in_reply_to"], 1);
+        assert_eq!(written[1]["dest"], "c1");
+        assert_eq!(written[1]["body"]["type"], "echo_ok");
+        assert_eq!(written[1]["body"]["in_reply_to"], 2);
+        assert_eq!(written[1]["body"]["echo"], "hello");
+    }
+
+    #[tokio::test]
+    async fn the_tick_interval_is_asked_again_after_every_tick() {
+        let (mut input, node_input) = tokio::io::duplex(1 << 16);
+        let (node_output, _output) = tokio::io::duplex(1 << 16);
+        let ticks = Rc::new(Cell::new(0));
+        let node = Ticker {
+            ticks: Rc::clone(&ticks),
+        };
+
+        let (served, ()) = tokio::join!(
+            main_loop(node_input, Box::new(node_output), |_| Ok(node)),
+            async {
+                write_lines(&mut input, &[init()]).await;
+                // Long enough for dozens of ticks, had the interval been asked only once.
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                drop(input);
+            }
+        );
+
+        served.unwrap();
+        assert_eq!(ticks.get(), 2);
+    }
+}
@@ -0,0 +1,97 @@
+//! Where the runtime gets the time from.
+
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::message::Message;
+
+/// A source of time for scheduling ticks and timing out requests.
+///
+/// The runtime still blocks on real time while it waits for input, but every decision about
+/// whether a tick is due or a request has timed out goes through the clock, and so does every
+/// wait of a blocking request. A [`MockClock`] therefore decides those on its own schedule.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+
+    /// Waits up to `timeout` for the reply to a blocking request.
+    fn recv_timeout(
+        &self,
+        replies: &Receiver<Message<Value>>,
+        timeout: Duration,
+    ) -> Result<Message<Value>, RecvTimeoutError> {
+        replies.recv_timeout(timeout)
+    }
+}
+
+/// The wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// [`Clock::sleep`] returns right away, having advanced the clock by as much as it was asked
+/// to sleep, and so does [`Clock::recv_timeout`] if there's no reply yet.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().expect("Clock lock poisoned.") += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().expect("Clock lock poisoned.")
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn recv_timeout(
+        &self,
+        replies: &Receiver<Message<Value>>,
+        timeout: Duration,
+    ) -> Result<Message<Value>, RecvTimeoutError> {
+        replies.try_recv().map_err(|error| match error {
+            TryRecvError::Empty => {
+                self.advance(timeout);
+                RecvTimeoutError::Timeout
+            }
+            TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+        })
+    }
+}
@@ -0,0 +1,235 @@
+//! One node that speaks two workloads at once, e.g. `echo` and `unique-ids` for trying things
+//! out, without a payload enum written by hand that names every message of both.
+//!
+//! A [`Composite`] hands each message to the first of its nodes whose payload it decodes as,
+//! and refuses one that neither does as `not-supported`, as any node would. Either node talks
+//! through the composite's [`Rpc`], so its requests, callbacks and `msg_id`s work as usual. For
+//! more than two workloads, nest composites:
+//!
+//! ```no_run
+//! use tempest::composite::Composite;
+//! use tempest::runtime;
+//! use tempest::workloads::{EchoNode, TsoNode, UniqueIdNode};
+//!
+//! runtime::run(|context| {
+//!     Ok(Composite::new(
+//!         EchoNode,
+//!         Composite::new(UniqueIdNode::new(context), TsoNode::default()),
+//!     ))
+//! })?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::time::{Duration, Instant};
+
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::message::{Body, Message};
+use crate::node::{Event, Node, NodeContext};
+use crate::rpc::Rpc;
+
+/// A message of either of two payloads, taken for the first's if it decodes as both.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OneOf<A, B> {
+    First(A),
+    Second(B),
+}
+
+impl<A: Serialize, B: Serialize> Serialize for OneOf<A, B> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            OneOf::First(payload) => payload.serialize(serializer),
+            OneOf::Second(payload) => payload.serialize(serializer),
+        }
+    }
+}
+
+impl<'de, A: DeserializeOwned, B: DeserializeOwned> Deserialize<'de> for OneOf<A, B> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let first = match A::deserialize(&value) {
+            Ok(payload) => return Ok(OneOf::First(payload)),
+            Err(error) => error,
+        };
+        B::deserialize(&value).map(OneOf::Second).map_err(|second| {
+            D::Error::custom(format!("neither node takes it ({first}; {second})"))
+        })
+    }
+}
+
+/// Two nodes run as one, each getting the messages of its own payload.
+///
+/// Both get every tick due to them and every timeout, which they can tell apart by `msg_id`
+/// since they share the ids. Neither's state is persisted.
+pub struct Composite<A, B> {
+    pub first: A,
+    pub second: B,
+    /// When each node last ticked.
+    ticked: [Option<Instant>; 2],
+}
+
+impl<A: Node + 'static, B: Node + 'static> Composite<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self {
+            first,
+            second,
+            ticked: [None; 2],
+        }
+    }
+
+    fn first(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    fn second(&mut self) -> &mut B {
+        &mut self.second
+    }
+
+    /// Whether the node with tick interval `interval` and index `index` is due a tick by `now`,
+    /// recording that it ticks if so.
+    fn due(&mut self, index: usize, interval: Option<Duration>, now: Instant) -> bool {
+        let Some(interval) = interval else {
+            return false;
+        };
+        let ticked = &mut self.ticked[index];
+        if ticked.is_some_and(|ticked| now.saturating_duration_since(ticked) < interval) {
+            return false;
+        }
+        *ticked = Some(now);
+        true
+    }
+}
+
+/// `message` with its payload made into another by `f`.
+fn map_payload<P, Q>(message: Message<P>, f: impl FnOnce(P) -> Q) -> Message<Q> {
+    Message {
+        source: message.source,
+        destination: message.destination,
+        body: Body {
+            id: message.body.id,
+            in_reply_to: message.body.in_reply_to,
+            payload: f(message.body.payload),
+        },
+    }
+}
+
+/// `message`'s payload, and `message` without it.
+fn take_payload<P>(message: Message<P>) -> (P, Message<()>) {
+    let mut payload = None;
+    let message = map_payload(message, |taken| payload = Some(taken));
+    (payload.expect("The payload was just taken."), message)
+}
+
+impl<A: Node + 'static, B: Node + 'static> Node for Composite<A, B> {
+    type Payload = OneOf<A::Payload, B::Payload>;
+
+    fn init(&mut self, context: &NodeContext, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        // Ticks are due an interval after init, as they would be for either node alone.
+        self.ticked = [Some(rpc.now()); 2];
+        rpc.scoped(Self::first, |rpc| self.first.init(context, rpc))?;
+        rpc.scoped(Self::second, |rpc| self.second.init(context, rpc))
+    }
+
+    fn step(&mut self, input: Message<Self::Payload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        let (payload, input) = take_payload(input);
+        match payload {
+            OneOf::First(payload) => {
+                let input = map_payload(input, |()| payload);
+                rpc.scoped(Self::first, |rpc| {
+                    self.first.on_event(Event::Message(input), rpc)
+                })
+            }
+            OneOf::Second(payload) => {
+                let input = map_payload(input, |()| payload);
+                rpc.scoped(Self::second, |rpc| {
+                    self.second.on_event(Event::Message(input), rpc)
+                })
+            }
+        }
+    }
+
+    /// Often enough for whichever node ticks more often. The other ticks no more often than
+    /// its own interval.
+    fn tick_interval(&self) -> Option<Duration> {
+        match (self.first.tick_interval(), self.second.tick_interval()) {
+            (Some(first), Some(second)) => Some(first.min(second)),
+            (first, second) => first.or(second),
+        }
+    }
+
+    fn tick(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        let now = rpc.now();
+        if self.due(0, self.first.tick_interval(), now) {
+            rpc.scoped(Self::first, |rpc| self.first.on_event(Event::Tick, rpc))?;
+        }
+        if self.due(1, self.second.tick_interval(), now) {
+            rpc.scoped(Self::second, |rpc| self.second.on_event(Event::Tick, rpc))?;
+        }
+        Ok(())
+    }
+
+    fn on_shutdown(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        rpc.scoped(Self::first, |rpc| self.first.on_shutdown(rpc))?;
+        rpc.scoped(Self::second, |rpc| self.second.on_shutdown(rpc))
+    }
+
+    fn summary(&self) -> Option<Value> {
+        match (self.first.summary(), self.second.summary()) {
+            (None, None) => None,
+            (first, second) => Some(serde_json::json!({ "first": first, "second": second })),
+        }
+    }
+
+    fn on_event(&mut self, event: Event<Self::Payload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.step(message, rpc),
+            Event::Tick => self.tick(rpc),
+            Event::Timeout(id) => {
+                rpc.scoped(Self::first, |rpc| {
+                    self.first.on_event(Event::Timeout(id), rpc)
+                })?;
+                rpc.scoped(Self::second, |rpc| {
+                    self.second.on_event(Event::Timeout(id), rpc)
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::ErrorCode;
+    use crate::sim::{NetworkConfig, Simulation};
+    use crate::workloads::{EchoNode, UniqueIdNode};
+
+    #[test]
+    fn each_message_is_answered_by_the_node_that_takes_it() {
+        let mut sim = Simulation::new(1, NetworkConfig::default(), |context| {
+            Ok(Composite::new(EchoNode, UniqueIdNode::new(context)))
+        })
+        .unwrap();
+        let mut request = |payload| {
+            sim.request("c1", "n0", payload, Duration::from_secs(1))
+                .unwrap()
+                .expect("Every request is answered.")
+                .body
+                .payload
+        };
+
+        let echo = request(json!({ "type": "echo", "echo": "hello" }));
+        assert_eq!(echo, json!({ "type": "echo_ok", "echo": "hello" }));
+
+        let generated = request(json!({ "type": "generate" }));
+        assert_eq!(generated["type"], "generate_ok");
+        assert!(generated["id"].is_string(), "{generated}");
+
+        let refused = request(json!({ "type": "topology", "topology": {} }));
+        assert_eq!(refused["type"], "error");
+        assert_eq!(refused["code"], u32::from(ErrorCode::NotSupported));
+    }
+}
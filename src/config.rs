@@ -0,0 +1,227 @@
+//! Settings read from a TOML file, for when there are too many to pass as env vars.
+//!
+//! Every setting is optional. Each one comes from, in order of precedence:
+//!
+//! 1. a command-line flag, where there is one, like `--log-level`;
+//! 2. its `TEMPEST_*` env variable;
+//! 3. the file given with `--config`;
+//! 4. the built-in default.
+//!
+//! A file with every setting looks like this:
+//!
+//! ```toml
+//! log_level = "info"          # TEMPEST_LOG
+//! channel_capacity = 4096     # TEMPEST_CHANNEL_CAP
+//! topology = "tree:4"         # TEMPEST_TOPOLOGY
+//!
+//! [gossip]
+//! profile = "low-latency"     # TEMPEST_GOSSIP_PROFILE
+//! interval_ms = 200           # TEMPEST_GOSSIP_INTERVAL_MS
+//! jitter = 0.1                # TEMPEST_GOSSIP_JITTER
+//! seed = 7                    # TEMPEST_GOSSIP_SEED
+//! fanout = 3                  # TEMPEST_GOSSIP_FANOUT
+//! mode = "pull"               # TEMPEST_GOSSIP_MODE
+//! eager = true                # TEMPEST_GOSSIP_EAGER
+//! ```
+//!
+//! The file's gossip settings are applied over its profile. Setting `TEMPEST_GOSSIP_PROFILE`
+//! starts over from that profile instead, ignoring the file's gossip settings. `topology` and
+//! `[gossip]` are only known to builds with the `broadcast` feature.
+
+use std::path::Path;
+#[cfg(feature = "broadcast")]
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::log::{self, Level};
+use crate::runtime;
+#[cfg(feature = "broadcast")]
+use crate::workloads::{GossipConfig, Topology};
+
+/// The settings from a config file, before env variables are taken into account.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub log_level: Option<Level>,
+    pub channel_capacity: Option<usize>,
+    #[cfg(feature = "broadcast")]
+    pub topology: Option<Topology>,
+    /// The gossip settings to start from, before `TEMPEST_GOSSIP_*`.
+    #[cfg(feature = "broadcast")]
+    pub gossip: GossipConfig,
+}
+
+/// What the file says, as written.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    log_level: Option<String>,
+    channel_capacity: Option<usize>,
+    #[cfg(feature = "broadcast")]
+    topology: Option<String>,
+    #[cfg(feature = "broadcast")]
+    #[serde(default)]
+    gossip: GossipFile,
+}
+
+#[cfg(feature = "broadcast")]
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct GossipFile {
+    profile: Option<String>,
+    interval_ms: Option<u64>,
+    jitter: Option<f64>,
+    seed: Option<u64>,
+    fanout: Option<usize>,
+    mode: Option<String>,
+    eager: Option<bool>,
+}
+
+impl Config {
+    /// Reads the config file at `path`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}.", path.display()))?;
+        text.parse()
+            .with_context(|| format!("Invalid config file {}.", path.display()))
+    }
+
+    /// Sets the log level and channel capacity from the file, for whichever isn't set by its
+    /// env variable. Flags applied after this override both.
+    pub fn apply(&self) {
+        if let Some(level) = self.log_level {
+            if std::env::var_os("TEMPEST_LOG").is_none() {
+                log::set_level(level);
+            }
+        }
+        if let Some(capacity) = self.channel_capacity {
+            if std::env::var_os("TEMPEST_CHANNEL_CAP").is_none() {
+                runtime::set_channel_capacity(capacity);
+            }
+        }
+    }
+
+    /// The topology from `TEMPEST_TOPOLOGY`, or else from the file.
+    #[cfg(feature = "broadcast")]
+    pub fn topology(&self) -> anyhow::Result<Topology> {
+        match self.topology {
+            Some(topology) if std::env::var_os("TEMPEST_TOPOLOGY").is_none() => Ok(topology),
+            _ => Topology::from_env(),
+        }
+    }
+
+    /// The file's gossip settings, with `TEMPEST_GOSSIP_*` applied over them.
+    #[cfg(feature = "broadcast")]
+    pub fn gossip(&self) -> anyhow::Result<GossipConfig> {
+        self.gossip.with_env()
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let file: ConfigFile = toml::from_str(text)?;
+
+        let log_level = file.log_level.as_deref().map(str::parse).transpose()?;
+        let channel_capacity = match file.channel_capacity {
+            Some(0) => bail!("channel_capacity must be positive."),
+            capacity => capacity,
+        };
+
+        Ok(Self {
+            log_level,
+            channel_capacity,
+            #[cfg(feature = "broadcast")]
+            topology: file
+                .topology
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .context("Invalid topology.")?,
+            #[cfg(feature = "broadcast")]
+            gossip: gossip(file.gossip)?,
+        })
+    }
+}
+
+/// The gossip settings from the file, over the profile it names.
+#[cfg(feature = "broadcast")]
+fn gossip(settings: GossipFile) -> anyhow::Result<GossipConfig> {
+    let mut gossip = match settings.profile.as_deref() {
+        Some(profile) => GossipConfig::profile(profile)?,
+        None => GossipConfig::default(),
+    };
+    match settings.interval_ms {
+        Some(0) => bail!("gossip.interval_ms must be positive."),
+        Some(interval) => gossip.interval = Duration::from_millis(interval),
+        None => {}
+    }
+    if let Some(jitter) = settings.jitter {
+        if !(0.0..1.0).contains(&jitter) {
+            bail!("gossip.jitter must be at least 0 and below 1, not {jitter}.");
+        }
+        gossip.jitter = jitter;
+    }
+    if let Some(seed) = settings.seed {
+        gossip.seed = seed;
+    }
+    match settings.fanout {
+        Some(0) => bail!("gossip.fanout must be positive."),
+        Some(fanout) => gossip.fanout = Some(fanout),
+        None => {}
+    }
+    if let Some(mode) = settings.mode {
+        gossip.mode = mode.parse()?;
+    }
+    if let Some(eager) = settings.eager {
+        gossip.eager = eager;
+    }
+    Ok(gossip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "broadcast")]
+    fn the_file_sets_the_gossip_interval_unless_its_env_variable_does() {
+        let config: Config = "[gossip]\ninterval_ms = 250\n".parse().unwrap();
+        assert_eq!(config.gossip.interval, Duration::from_millis(250));
+
+        let unset = config.gossip.with_vars(|_| None).unwrap();
+        assert_eq!(unset.interval, Duration::from_millis(250));
+        let set = config
+            .gossip
+            .with_vars(|name| (name == "TEMPEST_GOSSIP_INTERVAL_MS").then(|| "40".to_owned()))
+            .unwrap();
+        assert_eq!(set.interval, Duration::from_millis(40));
+    }
+
+    #[test]
+    fn missing_and_malformed_files_are_refused() {
+        let error = Config::load(Path::new("/nonexistent/tempest.toml")).unwrap_err();
+        assert!(
+            error.to_string().contains("/nonexistent/tempest.toml"),
+            "{error}"
+        );
+
+        for text in [
+            "log_level = \"loud\"",
+            "channel_capacity = 0",
+            "channel_capacity = \"big\"",
+            "unknown = 1",
+            "log_level = ",
+        ] {
+            assert!(text.parse::<Config>().is_err(), "{text}");
+        }
+
+        let config: Config = "log_level = \"debug\"\nchannel_capacity = 8"
+            .parse()
+            .unwrap();
+        assert_eq!(config.log_level, Some(Level::Debug));
+        assert_eq!(config.channel_capacity, Some(8));
+    }
+}
@@ -0,0 +1,287 @@
+//! Replicated state that converges by merging.
+//!
+//! Merging is associative, commutative and idempotent, so replicas that exchange their state,
+//! or just the [`delta`](GSet::delta) the other is missing, end up equal no matter in what
+//! order or how often their gossip arrives.
+
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+
+/// A set that only ever grows.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+#[serde(bound(
+    serialize = "T: Serialize + Eq + Hash",
+    deserialize = "T: Deserialize<'de> + Eq + Hash"
+))]
+pub struct GSet<T> {
+    elements: HashSet<T>,
+}
+
+impl<T> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash> PartialEq for GSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.elements == other.elements
+    }
+}
+
+impl<T: Eq + Hash> Eq for GSet<T> {}
+
+impl<T: Eq + Hash + Clone> GSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `element`, returning whether it is new.
+    pub fn insert(&mut self, element: T) -> bool {
+        self.elements.insert(element)
+    }
+
+    pub fn contains<Q>(&self, element: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.elements.contains(element)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+
+    /// Adds everything in `other`.
+    pub fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+
+    /// What `other` is missing, for merging into it.
+    pub fn delta(&self, other: &Self) -> Self {
+        self.elements.difference(&other.elements).cloned().collect()
+    }
+}
+
+impl<T: Eq + Hash> Extend<T> for GSet<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, elements: I) {
+        self.elements.extend(elements);
+    }
+}
+
+impl<T: Eq + Hash> FromIterator<T> for GSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(elements: I) -> Self {
+        Self {
+            elements: elements.into_iter().collect(),
+        }
+    }
+}
+
+/// A counter that only ever grows, kept as one count per node.
+///
+/// Each node only raises its own count, so merging takes the larger count for every node and
+/// the value is their sum.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct GCounter {
+    counts: BTreeMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `delta` to `node_id`'s count.
+    pub fn increment(&mut self, node_id: &str, delta: u64) {
+        // A node only gets an entry once it counts, so that counters with the same counts
+        // compare equal.
+        if delta > 0 {
+            *self.counts.entry(node_id.to_owned()).or_default() += delta;
+        }
+    }
+
+    /// Raises `node_id`'s count to `count`, unless it is higher already.
+    pub fn observe(&mut self, node_id: &str, count: u64) {
+        if count > 0 {
+            let current = self.counts.entry(node_id.to_owned()).or_default();
+            *current = (*current).max(count);
+        }
+    }
+
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.counts.get(node_id).copied().unwrap_or(0)
+    }
+
+    /// The total over all nodes.
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (node_id, count) in &other.counts {
+            self.observe(node_id, *count);
+        }
+    }
+
+    /// The counts `other` is behind on, for merging into it.
+    pub fn delta(&self, other: &Self) -> Self {
+        Self {
+            counts: self
+                .counts
+                .iter()
+                .filter(|(node_id, count)| **count > other.get(node_id))
+                .map(|(node_id, count)| (node_id.clone(), *count))
+                .collect(),
+        }
+    }
+}
+
+/// A counter that can go down as well as up, kept as a [`GCounter`] of increments and one of
+/// decrements.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `delta` to `node_id`'s share, counting it as a decrement if negative.
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.increment(node_id, delta.unsigned_abs());
+        } else {
+            self.decrements.increment(node_id, delta.unsigned_abs());
+        }
+    }
+
+    /// Every increment minus every decrement.
+    pub fn value(&self) -> i64 {
+        let value = i128::from(self.increments.value()) - i128::from(self.decrements.value());
+        value.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    /// The counts `other` is behind on, for merging into it.
+    pub fn delta(&self, other: &Self) -> Self {
+        Self {
+            increments: self.increments.delta(&other.increments),
+            decrements: self.decrements.delta(&other.decrements),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::message::arbitrary;
+
+    fn g_set() -> impl Strategy<Value = GSet<u8>> {
+        prop::collection::vec(any::<u8>(), 0..16).prop_map(GSet::from_iter)
+    }
+
+    fn g_counter() -> impl Strategy<Value = GCounter> {
+        prop::collection::vec((arbitrary::node_id(), 0..1000u64), 0..8).prop_map(|counts| {
+            let mut counter = GCounter::new();
+            for (node_id, count) in counts {
+                counter.observe(&node_id, count);
+            }
+            counter
+        })
+    }
+
+    /// A counter's replicas on three nodes, each added to on its own, and the sum of the adds.
+    fn replicas() -> impl Strategy<Value = (Vec<PnCounter>, i64)> {
+        prop::collection::vec((0..3usize, -1000..1000i64), 0..32).prop_map(|adds| {
+            let mut replicas = vec![PnCounter::new(); 3];
+            for (replica, delta) in &adds {
+                replicas[*replica].add(&format!("n{replica}"), *delta);
+            }
+            (replicas, adds.iter().map(|(_, delta)| delta).sum())
+        })
+    }
+
+    fn merged<T: Clone>(mut left: T, right: &T, merge: impl Fn(&mut T, &T)) -> T {
+        merge(&mut left, right);
+        left
+    }
+
+    proptest! {
+        #[test]
+        fn g_sets_merge_in_any_grouping_order_and_number(a in g_set(), b in g_set(), c in g_set()) {
+            let merge = GSet::merge;
+            prop_assert_eq!(
+                merged(merged(a.clone(), &b, merge), &c, merge),
+                merged(a.clone(), &merged(b.clone(), &c, merge), merge)
+            );
+            prop_assert_eq!(merged(a.clone(), &b, merge), merged(b.clone(), &a, merge));
+            prop_assert_eq!(merged(a.clone(), &a, merge), a.clone());
+            prop_assert_eq!(
+                merged(b.clone(), &a.delta(&b), merge),
+                merged(b.clone(), &a, merge)
+            );
+        }
+
+        #[test]
+        fn g_counters_merge_in_any_grouping_order_and_number(
+            a in g_counter(),
+            b in g_counter(),
+            c in g_counter(),
+        ) {
+            let merge = GCounter::merge;
+            prop_assert_eq!(
+                merged(merged(a.clone(), &b, merge), &c, merge),
+                merged(a.clone(), &merged(b.clone(), &c, merge), merge)
+            );
+            prop_assert_eq!(merged(a.clone(), &b, merge), merged(b.clone(), &a, merge));
+            prop_assert_eq!(merged(a.clone(), &a, merge), a.clone());
+            prop_assert_eq!(
+                merged(b.clone(), &a.delta(&b), merge),
+                merged(b.clone(), &a, merge)
+            );
+        }
+
+        #[test]
+        fn pn_counters_come_to_the_same_value_in_any_merge_order(
+            (replicas, total) in replicas(),
+            order in Just(vec![0, 1, 2, 0, 1, 2]).prop_shuffle(),
+        ) {
+            let mut forwards = PnCounter::new();
+            for replica in &replicas {
+                forwards.merge(replica);
+            }
+            // Each twice, in any order, as repeated gossip may deliver them.
+            let mut shuffled = PnCounter::new();
+            for replica in order {
+                shuffled.merge(&replicas[replica]);
+            }
+            prop_assert_eq!(&shuffled, &forwards);
+            prop_assert_eq!(shuffled.value(), total);
+        }
+    }
+}
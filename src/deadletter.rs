@@ -0,0 +1,144 @@
+//! A record of the messages a node couldn't handle, for finding out afterwards why a run failed.
+//!
+//! The node drops or refuses such messages rather than crash on them, and only logs them. Once
+//! [`set_path`] is called, each one is also appended to that file as a line of JSON, like
+//!
+//! ```text
+//! {"reason":"rejected","text":"Could not handle frobnicate: ...","message":{"src":"c1",...}}
+//! ```
+//!
+//! where `reason` is one of the [`Reason`]s, and `message` is the line as read for a message
+//! that isn't one.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::log;
+
+/// Why a message ended up in the dead-letter file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// A line of input that isn't a message.
+    Malformed,
+    /// A message for another node.
+    Misaddressed,
+    /// A message, other than `init`, that came in before `init`.
+    BeforeInit,
+    /// A reply to a request that had already timed out.
+    LateReply,
+    /// A reply to no request the node is waiting on, e.g. one delivered twice.
+    UnmatchedReply,
+    /// A message the node doesn't handle, which got an error reply if it could have one.
+    Rejected,
+}
+
+static SINK: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Appends every message the node couldn't handle to the file at `path`, creating it if need
+/// be. Only the first path set counts.
+pub fn set_path(path: &Path) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open {} for dead letters.", path.display()))?;
+    let _ = SINK.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Appends `message` to the dead-letter file, if there is one, with why it couldn't be handled.
+pub(crate) fn record(reason: Reason, text: Option<&str>, message: &impl Serialize) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+
+    #[derive(Serialize)]
+    struct Letter<'a, M> {
+        reason: Reason,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<&'a str>,
+        message: &'a M,
+    }
+
+    let result = serde_json::to_string(&Letter {
+        reason,
+        text,
+        message,
+    })
+    .map_err(std::io::Error::from)
+    .and_then(|line| {
+        let mut file = sink.lock().expect("Dead-letter lock poisoned.");
+        writeln!(file, "{line}")
+    });
+
+    if let Err(error) = result {
+        log::warning!("Could not write a dead letter: {error}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::runtime;
+    use crate::transport::InMemoryTransport;
+    use crate::workloads::EchoNode;
+
+    #[test]
+    fn misaddressed_and_unknown_messages_are_written_down() {
+        let path = std::env::temp_dir().join(format!("tempest-{}.deadletter", std::process::id()));
+        set_path(&path).unwrap();
+
+        // Other tests' dead letters go to the same file, so these come from a client of their
+        // own.
+        let message = |dest: &str, msg_id, kind: &str| json!({"src": "c86", "dest": dest, "body": {"type": kind, "msg_id": msg_id}});
+        let init = json!({
+            "src": "c86",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] },
+        });
+        let input = format!(
+            "{init}\n{}\n{}\n",
+            message("n2", 2, "echo"),
+            message("n1", 3, "frobnicate"),
+        );
+        runtime::run_with_transport(
+            Cursor::new(input),
+            InMemoryTransport::new(),
+            Arc::new(SystemClock),
+            |_| Ok(EchoNode),
+        )
+        .unwrap();
+
+        let letters: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .filter(|letter: &Value| letter["message"]["src"] == "c86")
+            .collect();
+        let reasons: Vec<(&Value, &Value)> = letters
+            .iter()
+            .map(|letter| (&letter["reason"], &letter["message"]["body"]["msg_id"]))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (&json!("misaddressed"), &json!(2)),
+                (&json!("rejected"), &json!(3))
+            ]
+        );
+        assert!(letters[1]["text"].is_string(), "{letters:?}");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,145 @@
+//! Recognizing requests Maelstrom delivered more than once.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::message::Message;
+
+/// Remembers the replies to the most recent requests, keyed by `(src, msg_id)`, so that a
+/// redelivered request can be answered again without applying its effect twice.
+///
+/// Only the `capacity` most recently used entries are kept. Requests without a `msg_id` are
+/// never remembered.
+#[derive(Debug)]
+pub struct Dedup<R> {
+    capacity: usize,
+    replies: HashMap<(String, usize), (u64, R)>,
+    // Keys in the order they were last used, tagged with when. A key used again is pushed
+    // anew, and the stale entry is skipped once it reaches the front.
+    order: VecDeque<((String, usize), u64)>,
+    uses: u64,
+}
+
+impl<R> Dedup<R> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+            uses: 0,
+        }
+    }
+
+    /// The reply already sent for `request`, if it was seen before.
+    pub fn get<P>(&mut self, request: &Message<P>) -> Option<&R> {
+        let key = key(request)?;
+        if !self.replies.contains_key(&key) {
+            return None;
+        }
+
+        let used = self.touch(&key);
+        if let Some((last_used, _)) = self.replies.get_mut(&key) {
+            *last_used = used;
+        }
+        self.compact();
+
+        self.replies.get(&key).map(|(_, reply)| reply)
+    }
+
+    /// Remembers `reply` as the answer to `request`.
+    pub fn insert<P>(&mut self, request: &Message<P>, reply: R) {
+        let Some(key) = key(request) else {
+            return;
+        };
+
+        let used = self.touch(&key);
+        self.replies.insert(key, (used, reply));
+
+        while self.replies.len() > self.capacity {
+            let Some((key, used)) = self.order.pop_front() else {
+                break;
+            };
+            if self.is_current(&key, used) {
+                self.replies.remove(&key);
+            }
+        }
+        self.compact();
+    }
+
+    /// Forgets the reply to `request`, so that it's taken for new if delivered again.
+    pub fn remove<P>(&mut self, request: &Message<P>) {
+        if let Some(key) = key(request) {
+            // Its entry in `order` is stale now, and skipped.
+            self.replies.remove(&key);
+        }
+    }
+
+    fn touch(&mut self, key: &(String, usize)) -> u64 {
+        self.uses += 1;
+        self.order.push_back((key.clone(), self.uses));
+        self.uses
+    }
+
+    /// Every use pushes a key, so drop stale ones before the queue outgrows the map by much.
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.capacity {
+            let mut order = std::mem::take(&mut self.order);
+            order.retain(|(key, used)| self.is_current(key, *used));
+            self.order = order;
+        }
+    }
+
+    fn is_current(&self, key: &(String, usize), used: u64) -> bool {
+        self.replies
+            .get(key)
+            .is_some_and(|(last_used, _)| *last_used == used)
+    }
+}
+
+fn key<P>(request: &Message<P>) -> Option<(String, usize)> {
+    Some((request.source.clone(), request.body.id?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Body;
+
+    fn request(source: &str, id: Option<usize>) -> Message<()> {
+        Message {
+            source: source.to_owned(),
+            destination: "n1".to_owned(),
+            body: Body {
+                id,
+                in_reply_to: None,
+                payload: (),
+            },
+        }
+    }
+
+    #[test]
+    fn requests_are_told_apart_by_source_and_msg_id() {
+        let mut dedup = Dedup::new(4);
+        dedup.insert(&request("c1", Some(1)), "first");
+
+        assert_eq!(dedup.get(&request("c1", Some(1))), Some(&"first"));
+        assert_eq!(dedup.get(&request("c2", Some(1))), None);
+        assert_eq!(dedup.get(&request("c1", Some(2))), None);
+
+        dedup.insert(&request("c1", None), "unnumbered");
+        assert_eq!(dedup.get(&request("c1", None)), None);
+    }
+
+    #[test]
+    fn the_least_recently_used_reply_is_forgotten_first() {
+        let mut dedup = Dedup::new(2);
+        dedup.insert(&request("c1", Some(1)), 1);
+        dedup.insert(&request("c1", Some(2)), 2);
+        // Looking the first up again makes the second the least recently used.
+        dedup.get(&request("c1", Some(1)));
+        dedup.insert(&request("c1", Some(3)), 3);
+
+        assert_eq!(dedup.get(&request("c1", Some(1))), Some(&1));
+        assert_eq!(dedup.get(&request("c1", Some(2))), None);
+        assert_eq!(dedup.get(&request("c1", Some(3))), Some(&3));
+    }
+}
@@ -0,0 +1,227 @@
+//! Telling which peers are alive, for workloads that would rather route around dead nodes
+//! than wait on them.
+//!
+//! A [`FailureDetector`] pings the peers it watches every so often, through the workload's own
+//! payload, which therefore needs `ping` and `pong_ok` messages. A peer counts as dead once it
+//! hasn't been heard from for the detector's timeout. That's only a guess, since a slow or
+//! partitioned peer looks just the same, so the first pong or other sign of life from it marks
+//! it alive again.
+//!
+//! ```ignore
+//! fn tick(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+//!     self.detector.ping(rpc, Payload::Ping {}, |node| &mut node.detector)?;
+//!     for peer in self.peers.iter().filter(|peer| self.detector.is_alive(peer)) {
+//!         // ...
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::log;
+use crate::message::{Body, Message};
+use crate::node::Node;
+use crate::rpc::Rpc;
+
+/// How long a peer may go unheard from before it counts as dead, unless set otherwise.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many rounds of pings go out per timeout, so that a peer that's alive gets several
+/// chances to answer before it's taken for dead.
+const PINGS_PER_TIMEOUT: u32 = 4;
+
+/// Guesses which of a set of peers are alive, by pinging them.
+#[derive(Debug)]
+pub struct FailureDetector {
+    timeout: Duration,
+    /// Every watched peer, with when it was last heard from, or when watching it started.
+    last_seen: BTreeMap<String, Option<Instant>>,
+    /// Peers that were dead as of the last round of pings, to log when that changes.
+    dead: Vec<String>,
+    last_ping: Option<Instant>,
+    /// The latest time the detector has been told of, which is what
+    /// [`FailureDetector::is_alive`] judges by.
+    now: Option<Instant>,
+}
+
+impl FailureDetector {
+    /// Watches `peers`, taking any that goes unheard from for `timeout` for dead.
+    pub fn new(peers: impl IntoIterator<Item = String>, timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_seen: peers.into_iter().map(|peer| (peer, None)).collect(),
+            dead: Vec::new(),
+            last_ping: None,
+            now: None,
+        }
+    }
+
+    /// Whether `peer` was heard from within the timeout, as of the last round of pings or
+    /// sign of life. A peer the detector doesn't watch, or hasn't started pinging yet, counts
+    /// as alive, since nothing says otherwise.
+    pub fn is_alive(&self, peer: &str) -> bool {
+        let (Some(Some(seen)), Some(now)) = (self.last_seen.get(peer), self.now) else {
+            return true;
+        };
+        now.saturating_duration_since(*seen) < self.timeout
+    }
+
+    /// Marks `peer` alive as of `now`, e.g. on any message from it. Pongs to
+    /// [`FailureDetector::ping`] do so by themselves.
+    pub fn heard_from(&mut self, peer: &str, now: Instant) {
+        let Some(seen) = self.last_seen.get_mut(peer) else {
+            return;
+        };
+        *seen = Some(now);
+        self.now = self.now.max(Some(now));
+
+        if let Some(index) = self.dead.iter().position(|dead| dead == peer) {
+            self.dead.swap_remove(index);
+            log::info!("{peer} is alive again.");
+        }
+    }
+
+    /// Pings every watched peer with `ping`, unless the last round went out too recently.
+    /// Meant to be called on every tick.
+    ///
+    /// `detector` finds this detector in the node again, for the pongs.
+    pub fn ping<N: Node + 'static>(
+        &mut self,
+        rpc: &mut Rpc<N>,
+        ping: impl Serialize,
+        detector: fn(&mut N) -> &mut Self,
+    ) -> anyhow::Result<()> {
+        let now = rpc.now();
+        self.now = self.now.max(Some(now));
+        self.notice_deaths();
+
+        let interval = self.timeout / PINGS_PER_TIMEOUT;
+        if self
+            .last_ping
+            .is_some_and(|last_ping| now.saturating_duration_since(last_ping) < interval)
+        {
+            return Ok(());
+        }
+        self.last_ping = Some(now);
+
+        let source = rpc.node_id()?.to_owned();
+        for (peer, seen) in &mut self.last_seen {
+            // Watching starts with the first ping, so that a peer gets the whole timeout to
+            // answer it.
+            seen.get_or_insert(now);
+
+            let request = Message {
+                source: source.clone(),
+                destination: peer.clone(),
+                body: Body {
+                    id: Some(rpc.next_id()),
+                    in_reply_to: None,
+                    payload: &ping,
+                },
+            };
+            // Even an error reply shows the peer is up.
+            let peer = peer.clone();
+            rpc.call(request, move |node, _pong, rpc| {
+                detector(node).heard_from(&peer, rpc.now());
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn notice_deaths(&mut self) {
+        let dead: Vec<String> = self
+            .last_seen
+            .keys()
+            .filter(|peer| !self.is_alive(peer) && !self.dead.contains(peer))
+            .cloned()
+            .collect();
+
+        for peer in dead {
+            log::info!("{peer} looks dead, not heard from for {:?}.", self.timeout);
+            self.dead.push(peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use crate::rpc::Waiters;
+    use crate::runtime::dispatch;
+    use crate::transport::InMemoryTransport;
+
+    struct Watcher {
+        detector: FailureDetector,
+    }
+
+    impl Node for Watcher {
+        type Payload = Value;
+
+        fn step(&mut self, _input: Message<Value>, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_peer_is_dead_once_it_stops_answering_and_alive_once_it_answers_again() {
+        let transport = InMemoryTransport::new();
+        let clock = Arc::new(MockClock::new());
+        let mut rpc = Rpc::new(transport.clone(), Waiters::default(), clock.clone());
+        rpc.node_id = Some("n1".to_owned());
+        let peers = ["n2".to_owned(), "n3".to_owned()];
+        let mut node = Watcher {
+            detector: FailureDetector::new(peers, PEER_TIMEOUT),
+        };
+
+        // A round of pings every 500ms. n3 answers every one, and n2 only when told to.
+        let round = |node: &mut Watcher, rpc: &mut Rpc<Watcher>, n2_answers: bool| {
+            node.detector
+                .ping(rpc, json!({"type": "ping"}), |node| &mut node.detector)
+                .unwrap();
+            for ping in transport.take() {
+                if ping.destination == "n3" || n2_answers {
+                    let pong = Message {
+                        source: ping.destination.clone(),
+                        destination: ping.source.clone(),
+                        body: Body {
+                            id: None,
+                            in_reply_to: ping.body.id,
+                            payload: json!({"type": "pong_ok"}),
+                        },
+                    };
+                    dispatch(node, &pong, rpc).unwrap();
+                }
+            }
+            clock.advance(PEER_TIMEOUT / PINGS_PER_TIMEOUT);
+            rpc.expire(node, clock.now()).unwrap();
+        };
+
+        for _ in 0..4 {
+            round(&mut node, &mut rpc, true);
+        }
+        assert!(node.detector.is_alive("n2"));
+
+        // n2 was last heard from by the fourth round, which the next three stay within the
+        // timeout of, and the one after that doesn't.
+        for _ in 0..3 {
+            round(&mut node, &mut rpc, false);
+            assert!(node.detector.is_alive("n2"));
+        }
+        round(&mut node, &mut rpc, false);
+        assert!(!node.detector.is_alive("n2"));
+        assert!(node.detector.is_alive("n3"));
+
+        round(&mut node, &mut rpc, true);
+        assert!(node.detector.is_alive("n2"));
+    }
+}
@@ -0,0 +1,1151 @@
+//! A client for Maelstrom's key-value services.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::message::{ErrorCode, Message};
+use crate::node::Node;
+use crate::rpc::{RetryPolicy, Rpc, RpcError, RPC_TIMEOUT};
+
+pub const SEQ_KV: &str = "seq-kv";
+pub const LIN_KV: &str = "lin-kv";
+pub const LWW_KV: &str = "lww-kv";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum KvRequest {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum KvReply {
+    ReadOk { value: Value },
+    WriteOk {},
+    CasOk {},
+    Error { code: u32, text: String },
+}
+
+/// Why a kv request failed.
+#[derive(Debug)]
+pub enum KvError {
+    /// The key has never been written.
+    NotFound,
+    /// A `cas` found a value other than `from`.
+    PreconditionFailed(String),
+    /// The service answered with some other error.
+    Service { code: u32, text: String },
+    /// The request didn't get a reply.
+    Rpc(RpcError),
+    /// The request or its reply couldn't be encoded.
+    Malformed(anyhow::Error),
+    /// What the node did holding a lock failed, see [`Rpc::with_lock`].
+    Failed(anyhow::Error),
+}
+
+impl KvError {
+    /// The error code to relay to a client whose request failed because of this error.
+    ///
+    /// Failures that leave it unknown whether the request took effect map to indefinite codes,
+    /// so that the checker doesn't assume it didn't.
+    pub fn code(&self) -> u32 {
+        match self {
+            KvError::NotFound => ErrorCode::KeyDoesNotExist.into(),
+            KvError::PreconditionFailed(_) => ErrorCode::PreconditionFailed.into(),
+            KvError::Service { code, .. } => *code,
+            KvError::Rpc(RpcError::Send(_)) => ErrorCode::TemporarilyUnavailable.into(),
+            KvError::Rpc(RpcError::Timeout | RpcError::Unreachable | RpcError::Closed) => {
+                ErrorCode::Timeout.into()
+            }
+            KvError::Rpc(RpcError::Remote { code, .. }) => *code,
+            KvError::Malformed(_) | KvError::Failed(_) => ErrorCode::Crash.into(),
+        }
+    }
+
+    /// Whether the service said the request didn't take effect, but may if sent again later.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            KvError::Service { code, .. } => {
+                ErrorCode::try_from(*code).is_ok_and(ErrorCode::is_retriable)
+            }
+            KvError::Rpc(error) => error.is_retriable(),
+            _ => false,
+        }
+    }
+
+    /// Whether the service said the request didn't take effect and won't if sent again.
+    pub fn is_abort(&self) -> bool {
+        match self {
+            KvError::NotFound | KvError::PreconditionFailed(_) => true,
+            KvError::Service { code, .. } => {
+                ErrorCode::try_from(*code).is_ok_and(ErrorCode::is_abort)
+            }
+            KvError::Rpc(error) => error.is_abort(),
+            KvError::Malformed(_) | KvError::Failed(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::NotFound => f.write_str("Key does not exist."),
+            KvError::PreconditionFailed(text) => write!(f, "Precondition failed: {text}"),
+            KvError::Service { code, text } => write!(f, "Error {code}: {text}"),
+            KvError::Rpc(error) => error.fmt(f),
+            KvError::Malformed(error) | KvError::Failed(error) => write!(f, "{error:#}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+impl From<RpcError> for KvError {
+    /// An error reply that ended a retried request counts the same as one decoded from a
+    /// single reply.
+    fn from(error: RpcError) -> Self {
+        match error {
+            RpcError::Remote { code, text } => refused(code, text),
+            error => KvError::Rpc(error),
+        }
+    }
+}
+
+impl From<serde_json::Error> for KvError {
+    fn from(error: serde_json::Error) -> Self {
+        KvError::Malformed(error.into())
+    }
+}
+
+/// Values this node wrote recently, for [`Rpc::read_your_writes`].
+#[derive(Debug)]
+pub(crate) struct Overlay {
+    window: Duration,
+    written: HashMap<(String, String), (Value, Instant)>,
+}
+
+impl Overlay {
+    fn insert(&mut self, service: &str, key: &Value, value: Value, now: Instant) {
+        let window = self.window;
+        self.written
+            .retain(|_, (_, written)| now.saturating_duration_since(*written) < window);
+        self.written
+            .insert((service.to_owned(), key.to_string()), (value, now));
+    }
+
+    fn forget(&mut self, service: &str, key: &Value) {
+        self.written.remove(&(service.to_owned(), key.to_string()));
+    }
+
+    /// What to make of `read`, the service's answer for `key`: the value this node wrote, if
+    /// it did so within the window and the service doesn't have it yet.
+    fn resolve(
+        &mut self,
+        service: &str,
+        key: &Value,
+        read: Result<Value, KvError>,
+        now: Instant,
+    ) -> Result<Value, KvError> {
+        let entry = (service.to_owned(), key.to_string());
+        let Some((value, written)) = self.written.get(&entry) else {
+            return read;
+        };
+
+        match read {
+            // Caught up, so the service can answer for itself from now on.
+            Ok(read) if read == *value => {
+                self.written.remove(&entry);
+                Ok(read)
+            }
+            Ok(_) | Err(KvError::NotFound)
+                if now.saturating_duration_since(*written) < self.window =>
+            {
+                Ok(value.clone())
+            }
+            read => {
+                self.written.remove(&entry);
+                read
+            }
+        }
+    }
+}
+
+/// Requests against `seq-kv`, `lin-kv` and the like.
+///
+/// These block until the service replies, or fail with [`RpcError::Timeout`] if it doesn't.
+/// They're a convenience for nodes that handle one request at a time anyway: the node handles
+/// nothing else in the meantime. The requests ending in `_then` don't wait.
+impl<N: Node> Rpc<N> {
+    /// Makes [`Rpc::read`] return what this node last wrote to a key, for up to `window` after
+    /// writing it, while the service still answers with an older value or none.
+    ///
+    /// `seq-kv` may serve reads stale, even to the node that just wrote. With this on, a node
+    /// reads its own writes: a write or successful cas is kept locally until a read returns
+    /// the same value, `window` passes, or a cas on the key fails, which shows the local value
+    /// is out of date. Past `window`, reads are as stale as the service makes them.
+    pub fn read_your_writes(&mut self, window: Duration) {
+        self.overlay = Some(Overlay {
+            window,
+            written: HashMap::new(),
+        });
+    }
+
+    pub fn read(&mut self, service: &str, key: impl Serialize) -> Result<Value, KvError> {
+        let key = serde_json::to_value(key)?;
+
+        let read = self.kv(service, KvRequest::Read { key: key.clone() }, true);
+        self.read_done(service, &key, read)
+    }
+
+    fn read_done(
+        &mut self,
+        service: &str,
+        key: &Value,
+        reply: Result<KvReply, KvError>,
+    ) -> Result<Value, KvError> {
+        let read = match reply {
+            Ok(KvReply::ReadOk { value }) => Ok(value),
+            Ok(reply) => Err(unexpected(reply)),
+            Err(error) => Err(error),
+        };
+
+        let now = self.now();
+        match &mut self.overlay {
+            Some(overlay) => overlay.resolve(service, key, read, now),
+            None => read,
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        service: &str,
+        key: impl Serialize,
+        value: impl Serialize,
+    ) -> Result<(), KvError> {
+        let key = serde_json::to_value(key)?;
+        let value = serde_json::to_value(value)?;
+
+        let request = KvRequest::Write {
+            key: key.clone(),
+            value: value.clone(),
+        };
+        let reply = self.kv(service, request, false);
+        self.write_done(service, &key, value, reply)
+    }
+
+    fn write_done(
+        &mut self,
+        service: &str,
+        key: &Value,
+        value: Value,
+        reply: Result<KvReply, KvError>,
+    ) -> Result<(), KvError> {
+        match reply? {
+            KvReply::WriteOk {} => {
+                let now = self.now();
+                if let Some(overlay) = &mut self.overlay {
+                    overlay.insert(service, key, value, now);
+                }
+                Ok(())
+            }
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub fn cas(
+        &mut self,
+        service: &str,
+        key: impl Serialize,
+        from: impl Serialize,
+        to: impl Serialize,
+        create_if_missing: bool,
+    ) -> Result<(), KvError> {
+        let key = serde_json::to_value(key)?;
+        let to = serde_json::to_value(to)?;
+        let request = KvRequest::Cas {
+            key: key.clone(),
+            from: serde_json::to_value(from)?,
+            to: to.clone(),
+            create_if_not_exists: create_if_missing,
+        };
+
+        let reply = self.kv(service, request, false);
+        self.cas_done(service, &key, to, reply)
+    }
+
+    fn cas_done(
+        &mut self,
+        service: &str,
+        key: &Value,
+        to: Value,
+        reply: Result<KvReply, KvError>,
+    ) -> Result<(), KvError> {
+        let result = match reply {
+            Ok(KvReply::CasOk {}) => Ok(()),
+            Ok(reply) => Err(unexpected(reply)),
+            Err(error) => Err(error),
+        };
+
+        let now = self.now();
+        if let Some(overlay) = &mut self.overlay {
+            match &result {
+                Ok(()) => overlay.insert(service, key, to, now),
+                Err(KvError::PreconditionFailed(_)) => overlay.forget(service, key),
+                Err(_) => {}
+            }
+        }
+        result
+    }
+
+    /// Replaces the number at `key` with `f` of it, or of `None` if the key has never been
+    /// written, and returns the new value.
+    ///
+    /// `f` may be called more than once: whenever another write lands between the read and
+    /// the cas, the value is read again and the update retried. Against `lin-kv`, this makes
+    /// the update linearizable.
+    ///
+    /// So it is when the service answers that it's [retriable](KvError::is_retriable), after
+    /// backing off as `policy` lays out, until `policy.max_attempts` have been answered that
+    /// way and the last answer is returned. The node is blocked meanwhile, backoff included.
+    pub fn cas_update(
+        &mut self,
+        service: &str,
+        key: impl Serialize,
+        policy: RetryPolicy,
+        f: impl Fn(Option<u64>) -> u64,
+    ) -> Result<u64, KvError> {
+        let key = serde_json::to_value(key)?;
+        let mut delay = policy.base_delay;
+        let mut attempts = 1;
+
+        loop {
+            let current = match self.read(service, &key) {
+                Ok(value) => Some(serde_json::from_value(value)?),
+                Err(KvError::NotFound) => None,
+                Err(error) => return Err(error),
+            };
+            let updated = f(current);
+
+            match self.cas(service, &key, current, updated, current.is_none()) {
+                Ok(()) => return Ok(updated),
+                Err(KvError::PreconditionFailed(_)) => continue,
+                Err(error) if error.is_retriable() && attempts < policy.max_attempts => {
+                    self.sleep(delay);
+                    delay = delay.mul_f64(policy.multiplier);
+                    attempts += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Only reads are retried: a retried write or cas could land after, and undo, a write by
+    /// someone else.
+    fn kv(
+        &mut self,
+        service: &str,
+        request: KvRequest,
+        idempotent: bool,
+    ) -> Result<KvReply, KvError> {
+        let reply = if idempotent {
+            self.request_retrying(service, request, RetryPolicy::default())?
+        } else {
+            self.request(service, request)?
+        };
+        decode(reply)
+    }
+}
+
+/// Requests against kv services that return right away, and hand `then` the outcome once the
+/// service replies or the request times out, just as the blocking requests would return it.
+///
+/// The node goes on handling messages in the meantime, so what `then` gets may be out of date
+/// with anything the node did since. Only an encoding error fails the call itself.
+impl<N: Node + 'static> Rpc<N> {
+    pub fn read_then(
+        &mut self,
+        service: &str,
+        key: impl Serialize,
+        then: impl FnOnce(&mut N, Result<Value, KvError>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let key = serde_json::to_value(key)?;
+        let request = KvRequest::Read { key: key.clone() };
+        let owned = service.to_owned();
+        self.kv_then(service, request, true, move |node, reply, rpc| {
+            let read = rpc.read_done(&owned, &key, reply);
+            then(node, read, rpc)
+        })
+    }
+
+    pub fn write_then(
+        &mut self,
+        service: &str,
+        key: impl Serialize,
+        value: impl Serialize,
+        then: impl FnOnce(&mut N, Result<(), KvError>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let key = serde_json::to_value(key)?;
+        let value = serde_json::to_value(value)?;
+        let request = KvRequest::Write {
+            key: key.clone(),
+            value: value.clone(),
+        };
+        let owned = service.to_owned();
+        self.kv_then(service, request, false, move |node, reply, rpc| {
+            let written = rpc.write_done(&owned, &key, value, reply);
+            then(node, written, rpc)
+        })
+    }
+
+    pub fn cas_then(
+        &mut self,
+        service: &str,
+        key: impl Serialize,
+        from: impl Serialize,
+        to: impl Serialize,
+        create_if_missing: bool,
+        then: impl FnOnce(&mut N, Result<(), KvError>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let key = serde_json::to_value(key)?;
+        let to = serde_json::to_value(to)?;
+        let request = KvRequest::Cas {
+            key: key.clone(),
+            from: serde_json::to_value(from)?,
+            to: to.clone(),
+            create_if_not_exists: create_if_missing,
+        };
+        let owned = service.to_owned();
+        self.kv_then(service, request, false, move |node, reply, rpc| {
+            let swapped = rpc.cas_done(&owned, &key, to, reply);
+            then(node, swapped, rpc)
+        })
+    }
+
+    /// [`Rpc::cas_update`], without blocking. Retriable errors are retried after backing off
+    /// as `policy` lays out, through [`Rpc::after`], so the node goes on meanwhile.
+    pub fn cas_update_then(
+        &mut self,
+        service: &str,
+        key: impl Serialize,
+        policy: RetryPolicy,
+        f: impl Fn(Option<u64>) -> u64 + 'static,
+        then: impl FnOnce(&mut N, Result<u64, KvError>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let update = CasUpdate {
+            service: service.to_owned(),
+            key: serde_json::to_value(key)?,
+            f: Box::new(f),
+            policy,
+            attempts: 1,
+            delay: policy.base_delay,
+        };
+        update.attempt(self, Box::new(then))
+    }
+
+    fn kv_then(
+        &mut self,
+        service: &str,
+        request: KvRequest,
+        idempotent: bool,
+        then: impl FnOnce(&mut N, Result<KvReply, KvError>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let then = move |node: &mut N, reply: Result<Message<Value>, RpcError>, rpc: &mut Self| {
+            then(node, reply.map_err(KvError::from).and_then(decode), rpc)
+        };
+        if idempotent {
+            self.request_retrying_then(service, request, RetryPolicy::default(), then)
+        } else {
+            self.request_then(service, request, RPC_TIMEOUT, then)
+        }
+    }
+}
+
+impl<N: Node> Rpc<N> {
+    /// A client for the kv service called `service`.
+    pub fn kv_client(&mut self, service: &'static str) -> KvClient<'_, N> {
+        KvClient { rpc: self, service }
+    }
+
+    pub fn seq_kv(&mut self) -> KvClient<'_, N> {
+        self.kv_client(SEQ_KV)
+    }
+
+    pub fn lin_kv(&mut self) -> KvClient<'_, N> {
+        self.kv_client(LIN_KV)
+    }
+
+    pub fn lww_kv(&mut self) -> KvClient<'_, N> {
+        self.kv_client(LWW_KV)
+    }
+}
+
+/// Typed requests to one of Maelstrom's key-value services, e.g.
+/// `rpc.lin_kv().cas_then(key, from, to, false, then)`.
+///
+/// The same requests as [`Rpc::read`] and friends, without naming the service each time, and
+/// with values read decoded as whatever the caller expects, a mismatch being
+/// [`KvError::Malformed`].
+pub struct KvClient<'a, N: Node> {
+    rpc: &'a mut Rpc<N>,
+    service: &'static str,
+}
+
+impl<N: Node> KvClient<'_, N> {
+    pub fn read<T: DeserializeOwned>(self, key: impl Serialize) -> Result<T, KvError> {
+        let value = self.rpc.read(self.service, key)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn write(self, key: impl Serialize, value: impl Serialize) -> Result<(), KvError> {
+        self.rpc.write(self.service, key, value)
+    }
+
+    pub fn cas(
+        self,
+        key: impl Serialize,
+        from: impl Serialize,
+        to: impl Serialize,
+        create_if_missing: bool,
+    ) -> Result<(), KvError> {
+        self.rpc.cas(self.service, key, from, to, create_if_missing)
+    }
+}
+
+impl<N: Node + 'static> KvClient<'_, N> {
+    pub fn read_then<T: DeserializeOwned>(
+        self,
+        key: impl Serialize,
+        then: impl FnOnce(&mut N, Result<T, KvError>, &mut Rpc<N>) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        self.rpc
+            .read_then(self.service, key, move |node, read, rpc| {
+                let read = read.and_then(|value| Ok(serde_json::from_value(value)?));
+                then(node, read, rpc)
+            })
+    }
+
+    pub fn write_then(
+        self,
+        key: impl Serialize,
+        value: impl Serialize,
+        then: impl FnOnce(&mut N, Result<(), KvError>, &mut Rpc<N>) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        self.rpc.write_then(self.service, key, value, then)
+    }
+
+    pub fn cas_then(
+        self,
+        key: impl Serialize,
+        from: impl Serialize,
+        to: impl Serialize,
+        create_if_missing: bool,
+        then: impl FnOnce(&mut N, Result<(), KvError>, &mut Rpc<N>) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        self.rpc
+            .cas_then(self.service, key, from, to, create_if_missing, then)
+    }
+}
+
+/// What becomes of a [`Rpc::cas_update_then`].
+type Updated<N> = Box<dyn FnOnce(&mut N, Result<u64, KvError>, &mut Rpc<N>) -> anyhow::Result<()>>;
+
+/// A [`Rpc::cas_update_then`] under way.
+struct CasUpdate {
+    service: String,
+    key: Value,
+    f: Box<dyn Fn(Option<u64>) -> u64>,
+    policy: RetryPolicy,
+    /// How many times the service has answered this update as retriable, plus one.
+    attempts: u32,
+    /// How long to back off for if it does again.
+    delay: Duration,
+}
+
+impl CasUpdate {
+    /// Reads the key, then tries to swap in the update, starting over should that fail.
+    fn attempt<N: Node + 'static>(self, rpc: &mut Rpc<N>, then: Updated<N>) -> anyhow::Result<()> {
+        let service = self.service.clone();
+        let key = self.key.clone();
+        rpc.read_then(&service.clone(), key.clone(), move |node, read, rpc| {
+            let current = match read.and_then(|value| Ok(serde_json::from_value(value)?)) {
+                Ok(value) => Some(value),
+                Err(KvError::NotFound) => None,
+                Err(error) => return then(node, Err(error), rpc),
+            };
+            let updated = (self.f)(current);
+
+            rpc.cas_then(
+                &service,
+                &key,
+                current,
+                updated,
+                current.is_none(),
+                move |node, swapped, rpc| match swapped {
+                    Ok(()) => then(node, Ok(updated), rpc),
+                    Err(KvError::PreconditionFailed(_)) => self.attempt(rpc, then),
+                    Err(error)
+                        if error.is_retriable() && self.attempts < self.policy.max_attempts =>
+                    {
+                        let delay = self.delay;
+                        let update = CasUpdate {
+                            attempts: self.attempts + 1,
+                            delay: delay.mul_f64(self.policy.multiplier),
+                            ..self
+                        };
+                        rpc.after(delay, move |_, rpc| update.attempt(rpc, then));
+                        Ok(())
+                    }
+                    Err(error) => then(node, Err(error), rpc),
+                },
+            )
+        })
+    }
+}
+
+/// A kv service's reply, with its errors turned into [`KvError`]s.
+pub(crate) fn decode(reply: Message<Value>) -> Result<KvReply, KvError> {
+    match serde_json::from_value(reply.body.payload)? {
+        KvReply::Error { code, text } => Err(refused(code, text)),
+        reply => Ok(reply),
+    }
+}
+
+/// The error a kv service answered with `code` and `text` stands for.
+fn refused(code: u32, text: String) -> KvError {
+    match ErrorCode::try_from(code) {
+        Ok(ErrorCode::KeyDoesNotExist) => KvError::NotFound,
+        Ok(ErrorCode::PreconditionFailed) => KvError::PreconditionFailed(text),
+        _ => KvError::Service { code, text },
+    }
+}
+
+pub(crate) fn unexpected(reply: KvReply) -> KvError {
+    KvError::Malformed(anyhow::anyhow!(
+        "Unexpected reply from kv service: {reply:?}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use crate::message::arbitrary;
+    use crate::message::Body;
+    use crate::rpc::Waiters;
+    use crate::runtime::dispatch;
+    use crate::transport::{InMemoryTransport, Transport};
+
+    /// Keeps what each read got, and how other requests turned out.
+    #[derive(Default)]
+    struct Reader {
+        reads: Vec<Value>,
+        outcomes: Vec<String>,
+    }
+
+    impl Node for Reader {
+        type Payload = Value;
+
+        fn step(&mut self, _input: Message<Value>, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Service {
+        rpc: Rpc<Reader>,
+        node: Reader,
+        transport: InMemoryTransport,
+        clock: Arc<MockClock>,
+    }
+
+    impl Service {
+        fn new() -> Self {
+            let transport = InMemoryTransport::new();
+            let clock = Arc::new(MockClock::new());
+            let rpc = Rpc::with_transport("n1", transport.clone()).with_clock(clock.clone());
+            Self {
+                rpc,
+                node: Reader::default(),
+                transport,
+                clock,
+            }
+        }
+
+        /// Answers the one request sent since the last answer with `payload`.
+        fn answer(&mut self, payload: Value) {
+            let [request] = self.transport.take().try_into().unwrap();
+            let reply = Message {
+                source: request.destination,
+                destination: request.source,
+                body: Body {
+                    id: None,
+                    in_reply_to: request.body.id,
+                    payload,
+                },
+            };
+            dispatch(&mut self.node, &reply, &mut self.rpc).unwrap();
+        }
+
+        /// The request sent last, and still unanswered.
+        fn request(&self) -> Value {
+            let sent = self.transport.sent();
+            let request = sent.last().expect("A request went out.");
+            assert_eq!(request.destination, LIN_KV);
+            request.body.payload.clone()
+        }
+
+        fn write(&mut self, value: u64) {
+            let written = |_: &mut Reader, written: Result<(), KvError>, _: &mut Rpc<Reader>| {
+                written?;
+                Ok(())
+            };
+            self.rpc.write_then(SEQ_KV, "k", value, written).unwrap();
+            self.answer(json!({"type": "write_ok"}));
+        }
+
+        /// Reads the key, which the service serves as `stale`.
+        fn read(&mut self, stale: u64) -> Value {
+            let read = |node: &mut Reader, read: Result<Value, KvError>, _: &mut Rpc<Reader>| {
+                node.reads.push(read?);
+                Ok(())
+            };
+            self.rpc.read_then(SEQ_KV, "k", read).unwrap();
+            self.answer(json!({"type": "read_ok", "value": stale}));
+            self.node.reads.pop().expect("The read is done.")
+        }
+    }
+
+    #[test]
+    fn stale_reads_are_served_as_is_by_default() {
+        let mut service = Service::new();
+        service.write(2);
+        assert_eq!(service.read(1), 1);
+    }
+
+    #[test]
+    fn own_writes_are_read_back_over_stale_replies() {
+        let mut service = Service::new();
+        service.rpc.read_your_writes(Duration::from_secs(1));
+
+        service.write(2);
+        assert_eq!(service.read(1), 2);
+        assert_eq!(service.read(1), 2);
+
+        // Past the window, the service answers for itself again.
+        service.clock.advance(Duration::from_secs(1));
+        assert_eq!(service.read(1), 1);
+    }
+
+    #[test]
+    fn own_writes_give_way_once_the_service_catches_up() {
+        let mut service = Service::new();
+        service.rpc.read_your_writes(Duration::from_secs(1));
+
+        service.write(2);
+        assert_eq!(service.read(2), 2);
+        // Someone else's write, after ours.
+        assert_eq!(service.read(3), 3);
+        // No longer this node's to vouch for.
+        assert_eq!(service.read(1), 1);
+    }
+
+    fn outcome<T: fmt::Debug>(
+        node: &mut Reader,
+        outcome: Result<T, KvError>,
+        _: &mut Rpc<Reader>,
+    ) -> anyhow::Result<()> {
+        let outcome = match outcome {
+            Ok(value) => format!("{value:?}"),
+            Err(KvError::Malformed(_)) => "malformed".to_owned(),
+            Err(error) => error.to_string(),
+        };
+        node.outcomes.push(outcome);
+        Ok(())
+    }
+
+    #[test]
+    fn clients_decode_reads_as_the_type_asked_for() {
+        let mut service = Service::new();
+
+        service
+            .rpc
+            .lin_kv()
+            .read_then("k", outcome::<Vec<u64>>)
+            .unwrap();
+        assert_eq!(service.request(), json!({"type": "read", "key": "k"}));
+        service.answer(json!({"type": "read_ok", "value": [1, 2]}));
+
+        service.rpc.lin_kv().read_then("k", outcome::<u64>).unwrap();
+        service.answer(json!({"type": "read_ok", "value": "two"}));
+
+        service.rpc.lin_kv().read_then("k", outcome::<u64>).unwrap();
+        service.answer(json!({"type": "error", "code": 20, "text": "missing"}));
+
+        assert_eq!(
+            service.node.outcomes,
+            ["[1, 2]", "malformed", "Key does not exist."]
+        );
+    }
+
+    #[test]
+    fn clients_turn_error_codes_into_kv_errors() {
+        let mut service = Service::new();
+
+        let cas = |service: &mut Service| {
+            service
+                .rpc
+                .lin_kv()
+                .cas_then("k", 1, 2, true, outcome)
+                .unwrap();
+        };
+        cas(&mut service);
+        assert_eq!(
+            service.request(),
+            json!({"type": "cas", "key": "k", "from": 1, "to": 2, "create_if_not_exists": true})
+        );
+        service.answer(json!({"type": "cas_ok"}));
+
+        cas(&mut service);
+        service.answer(json!({"type": "error", "code": 22, "text": "found 3"}));
+        cas(&mut service);
+        service.answer(json!({"type": "error", "code": 11, "text": "busy"}));
+
+        service.rpc.lin_kv().write_then("k", 4, outcome).unwrap();
+        assert_eq!(
+            service.request(),
+            json!({"type": "write", "key": "k", "value": 4})
+        );
+        service.answer(json!({"type": "write_ok"}));
+
+        let [cas_ok, failed, busy, written] = service.node.outcomes.clone().try_into().unwrap();
+        assert_eq!(cas_ok, "()");
+        assert_eq!(failed, "Precondition failed: found 3");
+        assert!(busy.contains("busy"), "{busy}");
+        assert_eq!(written, "()");
+    }
+
+    /// A `lin-kv` holding one number, which answers straight to the waiting request, and
+    /// lets another client's increment land right after each of the first `conflicts` reads.
+    struct ContendedLinKv {
+        waiters: Waiters,
+        value: Arc<Mutex<Option<u64>>>,
+        conflicts: usize,
+        swaps: Arc<Mutex<usize>>,
+    }
+
+    impl Transport for ContendedLinKv {
+        fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()> {
+            let mut value = self.value.lock().unwrap();
+            let payload = match serde_json::from_value(msg.body.payload.clone())? {
+                KvRequest::Read { .. } => {
+                    let read = match *value {
+                        Some(current) => json!({"type": "read_ok", "value": current}),
+                        None => json!({"type": "error", "code": 20, "text": "missing"}),
+                    };
+                    if self.conflicts > 0 {
+                        self.conflicts -= 1;
+                        *value = Some(value.unwrap_or(0) + 1);
+                    }
+                    read
+                }
+                KvRequest::Cas { from, to, .. } => {
+                    *self.swaps.lock().unwrap() += 1;
+                    if from == json!(*value) {
+                        *value = to.as_u64();
+                        json!({"type": "cas_ok"})
+                    } else {
+                        json!({"type": "error", "code": 22, "text": "conflict"})
+                    }
+                }
+                KvRequest::Write { .. } => unreachable!("cas_update never writes"),
+            };
+
+            let waiter = msg
+                .body
+                .id
+                .and_then(|id| self.waiters.lock().unwrap().remove(&id));
+            let reply = Message {
+                source: msg.destination.clone(),
+                destination: msg.source.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: msg.body.id,
+                    payload,
+                },
+            };
+            waiter
+                .expect("The request waits for its reply.")
+                .send(reply)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn updates_are_retried_until_no_write_lands_in_between() {
+        let waiters = Waiters::default();
+        let value = Arc::new(Mutex::new(Some(5)));
+        let swaps = Arc::new(Mutex::new(0));
+        let transport = ContendedLinKv {
+            waiters: Arc::clone(&waiters),
+            value: Arc::clone(&value),
+            conflicts: 2,
+            swaps: Arc::clone(&swaps),
+        };
+        let mut rpc: Rpc<Reader> = Rpc::new(transport, waiters, Arc::new(MockClock::new()));
+        rpc.node_id = Some("n1".to_owned());
+
+        let updated = rpc
+            .cas_update(LIN_KV, "k", RetryPolicy::default(), |current| {
+                current.unwrap_or(0) * 10
+            })
+            .unwrap();
+
+        // Read 5 and 6 in vain, each overtaken by another increment, then 7.
+        assert_eq!(updated, 70);
+        assert_eq!(*value.lock().unwrap(), Some(70));
+        assert_eq!(*swaps.lock().unwrap(), 3);
+    }
+
+    /// Answers each request straight to the waiting request with the next of `answers`,
+    /// keeping what was asked.
+    struct Scripted {
+        waiters: Waiters,
+        answers: VecDeque<Value>,
+        asked: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl Transport for Scripted {
+        fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()> {
+            self.asked.lock().unwrap().push(msg.body.payload.clone());
+            let payload = self.answers.pop_front().expect("An answer is scripted.");
+            let waiter = msg
+                .body
+                .id
+                .and_then(|id| self.waiters.lock().unwrap().remove(&id));
+            let reply = Message {
+                source: msg.destination.clone(),
+                destination: msg.source.clone(),
+                body: Body {
+                    id: None,
+                    in_reply_to: msg.body.id,
+                    payload,
+                },
+            };
+            waiter
+                .expect("The request waits for its reply.")
+                .send(reply)?;
+            Ok(())
+        }
+    }
+
+    /// Runs a blocking `cas_update` by `policy` against a service answering with `answers`,
+    /// returning its outcome, the types of the requests it sent and how long it took.
+    fn update(
+        policy: RetryPolicy,
+        answers: Vec<Value>,
+    ) -> (Result<u64, KvError>, Vec<Value>, Duration) {
+        let waiters = Waiters::default();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let transport = Scripted {
+            waiters: Arc::clone(&waiters),
+            answers: answers.into(),
+            asked: Arc::clone(&asked),
+        };
+        let clock = Arc::new(MockClock::new());
+        let mut rpc: Rpc<Reader> = Rpc::new(transport, waiters, clock.clone());
+        rpc.node_id = Some("n1".to_owned());
+
+        let start = clock.now();
+        let updated = rpc.cas_update(LIN_KV, "k", policy, |current| current.unwrap_or(0) + 1);
+        let asked = asked.lock().unwrap();
+        (
+            updated,
+            asked
+                .iter()
+                .map(|request| request["type"].clone())
+                .collect(),
+            clock.now() - start,
+        )
+    }
+
+    #[test]
+    fn blocking_updates_go_on_while_busy_but_stop_once_refused() {
+        let busy = json!({"type": "error", "code": 11, "text": "busy"});
+        let (updated, asked, _) = update(
+            RetryPolicy::default(),
+            vec![
+                busy.clone(),
+                json!({"type": "read_ok", "value": 1}),
+                busy,
+                json!({"type": "read_ok", "value": 1}),
+                json!({"type": "cas_ok"}),
+            ],
+        );
+        assert_eq!(updated.unwrap(), 2);
+        assert_eq!(asked, ["read", "read", "cas", "read", "cas"]);
+
+        let refused = json!({"type": "error", "code": 22, "text": "no"});
+        let (updated, asked, _) = update(RetryPolicy::default(), vec![refused]);
+        assert!(
+            matches!(updated, Err(KvError::PreconditionFailed(ref text)) if text == "no"),
+            "{updated:?}"
+        );
+        assert_eq!(asked, ["read"]);
+    }
+
+    #[test]
+    fn blocking_updates_back_off_while_busy_and_give_up_after_the_last_attempt() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+        };
+        let read_ok = json!({"type": "read_ok", "value": 1});
+        let busy = json!({"type": "error", "code": 11, "text": "busy"});
+        let answers = std::iter::repeat_n([read_ok, busy], 3).flatten().collect();
+
+        let (updated, asked, took) = update(policy, answers);
+        assert!(
+            updated.as_ref().is_err_and(KvError::is_retriable),
+            "{updated:?}"
+        );
+        assert_eq!(asked, ["read", "cas"].repeat(3));
+        // Backing off 100ms, then 200, and not again after the last.
+        assert_eq!(took, Duration::from_millis(300));
+    }
+
+    #[test]
+    fn updates_without_blocking_are_retried_when_overtaken() {
+        let mut service = Service::new();
+        service
+            .rpc
+            .cas_update_then(
+                LIN_KV,
+                "k",
+                RetryPolicy::default(),
+                |current| current.unwrap_or(0) + 1,
+                outcome,
+            )
+            .unwrap();
+
+        service.answer(json!({"type": "read_ok", "value": 1}));
+        assert_eq!(
+            service.request(),
+            json!({"type": "cas", "key": "k", "from": 1, "to": 2, "create_if_not_exists": false})
+        );
+        // Another client's write landed between the read and the cas.
+        service.answer(json!({"type": "error", "code": 22, "text": "found 4"}));
+        service.answer(json!({"type": "read_ok", "value": 4}));
+        assert_eq!(service.request()["to"], 5);
+        service.answer(json!({"type": "cas_ok"}));
+
+        assert_eq!(service.node.outcomes, ["5"]);
+    }
+
+    #[test]
+    fn updates_without_blocking_back_off_while_busy_and_give_up_after_the_last_attempt() {
+        let mut service = Service::new();
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+        };
+        service
+            .rpc
+            .cas_update_then(
+                LIN_KV,
+                "k",
+                policy,
+                |current| current.unwrap_or(0) + 1,
+                outcome,
+            )
+            .unwrap();
+
+        for delay in [100, 200] {
+            service.answer(json!({"type": "read_ok", "value": 1}));
+            service.answer(json!({"type": "error", "code": 11, "text": "busy"}));
+            assert!(service.transport.sent().is_empty());
+
+            let delay = Duration::from_millis(delay);
+            assert_eq!(
+                service.rpc.next_deadline(),
+                Some(service.clock.now() + delay)
+            );
+            service.clock.advance(delay);
+            let now = service.clock.now();
+            service.rpc.expire(&mut service.node, now).unwrap();
+        }
+        service.answer(json!({"type": "read_ok", "value": 1}));
+        service.answer(json!({"type": "error", "code": 11, "text": "busy"}));
+
+        assert!(service.transport.sent().is_empty());
+        assert_eq!(service.rpc.next_deadline(), None);
+        assert_eq!(service.node.outcomes, ["Error 11: busy"]);
+    }
+
+    fn request() -> impl Strategy<Value = KvRequest> {
+        prop_oneof![
+            arbitrary::value().prop_map(|key| KvRequest::Read { key }),
+            (arbitrary::value(), arbitrary::value())
+                .prop_map(|(key, value)| KvRequest::Write { key, value }),
+            (
+                arbitrary::value(),
+                arbitrary::value(),
+                arbitrary::value(),
+                any::<bool>()
+            )
+                .prop_map(|(key, from, to, create_if_not_exists)| KvRequest::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                }),
+        ]
+    }
+
+    fn reply() -> impl Strategy<Value = KvReply> {
+        prop_oneof![
+            arbitrary::value().prop_map(|value| KvReply::ReadOk { value }),
+            Just(KvReply::WriteOk {}),
+            Just(KvReply::CasOk {}),
+            (any::<u32>(), any::<String>()).prop_map(|(code, text)| KvReply::Error { code, text }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn requests_survive_the_wire(message in arbitrary::message(request())) {
+            arbitrary::round_trip(&message)?;
+        }
+
+        #[test]
+        fn replies_survive_the_wire(message in arbitrary::message(reply())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
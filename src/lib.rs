@@ -0,0 +1,30 @@
+//! Nodes for [Maelstrom](https://github.com/jepsen-io/maelstrom) workloads.
+//!
+//! A workload implements [`node::Node`] and is driven over stdin and stdout by
+//! [`runtime::run`]. With the `tokio` feature, [`async_runtime`] offers the same for nodes
+//! whose handlers are `async`. [`parallel`] runs handlers on several threads at once, for
+//! nodes whose handlers are CPU-bound. [`sim`] runs a whole cluster in one process instead, for
+//! testing without Maelstrom.
+
+#[cfg(feature = "tokio")]
+pub mod async_runtime;
+pub mod clock;
+pub mod composite;
+pub mod config;
+pub mod crdt;
+pub mod deadletter;
+pub mod dedup;
+pub mod heartbeat;
+pub mod kv;
+pub mod lock;
+pub mod log;
+pub mod message;
+pub mod metrics;
+pub mod node;
+pub mod parallel;
+pub(crate) mod random;
+pub mod rpc;
+pub mod runtime;
+pub mod sim;
+pub mod transport;
+pub mod workloads;
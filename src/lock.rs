@@ -0,0 +1,495 @@
+//! A lock shared by all nodes, kept in `lin-kv`, e.g. for transactions that have to see
+//! keys no other node is changing.
+//!
+//! A lock is a key that is `null` or missing while the lock is free, and names its holder
+//! while taken: it's acquired by a cas from `null` to the holder, and released by a cas back.
+//! Since `lin-kv` is linearizable, at most one cas from `null` wins.
+//!
+//! A holder that crashes never releases its lock, so a lock whose value hasn't changed for
+//! [`LOCK_TTL`] counts as stale and is taken over. That may be wrong, if the holder is only
+//! slow: each acquisition therefore comes with a fencing token, higher than any before it,
+//! which whatever the lock guards can check to turn away a holder that was taken over.
+//!
+//! Like the `_then` kv requests, taking a lock returns right away and hands the lock on once
+//! it's held, so that the node goes on handling messages, the holder's release among them,
+//! while it waits.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::kv::{KvError, LIN_KV};
+use crate::log;
+use crate::node::Node;
+use crate::rpc::{RetryPolicy, Rpc};
+
+/// How long a lock may stay with the same holder before others take it over.
+pub const LOCK_TTL: Duration = Duration::from_secs(5);
+
+/// How long to wait before trying a taken lock again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A lock this node holds, until given to [`Rpc::unlock_then`].
+#[derive(Debug)]
+#[must_use = "a lock is only released by Rpc::unlock_then"]
+pub struct Lock {
+    key: String,
+    token: u64,
+    value: Value,
+}
+
+impl Lock {
+    /// Higher than the token of any earlier holder of the same lock.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+}
+
+/// What becomes of a lock being taken.
+type Locked<N> = Box<dyn FnOnce(&mut N, Result<Lock, KvError>, &mut Rpc<N>) -> anyhow::Result<()>>;
+
+/// What becomes of the outcome of a [`Rpc::with_lock`].
+type Done<N, T> = Box<dyn FnOnce(&mut N, Result<T, KvError>, &mut Rpc<N>) -> anyhow::Result<()>>;
+
+/// A lock held for [`Rpc::with_lock`], which the body gives back with its outcome once done.
+///
+/// One that the body fails or lets go of before it's done is released by `with_lock`. One
+/// dropped later on, say in a callback that never ran, stays taken until it goes stale.
+#[must_use = "the lock is only released by Held::release"]
+pub struct Held<N: Node, T> {
+    token: u64,
+    /// Emptied once released. Shared with [`Rpc::with_lock`] while the body runs.
+    holding: Rc<RefCell<Option<Holding<N, T>>>>,
+}
+
+struct Holding<N: Node, T> {
+    lock: Lock,
+    then: Done<N, T>,
+}
+
+impl<N: Node + 'static, T: 'static> Held<N, T> {
+    /// The lock's fencing token.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// Releases the lock, then hands `result` on to [`Rpc::with_lock`]'s `then`.
+    ///
+    /// If the lock was taken over in the meantime, that's an error even if `result` isn't,
+    /// since the body didn't have the lock to itself.
+    pub fn release(self, rpc: &mut Rpc<N>, result: Result<T, KvError>) -> anyhow::Result<()> {
+        release(&self.holding, rpc, result)
+    }
+}
+
+impl<N: Node, T> Drop for Held<N, T> {
+    fn drop(&mut self) {
+        // Unless `with_lock` is still there to release it.
+        if Rc::strong_count(&self.holding) > 1 {
+            return;
+        }
+        if let Some(Holding { lock, .. }) = self.holding.borrow_mut().take() {
+            log::warning!(
+                "Lock {} was dropped without being released, it stays taken for {LOCK_TTL:?}.",
+                lock.key
+            );
+        }
+    }
+}
+
+/// Releases the lock in `holding`, unless it was already, then hands `result` on.
+fn release<N: Node + 'static, T: 'static>(
+    holding: &RefCell<Option<Holding<N, T>>>,
+    rpc: &mut Rpc<N>,
+    result: Result<T, KvError>,
+) -> anyhow::Result<()> {
+    let Some(Holding { lock, then }) = holding.borrow_mut().take() else {
+        return Ok(());
+    };
+    let key = lock.key.clone();
+    rpc.unlock_then(lock, move |node, unlocked, rpc| {
+        let result = match (result, unlocked) {
+            (Ok(value), Ok(())) => Ok(value),
+            (Ok(_), Err(error)) => Err(error),
+            (Err(error), unlocked) => {
+                if let Err(unlock_error) = unlocked {
+                    log::warning!("Could not release lock {key}: {unlock_error}");
+                }
+                Err(error)
+            }
+        };
+        then(node, result, rpc)
+    })
+}
+
+/// A lock being taken.
+struct Acquire<N: Node> {
+    key: String,
+    ttl: Duration,
+    token: u64,
+    /// What the lock holds once this node has it.
+    value: Value,
+    /// The taken lock as last read, and since when it has read that way.
+    seen: Option<(Value, Instant)>,
+    then: Locked<N>,
+}
+
+impl<N: Node + 'static> Acquire<N> {
+    /// Tries to swap this node in for a free lock, or for a stale one.
+    fn attempt(self, rpc: &mut Rpc<N>) -> anyhow::Result<()> {
+        let from = match &self.seen {
+            Some((taken, since)) if !taken.is_null() && rpc.now() - *since >= self.ttl => {
+                log::info!(
+                    "Taking over lock {} from {taken}, unchanged for {:?}.",
+                    self.key,
+                    self.ttl
+                );
+                taken.clone()
+            }
+            _ => Value::Null,
+        };
+
+        let (key, value) = (self.key.clone(), self.value.clone());
+        rpc.cas_then(
+            LIN_KV,
+            key,
+            &from,
+            value,
+            from.is_null(),
+            move |node, swapped, rpc| match swapped {
+                Ok(()) => self.acquired(node, rpc),
+                Err(KvError::PreconditionFailed(_)) => self.check(rpc),
+                // The cas may have gone through after all, so the lock may be this node's,
+                // which nobody would release before it went stale.
+                Err(error) if !error.is_abort() => self.give_up(rpc, error),
+                Err(error) => (self.then)(node, Err(error), rpc),
+            },
+        )
+    }
+
+    /// Reads who holds the lock, and tries again once it looks free or stale.
+    fn check(mut self, rpc: &mut Rpc<N>) -> anyhow::Result<()> {
+        let key = self.key.clone();
+        rpc.read_then(LIN_KV, key, move |node, read, rpc| {
+            let taken = match read {
+                Ok(taken) if taken == self.value => return self.acquired(node, rpc),
+                Ok(taken) => taken,
+                Err(KvError::NotFound) => Value::Null,
+                Err(error) => return (self.then)(node, Err(error), rpc),
+            };
+            if taken.is_null() {
+                return self.attempt(rpc);
+            }
+            if self.seen.as_ref().is_none_or(|(seen, _)| *seen != taken) {
+                self.seen = Some((taken, rpc.now()));
+            }
+            rpc.after(RETRY_INTERVAL, move |_, rpc| self.attempt(rpc));
+            Ok(())
+        })
+    }
+
+    fn acquired(self, node: &mut N, rpc: &mut Rpc<N>) -> anyhow::Result<()> {
+        let lock = Lock {
+            key: self.key,
+            token: self.token,
+            value: self.value,
+        };
+        (self.then)(node, Ok(lock), rpc)
+    }
+
+    /// Fails with `error`, first releasing the lock if a cas that failed with it went
+    /// through.
+    fn give_up(self, rpc: &mut Rpc<N>, error: KvError) -> anyhow::Result<()> {
+        let key = self.key.clone();
+        rpc.read_then(LIN_KV, key, move |node, read, rpc| match read {
+            Ok(taken) if taken == self.value => {
+                let lock = Lock {
+                    key: self.key,
+                    token: self.token,
+                    value: self.value,
+                };
+                let then = self.then;
+                rpc.unlock_then(lock, move |node, unlocked, rpc| {
+                    if let Err(unlock_error) = unlocked {
+                        log::warning!("Could not release a lock taken by mistake: {unlock_error}");
+                    }
+                    then(node, Err(error), rpc)
+                })
+            }
+            _ => (self.then)(node, Err(error), rpc),
+        })
+    }
+}
+
+impl<N: Node + 'static> Rpc<N> {
+    /// Takes the lock `key`, and hands it to `then` once held. Someone else's lock is waited
+    /// out, unless they have held it unchanged for `ttl`.
+    pub fn lock_then(
+        &mut self,
+        key: &str,
+        ttl: Duration,
+        then: impl FnOnce(&mut N, Result<Lock, KvError>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let holder = self.node_id()?.to_owned();
+        let key = key.to_owned();
+        let fence = format!("{key}/fence");
+        self.cas_update_then(
+            LIN_KV,
+            fence,
+            RetryPolicy::default(),
+            |token| token.map_or(1, |token| token + 1),
+            move |node, token, rpc| {
+                let token = match token {
+                    Ok(token) => token,
+                    Err(error) => return then(node, Err(error), rpc),
+                };
+                let acquire = Acquire {
+                    key,
+                    ttl,
+                    token,
+                    value: json!({ "holder": holder, "token": token }),
+                    seen: None,
+                    then: Box::new(then),
+                };
+                acquire.attempt(rpc)
+            },
+        )
+    }
+
+    /// Releases `lock`, and tells `then` whether that worked: it fails with
+    /// [`KvError::PreconditionFailed`] if someone else took the lock over in the meantime.
+    pub fn unlock_then(
+        &mut self,
+        lock: Lock,
+        then: impl FnOnce(&mut N, Result<(), KvError>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let Lock { key, token, value } = lock;
+        self.cas_then(
+            LIN_KV,
+            key.clone(),
+            value,
+            Value::Null,
+            false,
+            move |node, released, rpc| {
+                let released = match released {
+                    Err(KvError::PreconditionFailed(_)) => Err(KvError::PreconditionFailed(
+                        format!("Lock {key} was taken over from token {token}."),
+                    )),
+                    released => released,
+                };
+                then(node, released, rpc)
+            },
+        )
+    }
+
+    /// Runs `body` holding the lock `key`, which `body` releases through the [`Held`] it's
+    /// given once done, whether it succeeded or not. `then` gets the outcome `body` released
+    /// the lock with, or why the lock couldn't be taken or released.
+    ///
+    /// Should `body` fail, or drop the `Held` without releasing it, the lock is released for
+    /// it, and `then` gets the failure as [`KvError::Failed`].
+    pub fn with_lock<T: 'static>(
+        &mut self,
+        key: &str,
+        body: impl FnOnce(&mut N, Held<N, T>, &mut Self) -> anyhow::Result<()> + 'static,
+        then: impl FnOnce(&mut N, Result<T, KvError>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        self.lock_then(key, LOCK_TTL, move |node, lock, rpc| {
+            let lock = match lock {
+                Ok(lock) => lock,
+                Err(error) => return then(node, Err(error), rpc),
+            };
+            let key = lock.key.clone();
+            let token = lock.token;
+            let then: Done<N, T> = Box::new(then);
+            let holding = Rc::new(RefCell::new(Some(Holding { lock, then })));
+            let held = Held {
+                token,
+                holding: Rc::clone(&holding),
+            };
+
+            let failure = match body(node, held, rpc) {
+                // `then` has its outcome already.
+                Err(error) if holding.borrow().is_none() => return Err(error),
+                Err(error) => error,
+                // Released already, or held on to for later.
+                Ok(()) if Rc::strong_count(&holding) > 1 || holding.borrow().is_none() => {
+                    return Ok(())
+                }
+                Ok(()) => anyhow::anyhow!("Lock {key} was dropped without being released."),
+            };
+            release(&holding, rpc, Err(KvError::Failed(failure)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+    use crate::sim::{NetworkConfig, Simulation};
+
+    const KEY: &str = "lock";
+
+    /// Takes the lock on `take`, holding it for `hold` milliseconds, or for good on `hoard`.
+    #[derive(Default)]
+    struct Locker {
+        /// When each hold of the lock started and ended.
+        held: Vec<(Instant, Instant)>,
+        hoarded: Vec<Lock>,
+    }
+
+    impl Node for Locker {
+        type Payload = Value;
+
+        fn step(&mut self, input: Message<Value>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            let ttl = Duration::from_millis(input.body.payload["ttl"].as_u64().unwrap_or(5000));
+            match input.body.payload["type"].as_str() {
+                Some("take") => {
+                    let hold = Duration::from_millis(input.body.payload["hold"].as_u64().unwrap());
+                    rpc.with_lock(
+                        KEY,
+                        move |_, held, rpc| {
+                            let start = rpc.now();
+                            rpc.after(hold, move |node: &mut Locker, rpc| {
+                                node.held.push((start, rpc.now()));
+                                let token = held.token();
+                                held.release(rpc, Ok(token))
+                            });
+                            Ok(())
+                        },
+                        move |_, token, rpc| {
+                            rpc.reply(
+                                &input,
+                                json!({ "type": "take_ok", "token": token.unwrap() }),
+                            )
+                        },
+                    )
+                }
+                // Fails, or gives up the lock without releasing it, on `drop`.
+                Some(kind @ ("fail" | "drop")) => {
+                    let drop = kind == "drop";
+                    rpc.with_lock(
+                        KEY,
+                        move |_, held: Held<Locker, ()>, _| {
+                            if drop {
+                                std::mem::drop(held);
+                                return Ok(());
+                            }
+                            let _held = held;
+                            anyhow::bail!("Something broke.")
+                        },
+                        move |_, done, rpc| {
+                            let error = done.unwrap_err();
+                            assert!(matches!(error, KvError::Failed(_)), "{error}");
+                            let error = error.to_string();
+                            rpc.reply(&input, json!({ "type": "failed", "error": error }))
+                        },
+                    )
+                }
+                Some("hoard") => rpc.lock_then(KEY, ttl, move |node, lock, rpc| {
+                    let lock = lock.unwrap();
+                    let token = lock.token();
+                    node.hoarded.push(lock);
+                    rpc.reply(&input, json!({ "type": "hoard_ok", "token": token }))
+                }),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn replies(sim: &Simulation<Locker>, kind: &str) -> Vec<(Instant, Message<Value>)> {
+        sim.history()
+            .into_iter()
+            .filter(|(_, msg)| msg.body.payload["type"] == kind)
+            .collect()
+    }
+
+    #[test]
+    fn a_taken_lock_is_waited_for_until_released() {
+        let mut sim =
+            Simulation::new(2, NetworkConfig::default(), |_| Ok(Locker::default())).unwrap();
+        let hold = json!({ "type": "take", "hold": 200 });
+        sim.send("c1", "n0", &hold).unwrap();
+        sim.send("c2", "n1", &hold).unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        let taken = replies(&sim, "take_ok");
+        assert_eq!(taken.len(), 2);
+        assert_ne!(
+            taken[0].1.body.payload["token"],
+            taken[1].1.body.payload["token"]
+        );
+
+        let mut held: Vec<_> = ["n0", "n1"]
+            .iter()
+            .flat_map(|id| sim.node(id).unwrap().held.clone())
+            .collect();
+        held.sort();
+        assert_eq!(held.len(), 2);
+        // Whoever lost the race only got the lock once the winner let go, well before it
+        // could have gone stale.
+        assert!(held[1].0 >= held[0].1, "holds overlap: {held:?}");
+        assert!(held[1].0 - held[0].1 < LOCK_TTL);
+    }
+
+    #[test]
+    fn a_lock_is_released_for_a_body_that_fails_or_drops_it() {
+        for kind in ["fail", "drop"] {
+            let mut sim =
+                Simulation::new(2, NetworkConfig::default(), |_| Ok(Locker::default())).unwrap();
+            let failed = sim
+                .request("c1", "n0", json!({ "type": kind }), Duration::from_secs(1))
+                .unwrap()
+                .expect("The failure is handed on.");
+            let error = failed.body.payload["error"].as_str().unwrap();
+            assert!(
+                error.contains(if kind == "fail" { "broke" } else { "dropped" }),
+                "{kind}: {error}"
+            );
+
+            let asked = sim.now();
+            let taken = sim
+                .request(
+                    "c2",
+                    "n1",
+                    json!({ "type": "take", "hold": 0 }),
+                    Duration::from_secs(1),
+                )
+                .unwrap();
+            assert!(taken.is_some(), "{kind}: the lock is still taken");
+            assert!(
+                sim.now() - asked < RETRY_INTERVAL,
+                "{kind}: had to wait for it"
+            );
+        }
+    }
+
+    #[test]
+    fn a_lock_never_released_is_taken_over_once_stale() {
+        let mut sim =
+            Simulation::new(2, NetworkConfig::default(), |_| Ok(Locker::default())).unwrap();
+        sim.request(
+            "c1",
+            "n0",
+            json!({ "type": "hoard" }),
+            Duration::from_secs(1),
+        )
+        .unwrap()
+        .unwrap();
+        let asked = sim.now();
+        sim.send("c2", "n1", json!({ "type": "hoard", "ttl": 300 }))
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        let hoarded = replies(&sim, "hoard_ok");
+        assert_eq!(hoarded.len(), 2);
+        assert!(hoarded[1].0 - asked >= Duration::from_millis(300));
+        assert!(
+            hoarded[1].1.body.payload["token"].as_u64()
+                > hoarded[0].1.body.payload["token"].as_u64()
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! What the runtime writes to stderr, and how much of it.
+
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Once;
+
+use anyhow::bail;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::message::Message;
+
+/// How much goes to stderr, each level including those before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Nothing but the error a node fails with.
+    Error,
+    /// Input that was dropped or refused, and anything else that's probably a bug somewhere.
+    Warn,
+    /// The node starting and stopping, with its metrics, and peers going down or coming back.
+    Info,
+    /// Every message sent and received, one line each.
+    Debug,
+    /// Every message as a row of a table, with its payload, as with [`set_trace`].
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        Ok(match level {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => bail!("Unknown log level {level:?}, expected error, warn, info, debug or trace."),
+        })
+    }
+}
+
+/// Unset, so that the first look at it reads `TEMPEST_LOG`.
+const UNSET: u8 = u8::MAX;
+
+static LEVEL: AtomicU8 = AtomicU8::new(UNSET);
+
+/// Logs everything up to `level`. Without it, the level comes from `TEMPEST_LOG`, where `0`,
+/// `off` and `false` mean [`Level::Error`], and is [`Level::Warn`] otherwise.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+fn level() -> Level {
+    let level = match LEVEL.load(Ordering::Relaxed) {
+        UNSET => {
+            let level = match std::env::var("TEMPEST_LOG").as_deref() {
+                Ok("0" | "off" | "false") => Level::Error,
+                Ok(level) => level.parse().unwrap_or(Level::Warn),
+                Err(_) => Level::Warn,
+            };
+            // Racing to set it is fine, both read the same variable.
+            let _ =
+                LEVEL.compare_exchange(UNSET, level as u8, Ordering::Relaxed, Ordering::Relaxed);
+            return level;
+        }
+        level => level,
+    };
+
+    [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ][usize::from(level)]
+}
+
+pub(crate) fn enabled(level: Level) -> bool {
+    self::level() >= level
+}
+
+/// Like `eprintln!`, at [`Level::Warn`].
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Like `eprintln!`, at [`Level::Info`].
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {info, warning};
+
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Logs messages as a table meant for people to read, instead of one line of `key=value`s
+/// each, whatever the level. Off by default, since decoding every payload again slows down
+/// benchmarks.
+pub fn set_trace(enabled: bool) {
+    TRACE.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn log_recv<P: Serialize>(msg: &Message<P>) {
+    log("recv", msg);
+}
+
+pub(crate) fn log_send<P: Serialize>(msg: &Message<P>) {
+    log("send", msg);
+}
+
+fn log<P: Serialize>(direction: &str, msg: &Message<P>) {
+    if let Some(line) = line(level(), TRACE.load(Ordering::Relaxed), direction, msg) {
+        eprintln!("{line}");
+    }
+}
+
+/// What to log about `msg` at `level`, if anything: a row of the table if `tabular`, and one
+/// line of `key=value`s otherwise.
+fn line<P: Serialize>(
+    level: Level,
+    tabular: bool,
+    direction: &str,
+    msg: &Message<P>,
+) -> Option<String> {
+    if tabular || level >= Level::Trace {
+        return Some(trace(direction, msg));
+    }
+
+    if level < Level::Debug {
+        return None;
+    }
+
+    Some(format!(
+        "{direction} {} -> {} {} msg_id={} in_reply_to={}",
+        msg.source,
+        msg.destination,
+        msg.kind(),
+        Id(msg.body.id),
+        Id(msg.body.in_reply_to),
+    ))
+}
+
+/// Payloads longer than this many characters are cut short in the trace.
+const TRACE_PAYLOAD_WIDTH: usize = 60;
+
+fn trace<P: Serialize>(direction: &str, msg: &Message<P>) -> String {
+    static HEADER: Once = Once::new();
+    HEADER.call_once(|| {
+        eprintln!(
+            "{:<4}  {:<8}  {:<16}  {:>6}  {:>6}  payload",
+            "dir", "peer", "type", "msg_id", "reply"
+        );
+    });
+
+    let peer = match direction {
+        "recv" => &msg.source,
+        _ => &msg.destination,
+    };
+
+    format!(
+        "{direction:<4}  {peer:<8}  {:<16}  {:>6}  {:>6}  {}",
+        msg.kind(),
+        Id(msg.body.id),
+        Id(msg.body.in_reply_to),
+        payload(&msg.body.payload),
+    )
+}
+
+/// The payload's fields other than `type`, which has a column of its own.
+fn payload(payload: &impl Serialize) -> String {
+    let mut payload = serde_json::to_value(payload).unwrap_or(Value::Null);
+    if let Some(fields) = payload.as_object_mut() {
+        fields.remove("type");
+    }
+
+    let payload = payload.to_string();
+    match payload.char_indices().nth(TRACE_PAYLOAD_WIDTH) {
+        Some((end, _)) => format!("{}...", &payload[..end]),
+        None => payload,
+    }
+}
+
+struct Id(Option<usize>);
+
+impl Display for Id {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(id) => id.fmt(f),
+            None => f.pad("-"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::Body;
+
+    #[test]
+    fn messages_are_logged_only_at_debug_and_above() {
+        let msg = Message {
+            source: "c1".to_owned(),
+            destination: "n1".to_owned(),
+            body: Body {
+                id: Some(4),
+                in_reply_to: None,
+                payload: json!({"type": "echo", "echo": "hi"}),
+            },
+        };
+
+        for level in [Level::Error, Level::Warn, Level::Info] {
+            assert_eq!(line(level, false, "recv", &msg), None, "{level:?}");
+        }
+        assert_eq!(
+            line(Level::Debug, false, "recv", &msg).as_deref(),
+            Some("recv c1 -> n1 echo msg_id=4 in_reply_to=-")
+        );
+        let row = line(Level::Trace, false, "recv", &msg).unwrap();
+        assert!(row.contains(r#"{"echo":"hi"}"#), "{row}");
+        // Tracing asks for every message, whatever the level.
+        assert_eq!(line(Level::Error, true, "recv", &msg), Some(row));
+    }
+
+    #[test]
+    fn levels_are_told_by_name() {
+        assert_eq!("error".parse::<Level>().unwrap(), Level::Error);
+        assert_eq!("trace".parse::<Level>().unwrap(), Level::Trace);
+        assert!("loud".parse::<Level>().is_err());
+        assert!(Level::Error < Level::Warn && Level::Debug < Level::Trace);
+    }
+}
@@ -1,149 +1,140 @@
-use std::io::{StdoutLock, Write};
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context};
-use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use tempest::config::Config;
+use tempest::node::{Node, NodeContext};
+use tempest::runtime::{replay, run};
+#[cfg(feature = "kafka")]
+use tempest::workloads::KafkaNode;
+#[cfg(feature = "txn")]
+use tempest::workloads::TxnNode;
+#[cfg(feature = "broadcast")]
+use tempest::workloads::{BroadcastNode, CausalBroadcastNode, GSetNode, TreeBroadcastNode};
+use tempest::workloads::{
+    CounterNode, EchoNode, LinKvNode, PnCounterNode, SnowflakeIdNode, SrKvNode, TsoNode,
+    UniqueIdNode,
+};
+
+/// The workloads to pick from, as the first argument. Without one, the node echoes.
+const WORKLOADS: &[&str] = &[
+    "echo",
+    "unique-ids",
+    "snowflake-ids",
+    #[cfg(feature = "broadcast")]
+    "broadcast",
+    #[cfg(feature = "broadcast")]
+    "tree-broadcast",
+    #[cfg(feature = "broadcast")]
+    "causal-broadcast",
+    "g-counter",
+    #[cfg(feature = "broadcast")]
+    "g-set",
+    "pn-counter",
+    #[cfg(feature = "kafka")]
+    "kafka",
+    "lin-kv",
+    "sr-kv",
+    "tso",
+    #[cfg(feature = "txn")]
+    "txn",
+];
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Message {
-    #[serde(rename = "src")]
-    source: String,
-    #[serde(rename = "dest")]
-    destination: String,
-    body: Body,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Body {
-    #[serde(rename = "msg_id")]
-    id: Option<usize>,
-    in_reply_to: Option<usize>,
-
-    #[serde(flatten)]
-    payload: Payload,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Init {
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    InitOk {},
-
-    Echo {
-        echo: String,
-    },
-    EchoOk {
-        echo: String,
-    },
-}
-
-#[derive(Debug)]
-enum EchoNodeState {
-    Initializing,
-    Ready { self_id: String, message_id: usize },
-}
-
-struct EchoNode {
-    state: EchoNodeState,
-}
-
-impl EchoNode {
-    fn next(&mut self, message: Message, output: &mut StdoutLock) -> anyhow::Result<()> {
-        match &mut self.state {
-            EchoNodeState::Initializing => {
-                let payload = message.body.payload;
-
-                match payload {
-                    Payload::Echo { .. } => {
-                        // Not in ready state. Ignore.
-                    }
-
-                    Payload::Init {
-                        node_id,
-                        node_ids: _,
-                    } => {
-                        self.state = EchoNodeState::Ready {
-                            self_id: node_id,
-                            message_id: 1,
-                        };
-                        let reply = Message {
-                            source: message.destination,
-                            destination: message.source,
-                            body: Body {
-                                id: Some(0),
-                                in_reply_to: message.body.id,
-                                payload: Payload::InitOk {},
-                            },
-                        };
-
-                        serde_json::to_writer(&mut *output, &reply)
-                            .context("Could not encode maelstrom output.")?;
-
-                        output.write_all(b"\n").context("New Line")?;
-                    }
-
-                    _ => (),
-                }
+fn main() -> anyhow::Result<()> {
+    let mut workload = None;
+    let mut replay_from = None;
+    let mut config_from = None;
+    let mut log_level = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => tempest::log::set_trace(true),
+            "--log-level" => {
+                log_level = Some(args.next().context("--log-level needs a level.")?.parse()?);
             }
-
-            EchoNodeState::Ready {
-                self_id,
-                message_id,
-            } => {
-                let payload = message.body.payload;
-
-                if message.destination == self_id.as_ref() {
-                    match payload {
-                        Payload::Echo { echo } => {
-                            let reply = Message {
-                                source: message.destination,
-                                destination: message.source,
-                                body: Body {
-                                    id: Some(*message_id),
-                                    in_reply_to: message.body.id,
-                                    payload: Payload::EchoOk { echo },
-                                },
-                            };
-
-                            serde_json::to_writer(&mut *output, &reply)
-                                .context("Could not encode maelstrom output.")?;
-
-                            output.write_all(b"\n").context("New Line")?;
-
-                            *message_id += 1;
-                        }
-
-                        Payload::Init { .. } => {
-                            // Already in ready state. Fail!
-                            bail!("Received Init message while in ready state.")
-                        }
-
-                        _ => (),
-                    }
-                }
+            "--config" => {
+                config_from = Some(PathBuf::from(
+                    args.next().context("--config needs a path.")?,
+                ));
             }
+            "--max-in-flight" => tempest::rpc::set_max_in_flight(
+                args.next()
+                    .context("--max-in-flight needs a number.")?
+                    .parse()
+                    .context("--max-in-flight needs a number.")?,
+            ),
+            "--validate" => tempest::message::set_validate(true),
+            "--state-file" => tempest::runtime::set_state_file(PathBuf::from(
+                args.next().context("--state-file needs a path.")?,
+            )),
+            "--deadletter" => tempest::deadletter::set_path(Path::new(
+                &args.next().context("--deadletter needs a path.")?,
+            ))?,
+            "--summary" => tempest::metrics::set_summary_path(PathBuf::from(
+                args.next().context("--summary needs a path.")?,
+            )),
+            "--replay" => {
+                replay_from = Some(PathBuf::from(
+                    args.next().context("--replay needs a path.")?,
+                ));
+            }
+            _ if arg.starts_with("--") => bail!("Unknown option {arg}."),
+            _ if workload.is_none() => workload = Some(arg),
+            _ => bail!("Unexpected argument {arg}."),
         }
-        Ok(())
     }
-}
 
-fn main() -> anyhow::Result<()> {
-    let stdin = std::io::stdin().lock();
-    let mut stdout = std::io::stdout().lock();
-    let inputs = Deserializer::from_reader(stdin).into_iter::<Message>();
-    let mut node = EchoNode {
-        state: EchoNodeState::Initializing,
+    // Flags override env variables, which override the config file.
+    let config = match config_from {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
     };
+    config.apply();
+    if let Some(level) = log_level {
+        tempest::log::set_level(level);
+    }
 
-    for input in inputs {
-        let input = input.context("Could not decode maelstrom input.")?;
-
-        node.next(input, &mut stdout)?;
+    let replay_from = replay_from.as_deref();
+    match workload.as_deref().unwrap_or("echo") {
+        "echo" => start(replay_from, |_| Ok(EchoNode)),
+        "unique-ids" => start(replay_from, |context| Ok(UniqueIdNode::new(context))),
+        "snowflake-ids" => start(replay_from, SnowflakeIdNode::new),
+        #[cfg(feature = "broadcast")]
+        "broadcast" => {
+            let topology = config.topology()?;
+            let gossip = config.gossip()?;
+            start(replay_from, |context| {
+                Ok(BroadcastNode::with_topology(context, topology).with_gossip(gossip))
+            })
+        }
+        #[cfg(feature = "broadcast")]
+        "tree-broadcast" => start(replay_from, |context| Ok(TreeBroadcastNode::new(context))),
+        #[cfg(feature = "broadcast")]
+        "causal-broadcast" => start(replay_from, |context| Ok(CausalBroadcastNode::new(context))),
+        "g-counter" => start(replay_from, |context| Ok(CounterNode::new(context))),
+        #[cfg(feature = "broadcast")]
+        "g-set" => start(replay_from, |context| Ok(GSetNode::new(context))),
+        "pn-counter" => start(replay_from, |context| Ok(PnCounterNode::new(context))),
+        #[cfg(feature = "kafka")]
+        "kafka" => start(replay_from, |context| Ok(KafkaNode::new(context))),
+        "lin-kv" => start(replay_from, |context| Ok(LinKvNode::new(context))),
+        "sr-kv" => start(replay_from, |context| Ok(SrKvNode::new(context))),
+        "tso" => start(replay_from, |_| Ok(TsoNode::default())),
+        #[cfg(feature = "txn")]
+        "txn" => start(replay_from, |_| Ok(TxnNode)),
+        workload => bail!(
+            "Unknown workload {workload:?}, expected one of: {}.",
+            WORKLOADS.join(", ")
+        ),
     }
+}
 
-    Ok(())
+fn start<N: Node>(
+    replay_from: Option<&Path>,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<()> {
+    match replay_from {
+        Some(path) => replay(path, init),
+        None => run(init),
+    }
 }
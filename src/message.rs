@@ -0,0 +1,455 @@
+//! The messages Maelstrom exchanges with nodes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::bail;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+static VALIDATE: AtomicBool = AtomicBool::new(false);
+
+/// Checks every outgoing message against the shape Maelstrom expects before sending it, which
+/// costs encoding it an extra time. A bad one is logged, or an error in strict mode.
+pub fn set_validate(enabled: bool) {
+    VALIDATE.store(enabled, Ordering::Relaxed);
+}
+
+/// Always on in this crate's own tests, so that a node sending something malformed fails them.
+pub(crate) fn validating() -> bool {
+    cfg!(test) || VALIDATE.load(Ordering::Relaxed)
+}
+
+/// A single line of Maelstrom's protocol, carrying a workload-specific payload `P`.
+///
+/// Fields this doesn't know, such as the `id` Maelstrom puts on every message, are ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message<P> {
+    #[serde(rename = "src")]
+    pub source: String,
+    #[serde(rename = "dest")]
+    pub destination: String,
+    pub body: Body<P>,
+}
+
+/// The part of a message shared by every payload, with the payload flattened into it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Body<P> {
+    #[serde(rename = "msg_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<usize>,
+
+    /// Flattened into the body next to `msg_id` and `in_reply_to`. Payloads are internally
+    /// tagged with `type`, so a payload field named `type`, `msg_id` or `in_reply_to` would
+    /// clash with them on the wire.
+    ///
+    /// Payloads must not deny unknown fields: anything in the body that the payload's variant
+    /// doesn't name is dropped, so protocol additions don't break nodes that predate them.
+    #[serde(flatten)]
+    pub payload: P,
+}
+
+impl<P> Message<P> {
+    /// A reply to this message carrying `payload` under the fresh `msg_id` `id`, going back the
+    /// way this message came.
+    pub fn reply<Q>(&self, id: usize, payload: Q) -> Message<Q> {
+        Message {
+            source: self.destination.clone(),
+            destination: self.source.clone(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: self.body.id,
+                payload,
+            },
+        }
+    }
+}
+
+impl<P: Serialize> Message<P> {
+    /// The payload's `type`, or `?` if it has none.
+    pub(crate) fn kind(&self) -> String {
+        serde_json::to_value(&self.body.payload)
+            .ok()
+            .and_then(|payload| payload.get("type")?.as_str().map(str::to_owned))
+            .unwrap_or_else(|| "?".to_owned())
+    }
+
+    /// Panics if this is a reply that doesn't say what it replies to, which leaves the client
+    /// waiting for an answer it can never match up.
+    ///
+    /// Only checked in debug builds, since it costs an extra serialization per message.
+    pub(crate) fn debug_assert_in_reply_to(&self) {
+        if cfg!(debug_assertions) && self.body.in_reply_to.is_none() {
+            let kind = self.kind();
+            assert!(
+                !(kind.ends_with("_ok") || kind == "error"),
+                "Reply {kind} from {} to {} is missing in_reply_to.",
+                self.source,
+                self.destination,
+            );
+        }
+    }
+}
+
+impl<P: Serialize> Message<P> {
+    /// What, if anything, is wrong with this message as Maelstrom would see it on the wire.
+    ///
+    /// Unlike [`Message::debug_assert_in_reply_to`], this looks at the encoded message, so it
+    /// catches payloads whose encoding isn't what their type suggests.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let message = serde_json::to_value(self).map_err(|error| error.to_string())?;
+
+        for field in ["src", "dest"] {
+            if message[field].as_str().is_none_or(str::is_empty) {
+                return Err(format!(
+                    "{field} must be a node id, not {}.",
+                    message[field]
+                ));
+            }
+        }
+
+        let body = &message["body"];
+        let Some(kind) = body["type"].as_str().filter(|kind| !kind.is_empty()) else {
+            return Err(format!("The body needs a type: {body}"));
+        };
+        for field in ["msg_id", "in_reply_to"] {
+            if !body[field].is_null() && !body[field].is_u64() {
+                return Err(format!(
+                    "{field} of {kind} must be a number, not {}.",
+                    body[field]
+                ));
+            }
+        }
+        if (kind.ends_with("_ok") || kind == "error") && body["in_reply_to"].is_null() {
+            return Err(format!("Reply {kind} is missing in_reply_to."));
+        }
+        if kind == "error" && !(body["code"].is_u64() && body["text"].is_string()) {
+            return Err(format!("An error needs a numeric code and a text: {body}"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Message<Value> {
+    /// Decodes a message whose payload was left as raw JSON.
+    pub fn decode<P: DeserializeOwned>(&self) -> serde_json::Result<Message<P>> {
+        Ok(Message {
+            source: self.source.clone(),
+            destination: self.destination.clone(),
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                payload: P::deserialize(&self.body.payload)?,
+            },
+        })
+    }
+}
+
+/// The handshake Maelstrom opens every node with, which the runtime answers on the node's
+/// behalf.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum InitPayload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk {},
+}
+
+/// Maelstrom's standard error codes.
+///
+/// See <https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(into = "u32", try_from = "u32")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+        }
+    }
+}
+
+impl TryFrom<u32> for ErrorCode {
+    type Error = anyhow::Error;
+
+    fn try_from(code: u32) -> Result<Self, Self::Error> {
+        Ok(match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => bail!("Unknown maelstrom error code {code}."),
+        })
+    }
+}
+
+impl ErrorCode {
+    /// Whether the request definitely didn't take effect. Otherwise, as after a timeout or a
+    /// crash, it may have.
+    pub fn is_definite(self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash)
+    }
+
+    /// Whether the request didn't take effect but may well if sent again later.
+    pub fn is_retriable(self) -> bool {
+        self == ErrorCode::TemporarilyUnavailable
+    }
+
+    /// Whether the request didn't take effect and sending it again won't help, e.g. a cas
+    /// whose precondition failed.
+    pub fn is_abort(self) -> bool {
+        self.is_definite() && !self.is_retriable()
+    }
+}
+
+/// The `error` body, which can be sent in reply to a message of any workload.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPayload {
+    Error { code: u32, text: String },
+}
+
+/// Builds an `error` reply to `request`.
+pub fn error_reply<P>(
+    request: &Message<P>,
+    code: u32,
+    text: impl Into<String>,
+) -> Message<ErrorPayload> {
+    Message {
+        source: request.destination.clone(),
+        destination: request.source.clone(),
+        body: Body {
+            id: None,
+            in_reply_to: request.body.id,
+            payload: ErrorPayload::Error {
+                code,
+                text: text.into(),
+            },
+        },
+    }
+}
+
+/// Strategies for property tests of the wire format, shared with each payload's own tests.
+#[cfg(test)]
+pub(crate) mod arbitrary {
+    #[cfg(feature = "broadcast")]
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+
+    use proptest::prelude::*;
+    use proptest::test_runner::TestCaseError;
+
+    use super::*;
+
+    pub(crate) fn node_id() -> impl Strategy<Value = String> {
+        "[cn][0-9]{1,2}"
+    }
+
+    /// A `topology` naming each node's neighbors.
+    #[cfg(feature = "broadcast")]
+    pub(crate) fn topology() -> impl Strategy<Value = HashMap<String, Vec<String>>> {
+        prop::collection::hash_map(node_id(), prop::collection::vec(node_id(), 0..4), 0..4)
+    }
+
+    /// Any JSON without floats, which don't survive the trip exactly.
+    pub(crate) fn value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<u64>().prop_map(Value::from),
+            any::<String>().prop_map(Value::from),
+        ];
+        leaf.prop_recursive(3, 16, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+                prop::collection::btree_map(any::<String>(), inner, 0..4)
+                    .prop_map(|map| Value::Object(map.into_iter().collect())),
+            ]
+        })
+    }
+
+    /// A message carrying one of `payloads`, with or without a `msg_id` and `in_reply_to`.
+    pub(crate) fn message<P: Debug>(
+        payloads: impl Strategy<Value = P>,
+    ) -> impl Strategy<Value = Message<P>> {
+        (
+            node_id(),
+            node_id(),
+            any::<Option<usize>>(),
+            any::<Option<usize>>(),
+            payloads,
+        )
+            .prop_map(|(source, destination, id, in_reply_to, payload)| Message {
+                source,
+                destination,
+                body: Body {
+                    id,
+                    in_reply_to,
+                    payload,
+                },
+            })
+    }
+
+    /// Fails unless `message` comes back as itself from its JSON text.
+    ///
+    /// Going through the text rather than a [`Value`] matters: a payload field that shares a
+    /// name with the `type` tag, `msg_id` or `in_reply_to` is written out twice in the body,
+    /// which only decoding the text refuses, as Maelstrom's side would.
+    pub(crate) fn round_trip<P>(message: &Message<P>) -> Result<(), TestCaseError>
+    where
+        P: Serialize + DeserializeOwned + PartialEq + Debug,
+    {
+        let text = serde_json::to_string(message)
+            .map_err(|error| TestCaseError::fail(error.to_string()))?;
+        let decoded: Message<P> = serde_json::from_str(&text)
+            .map_err(|error| TestCaseError::fail(format!("{error} decoding {text}")))?;
+        prop_assert_eq!(&decoded, message, "{}", text);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::arbitrary::{self, node_id};
+    use super::*;
+
+    fn init_payload() -> impl Strategy<Value = InitPayload> {
+        prop_oneof![
+            (node_id(), prop::collection::vec(node_id(), 0..5))
+                .prop_map(|(node_id, node_ids)| InitPayload::Init { node_id, node_ids }),
+            Just(InitPayload::InitOk {}),
+        ]
+    }
+
+    fn error_payload() -> impl Strategy<Value = ErrorPayload> {
+        (any::<u32>(), any::<String>()).prop_map(|(code, text)| ErrorPayload::Error { code, text })
+    }
+
+    proptest! {
+        #[test]
+        fn init_survives_the_wire(message in arbitrary::message(init_payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+
+        #[test]
+        fn errors_survive_the_wire(message in arbitrary::message(error_payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+
+    #[test]
+    fn messages_maelstrom_would_choke_on_are_caught() {
+        let message = |source: &str, in_reply_to, payload| Message {
+            source: source.to_owned(),
+            destination: "c1".to_owned(),
+            body: Body {
+                id: Some(1),
+                in_reply_to,
+                payload,
+            },
+        };
+        let error = |code: Value| serde_json::json!({"type": "error", "code": code, "text": "no"});
+
+        let valid = [
+            message(
+                "n1",
+                Some(3),
+                serde_json::json!({"type": "echo_ok", "echo": 1}),
+            ),
+            message("n1", None, serde_json::json!({"type": "gossip"})),
+            message("n1", Some(3), error(Value::from(10))),
+        ];
+        for message in valid {
+            assert_eq!(message.validate(), Ok(()), "{message:?}");
+        }
+
+        let invalid = [
+            message("", Some(3), serde_json::json!({"type": "echo_ok"})),
+            message("n1", Some(3), serde_json::json!({"echo": 1})),
+            message("n1", None, serde_json::json!({"type": "echo_ok"})),
+            message(
+                "n1",
+                None,
+                serde_json::json!({"type": "gossip", "msg_id": "7"}),
+            ),
+            message("n1", Some(3), error(Value::from("crash"))),
+        ];
+        for message in invalid {
+            assert!(message.validate().is_err(), "{message:?}");
+        }
+    }
+
+    /// Serde refuses a variant field named `type` outright, but not one a level down, nor one
+    /// clashing with the body's own fields.
+    #[test]
+    fn payload_fields_named_like_the_body_s_do_not_survive_the_wire() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Kind {
+            r#type: String,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        #[serde(tag = "type")]
+        #[serde(rename_all = "snake_case")]
+        enum Clashing {
+            Typed(Kind),
+            Numbered { msg_id: usize },
+        }
+
+        for payload in [
+            Clashing::Typed(Kind {
+                r#type: "kind".to_owned(),
+            }),
+            Clashing::Numbered { msg_id: 2 },
+        ] {
+            let message = Message {
+                source: "c1".to_owned(),
+                destination: "n0".to_owned(),
+                body: Body {
+                    id: Some(1),
+                    in_reply_to: None,
+                    payload,
+                },
+            };
+            assert!(arbitrary::round_trip(&message).is_err(), "{message:?}");
+        }
+    }
+}
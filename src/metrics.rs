@@ -0,0 +1,298 @@
+//! Counters for comparing how a node behaves across runs.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+static SUMMARY_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Has the runtime write [`Metrics::to_json`], along with the node's [`Node::summary`], to
+/// `path` when the node shuts down, for scripts comparing runs. Only the first path set counts.
+///
+/// [`Node::summary`]: crate::node::Node::summary
+pub fn set_summary_path(path: PathBuf) {
+    let _ = SUMMARY_PATH.set(path);
+}
+
+pub(crate) fn summary_path() -> Option<&'static Path> {
+    SUMMARY_PATH.get().map(PathBuf::as_path)
+}
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the clock for [`ProcStats::uptime`]. Only the first call counts.
+pub(crate) fn start() {
+    STARTED.get_or_init(Instant::now);
+}
+
+pub(crate) fn record_written(bytes: usize) {
+    WRITTEN.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// What the whole process has used, on the wall clock rather than the runtime's clock.
+///
+/// The runtime prints this along with [`Metrics`] when the node shuts down, and adds it to
+/// the summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcStats {
+    /// Since the runtime started.
+    pub uptime: Duration,
+    /// The most memory the process has had resident, in bytes, where the platform says.
+    pub peak_memory: Option<u64>,
+    /// Bytes of messages written out as lines, to stdout unless the runtime was handed some
+    /// other output.
+    pub bytes_written: u64,
+}
+
+impl ProcStats {
+    pub fn collect() -> Self {
+        Self {
+            uptime: STARTED.get_or_init(Instant::now).elapsed(),
+            peak_memory: peak_memory(),
+            bytes_written: WRITTEN.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "uptime_ms": self.uptime.as_secs_f64() * 1000.0,
+            "peak_memory_bytes": self.peak_memory,
+            "bytes_written": self.bytes_written,
+        })
+    }
+}
+
+impl fmt::Display for ProcStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "uptime: {:.3}s", self.uptime.as_secs_f64())?;
+        if let Some(peak) = self.peak_memory {
+            write!(f, " peak memory: {:.1}MiB", peak as f64 / (1024.0 * 1024.0))?;
+        }
+        write!(f, " written: {} bytes", self.bytes_written)
+    }
+}
+
+/// The high water mark of resident memory, `VmHWM` in `/proc/self/status`.
+#[cfg(target_os = "linux")]
+fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peak_memory() -> Option<u64> {
+    None
+}
+
+/// What a node has sent and received so far, and how long its requests took to be answered.
+///
+/// The runtime prints this to stderr when the node shuts down.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    /// Messages received, by payload type.
+    pub received: BTreeMap<String, u64>,
+    /// Messages sent, by payload type.
+    pub sent: BTreeMap<String, u64>,
+    /// Messages for some other node, which the runtime dropped.
+    pub misaddressed: u64,
+    /// Round trips of requests that got a reply.
+    pub latencies: Histogram,
+    /// The same round trips, by where the request went.
+    pub latencies_by_destination: BTreeMap<String, Histogram>,
+    /// Requests that never got a reply.
+    pub timeouts: u64,
+    /// Times the output was flushed, each a write to stdout at most.
+    pub flushes: u64,
+}
+
+impl Metrics {
+    pub(crate) fn record_received(&mut self, kind: String) {
+        *self.received.entry(kind).or_default() += 1;
+    }
+
+    pub(crate) fn record_sent(&mut self, kind: String) {
+        *self.sent.entry(kind).or_default() += 1;
+    }
+
+    pub(crate) fn record_latency(&mut self, destination: &str, latency: Duration) {
+        self.latencies.record(latency);
+        self.latencies_by_destination
+            .entry(destination.to_owned())
+            .or_default()
+            .record(latency);
+    }
+
+    /// The same counts as a JSON object, with latencies in milliseconds.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "received": self.received,
+            "sent": self.sent,
+            "misaddressed": self.misaddressed,
+            "timeouts": self.timeouts,
+            "flushes": self.flushes,
+            "latencies": self.latencies.to_json(),
+            "latencies_by_destination": self
+                .latencies_by_destination
+                .iter()
+                .map(|(destination, latencies)| (destination.clone(), latencies.to_json()))
+                .collect::<serde_json::Map<_, _>>(),
+        })
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "received: {}", Counts(&self.received))?;
+        writeln!(f, "sent: {}", Counts(&self.sent))?;
+        writeln!(f, "misaddressed: {}", self.misaddressed)?;
+        writeln!(f, "timeouts: {}", self.timeouts)?;
+        writeln!(f, "flushes: {}", self.flushes)?;
+        write!(f, "latencies: {}", self.latencies)?;
+        for (destination, latencies) in &self.latencies_by_destination {
+            write!(
+                f,
+                "\n  to {destination}: p50 {} p99 {}",
+                Percentile(latencies.percentile(0.5)),
+                Percentile(latencies.percentile(0.99))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Counts<'a>(&'a BTreeMap<String, u64>);
+
+impl fmt::Display for Counts<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total: u64 = self.0.values().sum();
+        write!(f, "{total}")?;
+        for (kind, count) in self.0 {
+            write!(f, " {kind}={count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Upper bounds of the [`Histogram`] buckets, in milliseconds. Anything slower goes into one
+/// last bucket.
+const BUCKETS_MS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+/// Durations counted into fixed buckets, which is plenty to tell one run from another.
+#[derive(Debug, Default, Clone)]
+pub struct Histogram {
+    counts: [u64; BUCKETS_MS.len() + 1],
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| duration <= Duration::from_millis(*bound))
+            .unwrap_or(BUCKETS_MS.len());
+
+        self.counts[bucket] += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count())
+            .ok()
+            .filter(|count| *count > 0)?;
+        Some(self.total / count)
+    }
+
+    /// An upper bound on the `quantile`th duration, e.g. `0.99` for the 99th percentile: the
+    /// bound of the bucket it fell into, or the longest duration if that is smaller.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, bucket_count) in self.counts.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= rank {
+                let bound = BUCKETS_MS
+                    .get(bucket)
+                    .map_or(self.max, |bound| Duration::from_millis(*bound));
+                return Some(bound.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// The counts as JSON, with durations in milliseconds.
+    pub fn to_json(&self) -> Value {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+        // The last bucket has no upper bound.
+        let buckets: Vec<Value> = BUCKETS_MS
+            .iter()
+            .map(|bound| Some(*bound))
+            .chain([None])
+            .zip(self.counts)
+            .map(|(bound, count)| json!({ "le_ms": bound, "count": count }))
+            .collect();
+
+        json!({
+            "count": self.count(),
+            "mean_ms": self.mean().map(millis),
+            "p50_ms": self.percentile(0.5).map(millis),
+            "p99_ms": self.percentile(0.99).map(millis),
+            "buckets": buckets,
+        })
+    }
+}
+
+struct Percentile(Option<Duration>);
+
+impl fmt::Display for Percentile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(duration) => write!(f, "<={duration:?}"),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mean) = self.mean() else {
+            return f.write_str("none");
+        };
+
+        write!(f, "{} (mean {mean:?})", self.count())?;
+        for (bound, count) in BUCKETS_MS.iter().zip(self.counts) {
+            if count > 0 {
+                write!(f, " <={bound}ms={count}")?;
+            }
+        }
+        match self.counts[BUCKETS_MS.len()] {
+            0 => Ok(()),
+            count => write!(f, " >{}ms={count}", BUCKETS_MS[BUCKETS_MS.len() - 1]),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! The interface a workload implements.
+
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::deadletter::{self, Reason};
+use crate::message::{error_reply, ErrorCode, Message};
+use crate::rpc::{Rpc, RpcError};
+
+/// Who a node is, as told by Maelstrom's `init`.
+#[derive(Debug, Clone)]
+pub struct NodeContext {
+    pub node_id: String,
+    /// Every node in the cluster, this one included.
+    pub node_ids: Vec<String>,
+}
+
+impl NodeContext {
+    /// How many nodes are in the cluster, this one included. Never zero.
+    pub fn node_count(&self) -> usize {
+        self.node_ids.len()
+    }
+
+    /// Every other node in the cluster.
+    pub fn peers(&self) -> impl Iterator<Item = &str> {
+        self.node_ids
+            .iter()
+            .map(String::as_str)
+            .filter(|node_id| *node_id != self.node_id)
+    }
+
+    /// This node's position among all node ids in sorted order, the same on every node.
+    ///
+    /// Unlike the order of `node_ids`, this doesn't depend on how Maelstrom listed them.
+    pub fn node_index(&self) -> usize {
+        self.node_ids
+            .iter()
+            .filter(|node_id| **node_id < self.node_id)
+            .count()
+    }
+}
+
+/// What the runtime hands to [`Node::on_event`].
+#[derive(Debug)]
+pub enum Event<P> {
+    /// A message that doesn't answer a request registered with a callback.
+    Message(Message<P>),
+    /// [`Node::tick_interval`] has passed since the last tick.
+    Tick,
+    /// The request with this `msg_id`, registered through [`Rpc::call`], got no reply in time.
+    /// Its callback has been dropped, and so is its reply should it still arrive.
+    Timeout(usize),
+}
+
+/// A Maelstrom node, driven by [`crate::runtime::run`].
+///
+/// The runtime owns the node and calls into it from a single thread, one event at a time, so
+/// its state needs no locking: a handler always sees every earlier handler's writes, and no two
+/// handlers interleave. That is also why [`Rpc::request`] blocks the whole node while it waits.
+/// A workload whose handlers should run in parallel implements
+/// [`SharedNode`](crate::parallel::SharedNode) instead, and keeps its state behind locks of its
+/// own.
+pub trait Node: Sized {
+    /// Every message this node can receive or send, other than the `init` handshake.
+    type Payload: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Runs once, right after the runtime has answered Maelstrom's `init`.
+    ///
+    /// Nothing else reaches the node until this returns, so it is the place for setup that
+    /// needs to talk to other nodes or services. Messages that come in meanwhile wait their
+    /// turn.
+    fn init(&mut self, _context: &NodeContext, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Handles a message that doesn't answer a request registered with a callback.
+    fn step(&mut self, input: Message<Self::Payload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()>;
+
+    /// How often [`Node::tick`] should run, if at all.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Runs periodically, even when no messages are coming in.
+    fn tick(&mut self, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Runs once stdin has closed, right before the runtime flushes stdout and exits.
+    ///
+    /// Requests still awaiting a reply at this point never get one.
+    fn on_shutdown(&mut self, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// The node's own state worth putting in the summary written at shutdown, such as how many
+    /// values it ended up with. See [`crate::metrics::set_summary_path`].
+    fn summary(&self) -> Option<Value> {
+        None
+    }
+
+    /// The node's state that should survive a restart, if any. See [`Persistent`].
+    fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+        None
+    }
+
+    /// Reacts to anything that happens to the node.
+    ///
+    /// By default messages go to [`Node::step`], ticks to [`Node::tick`], and timeouts are
+    /// ignored.
+    fn on_event(&mut self, event: Event<Self::Payload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.step(message, rpc),
+            Event::Tick => self.tick(rpc),
+            Event::Timeout(_) => Ok(()),
+        }
+    }
+}
+
+/// State a node can save and load again, so that a restarted node picks up where it left off.
+///
+/// With a state file set through [`crate::runtime::set_state_file`], the runtime restores a
+/// node that offers this through [`Node::persistent`] right after building it, before
+/// answering `init`, and snapshots it once input has ended. Anything the node journaled with
+/// [`Rpc::journal`] since the last snapshot is restored on top of it.
+pub trait Persistent {
+    fn snapshot(&self) -> Value;
+
+    fn restore(&mut self, state: Value) -> anyhow::Result<()>;
+}
+
+/// Why the runtime couldn't go on driving a node.
+///
+/// The runtime's entry points return [`anyhow::Result`], so that nodes' own errors pass through
+/// with their context. Failures of the runtime itself are one of these, which callers can get
+/// back with [`anyhow::Error::downcast_ref`].
+#[derive(Debug, thiserror::Error)]
+pub enum NodeError {
+    /// A line of input couldn't be read.
+    #[error("Could not read maelstrom input.")]
+    Read(#[source] std::io::Error),
+    /// A line of output couldn't be written.
+    #[error("Could not write maelstrom output.")]
+    Write(#[source] std::io::Error),
+    /// Input couldn't be decoded as a message, or as one of the node's payloads, in strict
+    /// mode.
+    #[error("Could not decode maelstrom input.")]
+    Decode(#[source] serde_json::Error),
+    /// Output couldn't be encoded.
+    #[error("Could not encode maelstrom output.")]
+    Encode(#[source] serde_json::Error),
+    /// A message of type `init` whose payload wasn't an `init`.
+    #[error("Expected an init message.")]
+    UnexpectedInit,
+    /// An `init` whose `node_ids` don't include its `node_id`.
+    #[error("Init names this node {node_id}, which isn't among {node_ids:?}.")]
+    NotInCluster {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    /// The node was asked for its id before Maelstrom's `init` came in.
+    #[error("Node is not initialized yet.")]
+    NotInitialized,
+    /// A message addressed to another node, in strict mode.
+    #[error("Got a message for {actual}, but this is {expected}.")]
+    WrongDestination { expected: String, actual: String },
+    /// A message of this type had no `msg_id` for a reply to refer to, in strict mode, or was
+    /// sent to await a reply without one.
+    #[error("A {0} message has no msg_id.")]
+    MissingMsgId(String),
+    /// A message about to be sent isn't one Maelstrom would accept, in strict mode with
+    /// validation on.
+    #[error("Invalid outgoing message: {0}")]
+    Invalid(String),
+    /// A request didn't get its reply.
+    #[error("A request failed.")]
+    Rpc(#[from] RpcError),
+}
+
+/// Rejects a message the node can't handle.
+///
+/// Requests carrying a `msg_id` get a `not-supported` error reply, anything else is logged and
+/// dropped, so a stray message never takes the whole node down. Replies are never answered,
+/// even if they carry a `msg_id`: the node that sent one isn't expecting anything back, and
+/// two nodes doing the same could bounce errors between them for ever.
+pub fn reject<N: Node, P: Serialize + std::fmt::Debug>(
+    message: &Message<P>,
+    rpc: &mut Rpc<N>,
+    text: &str,
+) -> anyhow::Result<()> {
+    deadletter::record(Reason::Rejected, Some(text), message);
+    if message.body.id.is_none() || message.body.in_reply_to.is_some() {
+        crate::log::warning!("Dropping message: {text} {message:?}");
+        return Ok(());
+    }
+
+    let reply = error_reply(message, ErrorCode::NotSupported.into(), text);
+
+    rpc.send(&reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::message::Body;
+    use crate::runtime;
+    use crate::transport::InMemoryTransport;
+
+    struct Refuser;
+
+    impl Node for Refuser {
+        type Payload = Value;
+
+        fn step(&mut self, input: Message<Value>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            reject(&input, rpc, "Unsupported message type.")
+        }
+    }
+
+    fn message(id: Option<usize>, in_reply_to: Option<usize>) -> Message<Value> {
+        Message {
+            source: "n2".to_owned(),
+            destination: "n1".to_owned(),
+            body: Body {
+                id,
+                in_reply_to,
+                payload: json!({"type": "frobnicate"}),
+            },
+        }
+    }
+
+    /// What rejecting `message` sends.
+    fn rejected(message: Message<Value>) -> Vec<Message<Value>> {
+        let transport = InMemoryTransport::new();
+        let mut rpc = Rpc::with_transport("n1", transport.clone());
+        Refuser.step(message, &mut rpc).unwrap();
+        transport.sent()
+    }
+
+    #[test]
+    fn requests_are_refused_as_not_supported() {
+        let [reply] = rejected(message(Some(7), None)).try_into().unwrap();
+
+        assert_eq!(reply.destination, "n2");
+        assert_eq!(reply.body.in_reply_to, Some(7));
+        assert_eq!(reply.body.payload["type"], "error");
+        assert_eq!(reply.body.payload["code"], 10);
+    }
+
+    #[test]
+    fn messages_without_a_msg_id_are_dropped() {
+        assert!(rejected(message(None, None)).is_empty());
+    }
+
+    #[test]
+    fn replies_are_never_answered() {
+        assert!(rejected(message(Some(7), Some(3))).is_empty());
+        assert!(rejected(message(None, Some(3))).is_empty());
+    }
+
+    /// Replies to nothing: a node needs no more than its payload and a `step`.
+    struct Silent;
+
+    impl Node for Silent {
+        type Payload = Value;
+
+        fn step(&mut self, _input: Message<Value>, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_node_that_replies_to_nothing_only_answers_init() {
+        let input = [
+            json!({
+                "src": "c1",
+                "dest": "n1",
+                "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] },
+            }),
+            json!({ "src": "c1", "dest": "n1", "body": { "type": "echo", "msg_id": 2 } }),
+        ]
+        .map(|line| line.to_string())
+        .join("\n");
+        let transport = InMemoryTransport::new();
+        let clock = Arc::new(SystemClock);
+
+        runtime::run_with_transport(Cursor::new(input), transport.clone(), clock, |_| Ok(Silent))
+            .unwrap();
+
+        let [reply] = transport.sent().try_into().unwrap();
+        assert_eq!(reply.body.payload["type"], "init_ok");
+        assert_eq!(reply.body.in_reply_to, Some(1));
+    }
+
+    #[test]
+    fn rpc_errors_are_the_source() {
+        let error = NodeError::from(RpcError::Timeout);
+
+        let source = error.source().expect("An Rpc error has a source.");
+        assert!(matches!(
+            source.downcast_ref::<RpcError>(),
+            Some(RpcError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn variants_survive_anyhow() {
+        let error: anyhow::Error = NodeError::UnexpectedInit.into();
+
+        assert!(matches!(
+            error.downcast_ref::<NodeError>(),
+            Some(NodeError::UnexpectedInit)
+        ));
+    }
+}
@@ -0,0 +1,452 @@
+//! A multi-threaded alternative to [`crate::runtime`], for nodes whose handlers are CPU-bound.
+//!
+//! Messages are handed out to a pool of worker threads, each handling one at a time, so a
+//! node's handlers run in parallel. A [`SharedNode`] therefore takes `&self`, and all of its
+//! state is shared between the workers: it has to protect that state itself, e.g. with a
+//! [`Sharded`] map, which locks one shard at a time, or atomics.
+//!
+//! The locking discipline is the node's to keep, but these rules keep it simple:
+//!
+//! - Hold a lock only for as long as it takes to read or change the state behind it, and never
+//!   while sending through the [`SharedRpc`].
+//! - Hold one lock at a time. A handler that needs two keys reads or changes them one after the
+//!   other, so another handler may come in between; one that can't have that keeps them under
+//!   one key.
+//! - Expect concurrent messages in any order. Two messages from the same client are handled in
+//!   the order they came in only if the client waited for the first's reply before sending the
+//!   second, as Maelstrom's clients do.
+//!
+//! Handlers can't send requests and wait for the reply, and there are no ticks: this suits
+//! nodes that answer each message from their own state, and [`crate::runtime::run`] suits the
+//! rest. Replies to anything are dropped, since nothing here can be waiting for one.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::deadletter::{self, Reason};
+use crate::log;
+use crate::message::{error_reply, Body, ErrorCode, InitPayload, Message};
+use crate::metrics::{self, Metrics};
+use crate::node::{NodeContext, NodeError};
+use crate::rpc::{can_reply, validate};
+use crate::runtime::{
+    channel_capacity, decode, handshake, is_for, is_init, parse_line, reinit, Handshake,
+};
+use crate::transport::{LineTransport, Transport};
+
+/// A Maelstrom node whose handlers run on several threads at once, driven by [`run`].
+pub trait SharedNode: Send + Sync + 'static {
+    /// Every message this node can receive or send, other than the `init` handshake.
+    type Payload: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Handles a message, on whichever worker thread got it.
+    fn step(&self, input: Message<Self::Payload>, rpc: &SharedRpc) -> anyhow::Result<()>;
+}
+
+/// What the workers send through, one message at a time.
+struct Output {
+    transport: Box<dyn Transport + Send>,
+    metrics: Metrics,
+}
+
+impl Output {
+    fn send<P: Serialize>(&mut self, msg: &Message<P>) -> anyhow::Result<()> {
+        log::log_send(msg);
+        self.metrics.record_sent(msg.kind());
+        let payload = serde_json::to_value(&msg.body.payload).map_err(NodeError::Encode)?;
+        let msg = Message {
+            source: msg.source.clone(),
+            destination: msg.destination.clone(),
+            body: Body {
+                id: msg.body.id,
+                in_reply_to: msg.body.in_reply_to,
+                payload,
+            },
+        };
+        self.transport.send(&msg)?;
+        self.transport.flush()
+    }
+}
+
+/// A node's connection to the outside world, like [`crate::rpc::Rpc`] but shared by every
+/// worker thread, and only for sending.
+pub struct SharedRpc {
+    node_id: String,
+    next_id: AtomicUsize,
+    output: Mutex<Output>,
+}
+
+impl SharedRpc {
+    /// A fresh `msg_id` for a message this node is about to send.
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// This node's id.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Sends `msg` as is, and flushes it out.
+    pub fn send(&self, msg: &Message<impl Serialize>) -> anyhow::Result<()> {
+        msg.debug_assert_in_reply_to();
+        validate(msg)?;
+        self.output().send(msg)
+    }
+
+    /// Sends `payload` in reply to `request`, under a fresh `msg_id`.
+    ///
+    /// A request without a `msg_id` can't be answered, so the reply is skipped.
+    pub fn reply<P: Serialize>(
+        &self,
+        request: &Message<P>,
+        payload: impl Serialize,
+    ) -> anyhow::Result<()> {
+        if !can_reply(request)? {
+            return Ok(());
+        }
+
+        self.send(&request.reply(self.next_id(), payload))
+    }
+
+    fn output(&self) -> MutexGuard<'_, Output> {
+        self.output.lock().expect("Output lock poisoned.")
+    }
+}
+
+/// A map split into shards, each behind its own lock, so that handlers working on keys in
+/// different shards don't wait for each other.
+///
+/// Every method locks one shard at a time, and none hands out a reference into a shard, so a
+/// caller can't end up holding two locks.
+pub struct Sharded<K, V> {
+    shards: Vec<Mutex<HashMap<K, V>>>,
+}
+
+impl<K: Hash + Eq, V> Sharded<K, V> {
+    /// An empty map of `shards` shards, at least one. A few per worker thread is plenty.
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<HashMap<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Runs `f` on the value of `key`, made the default first if there is none, with its shard
+    /// locked.
+    pub fn update<R>(&self, key: K, f: impl FnOnce(&mut V) -> R) -> R
+    where
+        V: Default,
+    {
+        let mut shard = self.shard(&key).lock().expect("Shard lock poisoned.");
+        f(shard.entry(key).or_default())
+    }
+
+    /// The value of `key`, if any.
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let shard = self.shard(key).lock().expect("Shard lock poisoned.");
+        shard.get(key).cloned()
+    }
+
+    /// Folds every key and value into `init`, one shard after another.
+    ///
+    /// Each shard is locked in turn, so each is seen as it was at a moment of its own rather
+    /// than all at once: updates made meanwhile may or may not be counted.
+    pub fn fold<A>(&self, init: A, mut f: impl FnMut(A, &K, &V) -> A) -> A {
+        self.shards.iter().fold(init, |acc, shard| {
+            let shard = shard.lock().expect("Shard lock poisoned.");
+            shard
+                .iter()
+                .fold(acc, |acc, (key, value)| f(acc, key, value))
+        })
+    }
+}
+
+/// Runs the node built by `init` on `threads` worker threads, over stdin and stdout.
+pub fn run<N: SharedNode>(
+    threads: usize,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<()> {
+    let stdout = LineTransport::new(BufWriter::new(std::io::stdout()));
+    run_with_transport(threads, std::io::stdin(), stdout, init)
+}
+
+/// Like [`run`], but reads newline-delimited messages from `input` and sends through
+/// `transport`, e.g. to drive a node from a test.
+///
+/// Returns once input has ended and every worker has handled what it got. A handler's error
+/// stops its worker, and is returned then.
+pub fn run_with_transport<N: SharedNode>(
+    threads: usize,
+    input: impl Read,
+    transport: impl Transport + Send + 'static,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<()> {
+    metrics::start();
+    let mut output = Output {
+        transport: Box::new(transport),
+        metrics: Metrics::default(),
+    };
+    let next_id = AtomicUsize::new(0);
+    let mut lines = BufReader::new(input).lines();
+
+    let Some((node, node_id)) = start(&mut lines, &mut output, &next_id, init)? else {
+        return Ok(());
+    };
+    let node = Arc::new(node);
+    let rpc = Arc::new(SharedRpc {
+        node_id,
+        next_id,
+        output: Mutex::new(output),
+    });
+
+    let (tx, rx) = mpsc::sync_channel::<Message<Value>>(channel_capacity()?);
+    let rx = Arc::new(Mutex::new(rx));
+    let workers: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let (node, rpc, rx) = (Arc::clone(&node), Arc::clone(&rpc), Arc::clone(&rx));
+            thread::spawn(move || work(&*node, &rpc, &rx))
+        })
+        .collect();
+
+    let read = read(lines, &rpc, &tx);
+    // Closing the channel lets the workers finish what's queued and stop.
+    drop(tx);
+
+    let mut result = read;
+    for worker in workers {
+        let worked = worker
+            .join()
+            .map_err(|_| anyhow::anyhow!("Worker panicked."))
+            .and_then(|worked| worked);
+        result = result.and(worked);
+    }
+
+    log::info!("{}", rpc.output().metrics);
+    result
+}
+
+/// Waits for `init` and builds the node from it, returning it with its id, or `None` if input
+/// ends first.
+fn start<N: SharedNode>(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+    output: &mut Output,
+    next_id: &AtomicUsize,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<Option<(N, String)>> {
+    for line in lines {
+        let line = line.map_err(NodeError::Read)?;
+        let Some(input) = parse_line(&line)? else {
+            continue;
+        };
+        log::log_recv(&input);
+        output.metrics.record_received(input.kind());
+
+        match handshake(input)? {
+            Handshake::Init { context, mut reply } => {
+                let node = init(&context)?;
+                reply.body.id = Some(next_id.fetch_add(1, Ordering::Relaxed));
+                output.send(&reply)?;
+                return Ok(Some((node, context.node_id)));
+            }
+            Handshake::Refuse(reply) => output.send(&reply)?,
+            Handshake::Ignore => {}
+        }
+    }
+
+    log::warning!("Input ended before init.");
+    Ok(None)
+}
+
+/// Queues up every message of the rest of the input for the workers, answering a repeated
+/// `init` itself.
+fn read(
+    lines: impl Iterator<Item = std::io::Result<String>>,
+    rpc: &SharedRpc,
+    tx: &mpsc::SyncSender<Message<Value>>,
+) -> anyhow::Result<()> {
+    for line in lines {
+        let line = line.map_err(NodeError::Read)?;
+        let Some(input) = parse_line(&line)? else {
+            continue;
+        };
+        log::log_recv(&input);
+        rpc.output().metrics.record_received(input.kind());
+
+        if !is_for(&input, rpc.node_id())? {
+            rpc.output().metrics.misaddressed += 1;
+            continue;
+        }
+
+        if is_init(&input) {
+            match reinit(&input, rpc.node_id()) {
+                Ok(()) => rpc.reply(&input, InitPayload::InitOk {})?,
+                Err(text) => refuse(&input, rpc, &text)?,
+            }
+            continue;
+        }
+
+        if let Some(id) = input.body.in_reply_to {
+            log::warning!("Dropping a reply to {id}, which matches no request: {input:?}");
+            deadletter::record(Reason::UnmatchedReply, None, &input);
+            continue;
+        }
+
+        // Every worker has stopped, after an error that `run_with_transport` reports.
+        if tx.send(input).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handles messages off the queue until it closes, or a handler fails.
+fn work<N: SharedNode>(
+    node: &N,
+    rpc: &SharedRpc,
+    rx: &Mutex<mpsc::Receiver<Message<Value>>>,
+) -> anyhow::Result<()> {
+    loop {
+        // The lock is only held while waiting for the next message, not while handling it.
+        let next = rx.lock().expect("Queue lock poisoned.").recv();
+        let Ok(input) = next else {
+            return Ok(());
+        };
+
+        match decode::<N::Payload>(&input)? {
+            Ok(input) => node.step(input, rpc)?,
+            Err(text) => refuse(&input, rpc, &text)?,
+        }
+    }
+}
+
+/// Like [`crate::node::reject`], for [`SharedRpc`].
+fn refuse(input: &Message<Value>, rpc: &SharedRpc, text: &str) -> anyhow::Result<()> {
+    deadletter::record(Reason::Rejected, Some(text), input);
+    if input.body.id.is_none() || input.body.in_reply_to.is_some() {
+        log::warning!("Dropping message: {text} {input:?}");
+        return Ok(());
+    }
+
+    rpc.send(&error_reply(input, ErrorCode::NotSupported.into(), text))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::Cursor;
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::transport::InMemoryTransport;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum TallyPayload {
+        Add { key: String, delta: u64 },
+        AddOk {},
+    }
+
+    /// Counts per key, shared with the test.
+    struct Tally {
+        counts: Arc<Sharded<String, u64>>,
+    }
+
+    impl SharedNode for Tally {
+        type Payload = TallyPayload;
+
+        fn step(&self, input: Message<TallyPayload>, rpc: &SharedRpc) -> anyhow::Result<()> {
+            let TallyPayload::Add { key, delta } = &input.body.payload else {
+                return Ok(());
+            };
+            self.counts.update(key.clone(), |count| {
+                // Read, give way to the other workers, then write: that only adds up under the
+                // shard's lock.
+                let seen = *count;
+                thread::yield_now();
+                *count = seen + delta;
+            });
+            rpc.reply(&input, TallyPayload::AddOk {})
+        }
+    }
+
+    #[test]
+    fn concurrent_updates_to_one_key_are_never_lost() {
+        let counts = Sharded::<u64, u64>::new(4);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for key in (0..10).cycle().take(1000) {
+                        counts.update(key, |count| *count += 1);
+                    }
+                });
+            }
+        });
+
+        for key in 0..10 {
+            assert_eq!(counts.get(&key), Some(800));
+        }
+        assert_eq!(counts.fold(0, |total, _, count| total + count), 8000);
+    }
+
+    #[test]
+    fn concurrent_adds_are_never_lost() {
+        let mut input = json!({
+            "src": "c0",
+            "dest": "n0",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n0", "node_ids": ["n0"] },
+        })
+        .to_string();
+        for id in 0..2000 {
+            let add = json!({
+                "src": format!("c{}", id % 10),
+                "dest": "n0",
+                "body": { "type": "add", "msg_id": id, "key": format!("k{}", id % 3), "delta": 2 },
+            });
+            input = format!("{input}\n{add}");
+        }
+
+        let counts = Arc::new(Sharded::new(2));
+        let transport = InMemoryTransport::new();
+        let tally = Tally {
+            counts: Arc::clone(&counts),
+        };
+        run_with_transport(4, Cursor::new(input), transport.clone(), |_| Ok(tally)).unwrap();
+
+        let adds = |key: u64| (0..2000).filter(|id| id % 3 == key).count() as u64;
+        for key in 0..3 {
+            assert_eq!(counts.get(&format!("k{key}")), Some(2 * adds(key)));
+        }
+
+        // Every add is answered once, under a `msg_id` of its own.
+        let sent = transport.sent();
+        let answered: HashSet<_> = sent
+            .iter()
+            .filter(|reply| reply.body.payload["type"] == "add_ok")
+            .map(|reply| (reply.destination.clone(), reply.body.in_reply_to))
+            .collect();
+        assert_eq!(answered.len(), 2000);
+        let ids: HashSet<_> = sent.iter().map(|reply| reply.body.id).collect();
+        assert_eq!(ids.len(), sent.len());
+    }
+}
@@ -0,0 +1,31 @@
+//! Seeded pseudo-random numbers, for jitter and simulated networks that a run can repeat.
+
+/// A pseudo-random number in `[0, 1)` by splitmix64, moving `state` on to the next one.
+pub(crate) fn splitmix64(state: &mut u64) -> f64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_state_gives_the_same_numbers_in_the_unit_interval() {
+        let draw = |mut state: u64| {
+            (0..1000)
+                .map(|_| splitmix64(&mut state))
+                .collect::<Vec<_>>()
+        };
+
+        let numbers = draw(7);
+        assert!(numbers.iter().all(|n| (0.0..1.0).contains(n)));
+        assert_eq!(numbers, draw(7));
+        assert_ne!(numbers, draw(8));
+    }
+}
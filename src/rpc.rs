@@ -0,0 +1,1849 @@
+//! Sending messages and awaiting their replies.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::clock::{Clock, SystemClock};
+use crate::kv::Overlay;
+use crate::log;
+use crate::message::{self, Body, ErrorCode, ErrorPayload, Message};
+use crate::metrics::Metrics;
+use crate::node::{Event, Node, NodeError};
+use crate::runtime::{self, strict};
+use crate::transport::Transport;
+
+/// Whether `request` has a `msg_id` for a reply to refer to. If not, the reply would be lost on
+/// the client, so it is skipped with a warning, or refused in strict mode.
+pub(crate) fn can_reply<P: Serialize>(request: &Message<P>) -> Result<bool, NodeError> {
+    if request.body.id.is_some() {
+        return Ok(true);
+    }
+
+    if strict() {
+        return Err(NodeError::MissingMsgId(request.kind()));
+    }
+
+    log::warning!(
+        "Not replying to {} from {}, which has no msg_id.",
+        request.kind(),
+        request.source
+    );
+    Ok(false)
+}
+
+/// Checks `msg` before it goes out, if validation is on. See [`message::set_validate`].
+pub(crate) fn validate<P: Serialize>(msg: &Message<P>) -> Result<(), NodeError> {
+    if !message::validating() {
+        return Ok(());
+    }
+
+    match msg.validate() {
+        Ok(()) => Ok(()),
+        Err(problem) if strict() || cfg!(test) => Err(NodeError::Invalid(problem)),
+        Err(problem) => {
+            log::warning!("Sending an invalid message: {problem}");
+            Ok(())
+        }
+    }
+}
+
+/// Hands out fresh, strictly increasing `msg_id`s for a node's outgoing messages.
+#[derive(Debug, Default)]
+pub(crate) struct MsgIdGen {
+    next: usize,
+}
+
+impl MsgIdGen {
+    pub(crate) fn next(&mut self) -> usize {
+        let id = self.next;
+        self.next += 1;
+        id
+    }
+}
+
+/// Invoked with the reply to a request registered through [`Rpc::register`].
+pub type Callback<N> =
+    Box<dyn FnOnce(&mut N, Message<<N as Node>::Payload>, &mut Rpc<N>) -> anyhow::Result<()>>;
+
+/// Invoked with the reply to a request sent through [`Rpc::request_then`], as it came in, or
+/// with why there is none.
+pub type Continuation<N> =
+    Box<dyn FnOnce(&mut N, Result<Message<Value>, RpcError>, &mut Rpc<N>) -> anyhow::Result<()>>;
+
+/// Invoked once its time has come, see [`Rpc::after`].
+type Timer<N> = Box<dyn FnOnce(&mut N, &mut Rpc<N>) -> anyhow::Result<()>>;
+
+/// What becomes of the reply to a request awaiting one.
+pub(crate) enum Handler<N: Node> {
+    /// Gets the reply decoded as one of the node's payloads. Without one in time, it's dropped
+    /// and the node gets an [`Event::Timeout`] instead.
+    Callback(Callback<N>),
+    /// Gets the reply as is, or the timeout.
+    Continuation(Continuation<N>),
+}
+
+/// A request that was sent and awaits its reply.
+struct Pending<N: Node> {
+    sent: Instant,
+    timeout: Duration,
+    destination: String,
+    handler: Handler<N>,
+}
+
+/// A node's connection to the outside world.
+///
+/// Everything a node sends goes through here, so every message gets a fresh `msg_id` from the
+/// same `MsgIdGen`. Requests whose reply the node wants to react to are registered together
+/// with a callback. The main loop hands an inbound message whose `in_reply_to` matches a
+/// registered request to that callback instead of [`Node::step`]. If none arrives within
+/// [`RPC_TIMEOUT`], the callback is dropped and the node gets an [`Event::Timeout`] instead.
+/// [`Rpc::request_then`] and the kv requests ending in `_then` hand their continuation the
+/// reply or the timeout.
+///
+/// None of these block: the node goes on handling messages, ticks and other timeouts while
+/// the reply is on its way. [`Rpc::request`] and the other requests without a continuation
+/// block the node until the reply arrives, which is simpler to write but holds up everything
+/// else in the meantime.
+///
+/// For example, read-after-write against `seq-kv` chains two requests, answering the client
+/// only once the read confirms the write:
+///
+/// ```ignore
+/// let write = Message { /* write `key` = `value` to "seq-kv" */ };
+/// rpc.call(write, move |_node, _write_ok, rpc| {
+///     let read = Message { /* read `key` from "seq-kv" */ };
+///     rpc.call(read, move |_node, read_ok, rpc| {
+///         // `read_ok` carries `value`, reply to the client's original request.
+///         rpc.send(&reply)
+///     })
+/// })?;
+/// ```
+pub struct Rpc<N: Node> {
+    pub(crate) node_id: Option<String>,
+    pub(crate) metrics: Metrics,
+    ids: MsgIdGen,
+    clock: Arc<dyn Clock>,
+    transport: Box<dyn Transport>,
+    pending: HashMap<usize, Pending<N>>,
+    /// Requests waiting for one of the [`set_max_in_flight`] slots, oldest first, with how
+    /// long to wait for their replies once sent.
+    queued: VecDeque<(Message<Value>, Duration, Handler<N>)>,
+    /// What to run later, by when.
+    timers: Vec<(Instant, Timer<N>)>,
+    proxies: HashMap<usize, PendingProxy>,
+    waiters: Waiters,
+    /// How many requests to each peer have timed out since it was last heard from.
+    timeouts: HashMap<String, u32>,
+    /// Requests that timed out, whose replies are dropped should they still arrive.
+    expired: BTreeSet<usize>,
+    pub(crate) overlay: Option<Overlay>,
+    batch: WriteBatch,
+    /// Requests awaiting their replies in the `Rpc` this one is [scoped](Rpc::scoped) from,
+    /// which count against [`set_max_in_flight`] too.
+    outer_in_flight: usize,
+    /// The limit from [`set_max_in_flight`], if any.
+    max_in_flight: Option<usize>,
+    /// The file from [`set_state_file`](crate::runtime::set_state_file), if any.
+    pub(crate) state_file: Option<PathBuf>,
+}
+
+/// Messages sent but not flushed yet, and whether flushing is held back until the runtime is
+/// done with the current event. See [`Rpc::batched`].
+#[derive(Debug, Default)]
+struct WriteBatch {
+    open: bool,
+    unflushed: usize,
+    /// Whether a message went to a client since the last flush.
+    answered: bool,
+    /// Messages for other nodes, held back until the flush so that client messages go first.
+    deferred: Vec<Message<Value>>,
+}
+
+static MAX_IN_FLIGHT: OnceLock<usize> = OnceLock::new();
+
+/// Has [`Rpc::call`] keep at most `limit` requests awaiting their replies at once. Only the
+/// first limit set counts, and there is none by default.
+///
+/// A request over the limit is queued, and sent once a reply or timeout frees a slot, so a
+/// burst goes out at the pace its replies come back. Its timeout only starts once it's sent.
+/// Blocking requests don't count, since they hold up everything else anyway.
+pub fn set_max_in_flight(limit: usize) {
+    let _ = MAX_IN_FLIGHT.set(limit.max(1));
+}
+
+/// A request [`Rpc::forward`]ed to a peer, by the forward's `msg_id`, and who to relay the
+/// peer's reply to.
+#[derive(Debug)]
+struct PendingProxy {
+    sent: Instant,
+    peer: String,
+    client: String,
+    in_reply_to: usize,
+}
+
+/// Whether `id` is one of Maelstrom's clients, `c1`, `c2` and so on, rather than a node or a
+/// service.
+fn is_client(id: &str) -> bool {
+    id.strip_prefix('c')
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Requests blocked in [`Rpc::request`], keyed by `msg_id`.
+///
+/// Shared with the stdin reader, which hands a reply straight to its waiting request rather
+/// than queueing it behind the handler that is blocked on it.
+pub(crate) type Waiters = Arc<Mutex<HashMap<usize, mpsc::Sender<Message<Value>>>>>;
+
+impl<N: Node> Rpc<N> {
+    pub(crate) fn new(
+        transport: impl Transport + 'static,
+        waiters: Waiters,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            node_id: None,
+            metrics: Metrics::default(),
+            ids: MsgIdGen::default(),
+            clock,
+            transport: Box::new(transport),
+            pending: HashMap::new(),
+            queued: VecDeque::new(),
+            timers: Vec::new(),
+            proxies: HashMap::new(),
+            waiters,
+            timeouts: HashMap::new(),
+            expired: BTreeSet::new(),
+            overlay: None,
+            batch: WriteBatch::default(),
+            outer_in_flight: 0,
+            max_in_flight: MAX_IN_FLIGHT.get().copied(),
+            state_file: runtime::state_file(),
+        }
+    }
+
+    /// An `Rpc` for the node `node_id` that isn't attached to any runtime, for calling a node's
+    /// handlers directly, e.g. with an [`InMemoryTransport`](crate::transport::InMemoryTransport)
+    /// in tests.
+    ///
+    /// Nothing ever answers its blocking requests, so they time out.
+    pub fn with_transport(node_id: impl Into<String>, transport: impl Transport + 'static) -> Self {
+        let mut rpc = Self::new(transport, Waiters::default(), Arc::new(SystemClock));
+        rpc.node_id = Some(node_id.into());
+        rpc
+    }
+
+    /// Tells the time by `clock` instead, e.g. a [`MockClock`](crate::clock::MockClock) to
+    /// time requests out on a test's own schedule.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// A fresh `msg_id` for a message this node is about to send.
+    pub fn next_id(&mut self) -> usize {
+        self.ids.next()
+    }
+
+    /// The current time, as told by the runtime's [`Clock`].
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Blocks for `duration`, as told by the runtime's [`Clock`], without handling anything
+    /// that comes in meanwhile.
+    pub(crate) fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration);
+    }
+
+    /// This node's id, known once Maelstrom's `init` has come in.
+    pub fn node_id(&self) -> Result<&str, NodeError> {
+        self.node_id.as_deref().ok_or(NodeError::NotInitialized)
+    }
+
+    /// What this node has sent and received so far.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Sends `msg` as is.
+    pub fn send(&mut self, msg: &Message<impl Serialize>) -> anyhow::Result<()> {
+        msg.debug_assert_in_reply_to();
+        validate(msg)?;
+        log::log_send(msg);
+        self.metrics.record_sent(msg.kind());
+
+        let msg = Message {
+            source: msg.source.clone(),
+            destination: msg.destination.clone(),
+            body: Body {
+                id: msg.body.id,
+                in_reply_to: msg.body.in_reply_to,
+                payload: serde_json::to_value(&msg.body.payload).map_err(NodeError::Encode)?,
+            },
+        };
+        self.batch.unflushed += 1;
+        if !self.batch.open {
+            self.transport.send(&msg)?;
+            return self.flush();
+        }
+
+        if is_client(&msg.destination) {
+            self.batch.answered = true;
+            self.transport.send(&msg)
+        } else {
+            self.batch.deferred.push(msg);
+            Ok(())
+        }
+    }
+
+    /// Sends `payload` in reply to `request`, under a fresh `msg_id`.
+    ///
+    /// A request without a `msg_id` can't be answered, so the reply is skipped.
+    pub fn reply<P: Serialize>(
+        &mut self,
+        request: &Message<P>,
+        payload: impl Serialize,
+    ) -> anyhow::Result<()> {
+        if !can_reply(request)? {
+            return Ok(());
+        }
+
+        let reply = request.reply(self.next_id(), payload);
+        self.send(&reply)
+    }
+
+    /// Sends `payload` to `destination` without expecting a reply, e.g. for one-way gossip.
+    ///
+    /// The message still gets a fresh `msg_id`, so that it can be told apart in the logs, but
+    /// nothing waits for an answer to it.
+    pub fn notify(&mut self, destination: &str, payload: impl Serialize) -> anyhow::Result<()> {
+        let notification = Message {
+            source: self.node_id()?.to_owned(),
+            destination: destination.to_owned(),
+            body: Body {
+                id: Some(self.next_id()),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        self.send(&notification)
+    }
+
+    /// Flushes whatever was sent since the last flush, if anything.
+    pub(crate) fn flush(&mut self) -> anyhow::Result<()> {
+        if self.batch.unflushed == 0 {
+            return Ok(());
+        }
+
+        // Clients hear back before the rest is even encoded, however much of it there is.
+        let deferred = std::mem::take(&mut self.batch.deferred);
+        if std::mem::take(&mut self.batch.answered) && !deferred.is_empty() {
+            self.metrics.flushes += 1;
+            self.transport.flush()?;
+        }
+        for msg in deferred {
+            self.transport.send(&msg)?;
+        }
+        self.batch.unflushed = 0;
+        self.metrics.flushes += 1;
+        self.transport.flush()
+    }
+
+    /// Runs `handle` with flushing held back, then flushes everything it sent at once, so that
+    /// a handler fanning out to many peers costs one write, or two if it answered a client,
+    /// rather than one per message.
+    ///
+    /// Within the batch, messages to clients are written and flushed before those to nodes and
+    /// services, so that a reply doesn't sit behind a burst of gossip. The rest go out in the
+    /// next flush, in the order sent, so they are held up by no more than one event's client
+    /// messages and never starve. Blocking requests still flush before they wait.
+    pub(crate) fn batched<T>(
+        &mut self,
+        handle: impl FnOnce(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let nested = std::mem::replace(&mut self.batch.open, true);
+        let result = handle(self);
+        self.batch.open = nested;
+
+        // What was sent before an error still goes out.
+        if !nested {
+            self.flush()?;
+        }
+        result
+    }
+
+    /// Remembers `request` so that its reply is passed to `callback`.
+    pub fn register(
+        &mut self,
+        request: &Message<impl Serialize>,
+        callback: impl FnOnce(&mut N, Message<N::Payload>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let Some(id) = request.body.id else {
+            return Err(NodeError::MissingMsgId(request.kind()).into());
+        };
+
+        self.await_reply(
+            id,
+            &request.destination,
+            RPC_TIMEOUT,
+            Handler::Callback(Box::new(callback)),
+        );
+        Ok(())
+    }
+
+    fn await_reply(
+        &mut self,
+        id: usize,
+        destination: &str,
+        timeout: Duration,
+        handler: Handler<N>,
+    ) {
+        let pending = Pending {
+            sent: self.now(),
+            timeout,
+            destination: destination.to_owned(),
+            handler,
+        };
+        self.pending.insert(id, pending);
+    }
+
+    /// Registers `request` and sends it, or queues it while [`set_max_in_flight`] requests
+    /// are awaiting their replies already.
+    pub fn call(
+        &mut self,
+        request: Message<impl Serialize>,
+        callback: impl FnOnce(&mut N, Message<N::Payload>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        if !self.has_free_slot() {
+            let handler = Handler::Callback(Box::new(callback));
+            return self.queue(request, RPC_TIMEOUT, handler);
+        }
+
+        self.register(&request, callback)?;
+        self.send(&request)
+    }
+
+    fn queue(
+        &mut self,
+        request: Message<impl Serialize>,
+        timeout: Duration,
+        handler: Handler<N>,
+    ) -> anyhow::Result<()> {
+        if request.body.id.is_none() {
+            return Err(NodeError::MissingMsgId(request.kind()).into());
+        }
+        let request = Message {
+            source: request.source,
+            destination: request.destination,
+            body: Body {
+                id: request.body.id,
+                in_reply_to: request.body.in_reply_to,
+                payload: serde_json::to_value(request.body.payload).map_err(NodeError::Encode)?,
+            },
+        };
+        self.queued.push_back((request, timeout, handler));
+        Ok(())
+    }
+
+    /// Sends `payload` to `destination`, and hands `then` the reply once it arrives, or
+    /// [`RpcError::Timeout`] if it doesn't within `timeout`.
+    ///
+    /// Unlike [`Rpc::request_with_timeout`], this returns right away, and the timeout is up
+    /// to the event loop: the node handles other messages meanwhile. `then` gets any reply,
+    /// `error` replies included, as [`Rpc::request`] would return it. Once `destination`
+    /// [is down](Rpc::is_down), a timeout is [`RpcError::Unreachable`] instead.
+    pub fn request_then(
+        &mut self,
+        destination: &str,
+        payload: impl Serialize,
+        timeout: Duration,
+        then: impl FnOnce(&mut N, Result<Message<Value>, RpcError>, &mut Self) -> anyhow::Result<()>
+            + 'static,
+    ) -> anyhow::Result<()> {
+        let id = self.next_id();
+        let request = Message {
+            source: self.node_id()?.to_owned(),
+            destination: destination.to_owned(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+
+        let handler = Handler::Continuation(Box::new(then));
+        if !self.has_free_slot() {
+            return self.queue(request, timeout, handler);
+        }
+        self.await_reply(id, destination, timeout, handler);
+        self.send(&request)
+    }
+
+    /// Like [`Rpc::request_then`], but re-sends the request under a fresh `msg_id` as laid
+    /// out by `policy`, just as [`Rpc::request_retrying`] does, without blocking in between.
+    ///
+    /// Only use this for idempotent requests, since every attempt may take effect.
+    pub fn request_retrying_then(
+        &mut self,
+        destination: &str,
+        payload: impl Serialize,
+        policy: RetryPolicy,
+        then: impl FnOnce(&mut N, Result<Message<Value>, RpcError>, &mut Self) -> anyhow::Result<()>
+            + 'static,
+    ) -> anyhow::Result<()>
+    where
+        N: 'static,
+    {
+        let payload = serde_json::to_value(payload).map_err(NodeError::Encode)?;
+        let attempt = Attempt {
+            destination: destination.to_owned(),
+            payload,
+            policy,
+            number: 1,
+            timeout: policy.base_delay,
+        };
+        attempt.send(self, Box::new(then))
+    }
+
+    /// Runs `f` once `delay` has passed, by the runtime's clock, e.g. to back off before
+    /// trying again. Nothing waits in the meantime.
+    pub fn after(
+        &mut self,
+        delay: Duration,
+        f: impl FnOnce(&mut N, &mut Self) -> anyhow::Result<()> + 'static,
+    ) {
+        let at = self.now() + delay;
+        self.timers.push((at, Box::new(f)));
+    }
+
+    fn has_free_slot(&self) -> bool {
+        self.max_in_flight
+            .is_none_or(|limit| self.outer_in_flight + self.pending.len() < limit)
+    }
+
+    /// Sends queued requests for as long as there are free slots.
+    pub(crate) fn send_queued(&mut self) -> anyhow::Result<()> {
+        while self.has_free_slot() {
+            let Some((request, timeout, handler)) = self.queued.pop_front() else {
+                break;
+            };
+            let id = request.body.id.expect("Queued requests have a msg_id.");
+            self.await_reply(id, &request.destination, timeout, handler);
+            self.send(&request)?;
+        }
+        Ok(())
+    }
+
+    /// Sends `payload` to `to` on behalf of `original`, and relays `to`'s reply to whoever
+    /// sent `original`, as the reply to it, e.g. to hand a request to the node that can answer
+    /// it.
+    ///
+    /// The reply is relayed as is, errors included, and without the node seeing it. Should
+    /// `to` not answer within [`RPC_TIMEOUT`], the client gets a `timeout` error instead. A
+    /// request without a `msg_id` expects no reply, so `payload` is only passed on.
+    pub fn forward(
+        &mut self,
+        original: &Message<impl Serialize>,
+        to: &str,
+        payload: impl Serialize,
+    ) -> anyhow::Result<()> {
+        let Some(in_reply_to) = original.body.id else {
+            return self.notify(to, payload);
+        };
+
+        let id = self.next_id();
+        let forward = Message {
+            source: self.node_id()?.to_owned(),
+            destination: to.to_owned(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        self.send(&forward)?;
+
+        let proxy = PendingProxy {
+            sent: self.now(),
+            peer: to.to_owned(),
+            client: original.source.clone(),
+            in_reply_to,
+        };
+        self.proxies.insert(id, proxy);
+        Ok(())
+    }
+
+    /// Relays `reply` to the client, if it answers a [forwarded](Rpc::forward) request,
+    /// returning whether it did.
+    pub(crate) fn relay(&mut self, reply: &Message<Value>) -> anyhow::Result<bool> {
+        let Some(proxy) = reply
+            .body
+            .in_reply_to
+            .and_then(|id| self.proxies.remove(&id))
+        else {
+            return Ok(false);
+        };
+        self.metrics
+            .record_latency(&proxy.peer, self.clock.now() - proxy.sent);
+
+        let relayed = Message {
+            source: self.node_id()?.to_owned(),
+            destination: proxy.client,
+            body: Body {
+                id: Some(self.next_id()),
+                in_reply_to: Some(proxy.in_reply_to),
+                payload: &reply.body.payload,
+            },
+        };
+        self.send(&relayed)?;
+        Ok(true)
+    }
+
+    /// Takes what's waiting for the reply to the request with this `msg_id`, if any.
+    pub(crate) fn take(&mut self, id: usize) -> Option<Handler<N>> {
+        let pending = self.pending.remove(&id)?;
+        self.metrics
+            .record_latency(&pending.destination, self.clock.now() - pending.sent);
+        Some(pending.handler)
+    }
+
+    /// Whether `peer` looks down: its last [`UNREACHABLE_AFTER`] requests all timed out, and
+    /// nothing has come in from it since.
+    ///
+    /// This is only a hint for routing around a crashed or partitioned node. Requests to it
+    /// still go out, and the first message heard from it marks it up again.
+    pub fn is_down(&self, peer: &str) -> bool {
+        self.timeouts
+            .get(peer)
+            .is_some_and(|timeouts| *timeouts >= UNREACHABLE_AFTER)
+    }
+
+    /// Marks `peer` up again.
+    pub(crate) fn heard_from(&mut self, peer: &str) {
+        if self.is_down(peer) {
+            log::info!("Heard from {peer} again.");
+        }
+        self.timeouts.remove(peer);
+    }
+
+    fn timed_out(&mut self, peer: &str, id: usize) {
+        let timeouts = self.timeouts.entry(peer.to_owned()).or_default();
+        *timeouts += 1;
+        if *timeouts == UNREACHABLE_AFTER {
+            log::info!(
+                "{peer} looks down or partitioned away, after {UNREACHABLE_AFTER} timeouts."
+            );
+        }
+        self.metrics.timeouts += 1;
+
+        self.expired.insert(id);
+        if self.expired.len() > LATE_REPLIES {
+            self.expired.pop_first();
+        }
+    }
+
+    /// Whether `reply` answers a request that has timed out already, and was handled as such.
+    pub(crate) fn is_late(&mut self, reply: &Message<Value>) -> bool {
+        reply
+            .body
+            .in_reply_to
+            .is_some_and(|id| self.expired.remove(&id))
+    }
+
+    /// When the next request times out or timer comes due, if any is left.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|pending| pending.sent + pending.timeout)
+            .chain(self.proxies.values().map(|proxy| proxy.sent + RPC_TIMEOUT))
+            .chain(self.timers.iter().map(|(at, _)| *at))
+            .min()
+    }
+
+    /// Deals with everything due by `now`: tells the clients of forwarded requests that timed
+    /// out so, gives `node` an [`Event::Timeout`] for every registered request that timed out
+    /// and the continuation of every other one its timeout, in the order they were sent, then
+    /// runs the timers that are due.
+    pub(crate) fn expire(&mut self, node: &mut N, now: Instant) -> anyhow::Result<()> {
+        let mut proxies: Vec<usize> = self
+            .proxies
+            .iter()
+            .filter(|(_, proxy)| proxy.sent + RPC_TIMEOUT <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        proxies.sort_unstable();
+
+        for id in proxies {
+            let Some(proxy) = self.proxies.remove(&id) else {
+                continue;
+            };
+            self.timed_out(&proxy.peer, id);
+
+            let timeout = Message {
+                source: self.node_id()?.to_owned(),
+                destination: proxy.client,
+                body: Body {
+                    id: Some(self.next_id()),
+                    in_reply_to: Some(proxy.in_reply_to),
+                    payload: ErrorPayload::Error {
+                        code: ErrorCode::Timeout.into(),
+                        text: format!("{} did not answer in time.", proxy.peer),
+                    },
+                },
+            };
+            self.send(&timeout)?;
+        }
+
+        let mut expired: Vec<usize> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.sent + pending.timeout <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort_unstable();
+
+        for id in expired {
+            let Some(pending) = self.pending.remove(&id) else {
+                continue;
+            };
+            self.timed_out(&pending.destination, id);
+            // Free the slot first, so whatever comes of the timeout can take it.
+            self.send_queued()?;
+            match pending.handler {
+                Handler::Callback(_) => node.on_event(Event::Timeout(id), self)?,
+                Handler::Continuation(then) => {
+                    let error = if self.is_down(&pending.destination) {
+                        RpcError::Unreachable
+                    } else {
+                        RpcError::Timeout
+                    };
+                    then(node, Err(error), self)?;
+                }
+            }
+        }
+
+        // Timers set while these run are left for the next round, even if due already.
+        let (mut due, timers) = std::mem::take(&mut self.timers)
+            .into_iter()
+            .partition(|(at, _)| *at <= now);
+        self.timers = timers;
+        due.sort_by_key(|(at, _)| *at);
+        for (_, timer) in due {
+            timer(node, self)?;
+        }
+
+        self.send_queued()
+    }
+
+    /// Sends `payload` to `destination` and blocks until the reply arrives, giving up after
+    /// [`RPC_TIMEOUT`].
+    ///
+    /// A convenience for nodes that have nothing else to do meanwhile: no other message, tick
+    /// or timeout is handled until this returns. [`Rpc::request_then`] doesn't wait.
+    pub fn request(
+        &mut self,
+        destination: &str,
+        payload: impl Serialize,
+    ) -> Result<Message<Value>, RpcError> {
+        self.request_with_timeout(destination, payload, RPC_TIMEOUT)
+    }
+
+    /// Sends `payload` to `destination` and blocks until the reply arrives or `timeout`
+    /// passes.
+    ///
+    /// The stdin reader keeps going in the meantime. Messages other than the reply stay queued
+    /// for the main loop, and a reply that shows up after the timeout is dropped. Once
+    /// `destination` [`is down`](Rpc::is_down), a timeout fails with [`RpcError::Unreachable`].
+    pub fn request_with_timeout(
+        &mut self,
+        destination: &str,
+        payload: impl Serialize,
+        timeout: Duration,
+    ) -> Result<Message<Value>, RpcError> {
+        let id = self.next_id();
+        let request = Message {
+            source: self
+                .node_id()
+                .map_err(|error| RpcError::Send(error.into()))?
+                .to_owned(),
+            destination: destination.to_owned(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+
+        // Register before sending so the reply can't overtake us.
+        let (tx, rx) = mpsc::channel();
+        self.waiters
+            .lock()
+            .expect("Waiters lock poisoned.")
+            .insert(id, tx);
+
+        let sent = self.now();
+        if let Err(error) = self.send(&request).and_then(|()| self.flush()) {
+            self.forget(id);
+            return Err(RpcError::Send(error));
+        }
+
+        match self.clock.recv_timeout(&rx, timeout) {
+            Ok(reply) => {
+                self.metrics.record_received(reply.kind());
+                self.metrics
+                    .record_latency(destination, self.clock.now() - sent);
+                self.heard_from(destination);
+                Ok(reply)
+            }
+            Err(error) => {
+                self.forget(id);
+                match error {
+                    RecvTimeoutError::Timeout => {
+                        self.timed_out(destination, id);
+                        if self.is_down(destination) {
+                            Err(RpcError::Unreachable)
+                        } else {
+                            Err(RpcError::Timeout)
+                        }
+                    }
+                    RecvTimeoutError::Disconnected => Err(RpcError::Closed),
+                }
+            }
+        }
+    }
+
+    /// Like [`Rpc::request_with_timeout`], but re-sends the request under a fresh `msg_id`
+    /// whenever an attempt times out, as laid out by `policy`.
+    ///
+    /// Every attempt may reach `destination` even though its reply got lost, so only use this
+    /// for idempotent requests. Use [`Rpc::request`] for anything else. Retrying stops early
+    /// once `destination` is down.
+    ///
+    /// An error reply that [`is_retriable`](RpcError::is_retriable) is retried too, after
+    /// waiting out what would have been the attempt's timeout. Any other error reply, like
+    /// `precondition-failed`, ends the retries as [`RpcError::Remote`], and so does a retriable
+    /// one to the last attempt.
+    pub fn request_retrying(
+        &mut self,
+        destination: &str,
+        payload: impl Serialize,
+        policy: RetryPolicy,
+    ) -> Result<Message<Value>, RpcError> {
+        let payload =
+            serde_json::to_value(payload).map_err(|error| RpcError::Send(error.into()))?;
+        let mut timeout = policy.base_delay;
+
+        for _ in 1..policy.max_attempts {
+            match self.request_with_timeout(destination, &payload, timeout) {
+                Err(RpcError::Timeout) => timeout = timeout.mul_f64(policy.multiplier),
+                Ok(reply) if RpcError::from_reply(&reply).is_some_and(|e| e.is_retriable()) => {
+                    self.sleep(timeout);
+                    timeout = timeout.mul_f64(policy.multiplier);
+                }
+                result => return refused(result),
+            }
+        }
+
+        refused(self.request_with_timeout(destination, &payload, timeout))
+    }
+
+    /// Sends `payload` to every one of `destinations` at once, and hands `then` what `extract`
+    /// makes of each reply, in the order of `destinations`, once all of them have replied or
+    /// timed out after [`RPC_TIMEOUT`].
+    ///
+    /// A destination that doesn't reply in time, or whose reply `extract` turns down, counts as
+    /// `T::default()`, so that one unreachable node doesn't fail the whole gather. Unlike
+    /// [`Rpc::gather`], this returns right away.
+    pub fn gather_then<T: Default + 'static>(
+        &mut self,
+        destinations: &[String],
+        payload: impl Serialize,
+        extract: impl Fn(N::Payload) -> Option<T> + 'static,
+        then: impl FnOnce(&mut N, Vec<T>, &mut Self) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()>
+    where
+        N: 'static,
+    {
+        if destinations.is_empty() {
+            self.after(Duration::ZERO, move |node, rpc| then(node, Vec::new(), rpc));
+            return Ok(());
+        }
+
+        let payload = serde_json::to_value(payload).map_err(NodeError::Encode)?;
+        let gathering = Rc::new(RefCell::new(Gathering {
+            results: destinations.iter().map(|_| T::default()).collect(),
+            remaining: destinations.len(),
+            then: Some(Box::new(then)),
+        }));
+        let extract = Rc::new(extract);
+
+        for (index, destination) in destinations.iter().enumerate() {
+            let gathering = Rc::clone(&gathering);
+            let extract = Rc::clone(&extract);
+            self.request_then(
+                destination,
+                &payload,
+                RPC_TIMEOUT,
+                move |node, reply, rpc| {
+                    let result = reply
+                        .ok()
+                        .and_then(|reply| reply.decode::<N::Payload>().ok())
+                        .and_then(|reply| extract(reply.body.payload));
+
+                    let mut gathered = gathering.borrow_mut();
+                    if let Some(result) = result {
+                        gathered.results[index] = result;
+                    }
+                    gathered.remaining -= 1;
+                    if gathered.remaining > 0 {
+                        return Ok(());
+                    }
+
+                    let results = std::mem::take(&mut gathered.results);
+                    let then = gathered.then.take().expect("The gather ends once.");
+                    drop(gathered);
+                    then(node, results, rpc)
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Sends `payload` to every one of `destinations` at once, and blocks until all of them
+    /// reply or [`RPC_TIMEOUT`] passes.
+    ///
+    /// Returns what `extract` makes of each reply, in the order of `destinations`. A
+    /// destination that doesn't reply in time, or whose reply `extract` turns down, counts as
+    /// `T::default()`, so that one unreachable node doesn't fail the whole gather.
+    pub fn gather<T: Default>(
+        &mut self,
+        destinations: &[String],
+        payload: impl Serialize,
+        extract: impl Fn(N::Payload) -> Option<T>,
+    ) -> Result<Vec<T>, RpcError> {
+        let source = self
+            .node_id()
+            .map_err(|error| RpcError::Send(error.into()))?
+            .to_owned();
+
+        // Every request waits on the same channel, so replies are taken in whatever order
+        // they arrive.
+        let (tx, rx) = mpsc::channel();
+        let mut outstanding = HashMap::new();
+        for (index, destination) in destinations.iter().enumerate() {
+            let id = self.next_id();
+            let request = Message {
+                source: source.clone(),
+                destination: destination.clone(),
+                body: Body {
+                    id: Some(id),
+                    in_reply_to: None,
+                    payload: &payload,
+                },
+            };
+
+            self.waiters
+                .lock()
+                .expect("Waiters lock poisoned.")
+                .insert(id, tx.clone());
+            outstanding.insert(id, index);
+
+            if let Err(error) = self.send(&request) {
+                for id in outstanding.into_keys() {
+                    self.forget(id);
+                }
+                return Err(RpcError::Send(error));
+            }
+        }
+        drop(tx);
+        if let Err(error) = self.flush() {
+            for id in outstanding.into_keys() {
+                self.forget(id);
+            }
+            return Err(RpcError::Send(error));
+        }
+
+        let sent = self.now();
+        let deadline = sent + RPC_TIMEOUT;
+        let mut results: Vec<T> = destinations.iter().map(|_| T::default()).collect();
+
+        while !outstanding.is_empty() {
+            let remaining = deadline.saturating_duration_since(self.now());
+            let Ok(reply) = self.clock.recv_timeout(&rx, remaining) else {
+                break;
+            };
+            let Some(index) = reply
+                .body
+                .in_reply_to
+                .and_then(|id| outstanding.remove(&id))
+            else {
+                continue;
+            };
+
+            self.metrics.record_received(reply.kind());
+            self.metrics
+                .record_latency(&reply.source, self.clock.now() - sent);
+            self.heard_from(&reply.source);
+
+            if let Some(result) = serde_json::from_value(reply.body.payload)
+                .ok()
+                .and_then(&extract)
+            {
+                results[index] = result;
+            }
+        }
+
+        for (id, index) in outstanding {
+            self.forget(id);
+            self.timed_out(&destinations[index], id);
+        }
+
+        Ok(results)
+    }
+
+    /// Runs `f` with this `Rpc` lent out as the `Rpc` of `M`, the node inside `N` that `part`
+    /// finds, e.g. one of the two of a [`Composite`](crate::composite::Composite).
+    ///
+    /// `M` shares everything with `N`, down to the `msg_id`s. Callbacks it registers are kept
+    /// here, and get the reply through `part` and another scoped `Rpc` in turn.
+    pub(crate) fn scoped<M: Node + 'static, R>(
+        &mut self,
+        part: fn(&mut N) -> &mut M,
+        f: impl FnOnce(&mut Rpc<M>) -> R,
+    ) -> R
+    where
+        N: 'static,
+    {
+        let mut inner = self.lend();
+        let result = f(&mut inner);
+        self.take_back(part, inner);
+        result
+    }
+
+    /// This `Rpc`'s state, lent out as the `Rpc` of `M` until [taken back](Rpc::take_back).
+    ///
+    /// Kept apart from [`Rpc::scoped`] so that the timers `take_back` wraps, which call
+    /// `scoped` themselves, don't depend on its `f`, which would instantiate it without end.
+    fn lend<M: Node + 'static>(&mut self) -> Rpc<M> {
+        Rpc::<M> {
+            node_id: self.node_id.take(),
+            metrics: std::mem::take(&mut self.metrics),
+            ids: std::mem::take(&mut self.ids),
+            clock: Arc::clone(&self.clock),
+            transport: std::mem::replace(&mut self.transport, Box::new(Lent)),
+            pending: HashMap::new(),
+            queued: VecDeque::new(),
+            timers: Vec::new(),
+            proxies: std::mem::take(&mut self.proxies),
+            waiters: Arc::clone(&self.waiters),
+            timeouts: std::mem::take(&mut self.timeouts),
+            expired: std::mem::take(&mut self.expired),
+            overlay: self.overlay.take(),
+            batch: std::mem::take(&mut self.batch),
+            outer_in_flight: self.outer_in_flight + self.pending.len(),
+            max_in_flight: self.max_in_flight,
+            state_file: self.state_file.clone(),
+        }
+    }
+
+    /// Takes back the state lent to `inner`, with the callbacks and timers it registered.
+    fn take_back<M: Node + 'static>(&mut self, part: fn(&mut N) -> &mut M, inner: Rpc<M>)
+    where
+        N: 'static,
+    {
+        self.node_id = inner.node_id;
+        self.metrics = inner.metrics;
+        self.ids = inner.ids;
+        self.transport = inner.transport;
+        self.proxies = inner.proxies;
+        self.timeouts = inner.timeouts;
+        self.expired = inner.expired;
+        self.overlay = inner.overlay;
+        self.batch = inner.batch;
+        for (id, pending) in inner.pending {
+            let pending = Pending {
+                sent: pending.sent,
+                timeout: pending.timeout,
+                destination: pending.destination,
+                handler: lift(part, pending.handler),
+            };
+            self.pending.insert(id, pending);
+        }
+        for (request, timeout, handler) in inner.queued {
+            self.queued
+                .push_back((request, timeout, lift(part, handler)));
+        }
+        for (at, timer) in inner.timers {
+            let timer: Timer<N> =
+                Box::new(move |node, rpc| rpc.scoped(part, |rpc| timer(part(node), rpc)));
+            self.timers.push((at, timer));
+        }
+    }
+
+    fn forget(&mut self, id: usize) {
+        self.waiters
+            .lock()
+            .expect("Waiters lock poisoned.")
+            .remove(&id);
+    }
+}
+
+/// Turns a handler of `M` into one of `N`, the node it's part of.
+fn lift<N: Node + 'static, M: Node + 'static>(
+    part: fn(&mut N) -> &mut M,
+    handler: Handler<M>,
+) -> Handler<N> {
+    match handler {
+        Handler::Callback(callback) => Handler::Callback(Box::new(move |node, reply, rpc| {
+            let payload = serde_json::to_value(reply.body.payload).map_err(NodeError::Encode)?;
+            let reply = Message {
+                source: reply.source,
+                destination: reply.destination,
+                body: Body {
+                    id: reply.body.id,
+                    in_reply_to: reply.body.in_reply_to,
+                    payload,
+                },
+            }
+            .decode()
+            .map_err(NodeError::Decode)?;
+            rpc.scoped(part, |rpc| callback(part(node), reply, rpc))
+        })),
+        Handler::Continuation(then) => Handler::Continuation(Box::new(move |node, result, rpc| {
+            rpc.scoped(part, |rpc| then(part(node), result, rpc))
+        })),
+    }
+}
+
+/// What becomes of a [`Rpc::gather_then`]'s answers.
+type Gathered<N, T> = Box<dyn FnOnce(&mut N, Vec<T>, &mut Rpc<N>) -> anyhow::Result<()>>;
+
+/// A [`Rpc::gather_then`] under way.
+struct Gathering<N: Node, T> {
+    results: Vec<T>,
+    remaining: usize,
+    then: Option<Gathered<N, T>>,
+}
+
+/// One attempt of a request sent through [`Rpc::request_retrying_then`].
+struct Attempt {
+    destination: String,
+    payload: Value,
+    policy: RetryPolicy,
+    /// Counting from 1.
+    number: u32,
+    timeout: Duration,
+}
+
+impl Attempt {
+    fn send<N: Node + 'static>(
+        self,
+        rpc: &mut Rpc<N>,
+        then: Continuation<N>,
+    ) -> anyhow::Result<()> {
+        let destination = self.destination.clone();
+        let payload = self.payload.clone();
+        let timeout = self.timeout;
+        rpc.request_then(&destination, payload, timeout, move |node, result, rpc| {
+            if self.number >= self.policy.max_attempts {
+                return then(node, refused(result), rpc);
+            }
+            match result {
+                Err(RpcError::Timeout) => self.next().send(rpc, then),
+                Ok(reply) if RpcError::from_reply(&reply).is_some_and(|e| e.is_retriable()) => {
+                    // Wait out what would have been the attempt's timeout, as
+                    // `Rpc::request_retrying` does.
+                    rpc.after(self.timeout, move |_, rpc| self.next().send(rpc, then));
+                    Ok(())
+                }
+                result => then(node, refused(result), rpc),
+            }
+        })
+    }
+
+    fn next(self) -> Self {
+        Self {
+            number: self.number + 1,
+            timeout: self.timeout.mul_f64(self.policy.multiplier),
+            ..self
+        }
+    }
+}
+
+/// `result`, with an `error` reply turned into the [`RpcError`] it carries.
+fn refused(result: Result<Message<Value>, RpcError>) -> Result<Message<Value>, RpcError> {
+    match result {
+        Ok(reply) => RpcError::from_reply(&reply).map_or(Ok(reply), Err),
+        Err(error) => Err(error),
+    }
+}
+
+/// Stands in for the transport of an `Rpc` while it's [scoped](Rpc::scoped), which nothing
+/// should be sending through.
+struct Lent;
+
+impl Transport for Lent {
+    fn send(&mut self, _msg: &Message<Value>) -> anyhow::Result<()> {
+        anyhow::bail!("Sent through an Rpc that is lent out.")
+    }
+}
+
+/// How [`Rpc::request_retrying`] backs off.
+///
+/// The first attempt waits `base_delay` for its reply, and every retry waits `multiplier` times
+/// as long as the attempt before it, so a slow destination isn't flooded with retries.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(200),
+            multiplier: 2.0,
+        }
+    }
+}
+
+/// How long [`Rpc::request`] waits for a reply.
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many requests in a row to a peer have to time out for it to count as down.
+pub const UNREACHABLE_AFTER: u32 = 3;
+
+/// How many timed out requests to recognise late replies to.
+const LATE_REPLIES: usize = 1024;
+
+/// Why [`Rpc::request`] didn't get a reply.
+#[derive(Debug, thiserror::Error)]
+pub enum RpcError {
+    /// No reply arrived in time.
+    #[error("Timed out awaiting a reply.")]
+    Timeout,
+    /// No reply arrived in time, and the destination hasn't answered the requests before this
+    /// one either.
+    #[error("Timed out awaiting a reply from a node that is down.")]
+    Unreachable,
+    /// Stdin closed before the reply arrived.
+    #[error("Stdin closed while awaiting a reply.")]
+    Closed,
+    /// The request couldn't be sent.
+    #[error("Could not send request: {0:#}")]
+    Send(anyhow::Error),
+    /// The destination replied with an `error`.
+    #[error("Error {code}: {text}")]
+    Remote { code: u32, text: String },
+}
+
+impl RpcError {
+    /// The error `reply` carries, if it's an `error` reply.
+    pub fn from_reply(reply: &Message<Value>) -> Option<Self> {
+        if reply.kind() != "error" {
+            return None;
+        }
+        let error: ErrorPayload = serde_json::from_value(reply.body.payload.clone()).ok()?;
+        Some(error.into())
+    }
+
+    /// The Maelstrom error code, for an error reply with a known one.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            RpcError::Remote { code, .. } => ErrorCode::try_from(*code).ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether the destination said the request didn't take effect, but may if sent again
+    /// later, as with `temporarily-unavailable`.
+    ///
+    /// A timeout isn't retriable in this sense, since the request may have taken effect
+    /// anyway. Only an idempotent request can be sent again after one.
+    pub fn is_retriable(&self) -> bool {
+        self.code().is_some_and(ErrorCode::is_retriable)
+    }
+
+    /// Whether the destination said the request didn't take effect and won't if sent again,
+    /// as with `precondition-failed`.
+    pub fn is_abort(&self) -> bool {
+        self.code().is_some_and(ErrorCode::is_abort)
+    }
+}
+
+impl From<ErrorPayload> for RpcError {
+    fn from(error: ErrorPayload) -> Self {
+        let ErrorPayload::Error { code, text } = error;
+        RpcError::Remote { code, text }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::runtime::dispatch;
+    use crate::transport::InMemoryTransport;
+
+    /// Writes down what each continuation and timer got.
+    #[derive(Default)]
+    struct Probe {
+        got: Vec<String>,
+    }
+
+    impl Node for Probe {
+        type Payload = Value;
+
+        fn step(&mut self, input: Message<Value>, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            self.got
+                .push(format!("step {}", input.body.payload["type"]));
+            Ok(())
+        }
+    }
+
+    fn probe(result: Result<Message<Value>, RpcError>) -> String {
+        match result {
+            Ok(reply) => format!("reply {}", reply.body.payload["type"]),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    fn rpc() -> (Rpc<Probe>, InMemoryTransport, Arc<MockClock>) {
+        let transport = InMemoryTransport::new();
+        let clock = Arc::new(MockClock::new());
+        let mut rpc = Rpc::new(transport.clone(), Waiters::default(), clock.clone());
+        rpc.node_id = Some("n1".to_owned());
+        (rpc, transport, clock)
+    }
+
+    fn reply_to(request: &Message<Value>, payload: Value) -> Message<Value> {
+        Message {
+            source: request.destination.clone(),
+            destination: request.source.clone(),
+            body: Body {
+                id: None,
+                in_reply_to: request.body.id,
+                payload,
+            },
+        }
+    }
+
+    #[test]
+    fn continuations_get_their_reply_while_other_messages_go_on() {
+        let (mut rpc, transport, _) = rpc();
+        let mut node = Probe::default();
+        rpc.request_then(
+            "n2",
+            serde_json::json!({"type": "ping"}),
+            RPC_TIMEOUT,
+            |node: &mut Probe, result, _| {
+                node.got.push(probe(result));
+                Ok(())
+            },
+        )
+        .unwrap();
+        let [request] = transport.take().try_into().unwrap();
+
+        let other = Message {
+            source: "c1".to_owned(),
+            destination: "n1".to_owned(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                payload: serde_json::json!({"type": "echo"}),
+            },
+        };
+        dispatch(&mut node, &other, &mut rpc).unwrap();
+        let error = serde_json::json!({"type": "error", "code": 11, "text": "busy"});
+        dispatch(&mut node, &reply_to(&request, error), &mut rpc).unwrap();
+
+        assert_eq!(node.got, ["step \"echo\"", "reply \"error\""]);
+        assert_eq!(rpc.next_deadline(), None);
+    }
+
+    #[test]
+    fn continuations_time_out_on_the_clock() {
+        let (mut rpc, _, clock) = rpc();
+        let mut node = Probe::default();
+        let timeout = Duration::from_millis(100);
+        rpc.request_then(
+            "n2",
+            serde_json::json!({"type": "ping"}),
+            timeout,
+            |node: &mut Probe, result, _| {
+                node.got.push(probe(result));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(rpc.next_deadline(), Some(clock.now() + timeout));
+
+        clock.advance(timeout / 2);
+        rpc.expire(&mut node, clock.now()).unwrap();
+        assert!(node.got.is_empty());
+
+        clock.advance(timeout / 2);
+        rpc.expire(&mut node, clock.now()).unwrap();
+        assert_eq!(node.got, [RpcError::Timeout.to_string()]);
+    }
+
+    #[test]
+    fn a_peer_that_stops_answering_is_down_until_heard_from_again() {
+        let (mut rpc, transport, clock) = rpc();
+        let mut node = Probe::default();
+        let ping = |rpc: &mut Rpc<Probe>| {
+            rpc.request_then(
+                "n2",
+                serde_json::json!({"type": "ping"}),
+                RPC_TIMEOUT,
+                |node: &mut Probe, result, _| {
+                    node.got.push(probe(result));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        };
+
+        // n2 crashes: every request to it times out, and the last ones fail fast.
+        for _ in 0..UNREACHABLE_AFTER {
+            ping(&mut rpc);
+            clock.advance(RPC_TIMEOUT);
+            rpc.expire(&mut node, clock.now()).unwrap();
+        }
+        assert_eq!(
+            node.got,
+            [
+                RpcError::Timeout.to_string(),
+                RpcError::Timeout.to_string(),
+                RpcError::Unreachable.to_string(),
+            ]
+        );
+        assert!(rpc.is_down("n2"));
+
+        // n2 comes back, and its reply to the first request finally arrives, long after that
+        // request's continuation got its timeout.
+        ping(&mut rpc);
+        let sent = transport.take();
+        let pong = serde_json::json!({"type": "pong"});
+        dispatch(&mut node, &reply_to(&sent[0], pong.clone()), &mut rpc).unwrap();
+        assert!(!rpc.is_down("n2"));
+        assert_eq!(node.got.len(), 3, "{:?}", node.got);
+
+        dispatch(&mut node, &reply_to(&sent[3], pong), &mut rpc).unwrap();
+        assert_eq!(node.got[3..], ["reply \"pong\""]);
+        assert_eq!(rpc.next_deadline(), None);
+    }
+
+    #[test]
+    fn a_burst_never_has_more_requests_awaiting_replies_than_the_limit() {
+        let (mut rpc, transport, _) = rpc();
+        rpc.max_in_flight = Some(2);
+        let mut node = Probe::default();
+        for _ in 0..10 {
+            rpc.request_then(
+                "n2",
+                serde_json::json!({"type": "ping"}),
+                RPC_TIMEOUT,
+                |node: &mut Probe, result, _| {
+                    node.got.push(probe(result));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        }
+
+        let mut answered = 0;
+        while answered < 10 {
+            let sent = transport.take();
+            assert!(rpc.pending.len() <= 2, "{} pending", rpc.pending.len());
+            assert!(
+                !sent.is_empty() && sent.len() <= 2,
+                "{} sent at once",
+                sent.len()
+            );
+            for request in sent {
+                let pong = reply_to(&request, serde_json::json!({"type": "pong"}));
+                dispatch(&mut node, &pong, &mut rpc).unwrap();
+                answered += 1;
+                assert!(rpc.pending.len() <= 2, "{} pending", rpc.pending.len());
+            }
+        }
+        assert_eq!(node.got.len(), 10);
+        assert!(rpc.queued.is_empty() && rpc.pending.is_empty());
+    }
+
+    #[test]
+    fn only_requests_with_a_msg_id_are_replied_to() {
+        let (mut rpc, transport, _) = rpc();
+        let mut request = Message {
+            source: "c1".to_owned(),
+            destination: "n1".to_owned(),
+            body: Body {
+                id: Some(7),
+                in_reply_to: None,
+                payload: serde_json::json!({"type": "echo"}),
+            },
+        };
+
+        rpc.reply(&request, serde_json::json!({"type": "echo_ok"}))
+            .unwrap();
+        let [reply] = transport.take().try_into().unwrap();
+        assert_eq!(reply.destination, "c1");
+        assert_eq!(reply.body.in_reply_to, Some(7));
+
+        // Outside strict mode, a reply no one could match up is skipped rather than an error.
+        request.body.id = None;
+        rpc.reply(&request, serde_json::json!({"type": "echo_ok"}))
+            .unwrap();
+        assert!(transport.sent().is_empty());
+    }
+
+    #[test]
+    fn retries_go_out_under_fresh_ids_with_longer_timeouts() {
+        let (mut rpc, transport, clock) = rpc();
+        let mut node = Probe::default();
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+        };
+        rpc.request_retrying_then(
+            "n2",
+            serde_json::json!({"type": "read"}),
+            policy,
+            |node: &mut Probe, result, _| {
+                node.got.push(probe(result));
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        clock.advance(Duration::from_millis(100));
+        rpc.expire(&mut node, clock.now()).unwrap();
+        clock.advance(Duration::from_millis(200));
+        rpc.expire(&mut node, clock.now()).unwrap();
+
+        let sent = transport.take();
+        let ids: Vec<_> = sent.iter().map(|request| request.body.id).collect();
+        assert_eq!(ids, [Some(0), Some(1), Some(2)]);
+        assert!(node.got.is_empty());
+        assert_eq!(
+            rpc.next_deadline(),
+            Some(clock.now() + Duration::from_millis(400))
+        );
+
+        let read_ok = serde_json::json!({"type": "read_ok", "value": 1});
+        dispatch(&mut node, &reply_to(&sent[2], read_ok), &mut rpc).unwrap();
+        assert_eq!(node.got, ["reply \"read_ok\""]);
+    }
+
+    #[test]
+    fn retriable_errors_are_retried_after_a_delay() {
+        let (mut rpc, transport, clock) = rpc();
+        let mut node = Probe::default();
+        rpc.request_retrying_then(
+            "n2",
+            serde_json::json!({"type": "read"}),
+            RetryPolicy::default(),
+            |node: &mut Probe, result, _| {
+                node.got.push(probe(result));
+                Ok(())
+            },
+        )
+        .unwrap();
+        let [request] = transport.take().try_into().unwrap();
+
+        let busy = serde_json::json!({"type": "error", "code": 11, "text": "busy"});
+        dispatch(&mut node, &reply_to(&request, busy), &mut rpc).unwrap();
+        assert!(transport.sent().is_empty());
+
+        let delay = RetryPolicy::default().base_delay;
+        assert_eq!(rpc.next_deadline(), Some(clock.now() + delay));
+        clock.advance(delay);
+        rpc.expire(&mut node, clock.now()).unwrap();
+        assert_eq!(transport.sent().len(), 1);
+        assert!(node.got.is_empty());
+    }
+
+    #[test]
+    fn aborts_end_the_retries_at_once() {
+        let (mut rpc, transport, _) = rpc();
+        let mut node = Probe::default();
+        rpc.request_retrying_then(
+            "n2",
+            serde_json::json!({"type": "cas"}),
+            RetryPolicy::default(),
+            |node: &mut Probe, result, _| {
+                let error = result.unwrap_err();
+                assert!(
+                    matches!(error, RpcError::Remote { code: 22, .. }),
+                    "{error}"
+                );
+                assert!(error.is_abort());
+                node.got.push(error.to_string());
+                Ok(())
+            },
+        )
+        .unwrap();
+        let [request] = transport.take().try_into().unwrap();
+
+        let failed = serde_json::json!({"type": "error", "code": 22, "text": "found 3"});
+        dispatch(&mut node, &reply_to(&request, failed), &mut rpc).unwrap();
+        assert!(transport.sent().is_empty());
+        assert_eq!(rpc.next_deadline(), None);
+        assert_eq!(node.got, ["Error 22: found 3"]);
+    }
+
+    #[test]
+    fn timers_run_once_due_in_order() {
+        let (mut rpc, _, clock) = rpc();
+        let mut node = Probe::default();
+        for (name, millis) in [("late", 20), ("early", 10)] {
+            rpc.after(Duration::from_millis(millis), move |node: &mut Probe, _| {
+                node.got.push(name.to_owned());
+                Ok(())
+            });
+        }
+
+        clock.advance(Duration::from_millis(10));
+        rpc.expire(&mut node, clock.now()).unwrap();
+        assert_eq!(node.got, ["early"]);
+
+        clock.advance(Duration::from_millis(10));
+        rpc.expire(&mut node, clock.now()).unwrap();
+        assert_eq!(node.got, ["early", "late"]);
+        assert_eq!(rpc.next_deadline(), None);
+    }
+
+    #[test]
+    fn gathers_count_peers_that_do_not_answer_as_default() {
+        let (mut rpc, transport, clock) = rpc();
+        let mut node = Probe::default();
+        let peers = ["n2".to_owned(), "n3".to_owned(), "n4".to_owned()];
+        rpc.gather_then(
+            &peers,
+            serde_json::json!({"type": "read"}),
+            |reply: Value| reply["value"].as_u64(),
+            |node: &mut Probe, values, _| {
+                node.got.push(format!("{values:?}"));
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let sent = transport.take();
+        let read_ok = serde_json::json!({"type": "read_ok", "value": 4});
+        dispatch(&mut node, &reply_to(&sent[2], read_ok), &mut rpc).unwrap();
+        let error = serde_json::json!({"type": "error", "code": 11, "text": "busy"});
+        dispatch(&mut node, &reply_to(&sent[0], error), &mut rpc).unwrap();
+        assert!(node.got.is_empty());
+
+        clock.advance(RPC_TIMEOUT);
+        rpc.expire(&mut node, clock.now()).unwrap();
+        assert_eq!(node.got, ["[0, 0, 4]"]);
+    }
+
+    /// Answers the requests sent through it with `answers`, in order, straight to their
+    /// waiters, and drops any past those. A `null` answer is a reply lost on the way.
+    struct Answering {
+        waiters: Waiters,
+        answers: VecDeque<Value>,
+    }
+
+    impl Transport for Answering {
+        fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()> {
+            let Some(payload) = self
+                .answers
+                .pop_front()
+                .filter(|payload| !payload.is_null())
+            else {
+                return Ok(());
+            };
+            let waiter = msg
+                .body
+                .id
+                .and_then(|id| self.waiters.lock().unwrap().remove(&id));
+            if let Some(waiter) = waiter {
+                waiter.send(reply_to(msg, payload))?;
+            }
+            Ok(())
+        }
+    }
+
+    fn answering(answers: Vec<Value>) -> (Rpc<Probe>, Arc<MockClock>) {
+        let waiters = Waiters::default();
+        let transport = Answering {
+            waiters: Arc::clone(&waiters),
+            answers: answers.into(),
+        };
+        let clock = Arc::new(MockClock::new());
+        let mut rpc = Rpc::new(transport, waiters, clock.clone());
+        rpc.node_id = Some("n1".to_owned());
+        (rpc, clock)
+    }
+
+    #[test]
+    fn retries_back_off_on_the_clock() {
+        let (mut rpc, clock) = answering(Vec::new());
+        let start = clock.now();
+        let policy = RetryPolicy::default();
+
+        let result = rpc.request_retrying("n2", serde_json::json!({"type": "read"}), policy);
+        // 200ms, then 400 and 800, without a moment of real time spent waiting. The third
+        // timeout in a row marks the peer down, which ends the retries early.
+        assert!(matches!(result, Err(RpcError::Unreachable)));
+        assert_eq!(clock.now() - start, Duration::from_millis(1400));
+        assert_eq!(rpc.metrics().timeouts, 3);
+    }
+
+    #[test]
+    fn retriable_errors_wait_out_the_timeout_on_the_clock() {
+        let busy = serde_json::json!({"type": "error", "code": 11, "text": "busy"});
+        let read_ok = serde_json::json!({"type": "read_ok", "value": 1});
+        let (mut rpc, clock) = answering(vec![busy.clone(), busy, read_ok]);
+        let start = clock.now();
+
+        let reply = rpc
+            .request_retrying(
+                "n2",
+                serde_json::json!({"type": "read"}),
+                RetryPolicy::default(),
+            )
+            .unwrap();
+        assert_eq!(reply.body.payload["value"], 1);
+        assert_eq!(clock.now() - start, Duration::from_millis(600));
+    }
+
+    #[test]
+    fn lost_replies_are_retried_until_one_gets_through() {
+        let read_ok = serde_json::json!({"type": "read_ok", "value": 1});
+        let (mut rpc, clock) = answering(vec![Value::Null, Value::Null, read_ok]);
+        let start = clock.now();
+
+        let reply = rpc
+            .request_retrying(
+                "n2",
+                serde_json::json!({"type": "read"}),
+                RetryPolicy::default(),
+            )
+            .unwrap();
+        assert_eq!(reply.body.payload["value"], 1);
+        // The third attempt, under a `msg_id` of its own, after timeouts of 200ms and 400ms.
+        assert_eq!(reply.body.in_reply_to, Some(2));
+        assert_eq!(clock.now() - start, Duration::from_millis(600));
+        assert_eq!(rpc.metrics().timeouts, 2);
+    }
+
+    #[test]
+    fn gather_gives_up_on_the_clock() {
+        let read_ok = serde_json::json!({"type": "read_ok", "value": 1});
+        let (mut rpc, clock) = answering(vec![read_ok]);
+        let start = clock.now();
+
+        let peers = ["n2".to_owned(), "n3".to_owned()];
+        let values = rpc
+            .gather(
+                &peers,
+                serde_json::json!({"type": "read"}),
+                |reply: Value| reply["value"].as_u64(),
+            )
+            .unwrap();
+        assert_eq!(values, [1, 0]);
+        assert_eq!(clock.now() - start, RPC_TIMEOUT);
+    }
+
+    /// Notes each message as it's written, and each flush.
+    #[derive(Clone, Default)]
+    struct Recording {
+        writes: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Transport for Recording {
+        fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()> {
+            self.writes.lock().unwrap().push(msg.destination.clone());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            self.writes.lock().unwrap().push("flush".to_owned());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replies_are_flushed_before_gossip_is_written() {
+        let transport = Recording::default();
+        let mut rpc: Rpc<Probe> = Rpc::new(
+            transport.clone(),
+            Waiters::default(),
+            Arc::new(MockClock::new()),
+        );
+        rpc.node_id = Some("n1".to_owned());
+
+        rpc.batched(|rpc| {
+            for peer in ["n2", "n3"] {
+                rpc.notify(peer, serde_json::json!({"type": "gossip"}))?;
+            }
+            let request = Message {
+                source: "c1".to_owned(),
+                destination: "n1".to_owned(),
+                body: Body {
+                    id: Some(1),
+                    in_reply_to: None,
+                    payload: serde_json::json!({"type": "broadcast"}),
+                },
+            };
+            rpc.reply(&request, serde_json::json!({"type": "broadcast_ok"}))
+        })
+        .unwrap();
+
+        let writes = transport.writes.lock().unwrap().clone();
+        assert_eq!(writes, ["c1", "flush", "n2", "n3", "flush"]);
+    }
+
+    #[test]
+    fn a_reply_goes_out_ahead_of_a_thousand_gossip_messages_queued_before_it() {
+        let transport = Recording::default();
+        let mut rpc: Rpc<Probe> = Rpc::new(
+            transport.clone(),
+            Waiters::default(),
+            Arc::new(MockClock::new()),
+        );
+        rpc.node_id = Some("n1".to_owned());
+
+        rpc.batched(|rpc| {
+            for round in 0..1000 {
+                let peer = format!("n{}", 2 + round % 4);
+                rpc.notify(&peer, serde_json::json!({"type": "gossip"}))?;
+            }
+            let request = Message {
+                source: "c1".to_owned(),
+                destination: "n1".to_owned(),
+                body: Body {
+                    id: Some(1),
+                    in_reply_to: None,
+                    payload: serde_json::json!({"type": "broadcast"}),
+                },
+            };
+            rpc.reply(&request, serde_json::json!({"type": "broadcast_ok"}))
+        })
+        .unwrap();
+
+        let writes = transport.writes.lock().unwrap().clone();
+        assert_eq!(writes[..2], ["c1", "flush"]);
+        // None of the gossip is held back for good either.
+        let gossip = writes[2..].iter().filter(|write| write.starts_with('n'));
+        assert_eq!(gossip.count(), 1000);
+        assert_eq!(writes.last().unwrap(), "flush");
+    }
+}
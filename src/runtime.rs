@@ -0,0 +1,1160 @@
+//! Drives a [`Node`] over stdin and stdout.
+//!
+//! Stdin is read on its own thread, and whatever comes in is multiplexed with ticks and request
+//! timeouts into a single stream of [`Event`]s. [`replay`] reads a captured session from a file
+//! instead.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::clock::{Clock, SystemClock};
+use crate::deadletter::{self, Reason};
+use crate::log;
+use crate::message::{error_reply, Body, ErrorCode, ErrorPayload, InitPayload, Message};
+use crate::metrics::{self, Metrics, ProcStats};
+use crate::node::{reject, Event, Node, NodeContext, NodeError, Persistent};
+use crate::rpc::{Handler, Rpc, Waiters};
+use crate::transport::{LineTransport, Transport};
+
+/// Forwards `input` to the main loop, except for replies to requests blocked in
+/// [`Rpc::request`], which go straight to the waiting request.
+///
+/// Reads are buffered until a whole line is in, so a message may arrive in any number of
+/// pieces, and the last one needs no newline.
+fn read_input(
+    input: impl Read,
+    tx: &mpsc::SyncSender<Message<Value>>,
+    waiters: &Waiters,
+) -> anyhow::Result<()> {
+    for line in BufReader::new(input).lines() {
+        let line = line.map_err(NodeError::Read)?;
+        let Some(input) = parse_line(&line)? else {
+            continue;
+        };
+        log::log_recv(&input);
+
+        let waiter = input
+            .body
+            .in_reply_to
+            .and_then(|id| waiters.lock().expect("Waiters lock poisoned.").remove(&id));
+
+        match waiter {
+            // The request may have stopped waiting already, there's no one else to tell.
+            Some(waiter) => {
+                let _ = waiter.send(input);
+            }
+            None => {
+                if tx.send(input).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Set `TEMPEST_STRICT=1` to fail on the first line of input that isn't a message, the first
+/// message the node can't decode, or the first reply to a message without a `msg_id`, e.g. in
+/// CI. By default these are logged and skipped or refused, since Maelstrom can interleave
+/// diagnostics of its own.
+pub(crate) fn strict() -> bool {
+    static STRICT: OnceLock<bool> = OnceLock::new();
+
+    *STRICT.get_or_init(|| {
+        matches!(
+            std::env::var("TEMPEST_STRICT").as_deref(),
+            Ok("1" | "on" | "true")
+        )
+    })
+}
+
+/// How many messages the reader may get ahead of the node by, unless `TEMPEST_CHANNEL_CAP`
+/// says otherwise.
+const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+static CHANNEL_CAPACITY: OnceLock<usize> = OnceLock::new();
+
+/// Has the reader queue up at most `capacity` messages, whatever `TEMPEST_CHANNEL_CAP` says.
+/// Only the first capacity set counts.
+pub fn set_channel_capacity(capacity: usize) {
+    let _ = CHANNEL_CAPACITY.set(capacity.max(1));
+}
+
+/// Reads `TEMPEST_CHANNEL_CAP`, the most messages the reader queues up for the node, unless
+/// one was set with [`set_channel_capacity`].
+///
+/// Once that many are waiting, the reader stops reading until the node catches up, and
+/// Maelstrom's own flow control takes it from there instead of the queue growing without
+/// bound. Replies reach blocked requests without queueing, but not past a full queue: a request
+/// whose reply is stuck behind it waits until it times out.
+pub(crate) fn channel_capacity() -> anyhow::Result<usize> {
+    if let Some(capacity) = CHANNEL_CAPACITY.get() {
+        return Ok(*capacity);
+    }
+    let Ok(capacity) = std::env::var("TEMPEST_CHANNEL_CAP") else {
+        return Ok(DEFAULT_CHANNEL_CAPACITY);
+    };
+
+    match capacity.parse() {
+        Ok(capacity) if capacity > 0 => Ok(capacity),
+        _ => bail!("TEMPEST_CHANNEL_CAP must be a positive integer, not {capacity:?}."),
+    }
+}
+
+/// Decodes a line of input, or returns `None` for a blank line or, unless in strict mode, one
+/// that isn't a message.
+pub(crate) fn parse_line(line: &str) -> Result<Option<Message<Value>>, NodeError> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+
+    match serde_json::from_str(line) {
+        Ok(input) => Ok(Some(input)),
+        Err(error) if strict() => Err(NodeError::Decode(error)),
+        Err(error) => {
+            log::warning!("Skipping malformed input ({error}): {line}");
+            deadletter::record(Reason::Malformed, Some(&error.to_string()), &line);
+            Ok(None)
+        }
+    }
+}
+
+static STATE_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Has the runtime load the state of [`Node::persistent`] nodes from `path` when they start, if
+/// it exists, and save it there when they shut down. Only the first path set counts.
+pub fn set_state_file(path: PathBuf) {
+    let _ = STATE_FILE.set(path);
+}
+
+/// The path set with [`set_state_file`], if any.
+pub(crate) fn state_file() -> Option<PathBuf> {
+    STATE_FILE.get().cloned()
+}
+
+/// The file next to the state file at `path` that [`Rpc::journal`] appends to.
+fn journal_file(path: &Path) -> PathBuf {
+    let mut journal = path.to_owned().into_os_string();
+    journal.push(".journal");
+    journal.into()
+}
+
+/// Restores `node` from the state file at `path`, if there is one, then from each entry
+/// journaled since, oldest first, and saves the lot as the state to start the journal over.
+pub(crate) fn restore(
+    node: Option<&mut dyn Persistent>,
+    path: Option<&Path>,
+) -> anyhow::Result<()> {
+    let (Some(path), Some(node)) = (path, node) else {
+        return Ok(());
+    };
+
+    if let Some(state) = read_if_any(path)? {
+        let state = serde_json::from_slice(&state)
+            .with_context(|| format!("Could not decode the state in {}.", path.display()))?;
+        node.restore(state)
+            .with_context(|| format!("Could not restore the state in {}.", path.display()))?;
+    }
+
+    let journal = journal_file(path);
+    let Some(entries) = read_if_any(&journal)? else {
+        return Ok(());
+    };
+    // A node killed halfway through an append leaves the last line cut short.
+    for line in entries.split(|byte| *byte == b'\n') {
+        let Ok(entry) = serde_json::from_slice(line) else {
+            continue;
+        };
+        node.restore(entry)
+            .with_context(|| format!("Could not restore the journal in {}.", journal.display()))?;
+    }
+    // Else the next entry would be appended to a line cut short.
+    save(node, path)
+}
+
+fn read_if_any(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error).with_context(|| format!("Could not read {}.", path.display())),
+    }
+}
+
+/// Saves `node` to the state file at `path`, if there is one.
+pub(crate) fn snapshot(
+    node: Option<&mut dyn Persistent>,
+    path: Option<&Path>,
+) -> anyhow::Result<()> {
+    match (path, node) {
+        (Some(path), Some(node)) => save(node, path),
+        _ => Ok(()),
+    }
+}
+
+/// Saves `node` to the state file at `path`, which makes the journal up to now redundant.
+///
+/// Writes to a file next to the state file first, so that a node killed halfway through leaves
+/// the last state in place. Killed before the journal is gone, it restores the journal on top
+/// of a state that holds it already.
+fn save(node: &dyn Persistent, path: &Path) -> anyhow::Result<()> {
+    let mut partial = path.to_owned().into_os_string();
+    partial.push(".tmp");
+    let state = serde_json::to_vec(&node.snapshot()).map_err(NodeError::Encode)?;
+
+    fs::write(&partial, state)
+        .and_then(|()| fs::rename(&partial, path))
+        .with_context(|| format!("Could not save the state to {}.", path.display()))?;
+    match fs::remove_file(journal_file(path)) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error)
+            .with_context(|| format!("Could not clear the journal of {}.", path.display())),
+        _ => Ok(()),
+    }
+}
+
+impl<N: Node> Rpc<N> {
+    /// Appends `entry` to the journal next to the state file right away, if one is set, rather
+    /// than waiting for input to end. For nodes that mustn't acknowledge anything a crash
+    /// could make them forget.
+    ///
+    /// Only `entry` is written, however much state the node holds. On restart, each entry is
+    /// handed to [`Persistent::restore`] after the saved state, so a node that journals has to
+    /// restore by adding to what it holds.
+    pub fn journal(&mut self, entry: impl Serialize) -> anyhow::Result<()> {
+        match &self.state_file {
+            Some(path) => journal(path, entry),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Appends `entry` to the journal of the state file at `path`, see [`Rpc::journal`].
+pub(crate) fn journal(path: &Path, entry: impl Serialize) -> anyhow::Result<()> {
+    let journal = journal_file(path);
+    let mut line = serde_json::to_vec(&entry).map_err(NodeError::Encode)?;
+    line.push(b'\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&journal)
+        .and_then(|mut file| file.write_all(&line))
+        .with_context(|| format!("Could not journal to {}.", journal.display()))
+}
+
+/// Builds a node with `init` once Maelstrom's `init` comes in, then runs it until stdin
+/// closes.
+///
+/// The runtime answers `init` itself, so nodes never see it, and answers it again should it be
+/// delivered twice. It drops messages addressed to any node other than this one.
+pub fn run<N: Node>(init: impl FnOnce(&NodeContext) -> anyhow::Result<N>) -> anyhow::Result<()> {
+    run_with_clock(Arc::new(SystemClock), init)
+}
+
+/// Like [`run`], but schedules ticks and times out requests by `clock`.
+pub fn run_with_clock<N: Node>(
+    clock: Arc<dyn Clock>,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<()> {
+    run_with_transport(
+        std::io::stdin(),
+        LineTransport::new(BufWriter::new(std::io::stdout().lock())),
+        clock,
+        init,
+    )
+}
+
+/// Like [`run`], but reads newline-delimited messages from the file at `path` rather than
+/// stdin, e.g. the input of a failed Maelstrom run. Replies still go to stdout.
+///
+/// The file is read as fast as the node handles it, while ticks and timeouts keep to the wall
+/// clock. A tick that comes due while the node is busy fires once, not once for every interval
+/// it missed, so replaying a long session doesn't set off a burst of gossip.
+pub fn replay<N: Node>(
+    path: &Path,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<()> {
+    let file = File::open(path).with_context(|| format!("Could not open {}.", path.display()))?;
+    run_with_transport(
+        file,
+        LineTransport::new(BufWriter::new(std::io::stdout().lock())),
+        Arc::new(SystemClock),
+        init,
+    )
+}
+
+/// Like [`run_with_clock`], but reads newline-delimited messages from `input` and sends
+/// through `transport`, e.g. to drive a node from a test.
+pub fn run_with_transport<N: Node>(
+    input: impl Read + Send + 'static,
+    transport: impl Transport + 'static,
+    clock: Arc<dyn Clock>,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<()> {
+    metrics::start();
+    let waiters = Waiters::default();
+    let mut rpc = Rpc::new(transport, Arc::clone(&waiters), clock);
+
+    // Input is read on its own thread so that ticks fire even while none arrives, and so that
+    // replies reach requests blocked in `Rpc::request`.
+    let (tx, rx) = mpsc::sync_channel(channel_capacity()?);
+    let reader = thread::spawn(move || {
+        let result = read_input(input, &tx, &waiters);
+
+        // Dropping the senders wakes up any request still waiting for a reply.
+        waiters.lock().expect("Waiters lock poisoned.").clear();
+
+        result
+    });
+
+    if let Some(node) = start(&rx, &mut rpc, init)? {
+        serve(node, &rx, &mut rpc)?;
+    }
+
+    reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("Input reader panicked."))?
+}
+
+/// Waits for `init` and builds the node from it, or returns `None` if stdin closes first.
+fn start<N: Node>(
+    rx: &mpsc::Receiver<Message<Value>>,
+    rpc: &mut Rpc<N>,
+    init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+) -> anyhow::Result<Option<N>> {
+    for input in rx {
+        rpc.metrics.record_received(input.kind());
+
+        match handshake(input)? {
+            Handshake::Init { context, mut reply } => {
+                rpc.node_id = Some(context.node_id.clone());
+                let mut node = init(&context)?;
+                restore(node.persistent(), rpc.state_file.as_deref())?;
+
+                reply.body.id = Some(rpc.next_id());
+                rpc.batched(|rpc| {
+                    rpc.send(&reply)?;
+                    node.init(&context, rpc)
+                })?;
+
+                return Ok(Some(node));
+            }
+            Handshake::Refuse(reply) => rpc.send(&reply)?,
+            Handshake::Ignore => {}
+        }
+    }
+
+    log::warning!("Input ended before init.");
+    Ok(None)
+}
+
+fn serve<N: Node>(
+    mut node: N,
+    rx: &mpsc::Receiver<Message<Value>>,
+    rpc: &mut Rpc<N>,
+) -> anyhow::Result<()> {
+    let mut next_tick = node.tick_interval().map(|interval| rpc.now() + interval);
+
+    loop {
+        if let Some(at) = next_tick.filter(|at| *at <= rpc.now()) {
+            rpc.batched(|rpc| node.on_event(Event::Tick, rpc))?;
+            next_tick = node
+                .tick_interval()
+                .map(|interval| at.max(rpc.now()) + interval);
+        }
+
+        let now = rpc.now();
+        rpc.batched(|rpc| rpc.expire(&mut node, now))?;
+
+        let wake_at = next_tick.into_iter().chain(rpc.next_deadline()).min();
+        let input = match wake_at {
+            Some(at) => match rx.recv_timeout(at.saturating_duration_since(rpc.now())) {
+                Ok(input) => input,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(input) => input,
+                Err(_) => break,
+            },
+        };
+
+        rpc.batched(|rpc| dispatch(&mut node, &input, rpc))?;
+    }
+
+    node.on_shutdown(rpc)?;
+    snapshot(node.persistent(), rpc.state_file.as_deref())?;
+    log::info!("{}", rpc.metrics);
+    log::info!("{}", ProcStats::collect());
+    if let Some(path) = metrics::summary_path() {
+        write_summary(path, &rpc.metrics, node.summary())?;
+    }
+
+    rpc.flush()
+}
+
+/// Hands a message that came in after `init` to the node, or to the callback waiting for it.
+pub(crate) fn dispatch<N: Node>(
+    node: &mut N,
+    input: &Message<Value>,
+    rpc: &mut Rpc<N>,
+) -> anyhow::Result<()> {
+    rpc.metrics.record_received(input.kind());
+
+    if !is_for(input, rpc.node_id()?)? {
+        rpc.metrics.misaddressed += 1;
+        return Ok(());
+    }
+
+    if is_init(input) {
+        return match reinit(input, rpc.node_id()?) {
+            Ok(()) => rpc.reply(input, InitPayload::InitOk {}),
+            Err(text) => reject(input, rpc, &text),
+        };
+    }
+
+    rpc.heard_from(&input.source);
+    if rpc.is_late(input) {
+        log::warning!("Dropping reply to a request that timed out: {input:?}");
+        deadletter::record(Reason::LateReply, None, input);
+        return Ok(());
+    }
+
+    if rpc.relay(input)? {
+        return Ok(());
+    }
+
+    match input.body.in_reply_to.map(|id| (id, rpc.take(id))) {
+        Some((_, Some(Handler::Continuation(then)))) => {
+            rpc.send_queued()?;
+            then(node, Ok(input.clone()), rpc)
+        }
+        Some((id, Some(Handler::Callback(callback)))) => {
+            rpc.send_queued()?;
+            match decode::<N::Payload>(input)? {
+                Ok(reply) => callback(node, reply, rpc),
+                // As good as no reply at all.
+                Err(text) => {
+                    log::warning!("Could not decode the reply to {id}: {text}");
+                    node.on_event(Event::Timeout(id), rpc)
+                }
+            }
+        }
+        // Most likely a reply delivered twice, or a bug in whoever sent it. Either way there's
+        // nothing waiting for it.
+        Some((id, None)) => {
+            log::warning!("Dropping a reply to {id}, which matches no request: {input:?}");
+            deadletter::record(Reason::UnmatchedReply, None, input);
+            Ok(())
+        }
+        None => match decode::<N::Payload>(input)? {
+            Ok(input) => node.on_event(Event::Message(input), rpc),
+            Err(text) => reject(input, rpc, &text),
+        },
+    }
+}
+
+pub(crate) fn write_summary(
+    path: &Path,
+    metrics: &Metrics,
+    node: Option<Value>,
+) -> anyhow::Result<()> {
+    let mut summary = metrics.to_json();
+    summary["process"] = ProcStats::collect().to_json();
+    if let Some(node) = node {
+        summary["node"] = node;
+    }
+
+    let file =
+        File::create(path).with_context(|| format!("Could not create {}.", path.display()))?;
+    serde_json::to_writer_pretty(file, &summary)
+        .with_context(|| format!("Could not write the summary to {}.", path.display()))
+}
+
+/// What to do with a message that arrived before the node was built.
+pub(crate) enum Handshake {
+    /// Build the node from `context`, then send `reply` under a fresh `msg_id`.
+    Init {
+        context: NodeContext,
+        reply: Message<InitPayload>,
+    },
+    /// Send this error and keep waiting for `init`.
+    Refuse(Message<ErrorPayload>),
+    /// Keep waiting for `init`.
+    Ignore,
+}
+
+/// Requests that come in before `init` are refused as `temporarily-unavailable`, so that the
+/// client retries them later, and anything else is dropped. Either is logged, so that a node
+/// stuck waiting for `init` says what it got instead.
+pub(crate) fn handshake(input: Message<Value>) -> Result<Handshake, NodeError> {
+    if !is_init(&input) {
+        let (kind, source) = (input.kind(), &input.source);
+        if kind == "init_ok" {
+            log::warning!("Ignoring init_ok from {source}, only nodes send those.");
+            deadletter::record(Reason::BeforeInit, None, &input);
+            return Ok(Handshake::Ignore);
+        }
+
+        if input.body.id.is_none() {
+            log::warning!("Dropping {kind} from {source} before init: {input:?}");
+            deadletter::record(Reason::BeforeInit, None, &input);
+            return Ok(Handshake::Ignore);
+        }
+
+        log::warning!("Refusing {kind} from {source} before init.");
+        deadletter::record(Reason::BeforeInit, None, &input);
+        return Ok(Handshake::Refuse(error_reply(
+            &input,
+            ErrorCode::TemporarilyUnavailable.into(),
+            "Node is not initialized yet.",
+        )));
+    }
+
+    let input = input.decode::<InitPayload>().map_err(NodeError::Decode)?;
+    let InitPayload::Init { node_id, node_ids } = input.body.payload else {
+        return Err(NodeError::UnexpectedInit);
+    };
+    // Workloads count on finding themselves among the nodes, e.g. to pick an owner.
+    if !node_ids.contains(&node_id) {
+        return Err(NodeError::NotInCluster { node_id, node_ids });
+    }
+
+    log::info!("Starting as {node_id}, one of {} nodes.", node_ids.len());
+    Ok(Handshake::Init {
+        context: NodeContext { node_id, node_ids },
+        reply: Message {
+            source: input.destination,
+            destination: input.source,
+            body: Body {
+                id: None,
+                in_reply_to: input.body.id,
+                payload: InitPayload::InitOk {},
+            },
+        },
+    })
+}
+
+/// Decodes `input` as one of the node's payloads.
+///
+/// A message the node can't decode, most likely of a type it doesn't implement, isn't fatal: it
+/// comes back as the text to refuse it with, unless in strict mode.
+pub(crate) fn decode<P: DeserializeOwned>(
+    input: &Message<Value>,
+) -> Result<Result<Message<P>, String>, NodeError> {
+    match input.decode() {
+        Ok(input) => Ok(Ok(input)),
+        Err(error) if strict() => Err(NodeError::Decode(error)),
+        Err(error) => Ok(Err(format!("Could not handle {}: {error}", input.kind()))),
+    }
+}
+
+/// Whether `input` is addressed to `node_id`. A message for another node is logged and dropped,
+/// or an error in strict mode.
+pub(crate) fn is_for(input: &Message<Value>, node_id: &str) -> Result<bool, NodeError> {
+    if input.destination == node_id {
+        return Ok(true);
+    }
+
+    if strict() {
+        return Err(NodeError::WrongDestination {
+            expected: node_id.to_owned(),
+            actual: input.destination.clone(),
+        });
+    }
+
+    log::warning!("Dropping message for another node: {input:?}");
+    deadletter::record(Reason::Misaddressed, None, input);
+    Ok(false)
+}
+
+/// Whether to answer an `init` that came in after the node was built with another
+/// `init_ok`, or else why it's refused.
+///
+/// Maelstrom may deliver the first `init` again, which is answered again. An `init` for some
+/// other node can't be honoured without starting over, so it's refused.
+pub(crate) fn reinit(input: &Message<Value>, node_id: &str) -> Result<(), String> {
+    match input.decode::<InitPayload>().map(|init| init.body.payload) {
+        Ok(InitPayload::Init { node_id: again, .. }) if again == node_id => {
+            log::info!("Answering init from {} again.", input.source);
+            Ok(())
+        }
+        Ok(InitPayload::Init { node_id: other, .. }) => Err(format!(
+            "Node is already initialized as {node_id}, not {other}."
+        )),
+        Ok(InitPayload::InitOk {}) => Err("Unexpected init_ok.".to_owned()),
+        Err(error) => Err(format!("Could not decode init: {error}")),
+    }
+}
+
+pub(crate) fn is_init(input: &Message<Value>) -> bool {
+    input.body.payload.get("type") == Some(&Value::from("init"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{Cursor, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::Body;
+    use crate::transport::InMemoryTransport;
+    use crate::workloads::EchoNode;
+
+    /// Output the test keeps hold of while the runtime writes to it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn the_last_reply_goes_out_when_input_ends_without_a_newline() {
+        let init = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] },
+        });
+        let echo = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "echo", "msg_id": 2, "echo": "bye" },
+        });
+        let input = Cursor::new(format!("{init}\n{echo}"));
+        let output = Shared::default();
+        // Buffered as stdout is, so only a flush at the end gets the reply out.
+        let transport = LineTransport::new(BufWriter::new(output.clone()));
+
+        run_with_transport(input, transport, Arc::new(SystemClock), |_| Ok(EchoNode)).unwrap();
+
+        let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let replies: Vec<Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 2, "{written}");
+        assert_eq!(replies[1]["body"]["type"], "echo_ok");
+        assert_eq!(replies[1]["body"]["in_reply_to"], 2);
+    }
+
+    /// The node count the node is built with, after an `init` naming `node_ids`.
+    fn node_count(node_ids: Value) -> anyhow::Result<usize> {
+        let init = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": node_ids },
+        });
+        let count = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&count);
+        run_with_transport(
+            Cursor::new(format!("{init}\n")),
+            InMemoryTransport::new(),
+            Arc::new(SystemClock),
+            move |context| {
+                seen.store(context.node_count(), Ordering::SeqCst);
+                Ok(EchoNode)
+            },
+        )?;
+        Ok(count.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn nodes_are_built_only_when_init_counts_them_in() {
+        assert_eq!(node_count(json!(["n1"])).unwrap(), 1);
+        assert_eq!(node_count(json!(["n0", "n1", "n2"])).unwrap(), 3);
+
+        for node_ids in [json!([]), json!(["n0", "n2"])] {
+            let error = node_count(node_ids.clone()).unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<NodeError>(),
+                    Some(NodeError::NotInCluster { .. })
+                ),
+                "{node_ids}: {error:?}"
+            );
+        }
+    }
+
+    /// Answers every message with an error that has no code.
+    struct Sloppy;
+
+    impl Node for Sloppy {
+        type Payload = Value;
+
+        fn step(&mut self, input: Message<Value>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            rpc.reply(&input, json!({"type": "error", "text": "Something broke."}))
+        }
+    }
+
+    #[test]
+    fn a_malformed_reply_fails_the_run() {
+        let init = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] },
+        });
+        let echo = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "echo", "msg_id": 2, "echo": "hi" },
+        });
+        let input = Cursor::new(format!("{init}\n{echo}\n"));
+        let transport = InMemoryTransport::new();
+
+        let error = run_with_transport(input, transport.clone(), Arc::new(SystemClock), |_| {
+            Ok(Sloppy)
+        })
+        .unwrap_err();
+        assert!(
+            matches!(error.downcast_ref(), Some(NodeError::Invalid(_))),
+            "{error:?}"
+        );
+        // Only init_ok went out.
+        assert_eq!(transport.sent().len(), 1);
+    }
+
+    #[test]
+    fn init_is_answered_again_unless_it_names_another_node() {
+        let init = |msg_id, node_id| {
+            json!({
+                "src": "c1",
+                "dest": "n1",
+                "body": { "type": "init", "msg_id": msg_id, "node_id": node_id, "node_ids": ["n1", "n2"] },
+            })
+        };
+        let echo = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "echo", "msg_id": 4, "echo": "still n1" },
+        });
+        let input = Cursor::new(format!(
+            "{}\n{}\n{}\n{echo}\n",
+            init(1, "n1"),
+            init(2, "n1"),
+            init(3, "n2"),
+        ));
+        let transport = InMemoryTransport::new();
+
+        run_with_transport(input, transport.clone(), Arc::new(SystemClock), |_| {
+            Ok(EchoNode)
+        })
+        .unwrap();
+
+        let replies: Vec<(Option<usize>, Value)> = transport
+            .sent()
+            .into_iter()
+            .map(|msg| (msg.body.in_reply_to, msg.body.payload["type"].clone()))
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                (Some(1), json!("init_ok")),
+                (Some(2), json!("init_ok")),
+                (Some(3), json!("error")),
+                (Some(4), json!("echo_ok")),
+            ]
+        );
+    }
+
+    #[test]
+    fn an_echo_before_init_is_refused_until_init_comes() {
+        let echo = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "echo", "msg_id": 1, "echo": "early" },
+        });
+        let init_ok = json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": { "type": "init_ok", "in_reply_to": 7 },
+        });
+        let init = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 2, "node_id": "n1", "node_ids": ["n1"] },
+        });
+        let again = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "echo", "msg_id": 3, "echo": "on time" },
+        });
+        let input = Cursor::new(format!("{echo}\n{init_ok}\n{init}\n{again}\n"));
+        let transport = InMemoryTransport::new();
+
+        run_with_transport(input, transport.clone(), Arc::new(SystemClock), |_| {
+            Ok(EchoNode)
+        })
+        .unwrap();
+
+        let [refused, init_ok, echo_ok] = transport.sent().try_into().unwrap();
+        assert_eq!(refused.body.in_reply_to, Some(1));
+        assert_eq!(refused.body.payload["type"], "error");
+        assert_eq!(refused.body.payload["code"], 11);
+        assert_eq!(init_ok.body.in_reply_to, Some(2));
+        assert_eq!(init_ok.body.payload["type"], "init_ok");
+        assert_eq!(echo_ok.body.in_reply_to, Some(3));
+        assert_eq!(
+            echo_ok.body.payload,
+            json!({"type": "echo_ok", "echo": "on time"})
+        );
+    }
+
+    #[test]
+    fn fields_a_payload_does_not_name_are_ignored() {
+        let init = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] },
+        });
+        let echo =
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi","foo":1}}"#;
+        let input = Cursor::new(format!("{init}\n{echo}\n"));
+        let transport = InMemoryTransport::new();
+
+        run_with_transport(input, transport.clone(), Arc::new(SystemClock), |_| {
+            Ok(EchoNode)
+        })
+        .unwrap();
+
+        let [_, echo_ok] = transport.sent().try_into().unwrap();
+        assert_eq!(echo_ok.body.in_reply_to, Some(2));
+        assert_eq!(
+            echo_ok.body.payload,
+            json!({"type": "echo_ok", "echo": "hi"})
+        );
+    }
+
+    /// Keeps every entry it's restored from.
+    #[derive(Default)]
+    struct Tally(Vec<Value>);
+
+    impl Node for Tally {
+        type Payload = Value;
+
+        fn step(&mut self, _input: Message<Value>, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+            Some(self)
+        }
+    }
+
+    impl Persistent for Tally {
+        fn snapshot(&self) -> Value {
+            Value::from(self.0.clone())
+        }
+
+        fn restore(&mut self, state: Value) -> anyhow::Result<()> {
+            match state {
+                Value::Array(entries) => self.0.extend(entries),
+                entry => self.0.push(entry),
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn journaled_entries_are_restored_after_the_state_and_then_saved_with_it() {
+        let dir = std::env::temp_dir().join(format!("tempest-{}-journal", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("n1.json");
+        let mut rpc: Rpc<Tally> = Rpc::with_transport("n1", InMemoryTransport::new());
+        rpc.state_file = Some(path.clone());
+
+        let mut node = Tally(vec![json!(1)]);
+        snapshot(node.persistent(), Some(&path)).unwrap();
+        rpc.journal(json!(2)).unwrap();
+        rpc.journal(json!(3)).unwrap();
+        // Cut short by a crash halfway through an append.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(journal_file(&path))
+            .and_then(|mut journal| journal.write_all(b"{\"cut"))
+            .unwrap();
+
+        let mut restarted = Tally::default();
+        restore(restarted.persistent(), Some(&path)).unwrap();
+        assert_eq!(restarted.0, [json!(1), json!(2), json!(3)]);
+
+        // Saved as the state, so the journal starts over.
+        assert!(!journal_file(&path).exists());
+        rpc.journal(json!(4)).unwrap();
+        let mut again = Tally::default();
+        restore(again.persistent(), Some(&path)).unwrap();
+        assert_eq!(again.0, [json!(1), json!(2), json!(3), json!(4)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Counts the messages it's handed.
+    #[derive(Default)]
+    struct Counting {
+        stepped: usize,
+    }
+
+    impl Node for Counting {
+        type Payload = Value;
+
+        fn step(&mut self, _input: Message<Value>, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            self.stepped += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replies_that_match_no_request_never_reach_the_node() {
+        let transport = InMemoryTransport::new();
+        let mut rpc = Rpc::with_transport("n1", transport.clone());
+        let mut node = Counting::default();
+
+        let mut message = Message {
+            source: "n2".to_owned(),
+            destination: "n1".to_owned(),
+            body: Body {
+                id: Some(5),
+                in_reply_to: Some(99),
+                payload: json!({"type": "gossip_ok"}),
+            },
+        };
+        dispatch(&mut node, &message, &mut rpc).unwrap();
+        assert_eq!(node.stepped, 0);
+        assert!(transport.sent().is_empty());
+
+        message.body.in_reply_to = None;
+        dispatch(&mut node, &message, &mut rpc).unwrap();
+        assert_eq!(node.stepped, 1);
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let init = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] },
+        });
+        let echo = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "echo", "msg_id": 2, "echo": "still here" },
+        });
+        let input = Cursor::new(format!("{{garbage}}\n{init}\n{{garbage}}\n{echo}\n"));
+        let transport = InMemoryTransport::new();
+
+        run_with_transport(input, transport.clone(), Arc::new(SystemClock), |_| {
+            Ok(EchoNode)
+        })
+        .unwrap();
+
+        let [init_ok, echo_ok] = transport.sent().try_into().unwrap();
+        assert_eq!(init_ok.body.payload["type"], "init_ok");
+        assert_eq!(echo_ok.body.payload["type"], "echo_ok");
+        assert_eq!(echo_ok.body.payload["echo"], "still here");
+    }
+
+    /// Hands out one chunk per read, however much more would fit.
+    struct Chunked(VecDeque<Vec<u8>>);
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(chunk) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn a_message_split_across_reads_is_put_back_together() {
+        let init = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] },
+        });
+        let echo = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "echo", "msg_id": 2, "echo": "h\u{e9}llo" },
+        });
+        let echo = format!("{echo}\n").into_bytes();
+        // The second cut falls between the two bytes of the é.
+        let accent = echo.iter().position(|byte| *byte == 0xc3).unwrap();
+        let chunks = [
+            format!("{init}\n").into_bytes(),
+            echo[..10].to_vec(),
+            echo[10..=accent].to_vec(),
+            echo[accent + 1..].to_vec(),
+        ];
+        let transport = InMemoryTransport::new();
+
+        run_with_transport(
+            Chunked(chunks.into()),
+            transport.clone(),
+            Arc::new(SystemClock),
+            |_| Ok(EchoNode),
+        )
+        .unwrap();
+
+        let sent = transport.sent();
+        assert_eq!(sent.len(), 2, "{sent:?}");
+        assert_eq!(sent[1].body.in_reply_to, Some(2));
+        assert_eq!(sent[1].body.payload["echo"], "h\u{e9}llo");
+    }
+
+    /// Hands out `init`, then `messages` messages, one line per read, counting the lines.
+    struct Flood {
+        lines: VecDeque<String>,
+        read: Arc<AtomicUsize>,
+    }
+
+    impl Flood {
+        fn new(messages: usize, read: Arc<AtomicUsize>) -> Self {
+            let init = json!({
+                "src": "c1",
+                "dest": "n1",
+                "body": { "type": "init", "msg_id": 0, "node_id": "n1", "node_ids": ["n1"] },
+            });
+            let adds = (1..=messages).map(
+                |id| json!({ "src": "c1", "dest": "n1", "body": { "type": "add", "msg_id": id } }),
+            );
+            let lines = std::iter::once(init)
+                .chain(adds)
+                .map(|line| format!("{line}\n"))
+                .collect();
+            Self { lines, read }
+        }
+    }
+
+    impl Read for Flood {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(line) = self.lines.pop_front() else {
+                return Ok(0);
+            };
+            assert!(line.len() <= buf.len());
+            buf[..line.len()].copy_from_slice(line.as_bytes());
+            self.read.fetch_add(1, Ordering::SeqCst);
+            Ok(line.len())
+        }
+    }
+
+    /// Notes the most messages ever read but not yet handled.
+    struct Lagging {
+        read: Arc<AtomicUsize>,
+        handled: usize,
+        most_behind: Arc<AtomicUsize>,
+    }
+
+    impl Node for Lagging {
+        type Payload = Value;
+
+        fn step(&mut self, _input: Message<Value>, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            self.handled += 1;
+            // Counting init, which was handled before any of these.
+            let behind = self.read.load(Ordering::SeqCst) - 1 - self.handled;
+            self.most_behind.fetch_max(behind, Ordering::SeqCst);
+            thread::yield_now();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn the_reader_gets_no_further_ahead_than_the_channel_holds() {
+        let messages = 8 * channel_capacity().unwrap();
+        let read = Arc::new(AtomicUsize::new(0));
+        let most_behind = Arc::new(AtomicUsize::new(0));
+        let node = Lagging {
+            read: Arc::clone(&read),
+            handled: 0,
+            most_behind: Arc::clone(&most_behind),
+        };
+        let input = Flood::new(messages, Arc::clone(&read));
+
+        run_with_transport(
+            input,
+            InMemoryTransport::new(),
+            Arc::new(SystemClock),
+            |_| Ok(node),
+        )
+        .unwrap();
+
+        // Every message got handled, and no more were read ahead than the channel holds, plus
+        // the one the reader is blocked sending.
+        assert_eq!(read.load(Ordering::SeqCst), messages + 1);
+        assert!(most_behind.load(Ordering::SeqCst) <= channel_capacity().unwrap() + 1);
+    }
+
+    #[test]
+    fn misaddressed_messages_are_counted_and_dropped() {
+        let transport = InMemoryTransport::new();
+        let mut rpc = Rpc::with_transport("n1", transport.clone());
+        let mut node = Counting::default();
+
+        let mut message = Message {
+            source: "c1".to_owned(),
+            destination: "n2".to_owned(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                payload: json!({"type": "echo"}),
+            },
+        };
+        dispatch(&mut node, &message, &mut rpc).unwrap();
+        assert_eq!(node.stepped, 0);
+        assert_eq!(rpc.metrics().misaddressed, 1);
+        assert!(transport.sent().is_empty());
+
+        message.destination = "n1".to_owned();
+        dispatch(&mut node, &message, &mut rpc).unwrap();
+        assert_eq!(node.stepped, 1);
+        assert_eq!(rpc.metrics().misaddressed, 1);
+    }
+
+    #[cfg(feature = "broadcast")]
+    #[test]
+    fn replies_delivered_twice_are_dropped() {
+        use std::time::Duration;
+
+        use crate::sim::{NetworkConfig, Simulation};
+        use crate::workloads::{BroadcastNode, Topology};
+
+        let config = NetworkConfig {
+            duplicate_rate: 1.0,
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(3, config, |context| {
+            Ok(BroadcastNode::with_topology(context, Topology::Line))
+        })
+        .unwrap();
+
+        sim.send("c1", "n0", json!({"type": "broadcast", "message": 1}))
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        assert!(sim.duplicated() > 0);
+        for node_id in ["n0", "n1", "n2"] {
+            let sent = &sim.metrics(node_id).unwrap().sent;
+            assert_eq!(sent.get("error"), None, "{node_id} sent {sent:?}");
+        }
+    }
+}
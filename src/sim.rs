@@ -0,0 +1,757 @@
+//! Runs a whole cluster of nodes in one process, over a simulated network, so that a workload
+//! can be tried out without Maelstrom.
+//!
+//! Time is a [`MockClock`] that jumps from one event to the next: a message arriving, a tick
+//! coming due or a request timing out. The network delays, drops and thereby reorders messages
+//! between nodes by a seeded generator, so a run with the same seed and the same nodes makes
+//! the same choices, provided the nodes send their messages in the same order. Nodes that walk
+//! a `HashMap` to decide what to send don't always.
+//!
+//! Maelstrom's key-value services are simulated too, see [`SERVICES`].
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::clock::{Clock, MockClock};
+use crate::kv::{KvReply, KvRequest, LIN_KV, LWW_KV, SEQ_KV};
+use crate::message::{Body, ErrorCode, Message};
+use crate::metrics::Metrics;
+use crate::node::{Event, Node, NodeContext};
+use crate::random;
+use crate::rpc::{Rpc, Waiters};
+use crate::runtime::{self, dispatch};
+use crate::transport::Transport;
+
+/// The services the simulation answers itself, straight away and without ever losing a
+/// message. Each is a single linearizable store, so `seq-kv` and `lww-kv` are stricter here
+/// than in Maelstrom.
+pub const SERVICES: &[&str] = &[SEQ_KV, LIN_KV, LWW_KV];
+
+/// How the simulated network treats messages between nodes, much like Maelstrom's nemesis
+/// would.
+///
+/// Messages from and to clients are delayed the same way, but never dropped or duplicated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkConfig {
+    /// Each message takes between `min_delay` and `max_delay` to arrive, picked uniformly.
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// The share of messages between nodes that get lost, in `[0, 1]`.
+    pub drop_rate: f64,
+    /// The share of messages between nodes that arrive twice, in `[0, 1]`. Each copy is
+    /// delayed on its own, so the second may well arrive first.
+    pub duplicate_rate: f64,
+    pub seed: u64,
+}
+
+/// A network that delivers everything instantly, in the order it was sent.
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Runs `run` once for every seed in `seeds`, each with its own network like `config`, and
+/// stops at the first that fails, naming the seed.
+///
+/// With `TEMPEST_SIM_SEED` set, only that seed runs, to replay a failure.
+pub fn for_each_seed(
+    seeds: Range<u64>,
+    config: NetworkConfig,
+    mut run: impl FnMut(NetworkConfig) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let seeds = match std::env::var("TEMPEST_SIM_SEED") {
+        Ok(seed) => {
+            let seed = seed
+                .parse()
+                .with_context(|| format!("TEMPEST_SIM_SEED must be an integer, not {seed:?}."))?;
+            seed..seed + 1
+        }
+        Err(_) => seeds,
+    };
+
+    for seed in seeds {
+        run(NetworkConfig { seed, ..config }).with_context(|| {
+            format!("Failed with seed {seed}, replay it with TEMPEST_SIM_SEED={seed}.")
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Several nodes running the same workload, and the network between them and their clients.
+///
+/// Nodes are named `n0`, `n1` and so on, as in Maelstrom, and anything else they send to is
+/// taken for a client. A client's messages go in through [`Simulation::send`] and whatever
+/// reaches a client is kept in [`Simulation::history`].
+///
+/// Nothing can answer a blocking [`Rpc::request`] to another node while the requesting node
+/// is busy waiting for it, so such requests fail with
+/// [`RpcError::Closed`](crate::rpc::RpcError::Closed) right away. Requests through
+/// [`Rpc::call`] or [`Rpc::request_then`], and blocking ones to [`SERVICES`], work as usual.
+pub struct Simulation<N: Node> {
+    clock: Arc<MockClock>,
+    network: Rc<RefCell<Network>>,
+    nodes: BTreeMap<String, SimNode<N>>,
+    /// In the order `init` names them.
+    node_ids: Vec<String>,
+    /// Where nodes keep their state files, see [`Simulation::persist_in`].
+    state_dir: Option<PathBuf>,
+}
+
+struct SimNode<N: Node> {
+    node: N,
+    rpc: Rpc<N>,
+    next_tick: Option<Instant>,
+}
+
+/// What happens next, at the time it's due.
+enum Next {
+    Deliver,
+    Tick(String),
+    Expire(String),
+}
+
+impl<N: Node> Simulation<N> {
+    /// Builds `node_count` nodes with `init`, as the runtime would on `init`, and calls their
+    /// [`Node::init`] hooks.
+    pub fn new(
+        node_count: usize,
+        config: NetworkConfig,
+        mut init: impl FnMut(&NodeContext) -> anyhow::Result<N>,
+    ) -> anyhow::Result<Self> {
+        let rates = [config.drop_rate, config.duplicate_rate];
+        if !rates.iter().all(|rate| (0.0..=1.0).contains(rate))
+            || config.min_delay > config.max_delay
+        {
+            bail!("Invalid network {config:?}.");
+        }
+
+        let node_ids: Vec<String> = (0..node_count).map(|index| format!("n{index}")).collect();
+        let clock = Arc::new(MockClock::new());
+        let network = Rc::new(RefCell::new(Network {
+            config,
+            clock: Arc::clone(&clock),
+            state: config.seed,
+            nodes: node_ids.iter().cloned().collect(),
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            next_client_id: 0,
+            delivered: 0,
+            dropped: 0,
+            duplicated: 0,
+            history: Vec::new(),
+            stores: HashMap::new(),
+            cut: BTreeSet::new(),
+        }));
+
+        let mut sim = Self {
+            clock,
+            network,
+            nodes: BTreeMap::new(),
+            node_ids: node_ids.clone(),
+            state_dir: None,
+        };
+        for node_id in &node_ids {
+            let node = sim.build(node_id, &mut init)?;
+            sim.nodes.insert(node_id.clone(), node);
+        }
+        Ok(sim)
+    }
+
+    /// Builds `node_id` with `init` and calls its [`Node::init`] hook, restoring it from its
+    /// state file first if there is one.
+    fn build(
+        &self,
+        node_id: &str,
+        init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+    ) -> anyhow::Result<SimNode<N>> {
+        let context = NodeContext {
+            node_id: node_id.to_owned(),
+            node_ids: self.node_ids.clone(),
+        };
+        let waiters = Waiters::default();
+        let transport = SimTransport {
+            network: Rc::clone(&self.network),
+            waiters: Arc::clone(&waiters),
+        };
+        let clock = Arc::clone(&self.clock) as Arc<dyn Clock>;
+        let mut rpc = Rpc::new(transport, waiters, clock);
+        rpc.node_id = Some(node_id.to_owned());
+        rpc.state_file = self.state_file(node_id);
+
+        let mut node = init(&context)?;
+        runtime::restore(node.persistent(), rpc.state_file.as_deref())?;
+        node.init(&context, &mut rpc)
+            .with_context(|| format!("Could not initialize {node_id}."))?;
+        let next_tick = node
+            .tick_interval()
+            .map(|interval| self.clock.now() + interval);
+
+        Ok(SimNode {
+            node,
+            rpc,
+            next_tick,
+        })
+    }
+
+    fn state_file(&self, node_id: &str) -> Option<PathBuf> {
+        let dir = self.state_dir.as_ref()?;
+        Some(dir.join(format!("{node_id}.json")))
+    }
+
+    /// Has every node journal to a state file of its own in `dir`, as
+    /// [`set_state_file`](crate::runtime::set_state_file) would, so that
+    /// [`Simulation::restart`] can bring it back. Nodes are never snapshotted, since the
+    /// simulation doesn't shut them down.
+    pub fn persist_in(&mut self, dir: impl Into<PathBuf>) {
+        self.state_dir = Some(dir.into());
+        for node_id in &self.node_ids {
+            let state_file = self.state_file(node_id);
+            let node = self.nodes.get_mut(node_id).expect("Every node is built.");
+            node.rpc.state_file = state_file;
+        }
+    }
+
+    /// Kills `node_id` and builds it again with `init`, as if its process crashed and
+    /// Maelstrom started a new one. It keeps only what it saved to its state file, see
+    /// [`Simulation::persist_in`], and what it was waiting for replies to is forgotten.
+    /// Messages on their way to it still arrive.
+    pub fn restart(
+        &mut self,
+        node_id: &str,
+        init: impl FnOnce(&NodeContext) -> anyhow::Result<N>,
+    ) -> anyhow::Result<()> {
+        if !self.nodes.contains_key(node_id) {
+            bail!("There is no node {node_id} to restart.");
+        }
+        let node = self.build(node_id, init)?;
+        self.nodes.insert(node_id.to_owned(), node);
+        Ok(())
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// The ids of the nodes, in order.
+    pub fn node_ids(&self) -> impl Iterator<Item = &str> {
+        self.nodes.keys().map(String::as_str)
+    }
+
+    pub fn node(&self, node_id: &str) -> Option<&N> {
+        self.nodes.get(node_id).map(|node| &node.node)
+    }
+
+    pub fn metrics(&self, node_id: &str) -> Option<&Metrics> {
+        self.nodes.get(node_id).map(|node| node.rpc.metrics())
+    }
+
+    /// How many messages have arrived so far, at nodes or clients.
+    pub fn delivered(&self) -> usize {
+        self.network.borrow().delivered
+    }
+
+    /// How many messages between nodes the network has lost so far.
+    pub fn dropped(&self) -> usize {
+        self.network.borrow().dropped
+    }
+
+    /// How many messages between nodes the network has sent twice so far.
+    pub fn duplicated(&self) -> usize {
+        self.network.borrow().duplicated
+    }
+
+    /// Cuts every link between a node of `one` and a node of `other`, both ways, as Maelstrom's
+    /// partition nemesis does, until [`Simulation::heal`]. Messages sent across the cut from
+    /// then on are lost, and count as [dropped](Simulation::dropped). Those already on their
+    /// way still arrive.
+    pub fn partition(&mut self, one: &[&str], other: &[&str]) {
+        let mut network = self.network.borrow_mut();
+        for a in one {
+            for b in other {
+                network.cut.insert((a.to_string(), b.to_string()));
+                network.cut.insert((b.to_string(), a.to_string()));
+            }
+        }
+    }
+
+    /// Restores every link cut by [`Simulation::partition`].
+    pub fn heal(&mut self) {
+        self.network.borrow_mut().cut.clear();
+    }
+
+    /// Every message clients sent and received so far, each at the time it was sent or
+    /// received, oldest first. This is what a checker would judge the run by.
+    pub fn history(&self) -> Vec<(Instant, Message<Value>)> {
+        self.network.borrow().history.clone()
+    }
+
+    /// Sends `payload` from `client` to `node_id`, returning its `msg_id`. Client ids are
+    /// unique across all clients, so replies can be told apart by `in_reply_to` alone.
+    pub fn send(
+        &mut self,
+        client: &str,
+        node_id: &str,
+        payload: impl Serialize,
+    ) -> anyhow::Result<usize> {
+        let mut network = self.network.borrow_mut();
+        let id = network.next_client_id;
+        network.next_client_id += 1;
+
+        let msg = Message {
+            source: client.to_owned(),
+            destination: node_id.to_owned(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload: serde_json::to_value(payload)?,
+            },
+        };
+        network.history.push((self.clock.now(), msg.clone()));
+        network.route(msg);
+
+        Ok(id)
+    }
+
+    /// Sends `payload` as [`Simulation::send`] does, then runs until the reply reaches
+    /// `client`, or `timeout` passes.
+    pub fn request(
+        &mut self,
+        client: &str,
+        node_id: &str,
+        payload: impl Serialize,
+        timeout: Duration,
+    ) -> anyhow::Result<Option<Message<Value>>> {
+        let id = self.send(client, node_id, payload)?;
+        let deadline = self.now() + timeout;
+        let seen = self.network.borrow().history.len();
+
+        loop {
+            let reply = self.network.borrow().history[seen..]
+                .iter()
+                .find(|(_, msg)| msg.destination == client && msg.body.in_reply_to == Some(id))
+                .map(|(_, msg)| msg.clone());
+            if reply.is_some() {
+                return Ok(reply);
+            }
+
+            if !self.step(deadline)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Runs everything that comes due within `duration`, then moves the clock to its end.
+    pub fn run_for(&mut self, duration: Duration) -> anyhow::Result<()> {
+        let until = self.now() + duration;
+        while self.step(until)? {}
+        Ok(())
+    }
+
+    /// Runs the next event, if it comes due by `until`, and returns whether there was one.
+    /// Otherwise moves the clock to `until`.
+    pub fn step(&mut self, until: Instant) -> anyhow::Result<bool> {
+        let Some((at, next)) = self.next().filter(|(at, _)| *at <= until) else {
+            self.advance_to(until);
+            return Ok(false);
+        };
+        self.advance_to(at);
+
+        match next {
+            Next::Deliver => {
+                let msg = self.network.borrow_mut().pop();
+                if let Some(msg) = msg {
+                    self.deliver(msg)?;
+                }
+            }
+
+            Next::Tick(node_id) => {
+                let SimNode {
+                    node,
+                    rpc,
+                    next_tick,
+                } = self.nodes.get_mut(&node_id).expect("Node was just found.");
+                rpc.batched(|rpc| node.on_event(Event::Tick, rpc))?;
+                let now = self.clock.now();
+                *next_tick = node.tick_interval().map(|interval| now + interval);
+            }
+
+            Next::Expire(node_id) => {
+                let SimNode { node, rpc, .. } =
+                    self.nodes.get_mut(&node_id).expect("Node was just found.");
+                let now = self.clock.now();
+                rpc.batched(|rpc| rpc.expire(node, now))?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The earliest event. Deliveries go before ticks and timeouts due at the same time, and
+    /// those go by node id.
+    fn next(&self) -> Option<(Instant, Next)> {
+        let delivery = self
+            .network
+            .borrow()
+            .in_flight
+            .keys()
+            .next()
+            .map(|(at, _)| (*at, Next::Deliver));
+
+        let timers = self.nodes.iter().flat_map(|(node_id, node)| {
+            let tick = node.next_tick.map(|at| (at, Next::Tick(node_id.clone())));
+            let deadline = node
+                .rpc
+                .next_deadline()
+                .map(|at| (at, Next::Expire(node_id.clone())));
+            tick.into_iter().chain(deadline)
+        });
+
+        // `min_by_key` keeps the first of equals.
+        delivery.into_iter().chain(timers).min_by_key(|(at, _)| *at)
+    }
+
+    fn advance_to(&self, at: Instant) {
+        let now = self.clock.now();
+        if at > now {
+            self.clock.advance(at - now);
+        }
+    }
+
+    fn deliver(&mut self, msg: Message<Value>) -> anyhow::Result<()> {
+        match self.nodes.get_mut(&msg.destination) {
+            Some(SimNode { node, rpc, .. }) => rpc.batched(|rpc| dispatch(node, &msg, rpc)),
+            None => {
+                self.network
+                    .borrow_mut()
+                    .history
+                    .push((self.clock.now(), msg));
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Network {
+    config: NetworkConfig,
+    clock: Arc<MockClock>,
+    /// State of the generator picking delays, losses and duplicates.
+    state: u64,
+    nodes: BTreeSet<String>,
+    /// Messages on their way, by when they arrive and the order they were sent in.
+    in_flight: BTreeMap<(Instant, u64), Message<Value>>,
+    sent: u64,
+    next_client_id: usize,
+    delivered: usize,
+    dropped: usize,
+    duplicated: usize,
+    history: Vec<(Instant, Message<Value>)>,
+    /// Links that lose everything sent over them, as `(source, destination)`.
+    cut: BTreeSet<(String, String)>,
+    /// The contents of each of the [`SERVICES`], keyed by the JSON text of the key.
+    stores: HashMap<String, HashMap<String, Value>>,
+}
+
+impl Network {
+    /// A pseudo-random number in `[0, 1)`, by splitmix64.
+    fn random(&mut self) -> f64 {
+        random::splitmix64(&mut self.state)
+    }
+
+    fn route(&mut self, msg: Message<Value>) {
+        let between_nodes =
+            self.nodes.contains(&msg.source) && self.nodes.contains(&msg.destination);
+        let cut = self
+            .cut
+            .contains(&(msg.source.clone(), msg.destination.clone()));
+        if between_nodes && (cut || self.random() < self.config.drop_rate) {
+            self.dropped += 1;
+            return;
+        }
+
+        if between_nodes && self.random() < self.config.duplicate_rate {
+            self.duplicated += 1;
+            let delay = self.delay();
+            self.enqueue(msg.clone(), delay);
+        }
+        let delay = self.delay();
+        self.enqueue(msg, delay);
+    }
+
+    fn delay(&mut self) -> Duration {
+        let NetworkConfig {
+            min_delay,
+            max_delay,
+            ..
+        } = self.config;
+        min_delay + (max_delay - min_delay).mul_f64(self.random())
+    }
+
+    fn enqueue(&mut self, msg: Message<Value>, delay: Duration) {
+        let at = self.clock.now() + delay;
+        self.in_flight.insert((at, self.sent), msg);
+        self.sent += 1;
+    }
+
+    fn pop(&mut self) -> Option<Message<Value>> {
+        let (_, msg) = self.in_flight.pop_first()?;
+        self.delivered += 1;
+        Some(msg)
+    }
+
+    /// Answers a request to one of the [`SERVICES`].
+    fn serve(&mut self, request: &Message<Value>) -> Message<Value> {
+        let store = self.stores.entry(request.destination.clone()).or_default();
+
+        let missing = |key: &Value| KvReply::Error {
+            code: ErrorCode::KeyDoesNotExist.into(),
+            text: format!("Key {key} does not exist."),
+        };
+        let reply = match serde_json::from_value::<KvRequest>(request.body.payload.clone()) {
+            Ok(KvRequest::Read { key }) => match store.get(&key.to_string()) {
+                Some(value) => KvReply::ReadOk {
+                    value: value.clone(),
+                },
+                None => missing(&key),
+            },
+
+            Ok(KvRequest::Write { key, value }) => {
+                store.insert(key.to_string(), value);
+                KvReply::WriteOk {}
+            }
+
+            Ok(KvRequest::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            }) => match store.get_mut(&key.to_string()) {
+                Some(value) if *value == from => {
+                    *value = to;
+                    KvReply::CasOk {}
+                }
+                Some(value) => KvReply::Error {
+                    code: ErrorCode::PreconditionFailed.into(),
+                    text: format!("Expected {from}, but found {value}."),
+                },
+                None if create_if_not_exists => {
+                    store.insert(key.to_string(), to);
+                    KvReply::CasOk {}
+                }
+                None => missing(&key),
+            },
+
+            Err(error) => KvReply::Error {
+                code: ErrorCode::MalformedRequest.into(),
+                text: error.to_string(),
+            },
+        };
+
+        Message {
+            source: request.destination.clone(),
+            destination: request.source.clone(),
+            body: Body {
+                id: None,
+                in_reply_to: request.body.id,
+                payload: serde_json::to_value(reply).expect("Kv replies always encode."),
+            },
+        }
+    }
+}
+
+/// Hands a node's messages to the simulated network.
+struct SimTransport {
+    network: Rc<RefCell<Network>>,
+    /// The node's blocked requests, see [`Simulation`].
+    waiters: Waiters,
+}
+
+impl Transport for SimTransport {
+    fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()> {
+        let mut network = self.network.borrow_mut();
+
+        if SERVICES.contains(&msg.destination.as_str()) {
+            let reply = network.serve(msg);
+            let waiter = reply.body.in_reply_to.and_then(|id| {
+                self.waiters
+                    .lock()
+                    .expect("Waiters lock poisoned.")
+                    .remove(&id)
+            });
+            match waiter {
+                Some(waiter) => {
+                    let _ = waiter.send(reply);
+                }
+                None => network.enqueue(reply, Duration::ZERO),
+            }
+            return Ok(());
+        }
+
+        // Dropping the waiter fails the request rather than leaving the node to sit out its
+        // timeout on the wall clock.
+        if let Some(id) = msg.body.id {
+            self.waiters
+                .lock()
+                .expect("Waiters lock poisoned.")
+                .remove(&id);
+        }
+        network.route(msg.clone());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Passes each value a client adds on to every peer, and keeps the values it gets in the
+    /// order they arrive.
+    struct Sharing {
+        peers: Vec<String>,
+        arrived: Vec<u64>,
+    }
+
+    impl Node for Sharing {
+        type Payload = Value;
+
+        fn step(&mut self, input: Message<Value>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+            let value = input.body.payload["value"].as_u64().unwrap();
+            self.arrived.push(value);
+            if input.body.payload["type"] == "add" {
+                for peer in &self.peers {
+                    rpc.notify(peer, json!({"type": "share", "value": value}))?;
+                }
+                rpc.reply(&input, json!({"type": "add_ok"}))?;
+            }
+            Ok(())
+        }
+    }
+
+    fn sharing(node_count: usize, config: NetworkConfig) -> Simulation<Sharing> {
+        Simulation::new(node_count, config, |context| {
+            Ok(Sharing {
+                peers: context.peers().map(str::to_owned).collect(),
+                arrived: Vec::new(),
+            })
+        })
+        .unwrap()
+    }
+
+    /// Each client adds one value to the node of the same number, all at once.
+    fn add_everywhere(sim: &mut Simulation<Sharing>, values: u64) {
+        for value in 0..values {
+            let node_id = format!("n{}", value % 3);
+            let add = json!({"type": "add", "value": value});
+            sim.send(&format!("c{value}"), &node_id, add).unwrap();
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+    }
+
+    fn arrived(sim: &Simulation<Sharing>, node_id: &str) -> Vec<u64> {
+        sim.node(node_id).unwrap().arrived.clone()
+    }
+
+    #[test]
+    fn a_run_repeats_exactly_under_the_same_seed() {
+        let config = NetworkConfig {
+            max_delay: Duration::from_millis(50),
+            seed: 11,
+            ..NetworkConfig::default()
+        };
+        let run = |config| {
+            let mut sim = sharing(3, config);
+            let start = sim.now();
+            add_everywhere(&mut sim, 9);
+            let arrivals: Vec<Vec<u64>> = ["n0", "n1", "n2"]
+                .iter()
+                .map(|node_id| arrived(&sim, node_id))
+                .collect();
+            // The mock clock starts at the real time, which differs from run to run.
+            let history: Vec<(Duration, Message<Value>)> = sim
+                .history()
+                .into_iter()
+                .map(|(at, msg)| (at - start, msg))
+                .collect();
+            (arrivals, history, sim.delivered())
+        };
+
+        let (arrivals, history, delivered) = run(config);
+        for arrived in &arrivals {
+            let mut values = arrived.clone();
+            values.sort_unstable();
+            assert_eq!(values, (0..9).collect::<Vec<_>>());
+        }
+        // The clients' 9 adds and their replies, and between nodes each add passed on to 2
+        // peers.
+        assert_eq!(history.len(), 9 + 9);
+        assert_eq!(delivered, 9 + 9 + 9 * 2);
+
+        assert_eq!(run(config), (arrivals.clone(), history, delivered));
+        assert_ne!(run(NetworkConfig { seed: 12, ..config }).0, arrivals);
+    }
+
+    #[test]
+    fn duplicated_messages_arrive_out_of_order_but_all_arrive() {
+        let config = NetworkConfig {
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(100),
+            duplicate_rate: 0.5,
+            seed: 5,
+            ..NetworkConfig::default()
+        };
+        let mut sim = sharing(2, config);
+        // Sent in order, a millisecond apart, all from n0 to n1.
+        for value in 0..20 {
+            let add = json!({"type": "add", "value": value});
+            sim.send("c1", "n0", add).unwrap();
+            sim.run_for(Duration::from_millis(1)).unwrap();
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+
+        let arrived = arrived(&sim, "n1");
+        assert!(sim.duplicated() > 0);
+        assert_eq!(arrived.len(), 20 + sim.duplicated());
+        assert!(
+            arrived.windows(2).any(|pair| pair[0] > pair[1]),
+            "{arrived:?}"
+        );
+        let distinct: BTreeSet<u64> = arrived.into_iter().collect();
+        assert_eq!(distinct, (0..20).collect());
+        assert_eq!(sim.dropped(), 0);
+    }
+
+    #[test]
+    fn a_failing_seed_is_named_for_replaying() {
+        let mut tried = Vec::new();
+        let error = for_each_seed(0..10, NetworkConfig::default(), |config| {
+            tried.push(config.seed);
+            if config.seed == 3 {
+                bail!("Did not converge.");
+            }
+            Ok(())
+        })
+        .unwrap_err();
+
+        assert_eq!(tried, [0, 1, 2, 3]);
+        assert!(error.to_string().contains("TEMPEST_SIM_SEED=3"), "{error}");
+    }
+}
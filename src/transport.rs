@@ -0,0 +1,106 @@
+//! Where a node's outgoing messages go.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+use crate::message::Message;
+use crate::metrics;
+use crate::node::NodeError;
+
+/// Carries messages out of a node. [`crate::rpc::Rpc`] sends everything through one.
+///
+/// A transport may hold on to what it's sent until it's flushed. `Rpc` flushes once the
+/// runtime is done with the event that sent it, or right away outside of one.
+pub trait Transport {
+    fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()>;
+
+    /// Makes sure everything sent so far has gone out.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes each message as a single line of JSON, the way Maelstrom expects on stdout.
+pub struct LineTransport<W> {
+    output: W,
+}
+
+impl<W: Write> LineTransport<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<W: Write> Transport for LineTransport<W> {
+    /// The message and its trailing newline go into the same buffer, so a buffered `output`
+    /// writes all the messages of one event at once when it's flushed.
+    fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()> {
+        let mut output = Counting {
+            output: &mut self.output,
+            written: 0,
+        };
+        serde_json::to_writer(&mut output, msg).map_err(NodeError::Encode)?;
+        output.write_all(b"\n").map_err(NodeError::Write)?;
+
+        metrics::record_written(output.written);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.output.flush().map_err(NodeError::Write)?)
+    }
+}
+
+/// Counts what goes through it, for [`ProcStats`](crate::metrics::ProcStats).
+struct Counting<W> {
+    output: W,
+    written: usize,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.output.write(buf)?;
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Keeps every message sent through it, for tests to look at.
+///
+/// Clones share the same messages, so a test can keep one and hand the other to the node.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryTransport {
+    sent: Arc<Mutex<Vec<Message<Value>>>>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything sent so far, oldest first.
+    pub fn sent(&self) -> Vec<Message<Value>> {
+        self.sent.lock().expect("Transport lock poisoned.").clone()
+    }
+
+    /// Like [`InMemoryTransport::sent`], but also forgets the messages.
+    pub fn take(&self) -> Vec<Message<Value>> {
+        std::mem::take(&mut *self.sent.lock().expect("Transport lock poisoned."))
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn send(&mut self, msg: &Message<Value>) -> anyhow::Result<()> {
+        self.sent
+            .lock()
+            .expect("Transport lock poisoned.")
+            .push(msg.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! The Maelstrom workloads this crate implements, one node per workload.
+//!
+//! Each node has its own payload enum, [`Node::Payload`](crate::node::Node::Payload), naming
+//! only the messages of its workload. The binary picks one node by subcommand, and the runtime
+//! decodes every message's body into that node's payload, so no node ever matches on another
+//! workload's messages, and one that gets them refuses them as unsupported.
+//!
+//! The runtime is generic over the node, so there's no registry to look payloads up in: it
+//! reads each line as a message with a JSON body, and once it's past `init` and isn't a reply,
+//! deserializes the body as `N::Payload`. Payload enums are tagged by their `type` field, so the
+//! tag picks the variant and the other fields fill it in. A body that doesn't decode, such as
+//! another workload's message, is answered `not-supported`, or is fatal in strict mode.
+//! Replies to the node's own requests go to whatever awaits them, which decodes them itself.
+//!
+//! The `broadcast` feature builds the broadcast-like workloads, `kafka` the kafka log and
+//! `txn` the transactional store. All three are on by default. A build without one doesn't
+//! compile its nodes or payloads at all, and the binary has no subcommand for them.
+
+#[cfg(feature = "broadcast")]
+mod broadcast;
+#[cfg(feature = "broadcast")]
+mod causal;
+mod counter;
+mod echo;
+#[cfg(feature = "broadcast")]
+mod g_set;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod lin_kv;
+mod pn_counter;
+mod sr_kv;
+#[cfg(feature = "broadcast")]
+mod tree_broadcast;
+mod tso;
+#[cfg(feature = "txn")]
+mod txn;
+mod unique_ids;
+
+#[cfg(feature = "broadcast")]
+pub use broadcast::{BroadcastNode, GossipConfig, GossipMode, Topology};
+#[cfg(feature = "broadcast")]
+pub use causal::{CausalBroadcastNode, VectorClock};
+pub use counter::CounterNode;
+pub use echo::EchoNode;
+#[cfg(feature = "broadcast")]
+pub use g_set::GSetNode;
+#[cfg(feature = "kafka")]
+pub use kafka::{KafkaNode, Offsets};
+pub use lin_kv::LinKvNode;
+pub use pn_counter::PnCounterNode;
+pub use sr_kv::SrKvNode;
+#[cfg(feature = "broadcast")]
+pub use tree_broadcast::TreeBroadcastNode;
+pub use tso::TsoNode;
+#[cfg(feature = "txn")]
+pub use txn::TxnNode;
+pub use unique_ids::{SnowflakeIdNode, UniqueIdNode};
@@ -0,0 +1,1178 @@
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::crdt::GSet;
+use crate::log;
+use crate::message::{Body, Message};
+use crate::node::{reject, Node, NodeContext, Persistent};
+use crate::random;
+use crate::rpc::Rpc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastPayload {
+    Broadcast {
+        message: Value,
+    },
+    BroadcastOk {},
+    Read {},
+    ReadOk {
+        messages: Vec<Value>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk {},
+
+    /// Everything the receiver isn't known to have, batched into one message per neighbor per
+    /// tick.
+    Gossip {
+        messages: Vec<Value>,
+    },
+    GossipOk {},
+
+    /// Asks for every value whose key isn't in `digest`, which is sorted.
+    Sync {
+        digest: Vec<String>,
+    },
+    SyncOk {
+        messages: Vec<Value>,
+    },
+}
+
+/// How often a node logs how many values it holds and how many each neighbor still lacks, at
+/// [`log::Level::Info`].
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Children per node in [`Topology::Tree`] when no fanout is given.
+pub(crate) const DEFAULT_FANOUT: usize = 4;
+
+/// Gossips every broadcast value to its neighbours until all of them have it.
+///
+/// There is no separate gossip queue: a value is gossiped for as long as some neighbor isn't
+/// known to have it, so it is queued the moment the node holds it, before the client hears
+/// back. With a state file set, a new value is also journaled before it is acknowledged, to a
+/// client or to the peer that gossiped it, and a restarted node gossips everything it saved
+/// again.
+///
+/// The client is answered as soon as that's done. Gossip, even eager gossip, only goes out
+/// after the answer and never waits for a peer's reply, so how quickly a broadcast is
+/// acknowledged doesn't depend on the size of the cluster.
+pub struct BroadcastNode {
+    self_id: String,
+    neighbors: Vec<String>,
+    // Set when the neighbors were picked up front, so that Maelstrom's topology doesn't
+    // replace them.
+    fixed_neighbors: bool,
+    // `Value` isn't `Hash`, so values are keyed by their JSON text, see `key`.
+    messages: HashMap<String, Value>,
+    // Keys of the values each peer is known to have, either because it told us about them or
+    // because it acknowledged our gossip.
+    known: HashMap<String, GSet<String>>,
+    gossip: GossipConfig,
+    // Where in `neighbors` the next tick starts gossiping, when it can't reach all of them.
+    next_neighbor: usize,
+    // State of the generator jittering the gossip interval. `tick_interval` only gets `&self`.
+    jitter_state: Cell<u64>,
+    last_report: Option<Instant>,
+}
+
+impl BroadcastNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self {
+            self_id: context.node_id.clone(),
+            neighbors: Vec::new(),
+            fixed_neighbors: false,
+            messages: HashMap::new(),
+            known: HashMap::new(),
+            gossip: GossipConfig::default(),
+            next_neighbor: 0,
+            jitter_state: Cell::new(0),
+            last_report: None,
+        }
+    }
+
+    /// Gossips along `topology` rather than whatever Maelstrom's `topology` message says.
+    pub fn with_topology(context: &NodeContext, topology: Topology) -> Self {
+        let mut node = Self::new(context);
+        if let Some(neighbors) = topology.neighbors(context) {
+            node.neighbors = neighbors;
+            node.fixed_neighbors = true;
+        }
+        node
+    }
+
+    /// Gossips as often and as widely as `gossip` says.
+    pub fn with_gossip(mut self, gossip: GossipConfig) -> Self {
+        // Each node gets its own sequence from the same seed, so that they drift apart.
+        let mut hasher = DefaultHasher::new();
+        (gossip.seed, &self.self_id).hash(&mut hasher);
+        self.jitter_state.set(hasher.finish());
+
+        self.gossip = gossip;
+        self
+    }
+
+    /// A pseudo-random number in `[0, 1)`, by splitmix64.
+    fn random(&self) -> f64 {
+        let mut state = self.jitter_state.get();
+        let random = random::splitmix64(&mut state);
+        self.jitter_state.set(state);
+        random
+    }
+
+    /// The neighbors to gossip to this tick, taking turns if the fanout doesn't cover them
+    /// all.
+    fn gossip_targets(&mut self) -> Vec<String> {
+        let count = self.neighbors.len();
+        let fanout = self.gossip.fanout.map_or(count, |fanout| fanout.min(count));
+
+        let targets = (0..fanout)
+            .map(|offset| self.neighbors[(self.next_neighbor + offset) % count].clone())
+            .collect();
+        self.next_neighbor = (self.next_neighbor + fanout) % count.max(1);
+
+        targets
+    }
+
+    /// How many values are unacknowledged, summed over all neighbors.
+    fn total_unacknowledged(&self) -> usize {
+        self.neighbors
+            .iter()
+            .map(|neighbor| self.unacknowledged(neighbor).count())
+            .sum()
+    }
+
+    /// Logs, at most once per [`REPORT_INTERVAL`], how many values this node holds and how
+    /// many of them each neighbor is yet to acknowledge. Counts that stop going down mean
+    /// gossip is stuck, rather than slow.
+    fn report(&mut self, now: Instant) {
+        if !log::enabled(log::Level::Info)
+            || self
+                .last_report
+                .is_some_and(|last| now - last < REPORT_INTERVAL)
+        {
+            return;
+        }
+        self.last_report = Some(now);
+
+        let (held, pending) = self.sizes();
+        let pending: Vec<String> = pending
+            .iter()
+            .map(|(neighbor, count)| format!("{neighbor} {count}"))
+            .collect();
+        log::info!(
+            "Holding {held} values, unacknowledged by: {}.",
+            pending.join(", ")
+        );
+    }
+
+    /// How many values this node holds, and how many of them each neighbor is yet to
+    /// acknowledge.
+    fn sizes(&self) -> (usize, Vec<(&str, usize)>) {
+        let pending = self
+            .neighbors
+            .iter()
+            .map(|neighbor| (neighbor.as_str(), self.unacknowledged(neighbor).count()))
+            .collect();
+        (self.messages.len(), pending)
+    }
+
+    /// Sends each neighbor this tick whatever it isn't known to have.
+    fn push(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        for neighbor in self.gossip_targets() {
+            let values: Vec<_> = self
+                .unacknowledged(&neighbor)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            self.send_gossip(neighbor, values, rpc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the values under `keys`, which just arrived, straight on to every neighbor that
+    /// isn't known to have them, if gossip is eager.
+    fn spread(&mut self, keys: &[String], rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        if !self.gossip.eager || keys.is_empty() {
+            return Ok(());
+        }
+
+        for neighbor in self.neighbors.clone() {
+            let known = self.known.get(&neighbor);
+            let values: Vec<_> = keys
+                .iter()
+                .filter(|key| known.is_none_or(|known| !known.contains(*key)))
+                .map(|key| (key.clone(), self.messages[key].clone()))
+                .collect();
+            self.send_gossip(neighbor, values, rpc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gossips `values`, by key, to `neighbor`, and notes them as known to it once it
+    /// acknowledges them.
+    fn send_gossip(
+        &self,
+        neighbor: String,
+        values: Vec<(String, Value)>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let (keys, values): (Vec<_>, Vec<_>) = values.into_iter().unzip();
+
+        let gossip = Message {
+            source: self.self_id.clone(),
+            destination: neighbor.clone(),
+            body: Body {
+                id: Some(rpc.next_id()),
+                in_reply_to: None,
+                payload: BroadcastPayload::Gossip { messages: values },
+            },
+        };
+
+        // Values stay pending for a neighbor until it acknowledges them, so gossip lost to
+        // a partition is simply sent again once the link heals.
+        rpc.call(gossip, move |node, _gossip_ok, _rpc| {
+            node.known.entry(neighbor).or_default().extend(keys);
+            Ok(())
+        })
+    }
+
+    /// Asks each neighbor this tick for whatever this node doesn't have, by telling it
+    /// everything this node does have.
+    fn pull(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        let mut digest: Vec<String> = self.messages.keys().cloned().collect();
+        digest.sort_unstable();
+
+        for neighbor in self.gossip_targets() {
+            let sync = Message {
+                source: self.self_id.clone(),
+                destination: neighbor.clone(),
+                body: Body {
+                    id: Some(rpc.next_id()),
+                    in_reply_to: None,
+                    payload: BroadcastPayload::Sync {
+                        digest: digest.clone(),
+                    },
+                },
+            };
+
+            rpc.call(sync, move |node, sync_ok, _rpc| {
+                if let BroadcastPayload::SyncOk { messages } = sync_ok.body.payload {
+                    let peer = node.known.entry(neighbor).or_default();
+                    for value in messages {
+                        let key = key(&value);
+                        peer.insert(key.clone());
+                        node.messages.entry(key).or_insert(value);
+                    }
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// The values `peer` isn't known to have yet.
+    fn unacknowledged<'a>(&'a self, peer: &str) -> impl Iterator<Item = (&'a String, &'a Value)> {
+        let known = self.known.get(peer);
+        self.messages
+            .iter()
+            .filter(move |(key, _)| known.is_none_or(|known| !known.contains(*key)))
+    }
+}
+
+impl Node for BroadcastNode {
+    type Payload = BroadcastPayload;
+
+    fn init(&mut self, _context: &NodeContext, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        log::info!("Gossiping {:?}.", self.gossip);
+        Ok(())
+    }
+
+    fn step(
+        &mut self,
+        message: Message<BroadcastPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        // Values new to this node, to spread if gossip is eager.
+        let mut new = Vec::new();
+
+        let payload = match message.body.payload {
+            BroadcastPayload::Broadcast { ref message } => {
+                let key = key(message);
+                if let Entry::Vacant(entry) = self.messages.entry(key.clone()) {
+                    entry.insert(message.clone());
+                    new.push(key);
+                }
+                BroadcastPayload::BroadcastOk {}
+            }
+
+            BroadcastPayload::Read {} => BroadcastPayload::ReadOk {
+                messages: self.messages.values().cloned().collect(),
+            },
+
+            BroadcastPayload::Topology { ref topology } => {
+                if !self.fixed_neighbors {
+                    self.neighbors = topology.get(&self.self_id).cloned().unwrap_or_default();
+                }
+                BroadcastPayload::TopologyOk {}
+            }
+
+            BroadcastPayload::Gossip {
+                messages: ref gossip,
+            } => {
+                let peer = self.known.entry(message.source.clone()).or_default();
+                for value in gossip {
+                    let key = key(value);
+                    peer.insert(key.clone());
+                    if let Entry::Vacant(entry) = self.messages.entry(key.clone()) {
+                        entry.insert(value.clone());
+                        new.push(key);
+                    }
+                }
+                BroadcastPayload::GossipOk {}
+            }
+
+            BroadcastPayload::Sync { ref digest } => {
+                let peer = self.known.entry(message.source.clone()).or_default();
+                peer.extend(digest.iter().cloned());
+
+                let messages = self
+                    .messages
+                    .iter()
+                    .filter(|(key, _)| digest.binary_search(key).is_err())
+                    .map(|(_, value)| value.clone())
+                    .collect();
+                BroadcastPayload::SyncOk { messages }
+            }
+
+            _ => return reject(&message, rpc, "Unsupported message type."),
+        };
+
+        // Acknowledged values have to outlive a crash, whether a client or a peer is told:
+        // a peer that acknowledged gossip is never sent it again.
+        if !new.is_empty() {
+            let messages: Vec<_> = new.iter().map(|key| &self.messages[key]).collect();
+            rpc.journal(serde_json::json!({ "messages": messages }))?;
+        }
+
+        // Answer first, so that the client never waits on gossip.
+        rpc.reply(&message, payload)?;
+        self.spread(&new, rpc)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        let jitter = self.gossip.jitter;
+        if jitter == 0.0 {
+            return Some(self.gossip.interval);
+        }
+
+        let factor = 1.0 + jitter * (2.0 * self.random() - 1.0);
+        Some(self.gossip.interval.mul_f64(factor))
+    }
+
+    fn tick(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        self.report(rpc.now());
+        match self.gossip.mode {
+            GossipMode::Push => self.push(rpc),
+            GossipMode::Pull => self.pull(rpc),
+        }
+    }
+
+    fn on_shutdown(&mut self, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        log::info!(
+            "Shutting down with {} messages, {} not yet acknowledged by a neighbor.",
+            self.messages.len(),
+            self.total_unacknowledged()
+        );
+
+        Ok(())
+    }
+
+    fn summary(&self) -> Option<Value> {
+        Some(serde_json::json!({
+            "messages": self.messages.len(),
+            "unacknowledged": self.total_unacknowledged(),
+        }))
+    }
+
+    fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+        Some(self)
+    }
+}
+
+/// Only the values are saved. What peers have is learned again from their gossip.
+///
+/// Restoring adds to the values held, so that journaled values can be restored one entry at a
+/// time.
+impl Persistent for BroadcastNode {
+    fn snapshot(&self) -> Value {
+        serde_json::json!({ "messages": self.messages.values().collect::<Vec<_>>() })
+    }
+
+    fn restore(&mut self, state: Value) -> anyhow::Result<()> {
+        #[derive(Deserialize)]
+        struct State {
+            messages: Vec<Value>,
+        }
+
+        let State { messages } = serde_json::from_value(state)?;
+        self.messages
+            .extend(messages.into_iter().map(|value| (key(&value), value)));
+        Ok(())
+    }
+}
+
+/// The key a value is stored and gossiped under: its JSON text, with every number that equals
+/// an integer written as one, so that `1` and `1.0` count as the same value. Object fields are
+/// sorted already.
+pub(crate) fn key(value: &Value) -> String {
+    canonical(value).to_string()
+}
+
+fn canonical(value: &Value) -> Value {
+    match value {
+        Value::Number(number) => match number.as_f64() {
+            // Past 2^53 not every integer is a float, and floats that big are close enough.
+            Some(float)
+                if number.is_f64() && float.fract() == 0.0 && float.abs() < 2f64.powi(53) =>
+            {
+                Value::from(float as i64)
+            }
+            _ => value.clone(),
+        },
+        Value::Array(values) => values.iter().map(canonical).collect(),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), canonical(value)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+/// How a broadcast node schedules its gossip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GossipConfig {
+    /// How long to wait between rounds of gossip.
+    pub interval: Duration,
+    /// How far each wait may stray from `interval`, as a fraction of it in `[0, 1)`. Nodes
+    /// that all gossip on the same beat send their messages in bursts, while jittered ones
+    /// spread them out.
+    pub jitter: f64,
+    /// Seeds the jitter, together with the node's id, so that a run can be repeated.
+    pub seed: u64,
+    /// How many neighbors to gossip to each round, or `None` for all of them.
+    pub fanout: Option<usize>,
+    pub mode: GossipMode,
+    /// Whether to also pass every value on to all neighbors the moment it arrives, rather
+    /// than at the next round. A value then crosses each link as soon as it can, for about one
+    /// more message per value and link.
+    pub eager: bool,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(100),
+            jitter: 0.0,
+            seed: 0,
+            fanout: None,
+            mode: GossipMode::Push,
+            eager: false,
+        }
+    }
+}
+
+/// Which way values travel in a round of gossip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GossipMode {
+    /// Each node sends its neighbors the values they aren't known to have. A value is sent
+    /// again every round until acknowledged, even if the neighbor got it elsewhere meanwhile.
+    Push,
+    /// Each node sends its neighbors the keys of every value it has, and they answer with
+    /// just the values it's missing. Values are never sent twice over a link that works, at
+    /// the cost of a digest that grows with the number of values.
+    Pull,
+}
+
+impl FromStr for GossipMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "push" => Ok(GossipMode::Push),
+            "pull" => Ok(GossipMode::Pull),
+            _ => bail!("Unknown gossip mode {mode:?}, expected push or pull."),
+        }
+    }
+}
+
+impl GossipConfig {
+    /// Gossip for when values should get everywhere as soon as possible, whatever it costs in
+    /// messages: eager, to every neighbor, with rounds only to make up for lost messages.
+    pub fn low_latency() -> Self {
+        Self {
+            interval: Duration::from_millis(50),
+            eager: true,
+            ..Self::default()
+        }
+    }
+
+    /// The settings named `default` or `low-latency`.
+    pub fn profile(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "default" => Ok(Self::default()),
+            "low-latency" => Ok(Self::low_latency()),
+            _ => bail!("Unknown gossip profile {name:?}, expected default or low-latency."),
+        }
+    }
+
+    /// Reads `TEMPEST_GOSSIP_PROFILE`, either `default` or `low-latency`, to start from, then
+    /// `TEMPEST_GOSSIP_INTERVAL_MS`, `TEMPEST_GOSSIP_JITTER`, `TEMPEST_GOSSIP_SEED`,
+    /// `TEMPEST_GOSSIP_FANOUT`, `TEMPEST_GOSSIP_MODE` (`push` or `pull`) and
+    /// `TEMPEST_GOSSIP_EAGER` (`true` or `false`), keeping the profile's settings for whichever
+    /// is unset.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::default().with_env()
+    }
+
+    /// Reads the same variables as [`GossipConfig::from_env`], but keeps these settings for
+    /// whichever is unset, unless `TEMPEST_GOSSIP_PROFILE` picks a profile to start from.
+    pub fn with_env(self) -> anyhow::Result<Self> {
+        self.with_vars(|name| std::env::var(name).ok())
+    }
+
+    /// [`GossipConfig::with_env`], with the variables looked up through `var`.
+    pub(crate) fn with_vars(self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let mut config = match var("TEMPEST_GOSSIP_PROFILE") {
+            Some(profile) => Self::profile(&profile).context("Invalid TEMPEST_GOSSIP_PROFILE.")?,
+            None => self,
+        };
+        if let Some(interval) = positive(&var, "TEMPEST_GOSSIP_INTERVAL_MS")? {
+            config.interval = Duration::from_millis(interval);
+        }
+        if let Some(jitter) = var("TEMPEST_GOSSIP_JITTER") {
+            config.jitter = match jitter.parse() {
+                Ok(jitter) if (0.0..1.0).contains(&jitter) => jitter,
+                _ => bail!("TEMPEST_GOSSIP_JITTER must be at least 0 and below 1, not {jitter:?}."),
+            };
+        }
+        if let Some(seed) = var("TEMPEST_GOSSIP_SEED") {
+            config.seed = seed.parse().with_context(|| {
+                format!("TEMPEST_GOSSIP_SEED must be an integer, not {seed:?}.")
+            })?;
+        }
+        if let Some(fanout) = positive(&var, "TEMPEST_GOSSIP_FANOUT")? {
+            config.fanout = Some(usize::try_from(fanout)?);
+        }
+        if let Some(mode) = var("TEMPEST_GOSSIP_MODE") {
+            config.mode = mode.parse().context("Invalid TEMPEST_GOSSIP_MODE.")?;
+        }
+        if let Some(eager) = var("TEMPEST_GOSSIP_EAGER") {
+            config.eager = eager.parse().with_context(|| {
+                format!("TEMPEST_GOSSIP_EAGER must be true or false, not {eager:?}.")
+            })?;
+        }
+        Ok(config)
+    }
+}
+
+fn positive(var: impl Fn(&str) -> Option<String>, name: &str) -> anyhow::Result<Option<u64>> {
+    let Some(value) = var(name) else {
+        return Ok(None);
+    };
+
+    match value.parse() {
+        Ok(value) if value > 0 => Ok(Some(value)),
+        _ => bail!("{name} must be a positive integer, not {value:?}."),
+    }
+}
+
+/// Which nodes a broadcast node gossips to.
+///
+/// Every structure but Maelstrom's is built from `node_ids` in sorted order, so that all nodes
+/// agree on it. Fewer links mean fewer messages per broadcast, while shorter paths mean values
+/// get everywhere sooner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    /// Whatever Maelstrom's `topology` message says.
+    Maelstrom,
+    /// Each node talks to the one before and after it. The fewest links, but a value takes up
+    /// to `n - 1` hops to get across.
+    Line,
+    /// Each node talks to its parent and up to `fanout` children. As few links as a line, with
+    /// paths only logarithmic in the number of nodes.
+    ///
+    /// The root is the lowest id. Gossip spreads a value along every link from wherever it was
+    /// broadcast, repeating it until acknowledged. [`TreeBroadcastNode`] routes each value
+    /// through the root instead.
+    ///
+    /// [`TreeBroadcastNode`]: crate::workloads::TreeBroadcastNode
+    Tree { fanout: usize },
+    /// The links of two trees, one built over the nodes in order and one in reverse. The
+    /// inner nodes of one tree are leaves of the other, so with a fanout of 2 or more, every
+    /// pair of nodes is joined by two paths that share no node in between, and no single
+    /// partitioned node or link stops a value from spreading. Twice the links of a tree.
+    DoubleTree { fanout: usize },
+    /// Nodes are laid out in a square and talk to their neighbors in each direction. About
+    /// twice the links of a tree, and paths of about `2 * sqrt(n)` hops, but every node has
+    /// several routes to every other, so a single partitioned link slows nothing down.
+    Grid,
+}
+
+impl Topology {
+    /// Reads `TEMPEST_TOPOLOGY`, which is one of `maelstrom`, `line`, `tree`, `tree:<fanout>`,
+    /// `double-tree`, `double-tree:<fanout>` or `grid`, defaulting to `maelstrom`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("TEMPEST_TOPOLOGY") {
+            Ok(topology) => topology.parse(),
+            Err(_) => Ok(Topology::Maelstrom),
+        }
+    }
+
+    /// This node's neighbors, or `None` to wait for Maelstrom's.
+    fn neighbors(self, context: &NodeContext) -> Option<Vec<String>> {
+        let mut node_ids = context.node_ids.clone();
+        node_ids.sort();
+
+        let index = context.node_index();
+        let indices: Vec<usize> = match self {
+            Topology::Maelstrom => return None,
+            Topology::Line => index
+                .checked_sub(1)
+                .into_iter()
+                .chain([index + 1])
+                .collect(),
+            Topology::Tree { fanout } => tree(index, fanout),
+            Topology::DoubleTree { fanout } => {
+                let last = node_ids.len().saturating_sub(1).max(index);
+                let mut indices = tree(index, fanout);
+                indices.extend(
+                    tree(last - index, fanout)
+                        .into_iter()
+                        .filter(|index| *index <= last)
+                        .map(|index| last - index),
+                );
+                indices.sort_unstable();
+                indices.dedup();
+                indices
+            }
+            Topology::Grid => {
+                let width = (node_ids.len() as f64).sqrt().ceil() as usize;
+                let left = (!index.is_multiple_of(width)).then(|| index - 1);
+                let right = (!(index + 1).is_multiple_of(width)).then_some(index + 1);
+                let up = index.checked_sub(width);
+                let down = Some(index + width);
+                [left, right, up, down].into_iter().flatten().collect()
+            }
+        };
+
+        Some(
+            indices
+                .into_iter()
+                .filter_map(|index| node_ids.get(index).cloned())
+                .collect(),
+        )
+    }
+}
+
+/// The parent and children of node `index` in a tree of the given fanout, some of which may be
+/// past the last node.
+pub(crate) fn tree(index: usize, fanout: usize) -> Vec<usize> {
+    // Node `i` has nodes `fanout * i + 1` through `fanout * i + fanout` as children.
+    let fanout = fanout.max(1);
+    let parent = index.checked_sub(1).map(|index| index / fanout);
+    parent
+        .into_iter()
+        .chain(fanout * index + 1..=fanout * index + fanout)
+        .collect()
+}
+
+impl FromStr for Topology {
+    type Err = anyhow::Error;
+
+    fn from_str(topology: &str) -> Result<Self, Self::Err> {
+        Ok(match topology {
+            "maelstrom" => Topology::Maelstrom,
+            "line" => Topology::Line,
+            "tree" => Topology::Tree {
+                fanout: DEFAULT_FANOUT,
+            },
+            "double-tree" => Topology::DoubleTree {
+                fanout: DEFAULT_FANOUT,
+            },
+            "grid" => Topology::Grid,
+            _ => {
+                if let Some(fanout) = topology.strip_prefix("tree:") {
+                    Topology::Tree {
+                        fanout: parse_fanout(fanout)?,
+                    }
+                } else if let Some(fanout) = topology.strip_prefix("double-tree:") {
+                    Topology::DoubleTree {
+                        fanout: parse_fanout(fanout)?,
+                    }
+                } else {
+                    bail!("Unknown topology {topology:?}.")
+                }
+            }
+        })
+    }
+}
+
+fn parse_fanout(fanout: &str) -> anyhow::Result<usize> {
+    fanout
+        .parse()
+        .with_context(|| format!("Invalid tree fanout {fanout:?}."))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::sim::{NetworkConfig, Simulation};
+
+    fn context(index: usize, node_count: usize) -> NodeContext {
+        NodeContext {
+            node_id: format!("n{index}"),
+            node_ids: (0..node_count).map(|index| format!("n{index}")).collect(),
+        }
+    }
+
+    /// Every value `node_id` holds, sorted.
+    fn read(sim: &mut Simulation<BroadcastNode>, node_id: &str) -> Vec<u64> {
+        let reply = sim
+            .request(
+                "c0",
+                node_id,
+                BroadcastPayload::Read {},
+                Duration::from_secs(1),
+            )
+            .unwrap()
+            .expect("Reads are answered.");
+        let mut messages: Vec<u64> = reply.body.payload["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| value.as_u64().unwrap())
+            .collect();
+        messages.sort_unstable();
+        messages
+    }
+
+    #[test]
+    fn values_cross_a_healed_partition() {
+        let node_count = 8;
+        let topology = Topology::DoubleTree { fanout: 2 };
+        let mut sim = Simulation::new(node_count, NetworkConfig::default(), |context| {
+            Ok(BroadcastNode::with_topology(context, topology))
+        })
+        .unwrap();
+
+        let node_ids: Vec<String> = sim.node_ids().map(str::to_owned).collect();
+        let (left, right) = node_ids.split_at(node_count / 2);
+        let left: Vec<&str> = left.iter().map(String::as_str).collect();
+        let right: Vec<&str> = right.iter().map(String::as_str).collect();
+
+        sim.partition(&left, &right);
+        for (value, node_id) in node_ids.iter().enumerate() {
+            sim.send(
+                "c1",
+                node_id,
+                json!({"type": "broadcast", "message": value}),
+            )
+            .unwrap();
+        }
+        // Several rounds of gossip, none of which may cross.
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        assert!(sim.dropped() > 0);
+        assert_eq!(read(&mut sim, "n0"), [0, 1, 2, 3]);
+        assert_eq!(read(&mut sim, "n7"), [4, 5, 6, 7]);
+
+        // What piled up on either side goes across, not just what's broadcast from now on.
+        sim.heal();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        let all: Vec<u64> = (0..node_count as u64).collect();
+        for node_id in &node_ids {
+            assert_eq!(read(&mut sim, node_id), all, "{node_id} did not converge");
+        }
+    }
+
+    #[test]
+    fn acknowledged_values_outlive_a_peer_that_restarts() {
+        let dir = std::env::temp_dir().join(format!("tempest-{}-restart", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let init =
+            |context: &NodeContext| Ok(BroadcastNode::with_topology(context, Topology::Line));
+        let mut sim = Simulation::new(3, NetworkConfig::default(), init).unwrap();
+        sim.persist_in(&dir);
+
+        let mut acked = Vec::new();
+        let mut broadcast = |sim: &mut Simulation<BroadcastNode>, node_id, value: u64| {
+            let reply = sim
+                .request(
+                    "c1",
+                    node_id,
+                    json!({"type": "broadcast", "message": value}),
+                    Duration::from_secs(1),
+                )
+                .unwrap()
+                .expect("Broadcasts are answered.");
+            assert_eq!(reply.body.payload["type"], "broadcast_ok");
+            acked.push(value);
+        };
+        for value in 0..5 {
+            broadcast(&mut sim, "n0", value);
+        }
+        // Long enough for both of its neighbors to acknowledge n1's gossip, and n1 theirs.
+        sim.run_for(Duration::from_secs(1)).unwrap();
+
+        sim.restart("n1", init).unwrap();
+        for value in 5..10 {
+            broadcast(&mut sim, "n2", value);
+        }
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        for node_id in ["n0", "n1", "n2"] {
+            assert_eq!(read(&mut sim, node_id), acked, "{node_id} lost a value");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn values_of_any_kind_are_kept_once_however_their_numbers_are_written() {
+        let mut sim = Simulation::new(2, NetworkConfig::default(), |context| {
+            Ok(BroadcastNode::with_topology(context, Topology::Line))
+        })
+        .unwrap();
+
+        let values = [
+            json!(1),
+            json!(1.0),
+            json!("1"),
+            json!(1.5),
+            json!(null),
+            json!(true),
+            json!([1, 2.0]),
+            json!([1.0, 2]),
+            json!({"a": 1, "b": [1.0]}),
+            json!({"b": [1], "a": 1.0}),
+        ];
+        for (index, value) in values.iter().enumerate() {
+            let node_id = format!("n{}", index % 2);
+            let broadcast = json!({"type": "broadcast", "message": value});
+            sim.send("c1", &node_id, broadcast).unwrap();
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+
+        let expected: BTreeSet<String> = [
+            "1",
+            "\"1\"",
+            "1.5",
+            "null",
+            "true",
+            "[1,2]",
+            r#"{"a":1,"b":[1]}"#,
+        ]
+        .map(str::to_owned)
+        .into();
+        for node_id in ["n0", "n1"] {
+            let reply = sim
+                .request(
+                    "c0",
+                    node_id,
+                    BroadcastPayload::Read {},
+                    Duration::from_secs(1),
+                )
+                .unwrap()
+                .expect("Reads are answered.");
+            let messages = reply.body.payload["messages"].as_array().unwrap();
+            let held: BTreeSet<String> = messages.iter().map(key).collect();
+            assert_eq!(messages.len(), expected.len(), "{node_id}: {messages:?}");
+            assert_eq!(held, expected, "{node_id}");
+        }
+    }
+
+    #[test]
+    fn gossip_intervals_vary_within_the_jitter_band() {
+        let gossip = GossipConfig {
+            jitter: 0.2,
+            seed: 3,
+            ..GossipConfig::default()
+        };
+        let intervals = |index| {
+            let node = BroadcastNode::new(&context(index, 2)).with_gossip(gossip);
+            (0..200)
+                .map(|_| node.tick_interval().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let first = intervals(0);
+        let (low, high) = (Duration::from_millis(80), Duration::from_millis(120));
+        assert!(first.iter().all(|interval| (low..=high).contains(interval)));
+        // Spread over the band, rather than bunched up anywhere in it.
+        assert!(*first.iter().min().unwrap() < Duration::from_millis(85));
+        assert!(*first.iter().max().unwrap() > Duration::from_millis(115));
+
+        // The same seed gives the same node the same waits, and other nodes others.
+        assert_eq!(intervals(0), first);
+        assert_ne!(intervals(1), first);
+    }
+
+    /// The path from `from` up to the root of a tree of node indices and down to `to`.
+    fn path(from: usize, to: usize, fanout: usize) -> Vec<usize> {
+        let ancestors = |mut index: usize| {
+            let mut ancestors = vec![index];
+            while index > 0 {
+                index = (index - 1) / fanout;
+                ancestors.push(index);
+            }
+            ancestors
+        };
+        let up = ancestors(from);
+        let down = ancestors(to);
+        let meet = *up.iter().find(|index| down.contains(index)).unwrap();
+
+        let mut path: Vec<usize> = up.into_iter().take_while(|index| *index != meet).collect();
+        path.push(meet);
+        let mut down: Vec<usize> = down
+            .into_iter()
+            .take_while(|index| *index != meet)
+            .collect();
+        down.reverse();
+        path.extend(down);
+        path
+    }
+
+    #[test]
+    fn double_tree_paths_share_no_node_in_between() {
+        for fanout in 2..=4 {
+            for node_count in 2..=20 {
+                let last = node_count - 1;
+                // Trees are laid out over the ids in sorted order, where `n10` comes before `n2`.
+                let mut sorted = context(0, node_count).node_ids;
+                sorted.sort();
+                let index_of = |id: &String| sorted.iter().position(|sorted| sorted == id);
+                let neighbors: Vec<BTreeSet<usize>> = sorted
+                    .iter()
+                    .map(|id| {
+                        let index = id[1..].parse().unwrap();
+                        Topology::DoubleTree { fanout }
+                            .neighbors(&context(index, node_count))
+                            .unwrap()
+                            .iter()
+                            .map(|id| index_of(id).unwrap())
+                            .collect()
+                    })
+                    .collect();
+
+                for from in 0..node_count {
+                    for to in 0..node_count {
+                        if from == to {
+                            continue;
+                        }
+                        let first = path(from, to, fanout);
+                        let second: Vec<usize> = path(last - from, last - to, fanout)
+                            .into_iter()
+                            .map(|index| last - index)
+                            .collect();
+
+                        for path in [&first, &second] {
+                            for link in path.windows(2) {
+                                assert!(neighbors[link[0]].contains(&link[1]), "{path:?}");
+                            }
+                        }
+                        let inner = |path: &[usize]| -> BTreeSet<usize> {
+                            path[1..path.len() - 1].iter().copied().collect()
+                        };
+                        assert!(
+                            inner(&first).is_disjoint(&inner(&second)),
+                            "{first:?} and {second:?} meet, {node_count} nodes, fanout {fanout}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_sizes_reported_head_for_every_value_everywhere() {
+        let config = NetworkConfig {
+            max_delay: Duration::from_millis(30),
+            drop_rate: 0.2,
+            seed: 4,
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(5, config, |context| {
+            Ok(BroadcastNode::with_topology(context, Topology::Line))
+        })
+        .unwrap();
+        for value in 0..10 {
+            let broadcast = json!({"type": "broadcast", "message": value});
+            sim.send("c1", &format!("n{}", value % 5), broadcast)
+                .unwrap();
+        }
+
+        let node_ids: Vec<String> = sim.node_ids().map(str::to_owned).collect();
+        let mut last_held = vec![0; node_ids.len()];
+        let mut rises = 0;
+        for _ in 0..30 {
+            sim.run_for(Duration::from_millis(100)).unwrap();
+            for (node_id, last) in node_ids.iter().zip(&mut last_held) {
+                let (held, _) = sim.node(node_id).unwrap().sizes();
+                assert!(held >= *last, "{node_id} went from {last} to {held}");
+                rises += usize::from(held > *last && *last > 0);
+                *last = held;
+            }
+        }
+        // Values got around a hop per round, some after being lost on the way.
+        assert!(rises > 0);
+        assert!(sim.dropped() > 0);
+
+        for node_id in &node_ids {
+            let (held, pending) = sim.node(node_id).unwrap().sizes();
+            assert_eq!(held, 10, "{node_id}");
+            assert!(
+                pending.iter().all(|(_, count)| *count == 0),
+                "{node_id}: {pending:?}"
+            );
+        }
+    }
+
+    /// How long a value broadcast at one end of a line of 5 nodes takes to reach them all,
+    /// with hops of 10ms.
+    fn propagation(gossip: GossipConfig) -> Duration {
+        let config = NetworkConfig {
+            min_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(5, config, |context| {
+            let node = BroadcastNode::with_topology(context, Topology::Line);
+            Ok(node.with_gossip(gossip))
+        })
+        .unwrap();
+
+        let start = sim.now();
+        sim.send("c1", "n0", json!({"type": "broadcast", "message": 1}))
+            .unwrap();
+        let value = key(&json!(1));
+        let everywhere = |sim: &Simulation<BroadcastNode>| {
+            let node_ids: Vec<&str> = sim.node_ids().collect();
+            node_ids
+                .iter()
+                .all(|node_id| sim.node(node_id).unwrap().messages.contains_key(&value))
+        };
+        while !everywhere(&sim) {
+            assert!(sim.now() - start < Duration::from_secs(5), "{gossip:?}");
+            sim.run_for(Duration::from_millis(1)).unwrap();
+        }
+        sim.now() - start
+    }
+
+    #[test]
+    fn low_latency_gossip_takes_a_value_everywhere_in_as_many_hops_as_it_needs() {
+        // The one hop from the client, and 4 along the line.
+        let latency = propagation(GossipConfig::low_latency());
+        assert!(latency <= Duration::from_millis(50), "{latency:?}");
+
+        // Waiting for a round at every hop instead.
+        let latency = propagation(GossipConfig::default());
+        assert!(latency >= Duration::from_millis(200), "{latency:?}");
+    }
+
+    /// How long each broadcast to a cluster of `node_count` took to be acknowledged.
+    fn ack_latencies(node_count: usize) -> Vec<Duration> {
+        let config = NetworkConfig {
+            min_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(node_count, config, |context| {
+            let node = BroadcastNode::with_topology(context, Topology::Grid);
+            Ok(node.with_gossip(GossipConfig::low_latency()))
+        })
+        .unwrap();
+
+        let node_ids: Vec<String> = sim.node_ids().map(str::to_owned).collect();
+        for (value, node_id) in node_ids.iter().cycle().take(10).enumerate() {
+            let payload = json!({"type": "broadcast", "message": value});
+            sim.send("c1", node_id, payload).unwrap();
+            sim.run_for(Duration::from_millis(30)).unwrap();
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+
+        let history = sim.history();
+        let sent: HashMap<usize, Instant> = history
+            .iter()
+            .filter(|(_, msg)| msg.source == "c1")
+            .map(|(at, msg)| (msg.body.id.unwrap(), *at))
+            .collect();
+        history
+            .iter()
+            .filter(|(_, msg)| msg.body.payload["type"] == "broadcast_ok")
+            .map(|(at, msg)| *at - sent[&msg.body.in_reply_to.unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn broadcasts_are_acknowledged_as_fast_in_any_cluster() {
+        // One trip there and one back, with no wait on any peer in between.
+        let round_trip = Duration::from_millis(20);
+        for node_count in [3, 25] {
+            let latencies = ack_latencies(node_count);
+            assert_eq!(latencies.len(), 10, "{node_count} nodes");
+            assert!(
+                latencies.iter().all(|latency| *latency == round_trip),
+                "{node_count} nodes: {latencies:?}",
+            );
+        }
+    }
+
+    fn payload() -> impl Strategy<Value = BroadcastPayload> {
+        let values = || prop::collection::vec(arbitrary::value(), 0..4);
+        prop_oneof![
+            arbitrary::value().prop_map(|message| BroadcastPayload::Broadcast { message }),
+            Just(BroadcastPayload::BroadcastOk {}),
+            Just(BroadcastPayload::Read {}),
+            values().prop_map(|messages| BroadcastPayload::ReadOk { messages }),
+            arbitrary::topology().prop_map(|topology| BroadcastPayload::Topology { topology }),
+            Just(BroadcastPayload::TopologyOk {}),
+            values().prop_map(|messages| BroadcastPayload::Gossip { messages }),
+            Just(BroadcastPayload::GossipOk {}),
+            prop::collection::vec(any::<String>(), 0..4)
+                .prop_map(|digest| BroadcastPayload::Sync { digest }),
+            values().prop_map(|messages| BroadcastPayload::SyncOk { messages }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
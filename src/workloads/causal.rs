@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::log;
+use crate::message::{Body, Message};
+use crate::node::{reject, Node, NodeContext};
+use crate::rpc::Rpc;
+
+/// How many values each node has broadcast, by node id.
+pub type VectorClock = BTreeMap<String, u64>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum CausalBroadcastPayload {
+    Broadcast {
+        message: Value,
+    },
+    BroadcastOk {},
+    Read {},
+    ReadOk {
+        messages: Vec<Value>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk {},
+
+    /// A value broadcast by `origin`, tagged with `origin`'s clock right after it was
+    /// broadcast.
+    Causal {
+        origin: String,
+        clock: VectorClock,
+        message: Value,
+    },
+    CausalOk {},
+}
+
+const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Broadcasts values so that every node delivers them in an order consistent with causality.
+///
+/// A value broadcast after a node delivered another is delivered after it everywhere. Each
+/// node sends the values it broadcasts straight to every peer, until they acknowledge them.
+/// A value whose clock says it depends on values not delivered here yet waits in a buffer
+/// until they are.
+pub struct CausalBroadcastNode {
+    self_id: String,
+    peers: Vec<String>,
+    clock: VectorClock,
+    /// Delivered values, in the order they were delivered.
+    delivered: Vec<Value>,
+    /// Values that arrived ahead of their causal predecessors, keyed by origin and sequence.
+    buffered: HashMap<(String, u64), (VectorClock, Value)>,
+    /// What this node broadcast, by sequence, and sequences each peer has acknowledged.
+    own: BTreeMap<u64, (VectorClock, Value)>,
+    acknowledged: HashMap<String, BTreeSet<u64>>,
+}
+
+impl CausalBroadcastNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self {
+            self_id: context.node_id.clone(),
+            peers: context.peers().map(str::to_owned).collect(),
+            clock: context
+                .node_ids
+                .iter()
+                .map(|node_id| (node_id.clone(), 0))
+                .collect(),
+            delivered: Vec::new(),
+            buffered: HashMap::new(),
+            own: BTreeMap::new(),
+            acknowledged: HashMap::new(),
+        }
+    }
+
+    fn seen(&self, node_id: &str) -> u64 {
+        self.clock.get(node_id).copied().unwrap_or(0)
+    }
+
+    /// Whether `clock`, of a value from `origin`, is the next one from `origin` and depends on
+    /// nothing from anyone else that isn't delivered yet.
+    fn deliverable(&self, origin: &str, clock: &VectorClock) -> bool {
+        clock.iter().all(|(node_id, count)| {
+            if node_id == origin {
+                *count == self.seen(node_id) + 1
+            } else {
+                *count <= self.seen(node_id)
+            }
+        })
+    }
+
+    /// Delivers or buffers a value from a peer, then delivers whatever it unblocked.
+    fn receive(&mut self, origin: String, clock: VectorClock, message: Value) {
+        let sequence = clock.get(&origin).copied().unwrap_or(0);
+        if sequence <= self.seen(&origin) {
+            return;
+        }
+        self.buffered.insert((origin, sequence), (clock, message));
+
+        while let Some(key) = self
+            .buffered
+            .iter()
+            .find(|((origin, _), (clock, _))| self.deliverable(origin, clock))
+            .map(|(key, _)| key.clone())
+        {
+            let (_, message) = self.buffered.remove(&key).expect("Key was just found.");
+            *self.clock.entry(key.0).or_default() += 1;
+            self.delivered.push(message);
+        }
+    }
+}
+
+impl Node for CausalBroadcastNode {
+    type Payload = CausalBroadcastPayload;
+
+    fn step(
+        &mut self,
+        message: Message<CausalBroadcastPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        let payload = match message.body.payload {
+            CausalBroadcastPayload::Broadcast { ref message } => {
+                let sequence = self.seen(&self.self_id) + 1;
+                self.clock.insert(self.self_id.clone(), sequence);
+                self.delivered.push(message.clone());
+                self.own
+                    .insert(sequence, (self.clock.clone(), message.clone()));
+                CausalBroadcastPayload::BroadcastOk {}
+            }
+
+            CausalBroadcastPayload::Read {} => CausalBroadcastPayload::ReadOk {
+                messages: self.delivered.clone(),
+            },
+
+            // Every value goes straight from its origin to every peer.
+            CausalBroadcastPayload::Topology { .. } => CausalBroadcastPayload::TopologyOk {},
+
+            CausalBroadcastPayload::Causal {
+                ref origin,
+                ref clock,
+                message: ref value,
+            } => {
+                self.receive(origin.clone(), clock.clone(), value.clone());
+                CausalBroadcastPayload::CausalOk {}
+            }
+
+            _ => return reject(&message, rpc, "Unsupported message type."),
+        };
+
+        rpc.reply(&message, payload)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(RESEND_INTERVAL)
+    }
+
+    fn tick(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        for peer in &self.peers {
+            let acknowledged = self.acknowledged.get(peer);
+
+            for (sequence, (clock, value)) in &self.own {
+                if acknowledged.is_some_and(|acknowledged| acknowledged.contains(sequence)) {
+                    continue;
+                }
+
+                let causal = Message {
+                    source: self.self_id.clone(),
+                    destination: peer.clone(),
+                    body: Body {
+                        id: Some(rpc.next_id()),
+                        in_reply_to: None,
+                        payload: CausalBroadcastPayload::Causal {
+                            origin: self.self_id.clone(),
+                            clock: clock.clone(),
+                            message: value.clone(),
+                        },
+                    },
+                };
+
+                let (peer, sequence) = (peer.clone(), *sequence);
+                rpc.call(causal, move |node, _causal_ok, _rpc| {
+                    node.acknowledged.entry(peer).or_default().insert(sequence);
+                    Ok(())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_shutdown(&mut self, _rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        log::info!(
+            "Shutting down with {} values delivered, {} still waiting on their predecessors.",
+            self.delivered.len(),
+            self.buffered.len()
+        );
+
+        Ok(())
+    }
+
+    fn summary(&self) -> Option<Value> {
+        Some(serde_json::json!({
+            "delivered": self.delivered.len(),
+            "buffered": self.buffered.len(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::message::arbitrary;
+
+    fn clock(counts: [u64; 3]) -> VectorClock {
+        ["n0", "n1", "n2"]
+            .into_iter()
+            .map(str::to_owned)
+            .zip(counts)
+            .collect()
+    }
+
+    #[test]
+    fn values_wait_for_what_they_causally_depend_on() {
+        let context = NodeContext {
+            node_id: "n2".to_owned(),
+            node_ids: vec!["n0".to_owned(), "n1".to_owned(), "n2".to_owned()],
+        };
+        let mut node = CausalBroadcastNode::new(&context);
+
+        // n1 broadcast "reply" after delivering n0's "question", but it got here first.
+        node.receive("n1".to_owned(), clock([1, 1, 0]), "reply".into());
+        assert!(node.delivered.is_empty());
+        // n0's second value, ahead of its first.
+        node.receive("n0".to_owned(), clock([2, 0, 0]), "follow-up".into());
+        assert!(node.delivered.is_empty());
+
+        node.receive("n0".to_owned(), clock([1, 0, 0]), "question".into());
+        let delivered: Vec<&str> = node.delivered.iter().filter_map(Value::as_str).collect();
+        assert_eq!(delivered.len(), 3);
+        assert_eq!(delivered[0], "question");
+        let after = |value| delivered.iter().position(|v| *v == value).unwrap();
+        assert!(after("reply") > after("question"));
+        assert!(after("follow-up") > after("question"));
+        assert!(node.buffered.is_empty());
+
+        // Delivered already, so not again.
+        node.receive("n0".to_owned(), clock([1, 0, 0]), "question".into());
+        assert_eq!(node.delivered.len(), 3);
+    }
+
+    fn payload() -> impl Strategy<Value = CausalBroadcastPayload> {
+        let clock = prop::collection::btree_map(arbitrary::node_id(), any::<u64>(), 0..4);
+        prop_oneof![
+            arbitrary::value().prop_map(|message| CausalBroadcastPayload::Broadcast { message }),
+            Just(CausalBroadcastPayload::BroadcastOk {}),
+            Just(CausalBroadcastPayload::Read {}),
+            prop::collection::vec(arbitrary::value(), 0..4)
+                .prop_map(|messages| CausalBroadcastPayload::ReadOk { messages }),
+            arbitrary::topology()
+                .prop_map(|topology| CausalBroadcastPayload::Topology { topology }),
+            Just(CausalBroadcastPayload::TopologyOk {}),
+            (arbitrary::node_id(), clock, arbitrary::value()).prop_map(
+                |(origin, clock, message)| CausalBroadcastPayload::Causal {
+                    origin,
+                    clock,
+                    message,
+                }
+            ),
+            Just(CausalBroadcastPayload::CausalOk {}),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
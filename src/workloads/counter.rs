@@ -0,0 +1,377 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::crdt::GCounter;
+use crate::dedup::Dedup;
+use crate::kv::{KvError, SEQ_KV};
+use crate::log;
+use crate::message::{error_reply, Message};
+use crate::node::{reject, Node, NodeContext, Persistent};
+use crate::rpc::{RetryPolicy, Rpc};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum CounterPayload {
+    Add {
+        delta: u64,
+    },
+    AddOk {},
+    Read {},
+    ReadOk {
+        value: u64,
+    },
+
+    /// Asks a peer for its partial count.
+    Partial {},
+    PartialOk {
+        value: u64,
+    },
+}
+
+/// How many recent requests to recognize when they're delivered again.
+const DEDUP_CAPACITY: usize = 1024;
+
+/// How long `seq-kv` may take to serve this node's own writes back to it, before a read of its
+/// partial count stops preferring what it wrote.
+const OWN_WRITES_WINDOW: Duration = Duration::from_secs(1);
+
+/// A grow-only counter kept in `seq-kv`, one entry per node.
+///
+/// A read asks every peer for its partial count rather than reading them from `seq-kv`, which
+/// may serve them stale. A peer that doesn't answer counts with the highest partial count this
+/// node has heard from it before, since a grow-only count can only have grown since.
+pub struct CounterNode {
+    self_id: String,
+    node_ids: Vec<String>,
+    /// The reply to every recent request, or `None` while it's being worked on.
+    answered: Dedup<Option<CounterPayload>>,
+    /// This node's partial count as of its last add, which is all it persists.
+    partial: u64,
+    /// Every node's partial count, as of the last time it was heard.
+    observed: GCounter,
+}
+
+impl CounterNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self {
+            self_id: context.node_id.clone(),
+            node_ids: context.node_ids.clone(),
+            answered: Dedup::new(DEDUP_CAPACITY),
+            partial: 0,
+            observed: GCounter::new(),
+        }
+    }
+
+    /// Adds `delta` to this node's partial count, retrying whenever a concurrent `add` got
+    /// there first, and answers `request` once it's done.
+    fn add(
+        &mut self,
+        request: Message<CounterPayload>,
+        delta: u64,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        self.answered.insert(&request, None);
+        rpc.cas_update_then(
+            SEQ_KV,
+            &self.self_id,
+            RetryPolicy::default(),
+            move |current| current.unwrap_or(0) + delta,
+            move |node, partial, rpc| {
+                let result = partial.map(|partial| {
+                    // Adds that overlap may finish out of order.
+                    node.partial = node.partial.max(partial);
+                    CounterPayload::AddOk {}
+                });
+                node.answer(&request, result, rpc)
+            },
+        )
+    }
+
+    /// Sums the partial counts of all nodes, and answers `request` with the total.
+    fn read(
+        &mut self,
+        request: Message<CounterPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        self.answered.insert(&request, None);
+        let peers: Vec<String> = self
+            .node_ids
+            .iter()
+            .filter(|node_id| **node_id != self.self_id)
+            .cloned()
+            .collect();
+
+        let partial = |reply| match reply {
+            CounterPayload::PartialOk { value } => Some(value),
+            _ => None,
+        };
+        rpc.gather_then(
+            &peers.clone(),
+            CounterPayload::Partial {},
+            partial,
+            move |node, partials, rpc| {
+                node.observed.observe(&node.self_id, node.partial);
+                // Those that didn't answer count as 0, which leaves what was heard from them before.
+                for (peer, partial) in peers.iter().zip(partials) {
+                    node.observed.observe(peer, partial);
+                }
+                let value = node.observed.value();
+                node.answer(&request, Ok(CounterPayload::ReadOk { value }), rpc)
+            },
+        )
+    }
+
+    fn answer(
+        &mut self,
+        request: &Message<CounterPayload>,
+        result: Result<CounterPayload, KvError>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        match result {
+            Ok(payload) => {
+                self.answered.insert(request, Some(payload.clone()));
+                rpc.reply(request, payload)
+            }
+
+            // Tell the client rather than taking the node down. It's free to try again.
+            Err(error) => {
+                self.answered.remove(request);
+                rpc.send(&error_reply(request, error.code(), error.to_string()))
+            }
+        }
+    }
+}
+
+impl Node for CounterNode {
+    type Payload = CounterPayload;
+
+    /// Puts back a restored partial count that `seq-kv` lost, e.g. because it restarted too.
+    fn init(&mut self, _context: &NodeContext, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        // A stale read of the partial count would fail every add's cas until seq-kv caught up.
+        rpc.read_your_writes(OWN_WRITES_WINDOW);
+
+        if self.partial == 0 {
+            return Ok(());
+        }
+
+        let partial = self.partial;
+        match rpc.cas_update(SEQ_KV, &self.self_id, RetryPolicy::default(), |current| {
+            current.unwrap_or(0).max(partial)
+        }) {
+            Ok(partial) => self.partial = partial,
+            // The next add writes it back anyway.
+            Err(error) => log::warning!("Could not restore the partial count: {error}"),
+        }
+        Ok(())
+    }
+
+    fn step(
+        &mut self,
+        message: Message<CounterPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        if let CounterPayload::Partial {} = message.body.payload {
+            let value = self.partial;
+            return rpc.reply(&message, CounterPayload::PartialOk { value });
+        }
+
+        // Maelstrom may deliver a request again, which must not add its delta twice.
+        match self.answered.get(&message) {
+            Some(Some(payload)) => {
+                let payload = payload.clone();
+                return rpc.reply(&message, payload);
+            }
+            // The reply goes out once the first delivery is done.
+            Some(None) => return Ok(()),
+            None => {}
+        }
+
+        match message.body.payload {
+            CounterPayload::Add { delta } => self.add(message, delta, rpc),
+
+            CounterPayload::Read {} => self.read(message, rpc),
+
+            _ => reject(&message, rpc, "Unsupported message type."),
+        }
+    }
+
+    fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+        Some(self)
+    }
+}
+
+impl Persistent for CounterNode {
+    fn snapshot(&self) -> Value {
+        serde_json::json!({ "partial": self.partial })
+    }
+
+    fn restore(&mut self, state: Value) -> anyhow::Result<()> {
+        #[derive(Deserialize)]
+        struct State {
+            partial: u64,
+        }
+
+        let State { partial } = serde_json::from_value(state)?;
+        self.partial = partial;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::message::Body;
+    use crate::sim::{NetworkConfig, Simulation};
+    use crate::transport::InMemoryTransport;
+
+    #[test]
+    fn overlapping_adds_all_count() {
+        let mut sim = Simulation::new(2, NetworkConfig::default(), |context| {
+            Ok(CounterNode::new(context))
+        })
+        .unwrap();
+
+        // Sent together, so that they race for the same key.
+        for delta in 1..=5 {
+            sim.send("c1", "n0", CounterPayload::Add { delta }).unwrap();
+            sim.send("c2", "n1", CounterPayload::Add { delta }).unwrap();
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+
+        let add_oks = sim
+            .history()
+            .into_iter()
+            .filter(|(_, msg)| msg.body.payload["type"] == "add_ok")
+            .count();
+        assert_eq!(add_oks, 10);
+
+        let read = sim
+            .request("c3", "n0", CounterPayload::Read {}, Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.body.payload["value"], 30);
+    }
+
+    #[test]
+    fn a_restarted_node_keeps_the_adds_it_saved() {
+        let timeout = Duration::from_secs(1);
+        let mut sim = Simulation::new(1, NetworkConfig::default(), |context| {
+            Ok(CounterNode::new(context))
+        })
+        .unwrap();
+        for delta in [2, 3] {
+            sim.request("c1", "n0", CounterPayload::Add { delta }, timeout)
+                .unwrap()
+                .unwrap();
+        }
+        // As the state file holds it.
+        let saved = sim.node("n0").unwrap().snapshot().to_string();
+
+        // A fresh seq-kv too, which has lost everything.
+        let mut sim = Simulation::new(1, NetworkConfig::default(), |context| {
+            let mut node = CounterNode::new(context);
+            node.restore(serde_json::from_str(&saved)?)?;
+            Ok(node)
+        })
+        .unwrap();
+        sim.request("c1", "n0", CounterPayload::Add { delta: 1 }, timeout)
+            .unwrap()
+            .unwrap();
+        let read = sim
+            .request("c2", "n0", CounterPayload::Read {}, timeout)
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.body.payload["value"], 6);
+    }
+
+    #[test]
+    fn an_add_delivered_again_while_under_way_is_not_applied_twice() {
+        let transport = InMemoryTransport::new();
+        let mut rpc = Rpc::with_transport("n0", transport.clone());
+        let context = NodeContext {
+            node_id: "n0".to_owned(),
+            node_ids: vec!["n0".to_owned()],
+        };
+        let mut node = CounterNode::new(&context);
+
+        let add = Message {
+            source: "c1".to_owned(),
+            destination: "n0".to_owned(),
+            body: Body {
+                id: Some(1),
+                in_reply_to: None,
+                payload: CounterPayload::Add { delta: 3 },
+            },
+        };
+        node.step(add.clone(), &mut rpc).unwrap();
+        node.step(add, &mut rpc).unwrap();
+
+        // Just the one read of the partial count, and no reply until it's answered.
+        let sent = transport.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].destination, SEQ_KV);
+    }
+
+    fn read(sim: &mut Simulation<CounterNode>, node_id: &str) -> Value {
+        let reply = sim
+            .request(
+                "c0",
+                node_id,
+                CounterPayload::Read {},
+                Duration::from_secs(2),
+            )
+            .unwrap()
+            .expect("Reads are answered, peers or not.");
+        reply.body.payload["value"].clone()
+    }
+
+    #[test]
+    fn a_peer_that_does_not_answer_counts_as_last_heard() {
+        let mut sim = Simulation::new(3, NetworkConfig::default(), |context| {
+            Ok(CounterNode::new(context))
+        })
+        .unwrap();
+        for (delta, node_id) in [(1, "n0"), (2, "n1"), (3, "n2")] {
+            sim.send("c1", node_id, CounterPayload::Add { delta })
+                .unwrap();
+        }
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        assert_eq!(read(&mut sim, "n0"), 6);
+
+        sim.partition(&["n2"], &["n0", "n1"]);
+        sim.send("c1", "n2", CounterPayload::Add { delta: 10 })
+            .unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+
+        // n0 counts n2 as it last heard it, and n1, which never heard from it, as 0.
+        assert_eq!(read(&mut sim, "n0"), 6);
+        assert_eq!(read(&mut sim, "n1"), 3);
+
+        sim.heal();
+        assert_eq!(read(&mut sim, "n1"), 16);
+    }
+
+    fn payload() -> impl Strategy<Value = CounterPayload> {
+        prop_oneof![
+            any::<u64>().prop_map(|delta| CounterPayload::Add { delta }),
+            Just(CounterPayload::AddOk {}),
+            Just(CounterPayload::Read {}),
+            any::<u64>().prop_map(|value| CounterPayload::ReadOk { value }),
+            Just(CounterPayload::Partial {}),
+            any::<u64>().prop_map(|value| CounterPayload::PartialOk { value }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::message::{error_reply, ErrorCode, Message};
+use crate::node::{reject, Node};
+use crate::rpc::Rpc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum EchoPayload {
+    /// Taken as any JSON, missing being null, so that a bad `echo` is answered with an error
+    /// rather than refused as an unknown message.
+    Echo {
+        #[serde(default)]
+        echo: Value,
+    },
+    EchoOk {
+        echo: String,
+    },
+}
+
+/// Replies to every `echo` with the same text, and to one whose `echo` isn't text with a
+/// `malformed-request` error.
+#[derive(Default)]
+pub struct EchoNode;
+
+impl Node for EchoNode {
+    type Payload = EchoPayload;
+
+    fn step(&mut self, message: Message<EchoPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        match &message.body.payload {
+            EchoPayload::Echo {
+                echo: Value::String(echo),
+            } => {
+                let echo = echo.clone();
+                rpc.reply(&message, EchoPayload::EchoOk { echo })?;
+            }
+
+            EchoPayload::Echo { echo } => {
+                let text = match echo {
+                    Value::Null => "Echo is missing its echo.".to_owned(),
+                    echo => format!("Can only echo text, not {echo}."),
+                };
+                rpc.send(&error_reply(
+                    &message,
+                    ErrorCode::MalformedRequest.into(),
+                    text,
+                ))?;
+            }
+
+            _ => reject(&message, rpc, "Unsupported message type.")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::message::arbitrary;
+    use crate::runtime::run_with_transport;
+    use crate::transport::InMemoryTransport;
+
+    fn payload() -> impl Strategy<Value = EchoPayload> {
+        prop_oneof![
+            arbitrary::value().prop_map(|echo| EchoPayload::Echo { echo }),
+            any::<String>().prop_map(|echo| EchoPayload::EchoOk { echo }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+
+    /// Runs a node through `init` and then an `echo` with `body`, returning what it sent back
+    /// to the `echo`.
+    fn answer(body: Value) -> Message<Value> {
+        let init = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] },
+        });
+        let echo = json!({ "src": "c1", "dest": "n1", "body": body });
+        let transport = InMemoryTransport::new();
+
+        run_with_transport(
+            Cursor::new(format!("{init}\n{echo}\n")),
+            transport.clone(),
+            Arc::new(SystemClock),
+            |_| Ok(EchoNode),
+        )
+        .unwrap();
+
+        let [init_ok, reply] = transport.sent().try_into().unwrap();
+        assert_eq!(init_ok.body.payload["type"], "init_ok");
+        reply
+    }
+
+    #[test]
+    fn an_echo_without_its_echo_is_malformed() {
+        let reply = answer(json!({ "type": "echo", "msg_id": 2 }));
+
+        assert_eq!(reply.body.in_reply_to, Some(2));
+        assert_eq!(reply.body.payload["type"], "error");
+        assert_eq!(reply.body.payload["code"], 12);
+    }
+
+    #[test]
+    fn an_echo_of_a_number_is_malformed() {
+        let reply = answer(json!({ "type": "echo", "msg_id": 2, "echo": 1 }));
+
+        assert_eq!(reply.body.in_reply_to, Some(2));
+        assert_eq!(reply.body.payload["type"], "error");
+        assert_eq!(reply.body.payload["code"], 12);
+    }
+}
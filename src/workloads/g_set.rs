@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::broadcast::key;
+use crate::crdt::GSet;
+use crate::message::{Body, Message};
+use crate::node::{reject, Node, NodeContext};
+use crate::rpc::Rpc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum GSetPayload {
+    Add {
+        element: Value,
+    },
+    AddOk {},
+    Read {},
+    ReadOk {
+        value: Vec<Value>,
+    },
+
+    /// Elements the receiver isn't known to have, by their JSON text.
+    Merge {
+        elements: GSet<String>,
+    },
+    MergeOk {},
+}
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A grow-only set, replicated on every node as a [`GSet`].
+///
+/// Each node adds to and reads its own copy. Like broadcast, every tick it sends each peer the
+/// [`delta`](GSet::delta) between its copy and what the peer is known to have, until the peer
+/// acknowledges it, so copies left apart by a partition converge to their union once it heals.
+/// Elements are kept as their JSON text, under the same key as broadcast values, so `1` and
+/// `1.0` are the same element.
+pub struct GSetNode {
+    self_id: String,
+    peers: Vec<String>,
+    set: GSet<String>,
+    /// What each peer is known to have, from its gossip and its acknowledgements of ours.
+    known: HashMap<String, GSet<String>>,
+}
+
+impl GSetNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self {
+            self_id: context.node_id.clone(),
+            peers: context.peers().map(str::to_owned).collect(),
+            set: GSet::new(),
+            known: HashMap::new(),
+        }
+    }
+
+    fn elements(&self) -> anyhow::Result<Vec<Value>> {
+        self.set
+            .iter()
+            .map(|element| Ok(serde_json::from_str(element)?))
+            .collect()
+    }
+}
+
+impl Node for GSetNode {
+    type Payload = GSetPayload;
+
+    fn step(&mut self, message: Message<GSetPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        let payload = match message.body.payload {
+            GSetPayload::Add { ref element } => {
+                self.set.insert(key(element));
+                GSetPayload::AddOk {}
+            }
+
+            GSetPayload::Read {} => GSetPayload::ReadOk {
+                value: self.elements()?,
+            },
+
+            GSetPayload::Merge { ref elements } => {
+                self.set.merge(elements);
+                self.known
+                    .entry(message.source.clone())
+                    .or_default()
+                    .merge(elements);
+                GSetPayload::MergeOk {}
+            }
+
+            _ => return reject(&message, rpc, "Unsupported message type."),
+        };
+
+        rpc.reply(&message, payload)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(GOSSIP_INTERVAL)
+    }
+
+    fn tick(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        for peer in &self.peers {
+            let elements = match self.known.get(peer) {
+                Some(known) => self.set.delta(known),
+                None => self.set.clone(),
+            };
+            if elements.is_empty() {
+                continue;
+            }
+
+            let merge = Message {
+                source: self.self_id.clone(),
+                destination: peer.clone(),
+                body: Body {
+                    id: Some(rpc.next_id()),
+                    in_reply_to: None,
+                    payload: GSetPayload::Merge {
+                        elements: elements.clone(),
+                    },
+                },
+            };
+
+            let peer = peer.clone();
+            rpc.call(merge, move |node, _merge_ok, _rpc| {
+                node.known.entry(peer).or_default().merge(&elements);
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn summary(&self) -> Option<Value> {
+        Some(serde_json::json!({ "elements": self.set.len() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::sim::{NetworkConfig, Simulation};
+
+    #[test]
+    fn every_node_comes_to_hold_the_union_of_what_was_added_anywhere() {
+        let config = NetworkConfig {
+            max_delay: Duration::from_millis(20),
+            drop_rate: 0.2,
+            seed: 9,
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(3, config, |context| Ok(GSetNode::new(context))).unwrap();
+
+        sim.partition(&["n0"], &["n1", "n2"]);
+        let added = [
+            ("n0", vec![json!(1), json!("one")]),
+            ("n1", vec![json!(2), json!([2])]),
+            ("n2", vec![json!(3), json!({"three": 3})]),
+        ];
+        for (node_id, elements) in &added {
+            for element in elements {
+                let add = GSetPayload::Add {
+                    element: element.clone(),
+                };
+                sim.send("c1", node_id, add).unwrap();
+            }
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        sim.heal();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        let union: BTreeSet<String> = added
+            .iter()
+            .flat_map(|(_, elements)| elements.iter().map(key))
+            .collect();
+        for node_id in ["n0", "n1", "n2"] {
+            let read = sim
+                .request("c2", node_id, GSetPayload::Read {}, Duration::from_secs(1))
+                .unwrap()
+                .expect("Reads are answered.");
+            let held: BTreeSet<String> = read.body.payload["value"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(key)
+                .collect();
+            assert_eq!(held, union, "{node_id}");
+        }
+        assert!(sim.dropped() > 0);
+    }
+
+    fn payload() -> impl Strategy<Value = GSetPayload> {
+        prop_oneof![
+            arbitrary::value().prop_map(|element| GSetPayload::Add { element }),
+            Just(GSetPayload::AddOk {}),
+            Just(GSetPayload::Read {}),
+            prop::collection::vec(arbitrary::value(), 0..4)
+                .prop_map(|value| GSetPayload::ReadOk { value }),
+            prop::collection::vec(any::<String>(), 0..4).prop_map(|elements| {
+                GSetPayload::Merge {
+                    elements: elements.into_iter().collect(),
+                }
+            }),
+            Just(GSetPayload::MergeOk {}),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
@@ -0,0 +1,706 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::kv::{KvError, LIN_KV};
+use crate::log;
+use crate::message::{error_reply, Body, ErrorCode, Message};
+use crate::node::{reject, Event, Node, NodeContext};
+use crate::rpc::{RetryPolicy, Rpc};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KafkaPayload {
+    Send {
+        key: String,
+        msg: Value,
+    },
+    SendOk {
+        offset: u64,
+    },
+    Poll {
+        offsets: HashMap<String, u64>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(u64, Value)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, u64>,
+    },
+    CommitOffsetsOk {},
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, u64>,
+    },
+
+    /// What a key's owner answers when it can't serve a forwarded request.
+    Error {
+        code: u32,
+        text: String,
+    },
+}
+
+/// Most entries a single `poll` returns per key, so one poll can't turn into an unbounded
+/// number of lin-kv reads.
+const POLL_LIMIT: u64 = 32;
+
+/// Logs and committed offsets by key, keeping to Kafka's rules for offsets.
+///
+/// A key's first entry is at offset 0 and each entry after it at the next offset. Polling from
+/// an offset includes the entry at that offset. Committing an offset below the one committed
+/// already changes nothing, so commits never go backwards.
+#[derive(Debug, Default)]
+pub struct Offsets {
+    logs: HashMap<String, BTreeMap<u64, Value>>,
+    committed: HashMap<String, u64>,
+}
+
+impl Offsets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `msg` to `key`'s log, returning its offset: one past the last entry known.
+    pub fn append(&mut self, key: &str, msg: Value) -> u64 {
+        let log = self.logs.entry(key.to_owned()).or_default();
+        let offset = log.last_key_value().map_or(0, |(offset, _)| offset + 1);
+        log.insert(offset, msg);
+        offset
+    }
+
+    /// Records the entry at `offset`, handed out elsewhere.
+    pub fn insert(&mut self, key: &str, offset: u64, msg: Value) {
+        self.logs
+            .entry(key.to_owned())
+            .or_default()
+            .insert(offset, msg);
+    }
+
+    /// The offset of the last entry known for `key`.
+    pub fn last(&self, key: &str) -> Option<u64> {
+        let (offset, _) = self.logs.get(key)?.last_key_value()?;
+        Some(*offset)
+    }
+
+    pub fn get(&self, key: &str, offset: u64) -> Option<&Value> {
+        self.logs.get(key)?.get(&offset)
+    }
+
+    /// The entries from `from` on, up to the first offset with no entry known, and at most 32:
+    /// as many as a poll answers with.
+    pub fn poll(&self, key: &str, from: u64) -> Vec<(u64, Value)> {
+        let Some(log) = self.logs.get(key) else {
+            return Vec::new();
+        };
+
+        log.range(from..from + POLL_LIMIT)
+            .zip(from..)
+            .take_while(|((offset, _), expected)| **offset == *expected)
+            .map(|((offset, msg), _)| (*offset, msg.clone()))
+            .collect()
+    }
+
+    /// Commits `key` up to `offset`, returning whether that moved the committed offset.
+    pub fn commit(&mut self, key: &str, offset: u64) -> bool {
+        match self.committed.get_mut(key) {
+            Some(committed) if *committed >= offset => false,
+            Some(committed) => {
+                *committed = offset;
+                true
+            }
+            None => {
+                self.committed.insert(key.to_owned(), offset);
+                true
+            }
+        }
+    }
+
+    pub fn committed(&self, key: &str) -> Option<u64> {
+        self.committed.get(key).copied()
+    }
+}
+
+/// Kafka-style append-only logs, kept in `lin-kv` so that every node sees the same offsets.
+///
+/// Each key is owned by one node, and clients' `send`, `poll` and `commit_offsets` for a key
+/// are forwarded to its owner. Only the owner then appends to a key's log, so sends to it no
+/// longer race for offsets, and polls are mostly served from the owner's cache.
+pub struct KafkaNode {
+    self_id: String,
+    node_ids: Vec<String>,
+    // Entries never change once written, so anything seen in lin-kv can be kept around, as
+    // can the highest commit seen.
+    offsets: Offsets,
+    // Client requests waiting on the parts forwarded to other owners, by token, and the token
+    // of each part still awaiting a reply, by its `msg_id`.
+    proxied: HashMap<u64, Proxied>,
+    parts: HashMap<usize, u64>,
+    next_token: u64,
+}
+
+/// A client request split up among the owners of its keys.
+struct Proxied {
+    request: Message<KafkaPayload>,
+    remaining: usize,
+    reply: Option<KafkaPayload>,
+}
+
+/// The logs live in lin-kv so that every node sees the same offsets:
+///
+/// - `msg/<key>/<offset>` holds the entry at that offset, as `[msg]`,
+/// - `offset/<key>` holds about the latest offset taken for `key`, as a hint where to append,
+/// - `committed/<key>` holds the committed offset.
+impl KafkaNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self {
+            self_id: context.node_id.clone(),
+            node_ids: context.node_ids.clone(),
+            offsets: Offsets::new(),
+            proxied: HashMap::new(),
+            parts: HashMap::new(),
+            next_token: 0,
+        }
+    }
+
+    /// The node owning `key`, by rendezvous hashing, which every node agrees on.
+    fn owner(&self, key: &str) -> &str {
+        self.node_ids
+            .iter()
+            .max_by_key(|node_id| {
+                let mut hasher = DefaultHasher::new();
+                (key, node_id).hash(&mut hasher);
+                hasher.finish()
+            })
+            .map_or(&self.self_id, String::as_str)
+    }
+
+    /// Splits a request up by the owners of its keys, or returns `None` if this node owns them
+    /// all.
+    fn split(&self, payload: &KafkaPayload) -> Option<Vec<(String, KafkaPayload)>> {
+        let by_owner = |offsets: &HashMap<String, u64>| {
+            let mut parts: HashMap<String, HashMap<String, u64>> = HashMap::new();
+            for (key, offset) in offsets {
+                parts
+                    .entry(self.owner(key).to_owned())
+                    .or_default()
+                    .insert(key.clone(), *offset);
+            }
+            parts
+        };
+
+        let parts: Vec<(String, KafkaPayload)> = match payload {
+            KafkaPayload::Send { key, .. } => vec![(self.owner(key).to_owned(), payload.clone())],
+            KafkaPayload::Poll { offsets } => by_owner(offsets)
+                .into_iter()
+                .map(|(owner, offsets)| (owner, KafkaPayload::Poll { offsets }))
+                .collect(),
+            KafkaPayload::CommitOffsets { offsets } => by_owner(offsets)
+                .into_iter()
+                .map(|(owner, offsets)| (owner, KafkaPayload::CommitOffsets { offsets }))
+                .collect(),
+            _ => return None,
+        };
+
+        parts
+            .iter()
+            .any(|(owner, _)| *owner != self.self_id)
+            .then_some(parts)
+    }
+
+    /// Serves this node's share of `request` and forwards the rest to the owners, answering the
+    /// client once every part has been answered.
+    fn proxy(
+        &mut self,
+        request: Message<KafkaPayload>,
+        parts: Vec<(String, KafkaPayload)>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        let token = self.next_token;
+        self.next_token += 1;
+
+        let mut proxied = Proxied {
+            request,
+            remaining: 0,
+            reply: None,
+        };
+
+        for (owner, part) in parts {
+            if owner == self.self_id {
+                match self.handle(&part, rpc) {
+                    Some(Ok(reply)) => merge(&mut proxied.reply, reply),
+                    Some(Err(error)) => {
+                        let request = &proxied.request;
+                        return rpc.send(&error_reply(request, error.code(), error.to_string()));
+                    }
+                    None => {}
+                }
+                continue;
+            }
+
+            let id = rpc.next_id();
+            let forward = Message {
+                source: self.self_id.clone(),
+                destination: owner,
+                body: Body {
+                    id: Some(id),
+                    in_reply_to: None,
+                    payload: part,
+                },
+            };
+
+            self.parts.insert(id, token);
+            proxied.remaining += 1;
+            rpc.call(forward, move |node, reply, rpc| {
+                node.parts.remove(&id);
+                node.relay(token, reply.body.payload, rpc)
+            })?;
+        }
+
+        self.proxied.insert(token, proxied);
+        Ok(())
+    }
+
+    /// Takes in an owner's reply to one part of a proxied request.
+    fn relay(
+        &mut self,
+        token: u64,
+        reply: KafkaPayload,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        // Gone if another part failed already.
+        let Some(proxied) = self.proxied.get_mut(&token) else {
+            return Ok(());
+        };
+
+        if let KafkaPayload::Error { code, text } = reply {
+            let proxied = self.forget(token).expect("Token was just found.");
+            return rpc.send(&error_reply(&proxied.request, code, text));
+        }
+
+        merge(&mut proxied.reply, reply);
+        proxied.remaining -= 1;
+        if proxied.remaining > 0 {
+            return Ok(());
+        }
+
+        let proxied = self.proxied.remove(&token).expect("Token was just found.");
+        match proxied.reply {
+            Some(reply) => rpc.reply(&proxied.request, reply),
+            None => Ok(()),
+        }
+    }
+
+    /// Gives up on a proxied request, whose parts still underway are then answered to no one.
+    fn forget(&mut self, token: u64) -> Option<Proxied> {
+        self.parts.retain(|_, part| *part != token);
+        self.proxied.remove(&token)
+    }
+
+    /// Serves a request against lin-kv, or returns `None` if it isn't one.
+    fn handle(
+        &mut self,
+        payload: &KafkaPayload,
+        rpc: &mut Rpc<Self>,
+    ) -> Option<Result<KafkaPayload, KvError>> {
+        let known = &mut self.offsets;
+        Some(match payload {
+            KafkaPayload::Send { key, msg } => {
+                Self::send(rpc, known, key, msg).map(|offset| KafkaPayload::SendOk { offset })
+            }
+
+            KafkaPayload::Poll { offsets } => offsets
+                .iter()
+                .map(|(key, &from)| Ok((key.clone(), Self::poll(rpc, known, key, from)?)))
+                .collect::<Result<_, KvError>>()
+                .map(|msgs| KafkaPayload::PollOk { msgs }),
+
+            KafkaPayload::CommitOffsets { offsets } => offsets
+                .iter()
+                .try_for_each(|(key, &offset)| Self::commit(rpc, known, key, offset))
+                .map(|()| KafkaPayload::CommitOffsetsOk {}),
+
+            KafkaPayload::ListCommittedOffsets { keys } => keys
+                .iter()
+                .filter_map(|key| {
+                    Self::committed(rpc, key)
+                        .transpose()
+                        .map(|offset| Ok((key.clone(), offset?)))
+                })
+                .collect::<Result<_, KvError>>()
+                .map(|offsets| KafkaPayload::ListCommittedOffsetsOk { offsets }),
+
+            _ => return None,
+        })
+    }
+
+    /// Appends `msg` to `key`'s log in lin-kv, at the first free offset from where it looks
+    /// like the log ends.
+    ///
+    /// An entry is its own claim on its offset: a cas creates it only if the offset is free, so
+    /// no two sends land on the same offset, and no offset is ever taken without its entry,
+    /// which would end every poll there. Entries are kept as `[msg]`, so that even a `null`
+    /// message doesn't leave its offset looking free.
+    fn send(
+        rpc: &mut Rpc<Self>,
+        known: &mut Offsets,
+        key: &str,
+        msg: &Value,
+    ) -> Result<u64, KvError> {
+        let hint = format!("offset/{key}");
+        let mut offset = match known.last(key) {
+            Some(last) => last + 1,
+            None => match rpc.lin_kv().read::<u64>(&hint) {
+                Ok(latest) => latest + 1,
+                Err(KvError::NotFound) => 0,
+                Err(error) => return Err(error),
+            },
+        };
+
+        loop {
+            let entry = format!("msg/{key}/{offset}");
+            match rpc.lin_kv().cas(entry, Value::Null, [msg], true) {
+                Ok(()) => break,
+                Err(KvError::PreconditionFailed(_)) => offset += 1,
+                Err(error) => return Err(error),
+            }
+        }
+        known.insert(key, offset, msg.clone());
+
+        // A hint that's behind only costs the next send a few more tries.
+        if let Err(error) = rpc.lin_kv().write(&hint, offset) {
+            log::warning!("Could not note the latest offset of {key}: {error}");
+        }
+        Ok(offset)
+    }
+
+    /// Reads entries from `from` up to the first offset that hasn't been written yet.
+    fn poll(
+        rpc: &mut Rpc<Self>,
+        known: &mut Offsets,
+        key: &str,
+        from: u64,
+    ) -> Result<Vec<(u64, Value)>, KvError> {
+        let mut entries = known.poll(key, from);
+
+        for offset in from + entries.len() as u64..from + POLL_LIMIT {
+            let msg = match known.get(key, offset) {
+                Some(msg) => msg.clone(),
+                None => match rpc
+                    .lin_kv()
+                    .read::<[Value; 1]>(format!("msg/{key}/{offset}"))
+                {
+                    Ok([msg]) => {
+                        known.insert(key, offset, msg.clone());
+                        msg
+                    }
+                    Err(KvError::NotFound) => break,
+                    Err(error) => return Err(error),
+                },
+            };
+
+            entries.push((offset, msg));
+        }
+
+        Ok(entries)
+    }
+
+    /// Commits `key` up to `offset` in lin-kv, unless a later offset is committed already.
+    fn commit(
+        rpc: &mut Rpc<Self>,
+        known: &mut Offsets,
+        key: &str,
+        offset: u64,
+    ) -> Result<(), KvError> {
+        if !known.commit(key, offset) {
+            return Ok(());
+        }
+
+        // Another node may have committed further, before this one owned the key.
+        let committed = rpc.cas_update(
+            LIN_KV,
+            format!("committed/{key}"),
+            RetryPolicy::default(),
+            |committed| committed.map_or(offset, |committed| committed.max(offset)),
+        )?;
+        known.commit(key, committed);
+        Ok(())
+    }
+
+    fn committed(rpc: &mut Rpc<Self>, key: &str) -> Result<Option<u64>, KvError> {
+        match rpc.lin_kv().read(format!("committed/{key}")) {
+            Ok(offset) => Ok(Some(offset)),
+            Err(KvError::NotFound) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl Node for KafkaNode {
+    type Payload = KafkaPayload;
+
+    fn step(&mut self, message: Message<KafkaPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        // Requests from other nodes were forwarded to their owner already.
+        if !self.node_ids.contains(&message.source) {
+            if let Some(mut parts) = self.split(&message.body.payload) {
+                // All of it for one other owner, so its reply can go straight back.
+                if parts.len() == 1 {
+                    let (owner, part) = parts.pop().expect("There is one part.");
+                    return rpc.forward(&message, &owner, part);
+                }
+                return self.proxy(message, parts, rpc);
+            }
+        }
+
+        match self.handle(&message.body.payload, rpc) {
+            Some(Ok(payload)) => rpc.reply(&message, payload),
+
+            // Tell the client rather than taking the node down.
+            Some(Err(error)) => rpc.send(&error_reply(&message, error.code(), error.to_string())),
+
+            None => reject(&message, rpc, "Unsupported message type."),
+        }
+    }
+
+    fn on_event(&mut self, event: Event<KafkaPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.step(message, rpc),
+            Event::Tick => self.tick(rpc),
+            Event::Timeout(id) => {
+                let Some(proxied) = self
+                    .parts
+                    .get(&id)
+                    .copied()
+                    .and_then(|token| self.forget(token))
+                else {
+                    self.parts.remove(&id);
+                    return Ok(());
+                };
+
+                let text = "The owner of a key did not answer in time.";
+                rpc.send(&error_reply(
+                    &proxied.request,
+                    ErrorCode::Timeout.into(),
+                    text,
+                ))
+            }
+        }
+    }
+}
+
+/// Folds an owner's reply into what the client will get.
+fn merge(merged: &mut Option<KafkaPayload>, reply: KafkaPayload) {
+    match (merged.as_mut(), reply) {
+        (Some(KafkaPayload::PollOk { msgs }), KafkaPayload::PollOk { msgs: more }) => {
+            msgs.extend(more);
+        }
+        (_, reply) => *merged = Some(reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::sim::{NetworkConfig, Simulation};
+
+    fn sim() -> Simulation<KafkaNode> {
+        let config = NetworkConfig {
+            max_delay: Duration::from_millis(5),
+            ..NetworkConfig::default()
+        };
+        Simulation::new(3, config, |context| Ok(KafkaNode::new(context))).unwrap()
+    }
+
+    fn replies(sim: &Simulation<KafkaNode>, kind: &str) -> Vec<Value> {
+        sim.history()
+            .into_iter()
+            .filter(|(_, msg)| msg.body.payload["type"] == kind)
+            .map(|(_, msg)| msg.body.payload)
+            .collect()
+    }
+
+    #[test]
+    fn offsets_start_at_zero_and_polls_include_the_one_asked_for() {
+        let mut offsets = Offsets::new();
+        assert_eq!(offsets.poll("k", 0), []);
+        assert_eq!(offsets.last("k"), None);
+
+        assert_eq!(offsets.append("k", json!("a")), 0);
+        assert_eq!(offsets.append("k", json!("b")), 1);
+        assert_eq!(offsets.append("other", json!("c")), 0);
+        assert_eq!(offsets.poll("k", 0), [(0, json!("a")), (1, json!("b"))]);
+        assert_eq!(offsets.poll("k", 1), [(1, json!("b"))]);
+        assert_eq!(offsets.poll("k", 2), []);
+
+        // Polls stop at the first offset with no entry known, and at the limit.
+        offsets.insert("k", 3, json!("d"));
+        assert_eq!(offsets.poll("k", 0).len(), 2);
+        assert_eq!(offsets.append("k", json!("e")), 4);
+        for msg in 0..40 {
+            offsets.append("long", json!(msg));
+        }
+        let polled = offsets.poll("long", 5);
+        assert_eq!(polled.len(), POLL_LIMIT as usize);
+        assert_eq!(polled[0], (5, json!(5)));
+    }
+
+    #[test]
+    fn commits_never_go_backwards() {
+        let mut offsets = Offsets::new();
+        assert_eq!(offsets.committed("k"), None);
+
+        assert!(offsets.commit("k", 0));
+        assert_eq!(offsets.committed("k"), Some(0));
+        assert!(offsets.commit("k", 5));
+        assert!(!offsets.commit("k", 3));
+        assert!(!offsets.commit("k", 5));
+        assert_eq!(offsets.committed("k"), Some(5));
+        assert_eq!(offsets.committed("other"), None);
+    }
+
+    #[test]
+    fn sends_take_consecutive_offsets() {
+        let mut sim = sim();
+        for msg in 0..12 {
+            let node = format!("n{}", msg % 3);
+            sim.send("c1", &node, json!({"type": "send", "key": "k", "msg": msg}))
+                .unwrap();
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+
+        let mut offsets: Vec<u64> = replies(&sim, "send_ok")
+            .iter()
+            .map(|reply| reply["offset"].as_u64().unwrap())
+            .collect();
+        offsets.sort_unstable();
+        assert_eq!(offsets, (0..12).collect::<Vec<_>>());
+
+        let poll = json!({"type": "poll", "offsets": {"k": 0}});
+        let reply = sim
+            .request("c2", "n1", poll, Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        let polled: Vec<u64> = reply.body.payload["msgs"]["k"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry[0].as_u64().unwrap())
+            .collect();
+        assert_eq!(polled, (0..12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn null_messages_keep_their_offsets() {
+        let mut sim = sim();
+        for _ in 0..2 {
+            sim.send("c1", "n0", json!({"type": "send", "key": "k", "msg": null}))
+                .unwrap();
+            sim.run_for(Duration::from_millis(100)).unwrap();
+        }
+
+        let offsets: Vec<Value> = replies(&sim, "send_ok")
+            .into_iter()
+            .map(|reply| reply["offset"].clone())
+            .collect();
+        assert_eq!(offsets, [json!(0), json!(1)]);
+    }
+
+    #[test]
+    fn a_send_to_a_node_not_owning_the_key_is_answered_by_that_node() {
+        let config = NetworkConfig {
+            max_delay: Duration::from_millis(5),
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(2, config, |context| Ok(KafkaNode::new(context))).unwrap();
+        let node = sim.node("n0").unwrap();
+        let key = (0..)
+            .map(|index| format!("k{index}"))
+            .find(|key| node.owner(key) == "n1")
+            .unwrap();
+
+        let send = json!({"type": "send", "key": key, "msg": 7});
+        let id = sim.send("c1", "n0", send).unwrap();
+        sim.run_for(Duration::from_secs(1)).unwrap();
+
+        let replies: Vec<Message<Value>> = sim
+            .history()
+            .into_iter()
+            .map(|(_, msg)| msg)
+            .filter(|msg| msg.destination == "c1")
+            .collect();
+        assert_eq!(replies.len(), 1, "{replies:?}");
+        let reply = &replies[0];
+        assert_eq!(reply.source, "n0");
+        assert_eq!(reply.body.in_reply_to, Some(id));
+        assert_eq!(reply.body.payload, json!({"type": "send_ok", "offset": 0}));
+
+        assert_eq!(
+            sim.node("n1").unwrap().offsets.get(&key, 0),
+            Some(&json!(7))
+        );
+        assert!(sim.node("n0").unwrap().proxied.is_empty());
+    }
+
+    #[test]
+    fn a_timed_out_part_gives_up_on_the_whole_request() {
+        let mut sim = sim();
+        let node = sim.node("n0").unwrap();
+        let key_of = |owner: &str| {
+            (0..)
+                .map(|index| format!("k{index}"))
+                .find(|key| node.owner(key) == owner)
+                .unwrap()
+        };
+        let (first, second) = (key_of("n1"), key_of("n2"));
+
+        sim.partition(&["n0"], &["n1", "n2"]);
+        let poll = json!({"type": "poll", "offsets": {first: 0, second: 0}});
+        let reply = sim
+            .request("c1", "n0", poll, Duration::from_secs(2))
+            .unwrap()
+            .expect("The client hears back.");
+        assert_eq!(reply.body.payload["code"], u32::from(ErrorCode::Timeout));
+
+        let node = sim.node("n0").unwrap();
+        assert!(node.proxied.is_empty());
+        assert!(node.parts.is_empty());
+    }
+
+    fn payload() -> impl Strategy<Value = KafkaPayload> {
+        let offsets = || prop::collection::hash_map(any::<String>(), any::<u64>(), 0..4);
+        let msgs = prop::collection::vec((any::<u64>(), arbitrary::value()), 0..4);
+        prop_oneof![
+            (any::<String>(), arbitrary::value())
+                .prop_map(|(key, msg)| KafkaPayload::Send { key, msg }),
+            any::<u64>().prop_map(|offset| KafkaPayload::SendOk { offset }),
+            offsets().prop_map(|offsets| KafkaPayload::Poll { offsets }),
+            prop::collection::hash_map(any::<String>(), msgs, 0..4)
+                .prop_map(|msgs| KafkaPayload::PollOk { msgs }),
+            offsets().prop_map(|offsets| KafkaPayload::CommitOffsets { offsets }),
+            Just(KafkaPayload::CommitOffsetsOk {}),
+            prop::collection::vec(any::<String>(), 0..4)
+                .prop_map(|keys| KafkaPayload::ListCommittedOffsets { keys }),
+            offsets().prop_map(|offsets| KafkaPayload::ListCommittedOffsetsOk { offsets }),
+            (any::<u32>(), any::<String>())
+                .prop_map(|(code, text)| KafkaPayload::Error { code, text }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
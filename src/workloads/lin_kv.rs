@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::{error_reply, ErrorCode, Message};
+use crate::node::{reject, Node, NodeContext};
+use crate::rpc::Rpc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum LinKvPayload {
+    Read {
+        key: u64,
+    },
+    ReadOk {
+        value: u64,
+    },
+    Write {
+        key: u64,
+        value: u64,
+    },
+    WriteOk {},
+    /// Swaps `from` for `to`. A missing key counts as holding `null`, and with
+    /// `create_if_not_exists` a cas from `null` creates it as `to`. Without the flag, a missing
+    /// key is `key-does-not-exist` whatever `from` is.
+    Cas {
+        key: u64,
+        from: Option<u64>,
+        to: u64,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk {},
+}
+
+/// A linearizable key-value store, kept entirely on one node.
+///
+/// The node with the lowest id holds every register and applies operations one at a time.
+/// Every other node forwards what it's asked to that node and relays the answer, so all
+/// operations are ordered by a single authority. That also makes the store unavailable while
+/// the authority is unreachable.
+pub struct LinKvNode {
+    authority: String,
+    registers: HashMap<u64, u64>,
+}
+
+impl LinKvNode {
+    pub fn new(context: &NodeContext) -> Self {
+        let authority = context
+            .node_ids
+            .iter()
+            .min()
+            .unwrap_or(&context.node_id)
+            .clone();
+
+        Self {
+            authority,
+            registers: HashMap::new(),
+        }
+    }
+
+    /// Passes a request on to the authority, which answers the client through this node,
+    /// error or not. The node goes on serving other clients in the meantime.
+    fn forward(
+        &mut self,
+        message: Message<LinKvPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        if message.body.id.is_none() {
+            return reject(&message, rpc, "Unsupported message type.");
+        }
+
+        // Even if the authority doesn't answer in time, it may have applied the request, which
+        // the `timeout` the client then gets allows for.
+        rpc.forward(&message, &self.authority, &message.body.payload)
+    }
+}
+
+/// A register's value as the client would write it, `null` for a missing one.
+fn show(value: Option<u64>) -> String {
+    value.map_or_else(|| "null".to_owned(), |value| value.to_string())
+}
+
+fn missing(key: u64) -> (ErrorCode, String) {
+    (
+        ErrorCode::KeyDoesNotExist,
+        format!("Key {key} does not exist."),
+    )
+}
+
+impl Node for LinKvNode {
+    type Payload = LinKvPayload;
+
+    fn step(&mut self, message: Message<LinKvPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        if message.destination != self.authority {
+            return self.forward(message, rpc);
+        }
+
+        let result = match message.body.payload {
+            LinKvPayload::Read { key } => match self.registers.get(&key) {
+                Some(value) => Ok(LinKvPayload::ReadOk { value: *value }),
+                None => Err(missing(key)),
+            },
+
+            LinKvPayload::Write { key, value } => {
+                self.registers.insert(key, value);
+                Ok(LinKvPayload::WriteOk {})
+            }
+
+            LinKvPayload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match (self.registers.get_mut(&key), from) {
+                (Some(value), Some(from)) if *value == from => {
+                    *value = to;
+                    Ok(LinKvPayload::CasOk {})
+                }
+                (None, _) if !create_if_not_exists => Err(missing(key)),
+                (None, None) => {
+                    self.registers.insert(key, to);
+                    Ok(LinKvPayload::CasOk {})
+                }
+                (value, from) => Err((
+                    ErrorCode::PreconditionFailed,
+                    format!(
+                        "Expected {}, but found {}.",
+                        show(from),
+                        show(value.copied())
+                    ),
+                )),
+            },
+
+            _ => return reject(&message, rpc, "Unsupported message type."),
+        };
+
+        match result {
+            Ok(payload) => rpc.reply(&message, payload),
+            Err((code, text)) => rpc.send(&error_reply(&message, code.into(), text)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::sim::{NetworkConfig, Simulation};
+
+    fn sim() -> Simulation<LinKvNode> {
+        let config = NetworkConfig {
+            max_delay: Duration::from_millis(5),
+            ..NetworkConfig::default()
+        };
+        Simulation::new(3, config, |context| Ok(LinKvNode::new(context))).unwrap()
+    }
+
+    fn ask(sim: &mut Simulation<LinKvNode>, node_id: &str, request: Value) -> Value {
+        sim.request("c1", node_id, request, Duration::from_secs(2))
+            .unwrap()
+            .expect("Every request is answered.")
+            .body
+            .payload
+    }
+
+    #[test]
+    fn other_nodes_forward_to_the_authority() {
+        let mut sim = sim();
+
+        let written = ask(
+            &mut sim,
+            "n1",
+            json!({"type": "write", "key": 1, "value": 5}),
+        );
+        assert_eq!(written["type"], "write_ok");
+        let read = ask(&mut sim, "n2", json!({"type": "read", "key": 1}));
+        assert_eq!(read, json!({"type": "read_ok", "value": 5}));
+
+        // Errors come back as the authority gave them.
+        let missing = ask(&mut sim, "n2", json!({"type": "read", "key": 2}));
+        assert_eq!(missing["code"], u32::from(ErrorCode::KeyDoesNotExist));
+        let cas = json!({"type": "cas", "key": 1, "from": 4, "to": 6});
+        let failed = ask(&mut sim, "n1", cas);
+        assert_eq!(failed["code"], u32::from(ErrorCode::PreconditionFailed));
+
+        assert_eq!(sim.node("n0").unwrap().registers, HashMap::from([(1, 5)]));
+        assert!(sim.node("n1").unwrap().registers.is_empty());
+    }
+
+    #[test]
+    fn an_unreachable_authority_times_out_without_blocking_the_node() {
+        let mut sim = sim();
+        sim.partition(&["n1"], &["n0"]);
+
+        // Both are answered, the second without waiting out the first's timeout.
+        let first_sent = sim
+            .send("c1", "n1", json!({"type": "write", "key": 1, "value": 5}))
+            .unwrap();
+        sim.run_for(Duration::from_millis(100)).unwrap();
+        let second_sent = sim
+            .send("c2", "n1", json!({"type": "read", "key": 1}))
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        let answers: Vec<_> = sim
+            .history()
+            .into_iter()
+            .filter_map(|(at, msg)| Some((msg.body.in_reply_to?, at, msg.body.payload)))
+            .collect();
+        let [(first_id, first_at, first), (second_id, second_at, second)] =
+            answers.try_into().unwrap();
+        assert_eq!((first_id, second_id), (first_sent, second_sent));
+        assert_eq!(first["code"], u32::from(ErrorCode::Timeout));
+        assert_eq!(second["code"], u32::from(ErrorCode::Timeout));
+        assert!(second_at - first_at < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn cas_covers_every_case() {
+        let present = 5;
+        let cases = [
+            // (flag, key present, from matches, expected)
+            (false, true, true, Ok(Some(7))),
+            (false, true, false, Err(ErrorCode::PreconditionFailed)),
+            (false, false, true, Err(ErrorCode::KeyDoesNotExist)),
+            (false, false, false, Err(ErrorCode::KeyDoesNotExist)),
+            (true, true, true, Ok(Some(7))),
+            (true, true, false, Err(ErrorCode::PreconditionFailed)),
+            (true, false, true, Ok(Some(7))),
+            (true, false, false, Err(ErrorCode::PreconditionFailed)),
+        ];
+
+        for (create_if_not_exists, is_present, matches, expected) in cases {
+            let mut sim = sim();
+            if is_present {
+                ask(
+                    &mut sim,
+                    "n0",
+                    json!({"type": "write", "key": 1, "value": present}),
+                );
+            }
+            // What a missing key matches is `null`.
+            let from = match (is_present, matches) {
+                (true, true) => json!(present),
+                (false, true) => Value::Null,
+                (_, false) => json!(4),
+            };
+            let cas = json!({
+                "type": "cas",
+                "key": 1,
+                "from": from,
+                "to": 7,
+                "create_if_not_exists": create_if_not_exists,
+            });
+            let case = format!("flag {create_if_not_exists}, present {is_present}, from {from}");
+
+            let reply = ask(&mut sim, "n1", cas);
+            match expected {
+                Ok(_) => assert_eq!(reply["type"], "cas_ok", "{case}: {reply}"),
+                Err(code) => assert_eq!(reply["code"], u32::from(code), "{case}: {reply}"),
+            }
+            let registers = &sim.node("n0").unwrap().registers;
+            let after = expected.map_or(is_present.then_some(present), |value| value);
+            assert_eq!(registers.get(&1).copied(), after, "{case}");
+        }
+    }
+
+    fn payload() -> impl Strategy<Value = LinKvPayload> {
+        prop_oneof![
+            any::<u64>().prop_map(|key| LinKvPayload::Read { key }),
+            any::<u64>().prop_map(|value| LinKvPayload::ReadOk { value }),
+            (any::<u64>(), any::<u64>())
+                .prop_map(|(key, value)| LinKvPayload::Write { key, value }),
+            Just(LinKvPayload::WriteOk {}),
+            (
+                any::<u64>(),
+                any::<Option<u64>>(),
+                any::<u64>(),
+                any::<bool>()
+            )
+                .prop_map(|(key, from, to, create_if_not_exists)| LinKvPayload::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                }),
+            Just(LinKvPayload::CasOk {}),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
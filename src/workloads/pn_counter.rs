@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crdt::PnCounter;
+use crate::dedup::Dedup;
+use crate::heartbeat::{FailureDetector, PEER_TIMEOUT};
+use crate::message::Message;
+use crate::node::{reject, Node, NodeContext};
+use crate::rpc::Rpc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum PnCounterPayload {
+    Add {
+        delta: i64,
+    },
+    AddOk {},
+    Read {},
+    ReadOk {
+        value: i64,
+    },
+
+    /// The sender's whole counter, for the receiver to merge.
+    Merge {
+        counter: PnCounter,
+    },
+
+    Ping {},
+    PongOk {},
+}
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many recent requests to recognize when they're delivered again.
+const DEDUP_CAPACITY: usize = 1024;
+
+/// A counter that goes up and down, replicated on every node as a [`PnCounter`].
+///
+/// Each node adds to its own share and reads its own copy, and sends the whole counter to
+/// every peer on every tick. Merging takes the larger of each node's counts, so copies that
+/// drifted apart during a partition agree again once it heals, whichever way the adds went.
+/// Peers that look dead are skipped until they answer a ping again, which they then get the
+/// whole counter with.
+pub struct PnCounterNode {
+    self_id: String,
+    peers: Vec<String>,
+    counter: PnCounter,
+    answered: Dedup<PnCounterPayload>,
+    detector: FailureDetector,
+}
+
+impl PnCounterNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self {
+            self_id: context.node_id.clone(),
+            peers: context.peers().map(str::to_owned).collect(),
+            counter: PnCounter::new(),
+            answered: Dedup::new(DEDUP_CAPACITY),
+            detector: FailureDetector::new(context.peers().map(str::to_owned), PEER_TIMEOUT),
+        }
+    }
+}
+
+impl Node for PnCounterNode {
+    type Payload = PnCounterPayload;
+
+    fn step(
+        &mut self,
+        message: Message<PnCounterPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        self.detector.heard_from(&message.source, rpc.now());
+
+        // Maelstrom may deliver an add again, which must not count twice.
+        if let Some(payload) = self.answered.get(&message) {
+            let payload = payload.clone();
+            return rpc.reply(&message, payload);
+        }
+
+        let payload = match message.body.payload {
+            PnCounterPayload::Add { delta } => {
+                self.counter.add(&self.self_id, delta);
+                PnCounterPayload::AddOk {}
+            }
+
+            PnCounterPayload::Read {} => PnCounterPayload::ReadOk {
+                value: self.counter.value(),
+            },
+
+            PnCounterPayload::Merge { ref counter } => {
+                self.counter.merge(counter);
+                return Ok(());
+            }
+
+            PnCounterPayload::Ping {} => return rpc.reply(&message, PnCounterPayload::PongOk {}),
+
+            _ => return reject(&message, rpc, "Unsupported message type."),
+        };
+
+        self.answered.insert(&message, payload.clone());
+        rpc.reply(&message, payload)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(GOSSIP_INTERVAL)
+    }
+
+    fn tick(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        self.detector
+            .ping(rpc, PnCounterPayload::Ping {}, |node| &mut node.detector)?;
+
+        for peer in self
+            .peers
+            .iter()
+            .filter(|peer| self.detector.is_alive(peer))
+        {
+            let counter = self.counter.clone();
+            rpc.notify(peer, PnCounterPayload::Merge { counter })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::message::{arbitrary, Body};
+    use crate::transport::InMemoryTransport;
+
+    #[test]
+    fn an_add_delivered_twice_counts_once_and_is_acknowledged_twice() {
+        let context = NodeContext {
+            node_id: "n1".to_owned(),
+            node_ids: vec!["n1".to_owned(), "n2".to_owned()],
+        };
+        let mut node = PnCounterNode::new(&context);
+        let transport = InMemoryTransport::new();
+        let mut rpc = Rpc::with_transport("n1", transport.clone());
+
+        let add = Message {
+            source: "c1".to_owned(),
+            destination: "n1".to_owned(),
+            body: Body {
+                id: Some(4),
+                in_reply_to: None,
+                payload: PnCounterPayload::Add { delta: 3 },
+            },
+        };
+        node.step(add.clone(), &mut rpc).unwrap();
+        node.step(add, &mut rpc).unwrap();
+
+        assert_eq!(node.counter.value(), 3);
+        let acks: Vec<_> = transport
+            .take()
+            .into_iter()
+            .map(|reply| (reply.body.in_reply_to, reply.body.payload["type"].clone()))
+            .collect();
+        assert_eq!(
+            acks,
+            [(Some(4), "add_ok".into()), (Some(4), "add_ok".into())]
+        );
+    }
+
+    fn payload() -> impl Strategy<Value = PnCounterPayload> {
+        let counter =
+            prop::collection::vec((arbitrary::node_id(), any::<i32>()), 0..4).prop_map(|adds| {
+                let mut counter = PnCounter::new();
+                for (node_id, delta) in adds {
+                    counter.add(&node_id, delta.into());
+                }
+                counter
+            });
+        prop_oneof![
+            any::<i64>().prop_map(|delta| PnCounterPayload::Add { delta }),
+            Just(PnCounterPayload::AddOk {}),
+            Just(PnCounterPayload::Read {}),
+            any::<i64>().prop_map(|value| PnCounterPayload::ReadOk { value }),
+            counter.prop_map(|counter| PnCounterPayload::Merge { counter }),
+            Just(PnCounterPayload::Ping {}),
+            Just(PnCounterPayload::PongOk {}),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
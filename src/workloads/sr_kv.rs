@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::{error_reply, ErrorCode, Message};
+use crate::node::{reject, Node, NodeContext};
+use crate::rpc::Rpc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum SrKvPayload {
+    Read {
+        key: u64,
+    },
+    ReadOk {
+        value: u64,
+    },
+    Write {
+        key: u64,
+        value: u64,
+    },
+    WriteOk {},
+    Cas {
+        key: u64,
+        from: u64,
+        to: u64,
+    },
+    CasOk {},
+
+    /// The sender's `seq`th write.
+    Apply {
+        seq: u64,
+        write: Write,
+    },
+    /// How many writes the sender has made, for peers to tell whether they missed any.
+    Sequenced {
+        seq: u64,
+    },
+    /// Asks for every write of the recipient's from the `from`th on.
+    Resend {
+        from: u64,
+    },
+}
+
+/// A write as replicated, whether a client's write or a cas that succeeded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Write {
+    key: u64,
+    value: u64,
+    /// A Lamport timestamp and the writer, which decide between writes to the same key.
+    stamp: (u64, String),
+}
+
+/// How often a node tells its peers how many writes it has made.
+const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A sequentially consistent key-value store, replicated on every node.
+///
+/// Each node answers its own clients' writes and cas requests right away from its own copy, and
+/// numbers the writes it makes in order. Its peers apply them in that order, buffering any that
+/// arrive ahead of the ones before them, so each session's writes are seen in the order they
+/// were made everywhere, while writes from different nodes may interleave differently on each.
+/// Concurrent writes to one key are decided by their Lamport timestamps, so that all copies
+/// end up alike.
+///
+/// A gap in a node's writes, e.g. a dropped `apply`, is waited out rather than turned into an
+/// error, since the writer keeps every write it made: every 100ms each node tells its peers
+/// how many writes it has made, and a peer that's behind asks for the rest. That catches
+/// trailing writes too, which no later write would otherwise give away as missing.
+pub struct SrKvNode {
+    self_id: String,
+    peers: Vec<String>,
+    registers: HashMap<u64, (u64, (u64, String))>,
+    /// The highest Lamport timestamp seen.
+    clock: u64,
+    /// Every write this node made, in order, for resends.
+    log: Vec<Write>,
+    /// How many of each peer's writes have been applied here.
+    applied: HashMap<String, u64>,
+    /// Each peer's writes that arrived ahead of the ones before them, by sequence.
+    buffered: HashMap<String, BTreeMap<u64, Write>>,
+}
+
+impl SrKvNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self {
+            self_id: context.node_id.clone(),
+            peers: context.peers().map(str::to_owned).collect(),
+            registers: HashMap::new(),
+            clock: 0,
+            log: Vec::new(),
+            applied: HashMap::new(),
+            buffered: HashMap::new(),
+        }
+    }
+
+    /// Makes a write of this node's own, and sends it to every peer.
+    fn write(&mut self, key: u64, value: u64, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        self.clock += 1;
+        let write = Write {
+            key,
+            value,
+            stamp: (self.clock, self.self_id.clone()),
+        };
+        merge(&mut self.registers, &write);
+        self.log.push(write.clone());
+
+        let seq = self.log.len() as u64;
+        for peer in &self.peers {
+            let apply = SrKvPayload::Apply {
+                seq,
+                write: write.clone(),
+            };
+            rpc.notify(peer, apply)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `origin`'s `seq`th write, or buffers it until those before it are applied.
+    fn receive(&mut self, origin: String, seq: u64, write: Write) {
+        let applied = self.applied.entry(origin.clone()).or_default();
+        if seq <= *applied {
+            return;
+        }
+        let buffered = self.buffered.entry(origin).or_default();
+        buffered.insert(seq, write);
+
+        while let Some(write) = buffered.remove(&(*applied + 1)) {
+            *applied += 1;
+            self.clock = self.clock.max(write.stamp.0);
+            merge(&mut self.registers, &write);
+        }
+    }
+}
+
+/// Applies `write`, unless the key holds a later one.
+fn merge(registers: &mut HashMap<u64, (u64, (u64, String))>, write: &Write) {
+    let register = registers.get(&write.key);
+    if register.is_none_or(|(_, stamp)| *stamp < write.stamp) {
+        registers.insert(write.key, (write.value, write.stamp.clone()));
+    }
+}
+
+fn missing(key: u64) -> (ErrorCode, String) {
+    (
+        ErrorCode::KeyDoesNotExist,
+        format!("Key {key} does not exist."),
+    )
+}
+
+impl Node for SrKvNode {
+    type Payload = SrKvPayload;
+
+    fn step(&mut self, message: Message<SrKvPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        match message.body.payload {
+            SrKvPayload::Read { key } => match self.registers.get(&key) {
+                Some((value, _)) => rpc.reply(&message, SrKvPayload::ReadOk { value: *value }),
+                None => {
+                    let (code, text) = missing(key);
+                    rpc.send(&error_reply(&message, code.into(), text))
+                }
+            },
+
+            SrKvPayload::Write { key, value } => {
+                self.write(key, value, rpc)?;
+                rpc.reply(&message, SrKvPayload::WriteOk {})
+            }
+
+            SrKvPayload::Cas { key, from, to } => match self.registers.get(&key) {
+                Some((value, _)) if *value == from => {
+                    self.write(key, to, rpc)?;
+                    rpc.reply(&message, SrKvPayload::CasOk {})
+                }
+                Some((value, _)) => rpc.send(&error_reply(
+                    &message,
+                    ErrorCode::PreconditionFailed.into(),
+                    format!("Expected {from}, but found {value}."),
+                )),
+                None => {
+                    let (code, text) = missing(key);
+                    rpc.send(&error_reply(&message, code.into(), text))
+                }
+            },
+
+            SrKvPayload::Apply { seq, write } => {
+                self.receive(message.source, seq, write);
+                Ok(())
+            }
+
+            SrKvPayload::Sequenced { seq } => {
+                let applied = self.applied.get(&message.source).copied().unwrap_or(0);
+                if applied < seq {
+                    let from = applied + 1;
+                    rpc.notify(&message.source, SrKvPayload::Resend { from })?;
+                }
+                Ok(())
+            }
+
+            SrKvPayload::Resend { from } => {
+                let from = from.max(1);
+                for (seq, write) in (from..).zip(self.log.iter().skip(from as usize - 1)) {
+                    let apply = SrKvPayload::Apply {
+                        seq,
+                        write: write.clone(),
+                    };
+                    rpc.notify(&message.source, apply)?;
+                }
+                Ok(())
+            }
+
+            _ => reject(&message, rpc, "Unsupported message type."),
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(SYNC_INTERVAL)
+    }
+
+    fn tick(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        if self.log.is_empty() {
+            return Ok(());
+        }
+        let seq = self.log.len() as u64;
+        for peer in &self.peers {
+            rpc.notify(peer, SrKvPayload::Sequenced { seq })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::sim::{NetworkConfig, Simulation};
+
+    /// What `node` reads for `key`, asking again if the request or its reply got lost.
+    fn read(sim: &mut Simulation<SrKvNode>, node: &str, key: u64) -> Value {
+        loop {
+            let payload = SrKvPayload::Read { key };
+            if let Some(reply) = sim.request("c9", node, payload, SYNC_INTERVAL).unwrap() {
+                return reply.body.payload;
+            }
+        }
+    }
+
+    #[test]
+    fn each_client_reads_its_own_writes_in_order() {
+        let mut sim = Simulation::new(2, NetworkConfig::default(), |context| {
+            Ok(SrKvNode::new(context))
+        })
+        .unwrap();
+
+        for value in 1..=5 {
+            for (client, node, key) in [("c1", "n0", 1), ("c2", "n1", 2)] {
+                let write = SrKvPayload::Write { key, value };
+                let timeout = Duration::from_secs(1);
+                sim.request(client, node, write, timeout).unwrap().unwrap();
+                let read = sim
+                    .request(client, node, SrKvPayload::Read { key }, timeout)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(read.body.payload["value"], value);
+            }
+        }
+    }
+
+    #[test]
+    fn copies_agree_once_lost_writes_are_resent() {
+        let config = NetworkConfig {
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(20),
+            drop_rate: 0.3,
+            seed: 7,
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(3, config, |context| Ok(SrKvNode::new(context))).unwrap();
+
+        // Interleaved writes to shared keys, from a client per node, the last of which may
+        // well be lost on their way to some peers.
+        for value in 1..=20 {
+            let node = format!("n{}", value % 3);
+            let write = SrKvPayload::Write {
+                key: value % 4,
+                value,
+            };
+            sim.send(&format!("c{node}"), &node, write).unwrap();
+            sim.run_for(Duration::from_millis(5)).unwrap();
+        }
+        sim.run_for(Duration::from_secs(3)).unwrap();
+        assert!(sim.dropped() > 0);
+
+        for key in 0..4 {
+            let reads: Vec<Value> = ["n0", "n1", "n2"]
+                .iter()
+                .map(|node| read(&mut sim, node, key))
+                .collect();
+            assert_eq!(reads[0], reads[1], "key {key}: {reads:?}");
+            assert_eq!(reads[0], reads[2], "key {key}: {reads:?}");
+            assert_eq!(reads[0]["type"], json!("read_ok"), "key {key}: {reads:?}");
+        }
+    }
+
+    fn payload() -> impl Strategy<Value = SrKvPayload> {
+        let write = (
+            any::<u64>(),
+            any::<u64>(),
+            any::<u64>(),
+            arbitrary::node_id(),
+        )
+            .prop_map(|(key, value, time, writer)| Write {
+                key,
+                value,
+                stamp: (time, writer),
+            });
+        prop_oneof![
+            any::<u64>().prop_map(|key| SrKvPayload::Read { key }),
+            any::<u64>().prop_map(|value| SrKvPayload::ReadOk { value }),
+            (any::<u64>(), any::<u64>()).prop_map(|(key, value)| SrKvPayload::Write { key, value }),
+            Just(SrKvPayload::WriteOk {}),
+            (any::<u64>(), any::<u64>(), any::<u64>())
+                .prop_map(|(key, from, to)| SrKvPayload::Cas { key, from, to }),
+            Just(SrKvPayload::CasOk {}),
+            (any::<u64>(), write).prop_map(|(seq, write)| SrKvPayload::Apply { seq, write }),
+            any::<u64>().prop_map(|seq| SrKvPayload::Sequenced { seq }),
+            any::<u64>().prop_map(|from| SrKvPayload::Resend { from }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
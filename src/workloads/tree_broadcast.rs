@@ -0,0 +1,428 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::message::{Body, Message};
+use crate::node::{reject, Node, NodeContext};
+use crate::rpc::Rpc;
+use crate::workloads::broadcast::{key, tree, DEFAULT_FANOUT};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TreeBroadcastPayload {
+    Broadcast {
+        message: Value,
+    },
+    BroadcastOk {},
+    Read {},
+    ReadOk {
+        messages: Vec<Value>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk {},
+
+    /// Values on their way up to the root.
+    Forward {
+        messages: Vec<Value>,
+    },
+    ForwardOk {},
+    /// Values the root sent down, for the receiver and everything below it.
+    Deliver {
+        messages: Vec<Value>,
+    },
+    DeliverOk {},
+}
+
+/// How long a batch goes unacknowledged before it's sent again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a value may wait on the parent before it's also sent to the grandparent.
+const REROUTE_AFTER: Duration = Duration::from_millis(500);
+
+/// Broadcasts every value through the root of a spanning tree.
+///
+/// The tree is laid out over `node_ids` in sorted order, as for [`Topology::Tree`], so the
+/// root is the lowest id. A client's value is forwarded up from parent to parent to the root,
+/// which sends it down to its children, each of which sends it on to its own. A value takes
+/// one message and one acknowledgement per link on the way down, and per level on the way up,
+/// however many nodes there are: no node hears of a value twice from the same direction, as it
+/// may under gossip.
+///
+/// Each link retries until acknowledged, so a lost message only delays a value. A value that
+/// has waited on its parent for half a second is also forwarded to the grandparent, around
+/// the parent or the link to it, and reaches the rest of the tree that way.
+///
+/// [`Topology::Tree`]: crate::workloads::Topology::Tree
+pub struct TreeBroadcastNode {
+    parent: Option<String>,
+    grandparent: Option<String>,
+    children: Vec<String>,
+    // `Value` isn't `Hash`, so values are keyed by their JSON text, as for `BroadcastNode`.
+    messages: HashMap<String, Value>,
+    /// Values not yet acknowledged by the parent, with when they started waiting and when they
+    /// were last sent.
+    up: BTreeMap<String, (Instant, Option<Instant>)>,
+    /// Values each child hasn't acknowledged yet, with when they were last sent.
+    down: HashMap<String, BTreeMap<String, Option<Instant>>>,
+    /// Values this node has sent down already, or started to.
+    delivered: HashSet<String>,
+}
+
+impl TreeBroadcastNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self::with_fanout(context, DEFAULT_FANOUT)
+    }
+
+    /// A tree in which each node has up to `fanout` children.
+    pub fn with_fanout(context: &NodeContext, fanout: usize) -> Self {
+        let mut node_ids = context.node_ids.clone();
+        node_ids.sort();
+        let parent_of = |index: usize| index.checked_sub(1).map(|index| index / fanout.max(1));
+
+        let index = context.node_index();
+        let parent = parent_of(index);
+        let grandparent = parent.and_then(parent_of);
+        let children = tree(index, fanout)
+            .into_iter()
+            .filter(|child| Some(*child) != parent)
+            .filter_map(|child| node_ids.get(child).cloned())
+            .collect();
+
+        Self {
+            parent: parent.and_then(|index| node_ids.get(index).cloned()),
+            grandparent: grandparent.and_then(|index| node_ids.get(index).cloned()),
+            children,
+            messages: HashMap::new(),
+            up: BTreeMap::new(),
+            down: HashMap::new(),
+            delivered: HashSet::new(),
+        }
+    }
+
+    /// Takes in a value from a client or a child, to pass up, or down from the root.
+    fn take_from_below(&mut self, value: Value, now: Instant) {
+        let key = key(&value);
+        self.messages.entry(key.clone()).or_insert(value);
+        if self.parent.is_none() {
+            self.pass_down(key);
+        } else if !self.delivered.contains(&key) {
+            self.up.entry(key).or_insert((now, None));
+        }
+    }
+
+    /// Takes in a value sent down from above, which is on its way everywhere already.
+    fn take_from_above(&mut self, value: Value) {
+        let key = key(&value);
+        self.messages.entry(key.clone()).or_insert(value);
+        self.up.remove(&key);
+        self.pass_down(key);
+    }
+
+    fn pass_down(&mut self, key: String) {
+        if !self.delivered.insert(key.clone()) {
+            return;
+        }
+        for child in &self.children {
+            self.down
+                .entry(child.clone())
+                .or_default()
+                .insert(key.clone(), None);
+        }
+    }
+
+    /// Sends every value not acknowledged in the last [`RETRY_INTERVAL`] on its way.
+    fn flush(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        let now = rpc.now();
+        let due = |sent: &Option<Instant>| sent.is_none_or(|sent| now - sent >= RETRY_INTERVAL);
+
+        if let Some(parent) = self.parent.clone() {
+            let mut keys = Vec::new();
+            let mut rerouted = Vec::new();
+            for (key, (since, sent)) in &mut self.up {
+                if !due(sent) {
+                    continue;
+                }
+                *sent = Some(now);
+                keys.push(key.clone());
+                if now - *since >= REROUTE_AFTER {
+                    rerouted.push(key.clone());
+                }
+            }
+            self.send(parent, keys, Direction::Up, rpc)?;
+            if let Some(grandparent) = self.grandparent.clone() {
+                self.send(grandparent, rerouted, Direction::Up, rpc)?;
+            }
+        }
+
+        for child in self.children.clone() {
+            let Some(pending) = self.down.get_mut(&child) else {
+                continue;
+            };
+            let mut keys = Vec::new();
+            for (key, sent) in pending.iter_mut().filter(|(_, sent)| due(sent)) {
+                *sent = Some(now);
+                keys.push(key.clone());
+            }
+            self.send(child, keys, Direction::Down, rpc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the values under `keys` to `peer`, and stops sending them that way once `peer`
+    /// acknowledges them.
+    fn send(
+        &self,
+        peer: String,
+        keys: Vec<String>,
+        direction: Direction,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let messages = keys.iter().map(|key| self.messages[key].clone()).collect();
+        let payload = match direction {
+            Direction::Up => TreeBroadcastPayload::Forward { messages },
+            Direction::Down => TreeBroadcastPayload::Deliver { messages },
+        };
+        let request = Message {
+            source: rpc.node_id()?.to_owned(),
+            destination: peer.clone(),
+            body: Body {
+                id: Some(rpc.next_id()),
+                in_reply_to: None,
+                payload,
+            },
+        };
+
+        rpc.call(request, move |node, _ok, _rpc| {
+            match direction {
+                Direction::Up => keys.iter().for_each(|key| {
+                    node.up.remove(key);
+                }),
+                Direction::Down => {
+                    if let Some(pending) = node.down.get_mut(&peer) {
+                        keys.iter().for_each(|key| {
+                            pending.remove(key);
+                        });
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn pending(&self) -> usize {
+        self.up.len() + self.down.values().map(BTreeMap::len).sum::<usize>()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Up,
+    Down,
+}
+
+impl Node for TreeBroadcastNode {
+    type Payload = TreeBroadcastPayload;
+
+    fn step(
+        &mut self,
+        message: Message<TreeBroadcastPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        let now = rpc.now();
+        let payload = match message.body.payload {
+            TreeBroadcastPayload::Broadcast { ref message } => {
+                self.take_from_below(message.clone(), now);
+                TreeBroadcastPayload::BroadcastOk {}
+            }
+
+            TreeBroadcastPayload::Read {} => TreeBroadcastPayload::ReadOk {
+                messages: self.messages.values().cloned().collect(),
+            },
+
+            // The tree is built from the node ids, whatever Maelstrom suggests.
+            TreeBroadcastPayload::Topology { .. } => TreeBroadcastPayload::TopologyOk {},
+
+            TreeBroadcastPayload::Forward { ref messages } => {
+                for value in messages {
+                    self.take_from_below(value.clone(), now);
+                }
+                TreeBroadcastPayload::ForwardOk {}
+            }
+
+            TreeBroadcastPayload::Deliver { ref messages } => {
+                for value in messages {
+                    self.take_from_above(value.clone());
+                }
+                TreeBroadcastPayload::DeliverOk {}
+            }
+
+            _ => return reject(&message, rpc, "Unsupported message type."),
+        };
+
+        // Answer first, so that the client never waits on the tree.
+        rpc.reply(&message, payload)?;
+        self.flush(rpc)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(RETRY_INTERVAL)
+    }
+
+    fn tick(&mut self, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        self.flush(rpc)
+    }
+
+    fn summary(&self) -> Option<Value> {
+        Some(serde_json::json!({
+            "messages": self.messages.len(),
+            "unacknowledged": self.pending(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::sim::{NetworkConfig, Simulation};
+    use crate::workloads::BroadcastNode;
+
+    const NODES: usize = 10;
+    const OPS: u64 = 10;
+
+    /// Broadcasts a value at each node in turn, one per round of gossip, and returns how many
+    /// messages went between nodes per broadcast, once every node holds every value.
+    fn messages_per_op<N: Node>(sim: &mut Simulation<N>) -> f64 {
+        let node_ids: Vec<String> = sim.node_ids().map(str::to_owned).collect();
+        for value in 0..OPS {
+            let node_id = &node_ids[value as usize % node_ids.len()];
+            sim.send(
+                "c1",
+                node_id,
+                json!({"type": "broadcast", "message": value}),
+            )
+            .unwrap();
+            sim.run_for(Duration::from_millis(150)).unwrap();
+        }
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        // The history holds just what clients sent and got.
+        let between_nodes = sim.delivered() - sim.history().len();
+        for node_id in &node_ids {
+            assert_eq!(read(sim, node_id).len(), OPS as usize, "{node_id}");
+        }
+        between_nodes as f64 / OPS as f64
+    }
+
+    fn read<N: Node>(sim: &mut Simulation<N>, node_id: &str) -> Vec<u64> {
+        let reply = sim
+            .request(
+                "c0",
+                node_id,
+                json!({"type": "read"}),
+                Duration::from_secs(1),
+            )
+            .unwrap()
+            .expect("Reads are answered.");
+        let mut messages: Vec<u64> = reply.body.payload["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| value.as_u64().unwrap())
+            .collect();
+        messages.sort_unstable();
+        messages
+    }
+
+    #[test]
+    fn the_tree_takes_fewer_messages_than_flat_gossip() {
+        let mut tree = Simulation::new(NODES, NetworkConfig::default(), |context| {
+            Ok(TreeBroadcastNode::with_fanout(context, 3))
+        })
+        .unwrap();
+        let tree = messages_per_op(&mut tree);
+
+        // Every node gossips to every other.
+        let mut flat = Simulation::new(NODES, NetworkConfig::default(), |context| {
+            Ok(BroadcastNode::new(context))
+        })
+        .unwrap();
+        let node_ids: Vec<String> = flat.node_ids().map(str::to_owned).collect();
+        let topology: HashMap<&str, Vec<&String>> = node_ids
+            .iter()
+            .map(|id| {
+                (
+                    id.as_str(),
+                    node_ids.iter().filter(|peer| *peer != id).collect(),
+                )
+            })
+            .collect();
+        for node_id in &node_ids {
+            let payload = json!({"type": "topology", "topology": topology});
+            flat.send("c0", node_id, payload).unwrap();
+        }
+        let flat = messages_per_op(&mut flat);
+
+        // Up to the root and down every link, each acknowledged: at most 2 * (2 + 9).
+        assert!(tree <= 22.0, "{tree} messages per broadcast");
+        assert!(tree < flat / 2.0, "tree {tree}, flat {flat}");
+    }
+
+    #[test]
+    fn a_broadcast_gets_around_a_lost_link_to_the_parent() {
+        // With a fanout of 2, n7's parent is n3, whose parent is n1.
+        let mut sim = Simulation::new(NODES, NetworkConfig::default(), |context| {
+            Ok(TreeBroadcastNode::with_fanout(context, 2))
+        })
+        .unwrap();
+        let (leaf, parent) = ("n7", "n3");
+        assert_eq!(sim.node(leaf).unwrap().parent.as_deref(), Some(parent));
+        assert_eq!(sim.node(leaf).unwrap().grandparent.as_deref(), Some("n1"));
+
+        sim.partition(&[leaf], &[parent]);
+        sim.send("c1", leaf, json!({"type": "broadcast", "message": 1}))
+            .unwrap();
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        assert!(sim.dropped() > 0);
+        let node_ids: Vec<String> = sim.node_ids().map(str::to_owned).collect();
+        for node_id in &node_ids {
+            assert_eq!(read(&mut sim, node_id), [1], "{node_id}");
+        }
+    }
+
+    fn payload() -> impl Strategy<Value = TreeBroadcastPayload> {
+        let values = || prop::collection::vec(arbitrary::value(), 0..4);
+        prop_oneof![
+            arbitrary::value().prop_map(|message| TreeBroadcastPayload::Broadcast { message }),
+            Just(TreeBroadcastPayload::BroadcastOk {}),
+            Just(TreeBroadcastPayload::Read {}),
+            values().prop_map(|messages| TreeBroadcastPayload::ReadOk { messages }),
+            arbitrary::topology().prop_map(|topology| TreeBroadcastPayload::Topology { topology }),
+            Just(TreeBroadcastPayload::TopologyOk {}),
+            values().prop_map(|messages| TreeBroadcastPayload::Forward { messages }),
+            Just(TreeBroadcastPayload::ForwardOk {}),
+            values().prop_map(|messages| TreeBroadcastPayload::Deliver { messages }),
+            Just(TreeBroadcastPayload::DeliverOk {}),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+
+use crate::kv::{KvError, LIN_KV};
+use crate::log;
+use crate::message::{error_reply, Message};
+use crate::node::{reject, Node, NodeContext};
+use crate::rpc::{RetryPolicy, Rpc};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TsoPayload {
+    Ts {},
+    TsOk { ts: u64 },
+}
+
+/// The lin-kv key holding the last timestamp handed out.
+const KEY: &str = "tso";
+
+/// A timestamp oracle: every timestamp it hands out, on any node, is greater than all those
+/// handed out before it.
+///
+/// The last timestamp lives in `lin-kv`, and each new one is a cas on it, so concurrent
+/// requests on different nodes can't get the same one.
+#[derive(Default)]
+pub struct TsoNode {
+    /// The last timestamp this node knows of. A node that restarts picks it up from lin-kv
+    /// again, so it never goes backwards.
+    last: u64,
+}
+
+impl Node for TsoNode {
+    type Payload = TsoPayload;
+
+    fn init(&mut self, _context: &NodeContext, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        rpc.read_then(LIN_KV, KEY, |node: &mut Self, last, _rpc| {
+            match last {
+                Ok(last) => node.last = node.last.max(serde_json::from_value(last)?),
+                Err(KvError::NotFound) => {}
+                // The first cas reads it anyway.
+                Err(error) => log::warning!("Could not read the last timestamp: {error}"),
+            }
+            Ok(())
+        })
+    }
+
+    fn step(&mut self, message: Message<TsoPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        match message.body.payload {
+            TsoPayload::Ts {} => {
+                let last = self.last;
+                rpc.cas_update_then(
+                    LIN_KV,
+                    KEY,
+                    RetryPolicy::default(),
+                    move |current| current.unwrap_or(0).max(last) + 1,
+                    move |node, ts, rpc| match ts {
+                        Ok(ts) => {
+                            node.last = node.last.max(ts);
+                            rpc.reply(&message, TsoPayload::TsOk { ts })
+                        }
+                        Err(error) => {
+                            rpc.send(&error_reply(&message, error.code(), error.to_string()))
+                        }
+                    },
+                )
+            }
+
+            _ => reject(&message, rpc, "Unsupported message type."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::sim::{NetworkConfig, Simulation};
+
+    #[test]
+    fn concurrent_timestamps_are_unique() {
+        let config = NetworkConfig {
+            max_delay: Duration::from_millis(5),
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(3, config, |_| Ok(TsoNode::default())).unwrap();
+        for client in 0..30 {
+            let node = format!("n{}", client % 3);
+            sim.send(&format!("c{client}"), &node, TsoPayload::Ts {})
+                .unwrap();
+        }
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        let mut timestamps: Vec<u64> = sim
+            .history()
+            .into_iter()
+            .filter(|(_, msg)| msg.body.payload["type"] == "ts_ok")
+            .map(|(_, msg)| msg.body.payload["ts"].as_u64().unwrap())
+            .collect();
+        timestamps.sort_unstable();
+        assert_eq!(timestamps, (1..=30).collect::<Vec<_>>());
+    }
+
+    /// Every timestamp handed out so far.
+    fn handed_out(sim: &Simulation<TsoNode>) -> Vec<u64> {
+        sim.history()
+            .into_iter()
+            .filter(|(_, msg)| msg.body.payload["type"] == "ts_ok")
+            .map(|(_, msg)| msg.body.payload["ts"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn a_timestamp_is_later_than_every_one_handed_out_before_it_was_asked_for() {
+        let config = NetworkConfig {
+            max_delay: Duration::from_millis(5),
+            ..NetworkConfig::default()
+        };
+        let mut sim = Simulation::new(3, config, |_| Ok(TsoNode::default())).unwrap();
+
+        for round in 0..20 {
+            // Racing requests on the other two nodes, still under way.
+            for offset in 1..=2 {
+                let node = format!("n{}", (round + offset) % 3);
+                sim.send(&format!("c{round}-{offset}"), &node, TsoPayload::Ts {})
+                    .unwrap();
+            }
+            let before = handed_out(&sim).into_iter().max().unwrap_or(0);
+
+            let node = format!("n{}", round % 3);
+            let reply = sim
+                .request("c0", &node, TsoPayload::Ts {}, Duration::from_secs(1))
+                .unwrap()
+                .expect("Every request is answered.");
+            let ts = reply.body.payload["ts"].as_u64().unwrap();
+            assert!(ts > before, "round {round}: {ts} after {before}");
+        }
+    }
+
+    fn payload() -> impl Strategy<Value = TsoPayload> {
+        prop_oneof![
+            Just(TsoPayload::Ts {}),
+            any::<u64>().prop_map(|ts| TsoPayload::TsOk { ts }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::{self, IgnoredAny, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+
+use serde_json::Value;
+
+use crate::kv::{KvError, LIN_KV};
+use crate::lock::Held;
+use crate::message::{error_reply, ErrorCode, Message};
+use crate::node::{reject, Node};
+use crate::rpc::Rpc;
+
+/// A transaction micro-op, which Maelstrom encodes as `["r", key, value]` or
+/// `["w", key, value]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnOp {
+    /// `value` is `null` in requests and filled in with what was read in replies. A key never
+    /// written reads as `null` too, which is always written out rather than left off.
+    Read {
+        key: u64,
+        value: Option<u64>,
+    },
+    Write {
+        key: u64,
+        value: u64,
+    },
+}
+
+impl Serialize for TxnOp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            TxnOp::Read { key, value } => ("r", key, value).serialize(serializer),
+            TxnOp::Write { key, value } => ("w", key, value).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TxnOp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(TxnOpVisitor)
+    }
+}
+
+/// Reads a micro-op element by element, so that a missing or extra element and a write without
+/// a value are each reported as such.
+struct TxnOpVisitor;
+
+impl<'de> Visitor<'de> for TxnOpVisitor {
+    type Value = TxnOp;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(r#"a micro-op like ["r", key, null] or ["w", key, value]"#)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<TxnOp, A::Error> {
+        let op: OpKind = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let key = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let value: Option<u64> = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+
+        if seq.next_element::<IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+
+        match (op, value) {
+            (OpKind::Read, value) => Ok(TxnOp::Read { key, value }),
+            (OpKind::Write, Some(value)) => Ok(TxnOp::Write { key, value }),
+            (OpKind::Write, None) => Err(de::Error::custom("write op without a value")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TxnPayload {
+    Txn { txn: Vec<TxnOp> },
+    TxnOk { txn: Vec<TxnOp> },
+}
+
+/// Where the store is kept in `lin-kv`, as one map from key to value.
+const STORE: &str = "txn";
+
+/// The lock every transaction holds while it reads and writes the store.
+const STORE_LOCK: &str = "txn/lock";
+
+/// Read-write transactions against a store shared by all nodes.
+///
+/// Each transaction takes the store's lock, reads the store, applies itself and writes the
+/// store back, so that no other node's transaction lands in between. The write is a cas from
+/// what was read, which turns it away should the lock have been taken over meanwhile as stale.
+#[derive(Default)]
+pub struct TxnNode;
+
+/// `txn` applied to `store`, with its reads filled in.
+fn apply(store: &mut HashMap<u64, u64>, txn: &[TxnOp]) -> Vec<TxnOp> {
+    txn.iter()
+        .map(|op| match *op {
+            TxnOp::Read { key, .. } => TxnOp::Read {
+                key,
+                value: store.get(&key).copied(),
+            },
+            TxnOp::Write { key, value } => {
+                store.insert(key, value);
+                *op
+            }
+        })
+        .collect()
+}
+
+/// A transaction as applied, or why it couldn't be, as told apart from why the lock couldn't
+/// be taken or released.
+type Applied = Result<Vec<TxnOp>, KvError>;
+
+/// Reads the store, applies `txn` and writes the store back, all under `held`.
+fn run(
+    txn: Vec<TxnOp>,
+    held: Held<TxnNode, Applied>,
+    rpc: &mut Rpc<TxnNode>,
+) -> anyhow::Result<()> {
+    rpc.read_then(LIN_KV, STORE, move |_, read, rpc| {
+        let before = match read {
+            Ok(before) => before,
+            Err(KvError::NotFound) => Value::Null,
+            Err(error) => return held.release(rpc, Ok(Err(error))),
+        };
+        let mut store = match serde_json::from_value(before.clone()) {
+            Ok(store) => store,
+            Err(_) if before.is_null() => HashMap::new(),
+            Err(error) => return held.release(rpc, Ok(Err(error.into()))),
+        };
+        let txn = apply(&mut store, &txn);
+        if !txn.iter().any(|op| matches!(op, TxnOp::Write { .. })) {
+            return held.release(rpc, Ok(Ok(txn)));
+        }
+
+        rpc.cas_then(
+            LIN_KV,
+            STORE,
+            &before,
+            store,
+            before.is_null(),
+            move |_, written, rpc| held.release(rpc, Ok(written.map(|()| txn))),
+        )
+    })
+}
+
+impl Node for TxnNode {
+    type Payload = TxnPayload;
+
+    fn step(&mut self, message: Message<TxnPayload>, rpc: &mut Rpc<Self>) -> anyhow::Result<()> {
+        match &message.body.payload {
+            TxnPayload::Txn { txn } => {
+                let txn = txn.clone();
+                rpc.with_lock(
+                    STORE_LOCK,
+                    move |_, held, rpc| run(txn, held, rpc),
+                    move |_, done, rpc| {
+                        let (code, error) = match done {
+                            Ok(Ok(txn)) => return rpc.reply(&message, TxnPayload::TxnOk { txn }),
+                            Ok(Err(error)) if error.is_abort() => {
+                                (ErrorCode::TxnConflict.into(), error)
+                            }
+                            Ok(Err(error)) => (error.code(), error),
+                            // The lock was taken over, which may have been after the write
+                            // went through.
+                            Err(error) if error.is_abort() => (ErrorCode::Crash.into(), error),
+                            Err(error) => (error.code(), error),
+                        };
+                        rpc.send(&error_reply(&message, code, error.to_string()))
+                    },
+                )?;
+            }
+
+            _ => reject(&message, rpc, "Unsupported message type.")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+    use crate::message::arbitrary;
+    use crate::sim::{NetworkConfig, Simulation};
+
+    #[test]
+    fn a_txn_body_comes_back_byte_for_byte() {
+        let text = r#"{"src":"c1","dest":"n0","body":{"msg_id":3,"type":"txn","txn":[["r",1,null],["w",1,6],["r",2,null],["r",1,null]]}}"#;
+        let request: Message<TxnPayload> = serde_json::from_str(text).unwrap();
+        assert_eq!(serde_json::to_string(&request).unwrap(), text);
+
+        let TxnPayload::Txn { txn } = &request.body.payload else {
+            panic!("Not a txn: {request:?}");
+        };
+        let applied = apply(&mut HashMap::from([(2, 4)]), txn);
+        let reply = request.reply(0, TxnPayload::TxnOk { txn: applied });
+        // As asked, but with what each read saw, a key never written reading as null.
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"{"src":"n0","dest":"c1","body":{"msg_id":0,"in_reply_to":3,"type":"txn_ok","txn":[["r",1,null],["w",1,6],["r",2,4],["r",1,6]]}}"#
+        );
+    }
+
+    #[test]
+    fn a_read_of_an_absent_key_replies_with_a_literal_null() {
+        let mut sim = Simulation::new(1, NetworkConfig::default(), |_| Ok(TxnNode)).unwrap();
+        let txn = json!({ "type": "txn", "txn": [["r", 9, null], ["w", 1, 2], ["r", 1, null]] });
+        let reply = sim
+            .request("c1", "n0", txn, Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+
+        let text = serde_json::to_string(&reply.body.payload).unwrap();
+        assert_eq!(
+            text,
+            r#"{"txn":[["r",9,null],["w",1,2],["r",1,2]],"type":"txn_ok"}"#
+        );
+    }
+
+    #[test]
+    fn malformed_micro_ops_are_refused() {
+        for op in [
+            r#"["w",1,null]"#,
+            r#"["r",1]"#,
+            r#"["r",1,null,2]"#,
+            r#"["x",1,2]"#,
+        ] {
+            assert!(serde_json::from_str::<TxnOp>(op).is_err(), "{op}");
+        }
+    }
+
+    #[test]
+    fn transactions_on_different_nodes_apply_one_after_another() {
+        let mut sim = Simulation::new(2, NetworkConfig::default(), |_| Ok(TxnNode)).unwrap();
+        // Each reads the key, then writes its own value, so that each read names the
+        // transaction that ran just before.
+        for value in 1..=6u64 {
+            let node = if value % 2 == 0 { "n0" } else { "n1" };
+            let txn = json!({ "type": "txn", "txn": [["r", 1, null], ["w", 1, value]] });
+            sim.send("c1", node, txn).unwrap();
+        }
+        sim.run_for(Duration::from_secs(5)).unwrap();
+
+        let mut read: Vec<Option<u64>> = sim
+            .history()
+            .into_iter()
+            .filter(|(_, msg)| msg.body.payload["type"] == "txn_ok")
+            .map(|(_, msg)| msg.body.payload["txn"][0][2].as_u64())
+            .collect();
+        assert_eq!(read.len(), 6);
+
+        let last = sim
+            .request(
+                "c2",
+                "n0",
+                json!({ "type": "txn", "txn": [["r", 1, null]] }),
+                Duration::from_secs(1),
+            )
+            .unwrap()
+            .unwrap();
+        read.push(last.body.payload["txn"][0][2].as_u64());
+
+        // Every value but the first was written by one transaction and read by the next.
+        read.sort();
+        let mut expected = vec![None];
+        expected.extend((1..=6).map(Some));
+        assert_eq!(read, expected);
+    }
+
+    fn payload() -> impl Strategy<Value = TxnPayload> {
+        let op = prop_oneof![
+            (any::<u64>(), any::<Option<u64>>())
+                .prop_map(|(key, value)| TxnOp::Read { key, value }),
+            (any::<u64>(), any::<u64>()).prop_map(|(key, value)| TxnOp::Write { key, value }),
+        ];
+        let txn = prop::collection::vec(op, 0..4);
+        prop_oneof![
+            txn.clone().prop_map(|txn| TxnPayload::Txn { txn }),
+            txn.prop_map(|txn| TxnPayload::TxnOk { txn }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}
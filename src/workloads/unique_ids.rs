@@ -0,0 +1,216 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::message::Message;
+use crate::node::{reject, Node, NodeContext};
+use crate::rpc::Rpc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum UniqueIdPayload {
+    Generate {},
+    GenerateOk { id: String },
+}
+
+/// Hands out ids that are unique across the cluster, without coordinating.
+pub struct UniqueIdNode {
+    self_id: String,
+    counter: usize,
+}
+
+impl UniqueIdNode {
+    pub fn new(context: &NodeContext) -> Self {
+        Self {
+            self_id: context.node_id.clone(),
+            counter: 0,
+        }
+    }
+}
+
+impl Node for UniqueIdNode {
+    type Payload = UniqueIdPayload;
+
+    fn step(
+        &mut self,
+        message: Message<UniqueIdPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        match message.body.payload {
+            UniqueIdPayload::Generate {} => {
+                // Node ids are unique within the cluster and the counter never repeats on a
+                // node, so the pair is globally unique.
+                let id = format!("{}-{}", self.self_id, self.counter);
+                rpc.reply(&message, UniqueIdPayload::GenerateOk { id })?;
+
+                self.counter += 1;
+            }
+
+            _ => reject(&message, rpc, "Unsupported message type.")?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum SnowflakeIdPayload {
+    Generate {},
+    GenerateOk { id: u64 },
+}
+
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+
+/// Milliseconds since the Unix epoch at which ids start counting, 2024-01-01T00:00:00Z.
+const EPOCH_MS: u64 = 1_704_067_200_000;
+
+/// Hands out 64-bit ids, Snowflake-style, that also increase on each node.
+///
+/// From the most significant bit down, an id is laid out as:
+///
+/// - 1 bit, always 0, so that ids fit in a signed 64-bit integer,
+/// - 41 bits of milliseconds since the start of 2024, enough for about 69 years,
+/// - 10 bits of the node's index among its cluster, so up to 1024 nodes,
+/// - 12 bits of sequence within the millisecond, so up to 4096 ids per millisecond per node.
+///
+/// A node that runs out of sequence within a millisecond waits for the next one.
+pub struct SnowflakeIdNode {
+    node_index: u64,
+    last_ms: u64,
+    sequence: u64,
+}
+
+impl SnowflakeIdNode {
+    pub fn new(context: &NodeContext) -> anyhow::Result<Self> {
+        let node_index = context.node_index() as u64;
+        if node_index >= 1 << NODE_BITS {
+            bail!(
+                "Snowflake ids only fit {} nodes, not {}.",
+                1 << NODE_BITS,
+                context.node_ids.len()
+            );
+        }
+
+        Ok(Self {
+            node_index,
+            last_ms: 0,
+            sequence: 0,
+        })
+    }
+
+    fn next_id(&mut self) -> u64 {
+        // Never go back in time, even if the wall clock does.
+        let mut ms = now_ms().max(self.last_ms);
+
+        if ms == self.last_ms {
+            self.sequence = (self.sequence + 1) & ((1 << SEQUENCE_BITS) - 1);
+            if self.sequence == 0 {
+                while ms <= self.last_ms {
+                    std::hint::spin_loop();
+                    ms = now_ms();
+                }
+            }
+        } else {
+            self.sequence = 0;
+        }
+        self.last_ms = ms;
+
+        (ms << (NODE_BITS + SEQUENCE_BITS)) | (self.node_index << SEQUENCE_BITS) | self.sequence
+    }
+}
+
+fn now_ms() -> u64 {
+    let since_unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_unix.as_millis() as u64).saturating_sub(EPOCH_MS)
+}
+
+impl Node for SnowflakeIdNode {
+    type Payload = SnowflakeIdPayload;
+
+    fn step(
+        &mut self,
+        message: Message<SnowflakeIdPayload>,
+        rpc: &mut Rpc<Self>,
+    ) -> anyhow::Result<()> {
+        match message.body.payload {
+            SnowflakeIdPayload::Generate {} => {
+                let id = self.next_id();
+                rpc.reply(&message, SnowflakeIdPayload::GenerateOk { id })?;
+            }
+
+            _ => reject(&message, rpc, "Unsupported message type.")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::message::arbitrary;
+
+    fn snowflake(node_id: &str) -> SnowflakeIdNode {
+        let context = NodeContext {
+            node_id: node_id.to_owned(),
+            node_ids: vec!["n0".to_owned(), "n1".to_owned()],
+        };
+        SnowflakeIdNode::new(&context).unwrap()
+    }
+
+    #[test]
+    fn snowflake_ids_increase_on_each_node_and_never_collide() {
+        let mut seen = HashSet::new();
+        for (index, node_id) in ["n0", "n1"].into_iter().enumerate() {
+            let mut node = snowflake(node_id);
+            // More than one millisecond's worth of sequence, however fast this runs.
+            let ids: Vec<u64> = (0..3 * (1 << SEQUENCE_BITS))
+                .map(|_| node.next_id())
+                .collect();
+
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{node_id}");
+            for id in ids {
+                assert_eq!(id >> 63, 0);
+                assert_eq!((id >> SEQUENCE_BITS) & ((1 << NODE_BITS) - 1), index as u64);
+                assert!(seen.insert(id), "{id} handed out twice");
+            }
+        }
+    }
+
+    fn payload() -> impl Strategy<Value = UniqueIdPayload> {
+        prop_oneof![
+            Just(UniqueIdPayload::Generate {}),
+            any::<String>().prop_map(|id| UniqueIdPayload::GenerateOk { id }),
+        ]
+    }
+
+    fn snowflake_payload() -> impl Strategy<Value = SnowflakeIdPayload> {
+        prop_oneof![
+            Just(SnowflakeIdPayload::Generate {}),
+            any::<u64>().prop_map(|id| SnowflakeIdPayload::GenerateOk { id }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn payloads_survive_the_wire(message in arbitrary::message(payload())) {
+            arbitrary::round_trip(&message)?;
+        }
+
+        #[test]
+        fn snowflake_payloads_survive_the_wire(
+            message in arbitrary::message(snowflake_payload())
+        ) {
+            arbitrary::round_trip(&message)?;
+        }
+    }
+}